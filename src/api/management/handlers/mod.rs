@@ -1,4 +1,8 @@
+pub mod orders;
+pub mod payouts;
 pub mod products;
+pub mod promotions;
+pub mod reviews;
 pub mod stores;
 pub mod variants;
 pub mod categories;
\ No newline at end of file
@@ -0,0 +1,7 @@
+mod routes;
+
+use axum::{routing, Router};
+
+pub fn router() -> Router {
+    Router::new().route("/round-money", routing::post(routes::round_orders_money))
+}
@@ -0,0 +1,13 @@
+use crate::{
+    db::{AdminOrderFunctions, AxumDBExtansion},
+    prelude::*,
+};
+use axum::response::IntoResponse;
+use shoppa_core::ResponseBuilder;
+
+/// A one time migration of orders saved before amounts were summed in minor units
+pub async fn round_orders_money(db: AxumDBExtansion) -> HandlerResult {
+    let changed = db.round_orders_money().await?;
+
+    Ok(ResponseBuilder::success(Some(changed), None, None).into_response())
+}
@@ -0,0 +1,11 @@
+mod routes;
+mod types;
+
+use axum::{routing, Router};
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/", routing::post(routes::create_payout))
+        .route("/", routing::get(routes::get_payouts))
+        .route("/:payout_id/paid", routing::patch(routes::mark_payout_paid))
+}
@@ -0,0 +1,87 @@
+use super::types::{CreatePayoutPayload, MarkPayoutPaidPayload, PayoutsQuery};
+use crate::{
+    db::{AxumDBExtansion, AxumLocalDBExtansion, CurrencyFunctions, LedgerFunctions, PayoutStatus},
+    prelude::*,
+};
+use axum::{
+    extract::{Path, Query},
+    response::IntoResponse,
+};
+use bson::oid::ObjectId;
+use shoppa_core::{db::Pagination, extractors::JsonWithValidation, ResponseBuilder};
+
+pub async fn create_payout(
+    db: AxumDBExtansion,
+    local_db: AxumLocalDBExtansion,
+    JsonWithValidation(payload): JsonWithValidation<CreatePayoutPayload>,
+) -> HandlerResult {
+    let currency = match db
+        .get_stores_currency(&[payload.store])
+        .await?
+        .remove(&payload.store)
+    {
+        Some(currency) => currency,
+        None => {
+            return Ok(
+                ResponseBuilder::<()>::error("StoreNotFound", None, None, Some(404))
+                    .into_response(),
+            );
+        }
+    };
+
+    let payout = match local_db.create_payout(&payload.store, currency).await? {
+        Some(payout) => payout,
+        None => {
+            return Ok(
+                ResponseBuilder::<()>::error("NothingToPayOut", None, None, Some(409))
+                    .into_response(),
+            );
+        }
+    };
+
+    Ok(ResponseBuilder::success(Some(payout), None, Some(201)).into_response())
+}
+
+pub async fn get_payouts(
+    local_db: AxumLocalDBExtansion,
+    pagination: Pagination,
+    Query(query): Query<PayoutsQuery>,
+) -> HandlerResult {
+    let payouts = local_db
+        .get_payouts(query.store.as_ref(), Some(pagination))
+        .await?;
+
+    Ok(ResponseBuilder::paginated_response(&payouts).into_response())
+}
+
+pub async fn mark_payout_paid(
+    local_db: AxumLocalDBExtansion,
+    Path(payout_id): Path<ObjectId>,
+    JsonWithValidation(payload): JsonWithValidation<MarkPayoutPaidPayload>,
+) -> HandlerResult {
+    let mut payout = match local_db.get_payout_by_id(&payout_id).await? {
+        Some(payout) => payout,
+        None => {
+            return Ok(
+                ResponseBuilder::<()>::error("PayoutNotFound", None, None, Some(404))
+                    .into_response(),
+            );
+        }
+    };
+
+    if !local_db
+        .mark_payout_paid(&payout, payload.reference.clone())
+        .await?
+    {
+        return Ok(
+            ResponseBuilder::<()>::error("PayoutAlreadyPaid", None, None, Some(409))
+                .into_response(),
+        );
+    }
+
+    payout.status = PayoutStatus::Paid;
+    payout.reference = payload.reference;
+    payout.paid_at = Some(bson::DateTime::now());
+
+    Ok(ResponseBuilder::success(Some(payout), None, None).into_response())
+}
@@ -0,0 +1,17 @@
+use crate::prelude::types::*;
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct CreatePayoutPayload {
+    pub store: ObjectId,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PayoutsQuery {
+    pub store: Option<ObjectId>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct MarkPayoutPaidPayload {
+    #[validate(length(min = 1, max = 128))]
+    pub reference: Option<String>,
+}
@@ -1,6 +1,6 @@
 use super::types;
 use crate::{
-    db::{AdminProductFunctions, AxumDBExtansion},
+    db::{queue_stock_alerts, AdminProductFunctions, AxumDBExtansion, AxumLocalDBExtansion},
     prelude::*,
 };
 use axum::{extract::Path, response::IntoResponse};
@@ -50,6 +50,7 @@ pub async fn add_product_item(
 
 pub async fn edit_product_item(
     db: AxumDBExtansion,
+    local_db: AxumLocalDBExtansion,
     Path((product_id, item_id)): Path<(ObjectId, ObjectId)>,
     JsonWithValidation(payload): JsonWithValidation<types::EditProductItemPayload>,
 ) -> HandlerResult {
@@ -105,6 +106,15 @@ pub async fn edit_product_item(
         )
         .await?;
 
+    if let Some(edited) = &prouct {
+        if queue_stock_alerts(&local_db, &product, edited, &item_id)
+            .await
+            .is_err()
+        {
+            tracing::error!("Failed to queue stock alerts of product {}", product_id);
+        }
+    }
+
     Ok(
         ResponseBuilder::success(prouct, Some("Product item edited successfully"), None)
             .into_response(),
@@ -0,0 +1,11 @@
+mod routes;
+mod types;
+
+use axum::{routing, Router};
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/", routing::post(routes::create_promotion))
+        .route("/", routing::get(routes::get_promotions))
+        .route("/:promotion_id", routing::patch(routes::update_promotion))
+}
@@ -0,0 +1,71 @@
+use super::types::{ManagementCreatePromotionPayload, PromotionsQuery};
+use crate::{
+    db::{AxumLocalDBExtansion, Promotion, PromotionFunctions, UpdatePromotionPayload},
+    prelude::*,
+};
+use axum::{
+    extract::{Path, Query},
+    response::IntoResponse,
+};
+use bson::oid::ObjectId;
+use shoppa_core::{db::Pagination, extractors::JsonWithValidation, ResponseBuilder};
+
+pub async fn create_promotion(
+    local_db: AxumLocalDBExtansion,
+    JsonWithValidation(payload): JsonWithValidation<ManagementCreatePromotionPayload>,
+) -> HandlerResult {
+    if local_db
+        .get_promotion_by_code(&payload.promotion.code)
+        .await?
+        .is_some()
+    {
+        return Ok(
+            ResponseBuilder::<()>::error("CouponCodeTaken", None, None, Some(409)).into_response(),
+        );
+    }
+
+    let promotion = match Promotion::from_payload(payload.promotion, payload.store) {
+        Some(promotion) => promotion,
+        None => {
+            return Ok(
+                ResponseBuilder::<()>::error("InvalidPromotion", None, None, Some(400))
+                    .into_response(),
+            );
+        }
+    };
+
+    local_db.insert_new_promotion(&promotion).await?;
+
+    Ok(ResponseBuilder::success(Some(promotion), None, Some(201)).into_response())
+}
+
+pub async fn get_promotions(
+    local_db: AxumLocalDBExtansion,
+    pagination: Pagination,
+    Query(query): Query<PromotionsQuery>,
+) -> HandlerResult {
+    let promotions = local_db
+        .get_promotions(query.store.as_ref(), Some(pagination))
+        .await?;
+
+    Ok(ResponseBuilder::paginated_response(&promotions).into_response())
+}
+
+pub async fn update_promotion(
+    local_db: AxumLocalDBExtansion,
+    Path(promotion_id): Path<ObjectId>,
+    JsonWithValidation(payload): JsonWithValidation<UpdatePromotionPayload>,
+) -> HandlerResult {
+    let res = local_db
+        .set_promotion_active(&promotion_id, None, payload.active)
+        .await?;
+
+    if res.matched_count == 0 {
+        return Ok(
+            ResponseBuilder::<()>::error("PromotionNotFound", None, None, Some(404))
+                .into_response(),
+        );
+    }
+
+    Ok(ResponseBuilder::<()>::success(None, None, None).into_response())
+}
@@ -0,0 +1,15 @@
+use crate::{db::CreatePromotionPayload, prelude::types::*};
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct ManagementCreatePromotionPayload {
+    #[serde(flatten)]
+    #[validate]
+    pub promotion: CreatePromotionPayload,
+    // None creates a platform wide promotion
+    pub store: Option<ObjectId>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PromotionsQuery {
+    pub store: Option<ObjectId>,
+}
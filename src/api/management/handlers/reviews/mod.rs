@@ -0,0 +1,9 @@
+mod routes;
+
+use axum::{routing, Router};
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/", routing::get(routes::get_reviews))
+        .route("/:review_id", routing::patch(routes::update_review))
+}
@@ -0,0 +1,41 @@
+use crate::{
+    db::{
+        refresh_product_rating, AxumDBExtansion, AxumLocalDBExtansion, ReviewFunctions,
+        UpdateReviewPayload,
+    },
+    prelude::*,
+};
+use axum::{extract::Path, response::IntoResponse};
+use bson::oid::ObjectId;
+use shoppa_core::{db::Pagination, extractors::JsonWithValidation, ResponseBuilder};
+
+pub async fn get_reviews(local_db: AxumLocalDBExtansion, pagination: Pagination) -> HandlerResult {
+    let reviews = local_db.get_reviews(None, Some(pagination)).await?;
+
+    Ok(ResponseBuilder::paginated_response(&reviews).into_response())
+}
+
+pub async fn update_review(
+    db: AxumDBExtansion,
+    local_db: AxumLocalDBExtansion,
+    Path(review_id): Path<ObjectId>,
+    JsonWithValidation(payload): JsonWithValidation<UpdateReviewPayload>,
+) -> HandlerResult {
+    let review = match local_db
+        .set_review_hidden(&review_id, payload.hidden)
+        .await?
+    {
+        Some(review) => review,
+        None => {
+            return Ok(
+                ResponseBuilder::<()>::error("ReviewNotFound", None, None, Some(404))
+                    .into_response(),
+            );
+        }
+    };
+
+    // hidden reviews don't count towards the product rating
+    refresh_product_rating(&db, &local_db, &review.product).await?;
+
+    Ok(ResponseBuilder::success(Some(review), None, None).into_response())
+}
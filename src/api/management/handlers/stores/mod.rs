@@ -27,5 +27,18 @@ pub fn router() -> Router {
             "/:store_oid/locations/:location_oid",
             routing::patch(routes::update_store_location),
         )
+        .route(
+            "/:store_oid/commission",
+            routing::get(routes::get_store_commission),
+        )
+        .route(
+            "/:store_oid/commission/rates",
+            routing::post(routes::add_store_commission_rate),
+        )
+        .route(
+            "/:store_oid/commission/categories",
+            routing::post(routes::add_store_category_commission),
+        )
+        .route("/:store_oid/tax", routing::put(routes::set_store_tax_terms))
         .route("/", routing::get(routes::get_stores))
 }
@@ -1,6 +1,10 @@
 use super::types;
 use crate::{
-    db::{AdminStoreFunctions, AxumDBExtansion},
+    db::{
+        commission_effective_from, AddCategoryCommissionPayload, AddCommissionRatePayload,
+        AdminStoreFunctions, AxumDBExtansion, CategoryCommissionRate, CommissionFunctions,
+        CommissionRate, StoreTaxTerms, TaxFunctions,
+    },
     helpers::types::AxumStorgeClientExtension,
     prelude::*,
 };
@@ -243,3 +247,126 @@ pub async fn get_stores(db: AxumDBExtansion, pagination: Pagination) -> HandlerR
 
     Ok(ResponseBuilder::paginated_response(&stores).into_response())
 }
+
+pub async fn get_store_commission(
+    db: AxumDBExtansion,
+    Path(store_id): Path<ObjectId>,
+) -> HandlerResult {
+    let commission = db.get_store_commission(&store_id).await?;
+
+    if commission.is_none() {
+        return Ok(
+            ResponseBuilder::<u16>::error("", None, Some("store not found"), Some(400))
+                .into_response(),
+        );
+    }
+
+    Ok(ResponseBuilder::success(commission, None, None).into_response())
+}
+
+pub async fn add_store_commission_rate(
+    db: AxumDBExtansion,
+    Path(store_id): Path<ObjectId>,
+    JsonWithValidation(payload): JsonWithValidation<AddCommissionRatePayload>,
+) -> HandlerResult {
+    let effective_from = match commission_effective_from(payload.effective_from) {
+        Some(effective_from) => effective_from,
+        None => {
+            return Ok(ResponseBuilder::<()>::error(
+                "CommissionEffectiveInPast",
+                None,
+                None,
+                Some(400),
+            )
+            .into_response());
+        }
+    };
+
+    let rate = CommissionRate {
+        percent: payload.percent,
+        fixed_fee: payload.fixed_fee,
+        effective_from,
+    };
+
+    if db
+        .add_store_commission_rate(&store_id, &rate)
+        .await?
+        .is_none()
+    {
+        return Ok(
+            ResponseBuilder::<u16>::error("", None, Some("store not found"), Some(400))
+                .into_response(),
+        );
+    }
+
+    let commission = db.get_store_commission(&store_id).await?;
+
+    Ok(ResponseBuilder::success(commission, None, None).into_response())
+}
+
+pub async fn add_store_category_commission(
+    db: AxumDBExtansion,
+    Path(store_id): Path<ObjectId>,
+    JsonWithValidation(payload): JsonWithValidation<AddCategoryCommissionPayload>,
+) -> HandlerResult {
+    let effective_from = match commission_effective_from(payload.effective_from) {
+        Some(effective_from) => effective_from,
+        None => {
+            return Ok(ResponseBuilder::<()>::error(
+                "CommissionEffectiveInPast",
+                None,
+                None,
+                Some(400),
+            )
+            .into_response());
+        }
+    };
+
+    if db
+        .get_category_by_id(&payload.category, None, None, None)
+        .await?
+        .is_none()
+    {
+        return Ok(
+            ResponseBuilder::<()>::error("CategoryNotFound", None, None, Some(404)).into_response(),
+        );
+    }
+
+    let rate = CategoryCommissionRate {
+        category: payload.category,
+        percent: payload.percent,
+        effective_from,
+    };
+
+    if db
+        .add_store_category_commission(&store_id, &rate)
+        .await?
+        .is_none()
+    {
+        return Ok(
+            ResponseBuilder::<u16>::error("", None, Some("store not found"), Some(400))
+                .into_response(),
+        );
+    }
+
+    let commission = db.get_store_commission(&store_id).await?;
+
+    Ok(ResponseBuilder::success(commission, None, None).into_response())
+}
+
+pub async fn set_store_tax_terms(
+    db: AxumDBExtansion,
+    Path(store_id): Path<ObjectId>,
+    JsonWithValidation(payload): JsonWithValidation<StoreTaxTerms>,
+) -> HandlerResult {
+    let store = db.set_store_tax_terms(&store_id, &payload).await?;
+
+    if store.is_none() {
+        return Ok(
+            ResponseBuilder::<u16>::error("", None, Some("store not found"), Some(400))
+                .into_response(),
+        );
+    }
+
+    Ok(ResponseBuilder::success(store, None, None).into_response())
+}
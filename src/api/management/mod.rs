@@ -9,6 +9,10 @@ pub fn router() -> Router {
     return Router::new()
         .nest("/stores", handlers::stores::router())
         .nest("/products", handlers::products::router())
+        .nest("/promotions", handlers::promotions::router())
+        .nest("/reviews", handlers::reviews::router())
+        .nest("/orders", handlers::orders::router())
+        .nest("/payouts", handlers::payouts::router())
         .nest("/variants", handlers::variants::router())
         .nest("/categories", handlers::categories::router());
 }
@@ -1,4 +1,5 @@
 pub mod invoices;
 pub mod management;
+pub mod payments;
 pub mod stores;
 pub mod v1;
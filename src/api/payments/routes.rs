@@ -0,0 +1,180 @@
+use super::types;
+use crate::{
+    db::{
+        AxumDBExtansion, AxumLocalDBExtansion, ChargeCurrency, CurrencyFunctions,
+        DiscountFunctions, OrderPaymentStatus, PaymentEvent, PaymentEventOutcome,
+        PaymentStateFunctions,
+    },
+    helpers::{
+        security::verify_payload_signature,
+        types::{AxumEmailClientExtension, AxumInvoiceClientExtension, AxumStorgeClientExtension},
+    },
+    payments,
+    prelude::*,
+    workers,
+};
+use axum::{http::HeaderMap, response::IntoResponse};
+use bson::doc;
+use bytes::Bytes;
+use serde_json::json;
+use shoppa_core::{
+    db::models::{DBModel, Order},
+    ResponseBuilder,
+};
+use validator::Validate;
+
+// Hex encoded HMAC-SHA256 of the raw body, signed with PAYMENT_WEBHOOK_SECRET
+const SIGNATURE_HEADER: &str = "x-shoppa-signature";
+
+pub async fn payment_webhook(
+    db: AxumDBExtansion,
+    local_db: AxumLocalDBExtansion,
+    storage_client: AxumStorgeClientExtension,
+    invoice_client: AxumInvoiceClientExtension,
+    email_client: AxumEmailClientExtension,
+    headers: HeaderMap,
+    body: Bytes,
+) -> HandlerResult {
+    let signature = headers
+        .get(SIGNATURE_HEADER)
+        .and_then(|signature| signature.to_str().ok())
+        .unwrap_or_default();
+
+    if !verify_payload_signature(&ENV_VARS.PAYMENT_WEBHOOK_SECRET, &body, signature) {
+        return Ok(
+            ResponseBuilder::<()>::error("InvalidSignature", None, None, Some(401)).into_response(),
+        );
+    }
+
+    let payload: types::PaymentWebhookPayload = match serde_json::from_slice(&body) {
+        Ok(payload) => payload,
+        Err(_) => {
+            return Ok(
+                ResponseBuilder::<()>::error("InvalidPayload", None, None, Some(400))
+                    .into_response(),
+            );
+        }
+    };
+
+    // pending and declined are set by the checkout only
+    if payload.validate().is_err()
+        || matches!(
+            payload.status,
+            OrderPaymentStatus::Pending | OrderPaymentStatus::Declined
+        )
+    {
+        return Ok(
+            ResponseBuilder::<()>::error("InvalidPayload", None, None, Some(400)).into_response(),
+        );
+    }
+
+    let order = match db
+        .get_order(
+            doc! {
+                Order::fields().order_number: &payload.order_number
+            },
+            None,
+            None,
+            None,
+        )
+        .await?
+    {
+        Some(order) => order,
+        None => {
+            return Ok(
+                ResponseBuilder::<()>::error("OrderNotFound", None, None, Some(404))
+                    .into_response(),
+            );
+        }
+    };
+
+    let order_id = order.id()?.clone();
+    let status = payload.status;
+    let event_id = payload.event_id.clone();
+    let amount = payload.amount;
+
+    let outcome = db
+        .apply_payment_event(
+            &order_id,
+            PaymentEvent::new(
+                payload.event_id,
+                payload.status,
+                payload.amount,
+                payload.reason,
+            ),
+        )
+        .await?;
+
+    // A redelivery of the event is handled again, a previous delivery
+    // could have failed halfway and every step is only done once
+    let current = match outcome {
+        PaymentEventOutcome::Applied => true,
+        PaymentEventOutcome::Duplicate => db
+            .get_order_payment_state(&order_id)
+            .await?
+            .map(|state| state.status == status)
+            .unwrap_or(false),
+        PaymentEventOutcome::Ignored => false,
+    };
+
+    if current {
+        match status {
+            // The payment was approved, it's recorded the same way the checkout records it,
+            // by whichever of them is first, so the post payment steps run once
+            OrderPaymentStatus::Authorized | OrderPaymentStatus::Captured => {
+                let discount = db.get_order_discount(&order_id).await?;
+                let charge_currency = db
+                    .get_order_currency(&order_id)
+                    .await?
+                    .unwrap_or_else(ChargeCurrency::platform);
+
+                let part_amounts = payments::order_part_amounts(
+                    &order,
+                    discount.as_ref(),
+                    charge_currency.currency,
+                );
+
+                let res = db
+                    .record_order_payment(&order_id, status, &part_amounts)
+                    .await?;
+
+                if res.modified_count == 1 {
+                    tokio::spawn(workers::process_post_payment_job(
+                        db.0.clone(),
+                        local_db.0.clone(),
+                        storage_client.0.clone(),
+                        invoice_client.0.clone(),
+                        email_client.0.clone(),
+                        order_id,
+                    ));
+                }
+            }
+            OrderPaymentStatus::Voided => {
+                tracing::info!("Payment of order {} was voided", order.order_number);
+            }
+            // a failed or charged back payment is taken back below
+            OrderPaymentStatus::Failed
+            | OrderPaymentStatus::ChargedBack
+            | OrderPaymentStatus::Pending
+            | OrderPaymentStatus::Declined => {}
+        }
+    }
+
+    if current
+        && matches!(
+            status,
+            OrderPaymentStatus::Failed | OrderPaymentStatus::ChargedBack
+        )
+    {
+        tracing::warn!(
+            "Payment of order {} was taken back ({})",
+            order.order_number,
+            status
+        );
+
+        payments::reverse_order_payment(&db, &local_db, &order_id, &event_id, status, amount)
+            .await?;
+    }
+
+    Ok(ResponseBuilder::success(Some(json!({ "outcome": outcome })), None, None).into_response())
+}
@@ -0,0 +1,14 @@
+use crate::{db::OrderPaymentStatus, prelude::types::*};
+
+#[derive(Deserialize, Validate)]
+pub struct PaymentWebhookPayload {
+    #[validate(length(min = 1, max = 128))]
+    pub event_id: String,
+    #[validate(length(min = 1))]
+    pub order_number: String,
+    pub status: OrderPaymentStatus,
+    // The charged back amount, a partial chargeback takes back only its share
+    #[validate(range(min = 0.0))]
+    pub amount: Option<f64>,
+    pub reason: Option<String>,
+}
@@ -0,0 +1,9 @@
+use axum::{routing, Router};
+mod routes;
+mod types;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/", routing::get(routes::get_ledger_summary))
+        .route("/payouts", routing::get(routes::get_payouts))
+}
@@ -0,0 +1,39 @@
+use super::super::super::middlewares::CurrentUser;
+use super::types;
+use crate::{
+    db::{AxumDBExtansion, AxumLocalDBExtansion, CurrencyFunctions, LedgerFunctions},
+    prelude::*,
+};
+use axum::{extract::Query, response::IntoResponse};
+use shoppa_core::{db::Pagination, ResponseBuilder};
+
+pub async fn get_ledger_summary(
+    db: AxumDBExtansion,
+    local_db: AxumLocalDBExtansion,
+    current_user: CurrentUser,
+    Query(query): Query<types::LedgerSummaryQuery>,
+) -> HandlerResult {
+    let currency = db
+        .get_stores_currency(&[current_user.store_id])
+        .await?
+        .remove(&current_user.store_id)
+        .unwrap_or_default();
+
+    let summary = local_db
+        .get_store_ledger_summary(&current_user.store_id, currency, query.from, query.to)
+        .await?;
+
+    Ok(ResponseBuilder::success(Some(summary), None, None).into_response())
+}
+
+pub async fn get_payouts(
+    local_db: AxumLocalDBExtansion,
+    current_user: CurrentUser,
+    pagination: Pagination,
+) -> HandlerResult {
+    let payouts = local_db
+        .get_payouts(Some(&current_user.store_id), Some(pagination))
+        .await?;
+
+    Ok(ResponseBuilder::paginated_response(&payouts).into_response())
+}
@@ -0,0 +1,7 @@
+use crate::prelude::types::*;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct LedgerSummaryQuery {
+    pub from: Option<chrono::NaiveDate>,
+    pub to: Option<chrono::NaiveDate>,
+}
@@ -1,9 +1,12 @@
 pub mod invoices;
+pub mod ledger;
 pub mod login;
 pub mod logout;
 pub mod me;
 pub mod orders;
 pub mod products;
+pub mod promotions;
 pub mod registration;
+pub mod reviews;
 pub mod store;
 pub mod variants;
@@ -6,5 +6,9 @@ pub fn router() -> Router {
     Router::new()
         .route("/:order_oid", routing::patch(routes::update_order))
         .route("/:order_oid", routing::get(routes::get_order))
+        .route(
+            "/:order_oid/refunds",
+            routing::post(routes::refund_order_part),
+        )
         .route("/", routing::get(routes::get_orders))
 }
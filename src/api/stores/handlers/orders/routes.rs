@@ -1,16 +1,33 @@
 use super::super::super::middlewares::CurrentUser;
 use super::types;
 use crate::{
-    db::{AxumDBExtansion, OrderFunctions},
+    db::{
+        AxumDBExtansion, AxumLocalDBExtansion, ChargeCurrency, CurrencyFunctions,
+        DiscountFunctions, LedgerFunctions, LedgerTransaction, OrderFunctions,
+        OrderPartPaymentStatus, PaymentStateFunctions, ProductFunctions, RefundFunctions,
+        StoreRefund, StoreRefundStatus,
+    },
+    emails::CustomerEmailFunctions,
+    helpers::{
+        money::Money,
+        types::{AxumEmailClientExtension, AxumPaymentClientExtension},
+    },
+    payments::{self, RefundCreditCard, RefundPlanError, RefundResult, RefundablePartItem},
     prelude::*,
+    tokens::ORDER_EMAIL_TOKEN_MANAGER,
 };
 use axum::{
     extract::{Path, Query},
     response::IntoResponse,
 };
-use bson::{doc, oid::ObjectId};
+use bson::oid::ObjectId;
 use shoppa_core::{
-    db::{models::Order, Pagination},
+    db::{
+        models::OrderPartStatus,
+        populate::{FieldPopulate, OrderPopulate},
+        Pagination,
+    },
+    email_sender::EmailClient,
     extractors::JsonWithValidation,
     ResponseBuilder,
 };
@@ -50,22 +67,415 @@ pub async fn get_order(
 
 pub async fn update_order(
     db: AxumDBExtansion,
+    local_db: AxumLocalDBExtansion,
+    payment_client: AxumPaymentClientExtension,
+    email_client: AxumEmailClientExtension,
     current_user: CurrentUser,
     Path(order_oid): Path<ObjectId>,
     JsonWithValidation(payload): JsonWithValidation<types::UpdateOrderStatusPayload>,
 ) -> HandlerResult {
-    let filters = doc! {
-        Order::fields().id: order_oid,
-        Order::fields().parts(true).store: current_user.store_id,
+    let new_status = payload.status.to_string();
+
+    db.check_order_part_status_transition(&order_oid, &current_user.store_id, &payload.status)
+        .await?;
+
+    // An authorized payment is captured for the part before it's marked as shipped,
+    // and released before it's marked as canceled, so a part never has the new status
+    // without its payment. A failed capture is retried by the authorizations worker
+    match payload.status {
+        OrderPartStatus::Shipped => {
+            payments::capture_order_part(
+                &db,
+                payment_client.as_ref(),
+                &order_oid,
+                &current_user.store_id,
+            )
+            .await?;
+        }
+        OrderPartStatus::Canceled => {
+            payments::void_order_part(
+                &db,
+                &local_db,
+                payment_client.as_ref(),
+                &order_oid,
+                &current_user.store_id,
+                OrderPartPaymentStatus::Voided,
+            )
+            .await?;
+        }
+        _ => {}
+    }
+
+    let order = db
+        .update_order_part_status(
+            &order_oid,
+            &current_user.store_id,
+            payload.status,
+            &current_user.user_id,
+            payload.note,
+        )
+        .await?;
+
+    send_order_status_email(
+        &db,
+        &email_client,
+        &order_oid,
+        &current_user.store_id,
+        new_status,
+    )
+    .await;
+
+    Ok(ResponseBuilder::success(Some(order), None, None).into_response())
+}
+
+// The status is already changed, so a failure here is logged and doesn't fail the request
+async fn send_order_status_email(
+    db: &AxumDBExtansion,
+    email_client: &EmailClient,
+    order_id: &ObjectId,
+    store_id: &ObjectId,
+    status: String,
+) {
+    if ENV_VARS.ORDER_STATUS_TEMPLATE_ID.is_empty() {
+        return;
+    }
+
+    let populate = OrderPopulate {
+        stores: FieldPopulate::Field,
+        products: FieldPopulate::None,
+        user: FieldPopulate::Field,
+        options: None,
     };
 
-    let update = doc! {
-        "$set": {
-            "parts.$.status": payload.status.to_string(),
+    let order = match db
+        .get_order_by_id(order_id, None, Some(populate), None)
+        .await
+    {
+        Ok(Some(order)) => order,
+        _ => {
+            tracing::error!("Failed to get order {} for its status email", order_id);
+            return;
         }
     };
 
-    let order = db.update_order(filters, update, None, None).await?;
+    let store_name = order
+        .parts
+        .iter()
+        .find(|part| part.store.ref_doc_id() == store_id)
+        .and_then(|part| part.store.as_populated())
+        .map(|store| store.name.clone())
+        .unwrap_or_default();
 
-    Ok(ResponseBuilder::success(Some(order), None, None).into_response())
+    let customer_name = order
+        .user
+        .as_populated()
+        .and_then(|user| user.name.clone())
+        .unwrap_or_default();
+
+    let status_token = match ORDER_EMAIL_TOKEN_MANAGER.generate_urlsafe_token(&order, None) {
+        Ok(token) => token,
+        Err(_) => {
+            tracing::error!("Failed to create status token of order {}", order_id);
+            return;
+        }
+    };
+
+    let email = email_client
+        .order_status_email(
+            customer_name.clone(),
+            order.order_number.clone(),
+            store_name,
+            status,
+            status_token,
+        )
+        .add_to((order.info.email.clone(), customer_name).into())
+        .build();
+
+    if email_client.send(email).await.is_err() {
+        tracing::error!("Failed to send status email of order {}", order_id);
+    }
+}
+
+pub async fn refund_order_part(
+    db: AxumDBExtansion,
+    local_db: AxumLocalDBExtansion,
+    payment_client: AxumPaymentClientExtension,
+    current_user: CurrentUser,
+    Path(order_oid): Path<ObjectId>,
+    JsonWithValidation(payload): JsonWithValidation<types::RefundOrderPartPayload>,
+) -> HandlerResult {
+    if !payment_client.supports_refunds() {
+        return Ok(
+            ResponseBuilder::<()>::error("RefundsNotSupported", None, None, Some(400))
+                .into_response(),
+        );
+    }
+
+    let order = match db.get_order_by_id(&order_oid, None, None, None).await? {
+        Some(order) => order,
+        None => {
+            return Ok(
+                ResponseBuilder::<()>::error("Order not found", None, None, Some(404))
+                    .into_response(),
+            );
+        }
+    };
+
+    let part = match order
+        .parts
+        .iter()
+        .find(|part| part.store.ref_doc_id() == &current_user.store_id)
+    {
+        Some(part) => part,
+        None => {
+            return Ok(
+                ResponseBuilder::<()>::error("Order not found", None, None, Some(404))
+                    .into_response(),
+            );
+        }
+    };
+
+    // The refund is in the currency the order was charged in
+    let charge_currency = db
+        .get_order_currency(&order_oid)
+        .await?
+        .unwrap_or_else(ChargeCurrency::platform);
+
+    let charged = charge_currency.currency;
+
+    // Only captured money can be refunded, an uncaptured part is canceled instead
+    let part_payment = db
+        .get_order_part_payments(&order_oid)
+        .await?
+        .into_iter()
+        .find(|entry| entry.store == current_user.store_id);
+
+    let captured = match part_payment {
+        Some(entry) => {
+            if entry.payment.status != OrderPartPaymentStatus::Captured {
+                return Ok(ResponseBuilder::<()>::error(
+                    "OrderPartNotCaptured",
+                    None,
+                    None,
+                    Some(409),
+                )
+                .into_response());
+            }
+
+            Money::from_major(entry.payment.amount, charged)
+        }
+        // orders from before part payments were charged in full,
+        // without the store share of the order discount
+        None => {
+            let part_discount = db
+                .get_order_discount(&order_oid)
+                .await?
+                .map(|discount| discount.for_store(&current_user.store_id, charged))
+                .unwrap_or(Money::zero(charged));
+
+            Money::from_major(part.total, charged) - part_discount
+        }
+    };
+
+    let refunds = db.get_order_refunds(&order_oid).await?;
+
+    let part_refunds: Vec<&StoreRefund> = refunds
+        .iter()
+        .filter(|refund| refund.store == current_user.store_id && refund.is_counted())
+        .collect();
+
+    let part_items: Vec<RefundablePartItem> = part
+        .items
+        .iter()
+        .map(|item| RefundablePartItem {
+            product: item.product_id().clone(),
+            item_id: item.item_id.clone(),
+            quantity: item.quantity,
+            price: Money::from_major(item.price, charged),
+        })
+        .collect();
+
+    let requested = payload.items.map(|items| {
+        items
+            .into_iter()
+            .map(|item| (item.item_id, item.quantity))
+            .collect()
+    });
+
+    let plan = match payments::plan_refund(&part_items, captured, &part_refunds, requested) {
+        Ok(plan) => plan,
+        Err(RefundPlanError::AlreadyRefunded) => {
+            return Ok(ResponseBuilder::<()>::error(
+                "Order part was already fully refunded",
+                None,
+                None,
+                None,
+            )
+            .into_response());
+        }
+        Err(RefundPlanError::Items(errors)) => {
+            return Ok(ResponseBuilder::error("", Some(errors), None, None).into_response());
+        }
+    };
+
+    // the ledger is in the store currency, by the rate the order was charged at
+    let store_currency = db
+        .get_stores_currency(&[current_user.store_id])
+        .await?
+        .remove(&current_user.store_id)
+        .unwrap_or_default();
+
+    let ledger_amount = Money::from_major(
+        charge_currency.to_store(plan.amount.to_major(), store_currency)?,
+        store_currency,
+    );
+
+    let mut refund = StoreRefund::new(
+        current_user.store_id,
+        plan.items,
+        plan.amount.to_major(),
+        payload.reason,
+        current_user.user_id,
+    );
+
+    if !db
+        .insert_pending_refund(&order_oid, &refund, refunds.len())
+        .await?
+    {
+        return Ok(ResponseBuilder::<()>::error(
+            "Order refunds changed, please try again",
+            None,
+            None,
+            Some(409),
+        )
+        .into_response());
+    }
+
+    let refund_res = payment_client
+        .refund_credit_card(RefundCreditCard {
+            order_number: order.order_number.clone(),
+            token: order.transaction.token.clone(),
+            amount: refund.amount,
+            currency_code: Some(charged.to_string()),
+        })
+        .await;
+
+    let refund_info = match refund_res {
+        Ok(RefundResult::Success(info)) => info,
+        Ok(RefundResult::Failure(err)) => {
+            if db.fail_refund(&order_oid, &refund.id, &err).await.is_err() {
+                tracing::error!("Failed to save failed refund {}", refund.id);
+            }
+
+            return Ok(ResponseBuilder::<()>::error(
+                "Failed to refund",
+                None,
+                Some(&err),
+                Some(400),
+            )
+            .into_response());
+        }
+        Err(e) => {
+            if db
+                .fail_refund(&order_oid, &refund.id, "Refund request failed")
+                .await
+                .is_err()
+            {
+                tracing::error!("Failed to save failed refund {}", refund.id);
+            }
+
+            return Err(e);
+        }
+    };
+
+    // The credit note document needs a credit note invoice type in the invoice service,
+    // until then only its number is kept on the refund
+    let credit_note_number = format!(
+        "CN-{}",
+        db.get_and_increase_counter_by1("credit_note", None)
+            .await?
+            .value
+    );
+
+    // From here on the money is back with the customer, so any failure is logged
+    // with the refund id to be completed manually
+    let mut db_session = db.start_session().await?;
+
+    if db_session.start_transaction(None).await.is_err() {
+        tracing::error!(
+            "Failed to save refund {} of order {}",
+            refund.id,
+            order.order_number
+        );
+        return Ok(
+            ResponseBuilder::<()>::error("Failed to save refund", None, None, Some(500))
+                .into_response(),
+        );
+    }
+
+    if let Err(e) = db
+        .complete_refund(
+            &order_oid,
+            &refund,
+            refund_info.reference.clone(),
+            credit_note_number.clone(),
+            plan.left.to_major(),
+            Some(&mut db_session),
+        )
+        .await
+    {
+        tracing::error!(
+            "Failed to save refund {} of order {}",
+            refund.id,
+            order.order_number
+        );
+        let _ = db_session.abort_transaction().await;
+        return Err(e);
+    }
+
+    // restocking the refunded items
+    for item in &refund.items {
+        if let Err(e) = db
+            .release_product_item_stock(
+                &item.product,
+                &item.item_id,
+                item.quantity as i64,
+                Some(&mut db_session),
+            )
+            .await
+        {
+            tracing::error!(
+                "Failed to save refund {} of order {}",
+                refund.id,
+                order.order_number
+            );
+            let _ = db_session.abort_transaction().await;
+            return Err(e);
+        }
+    }
+
+    db.commit_transaction(&mut db_session, Some(16)).await?;
+
+    if local_db
+        .insert_ledger_transaction(&LedgerTransaction::refund(
+            order_oid,
+            current_user.store_id,
+            &refund.id,
+            ledger_amount,
+        ))
+        .await
+        .is_err()
+    {
+        tracing::error!(
+            "Failed to write ledger transaction of refund {} of order {}",
+            refund.id,
+            order.order_number
+        );
+    }
+
+    refund.status = StoreRefundStatus::Done;
+    refund.provider_reference = Some(refund_info.reference);
+    refund.credit_note_number = Some(credit_note_number);
+
+    Ok(ResponseBuilder::success(Some(refund), None, Some(201)).into_response())
 }
@@ -5,6 +5,8 @@ use validator::Validate;
 #[derive(Deserialize, Serialize, Debug, Clone, Validate)]
 pub struct UpdateOrderStatusPayload {
     pub status: OrderPartStatus,
+    #[validate(length(max = 500))]
+    pub note: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -14,3 +16,19 @@ pub struct OrdersQuery {
     pub status: Option<OrderPartStatus>,
     pub utm: Option<String>,
 }
+
+#[derive(Deserialize, Serialize, Debug, Clone, Validate)]
+pub struct RefundItemPayload {
+    pub item_id: ObjectId,
+    #[validate(range(min = 1))]
+    pub quantity: u32,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Validate)]
+pub struct RefundOrderPartPayload {
+    // When missing, everything that is left of the store part is refunded
+    #[validate]
+    pub items: Option<Vec<RefundItemPayload>>,
+    #[validate(length(max = 500))]
+    pub reason: Option<String>,
+}
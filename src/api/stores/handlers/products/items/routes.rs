@@ -1,7 +1,7 @@
 use super::super::super::super::middlewares::CurrentUser;
 use super::types;
 use crate::{
-    db::{AxumDBExtansion, StoreProductFunctions},
+    db::{queue_stock_alerts, AxumDBExtansion, AxumLocalDBExtansion, StoreProductFunctions},
     prelude::*,
 };
 use axum::{extract::Path, response::IntoResponse};
@@ -52,6 +52,7 @@ pub async fn add_product_item(
 
 pub async fn edit_product_item(
     db: AxumDBExtansion,
+    local_db: AxumLocalDBExtansion,
     current_user: CurrentUser,
     Path((product_id, item_id)): Path<(ObjectId, ObjectId)>,
     JsonWithValidation(payload): JsonWithValidation<types::EditProductItemPayload>,
@@ -95,7 +96,7 @@ pub async fn edit_product_item(
         .return_document(Some(mongodb::options::ReturnDocument::After))
         .build();
 
-    let product = db
+    let edited = db
         .edit_product_item(
             &product_id,
             &current_user.store_id,
@@ -111,15 +112,25 @@ pub async fn edit_product_item(
         )
         .await?;
 
-    if product.is_none() {
+    if edited.is_none() {
         return Ok(
             ResponseBuilder::error("", Some(""), Some("product item not found"), Some(404))
                 .into_response(),
         );
     };
 
+    if let Some(edited) = &edited {
+        // the edit is saved, a failure only means the alerts of this change are not sent
+        if queue_stock_alerts(&local_db, &product, edited, &item_id)
+            .await
+            .is_err()
+        {
+            tracing::error!("Failed to queue stock alerts of product {}", product_id);
+        }
+    }
+
     Ok(
-        ResponseBuilder::success(product, Some("Product item edited successfully"), None)
+        ResponseBuilder::success(edited, Some("Product item edited successfully"), None)
             .into_response(),
     )
 }
@@ -0,0 +1,9 @@
+use axum::{routing, Router};
+mod routes;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/", routing::post(routes::create_promotion))
+        .route("/", routing::get(routes::get_promotions))
+        .route("/:promotion_id", routing::patch(routes::update_promotion))
+}
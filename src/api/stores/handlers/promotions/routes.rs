@@ -0,0 +1,74 @@
+use super::super::super::middlewares::CurrentUser;
+use crate::{
+    db::{
+        AxumLocalDBExtansion, CreatePromotionPayload, Promotion, PromotionFunctions,
+        UpdatePromotionPayload,
+    },
+    prelude::*,
+};
+use axum::{extract::Path, response::IntoResponse};
+use bson::oid::ObjectId;
+use shoppa_core::{db::Pagination, extractors::JsonWithValidation, ResponseBuilder};
+
+pub async fn create_promotion(
+    local_db: AxumLocalDBExtansion,
+    current_user: CurrentUser,
+    JsonWithValidation(payload): JsonWithValidation<CreatePromotionPayload>,
+) -> HandlerResult {
+    if local_db
+        .get_promotion_by_code(&payload.code)
+        .await?
+        .is_some()
+    {
+        return Ok(
+            ResponseBuilder::<()>::error("CouponCodeTaken", None, None, Some(409)).into_response(),
+        );
+    }
+
+    // store promotions only apply to the store part of the cart
+    let promotion = match Promotion::from_payload(payload, Some(current_user.store_id)) {
+        Some(promotion) => promotion,
+        None => {
+            return Ok(
+                ResponseBuilder::<()>::error("InvalidPromotion", None, None, Some(400))
+                    .into_response(),
+            );
+        }
+    };
+
+    local_db.insert_new_promotion(&promotion).await?;
+
+    Ok(ResponseBuilder::success(Some(promotion), None, Some(201)).into_response())
+}
+
+pub async fn get_promotions(
+    local_db: AxumLocalDBExtansion,
+    current_user: CurrentUser,
+    pagination: Pagination,
+) -> HandlerResult {
+    let promotions = local_db
+        .get_promotions(Some(&current_user.store_id), Some(pagination))
+        .await?;
+
+    Ok(ResponseBuilder::paginated_response(&promotions).into_response())
+}
+
+pub async fn update_promotion(
+    local_db: AxumLocalDBExtansion,
+    current_user: CurrentUser,
+    Path(promotion_id): Path<ObjectId>,
+    JsonWithValidation(payload): JsonWithValidation<UpdatePromotionPayload>,
+) -> HandlerResult {
+    let res = local_db
+        .set_promotion_active(&promotion_id, Some(&current_user.store_id), payload.active)
+        .await?;
+
+    if res.matched_count == 0 {
+        return Ok(
+            ResponseBuilder::<()>::error("PromotionNotFound", None, None, Some(404))
+                .into_response(),
+        );
+    }
+
+    Ok(ResponseBuilder::<()>::success(None, None, None).into_response())
+}
@@ -0,0 +1,8 @@
+use axum::{routing, Router};
+mod routes;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/", routing::get(routes::get_reviews))
+        .route("/:review_id/reply", routing::put(routes::reply_to_review))
+}
@@ -0,0 +1,45 @@
+use super::super::super::middlewares::CurrentUser;
+use crate::{
+    db::{AxumLocalDBExtansion, ReplyToReviewPayload, ReviewFunctions, ReviewReply},
+    prelude::*,
+};
+use axum::{extract::Path, response::IntoResponse};
+use bson::{oid::ObjectId, DateTime as BsonDateTime};
+use shoppa_core::{db::Pagination, extractors::JsonWithValidation, ResponseBuilder};
+
+pub async fn get_reviews(
+    local_db: AxumLocalDBExtansion,
+    current_user: CurrentUser,
+    pagination: Pagination,
+) -> HandlerResult {
+    let reviews = local_db
+        .get_reviews(Some(&current_user.store_id), Some(pagination))
+        .await?;
+
+    Ok(ResponseBuilder::paginated_response(&reviews).into_response())
+}
+
+pub async fn reply_to_review(
+    local_db: AxumLocalDBExtansion,
+    current_user: CurrentUser,
+    Path(review_id): Path<ObjectId>,
+    JsonWithValidation(payload): JsonWithValidation<ReplyToReviewPayload>,
+) -> HandlerResult {
+    let reply = ReviewReply {
+        text: payload.text,
+        replied_by: current_user.user_id,
+        created_at: BsonDateTime::now(),
+    };
+
+    let res = local_db
+        .reply_to_review(&review_id, &current_user.store_id, reply)
+        .await?;
+
+    if res.matched_count == 0 {
+        return Ok(
+            ResponseBuilder::<()>::error("ReviewNotFound", None, None, Some(404)).into_response(),
+        );
+    }
+
+    Ok(ResponseBuilder::<()>::success(None, None, None).into_response())
+}
@@ -12,6 +12,8 @@ pub fn router() -> Router {
         )
         .route("/", routing::patch(routes::update_store))
         .route("/", routing::get(routes::get_current_user_store))
+        .route("/currency", routing::get(routes::get_store_currency))
+        .route("/currency", routing::put(routes::set_store_currency))
         .route("/locations", routing::post(routes::add_store_locations))
         .route(
             "/locations/:location_oid",
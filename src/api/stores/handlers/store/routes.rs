@@ -1,8 +1,11 @@
 use super::types;
 use crate::{
     api::stores::middlewares::CurrentUser,
-    db::{AxumDBExtansion, StoreUserStoreFunctions},
-    helpers::types::AxumStorgeClientExtension,
+    db::{
+        AxumDBExtansion, CurrencyFunctions, ProductFunctions, SetStoreCurrencyPayload,
+        StoreUserStoreFunctions,
+    },
+    helpers::{money::EXCHANGE_RATES, types::AxumStorgeClientExtension},
     prelude::*,
 };
 use axum::{extract::Path, response::IntoResponse};
@@ -220,3 +223,63 @@ pub async fn get_current_user_store(
 
     Ok(ResponseBuilder::success(store, None, None).into_response())
 }
+
+pub async fn get_store_currency(db: AxumDBExtansion, current_user: CurrentUser) -> HandlerResult {
+    let currency = db
+        .get_stores_currency(&[current_user.store_id])
+        .await?
+        .remove(&current_user.store_id);
+
+    if currency.is_none() {
+        return Ok(
+            ResponseBuilder::<u16>::error("", None, Some("store not found"), Some(400))
+                .into_response(),
+        );
+    }
+
+    Ok(ResponseBuilder::success(currency, None, None).into_response())
+}
+
+pub async fn set_store_currency(
+    db: AxumDBExtansion,
+    current_user: CurrentUser,
+    JsonWithValidation(payload): JsonWithValidation<SetStoreCurrencyPayload>,
+) -> HandlerResult {
+    if !EXCHANGE_RATES.supports(payload.currency) {
+        return Ok(ResponseBuilder::<u16>::error(
+            "CurrencyNotSupported",
+            None,
+            Some("there is no exchange rate for this currency"),
+            Some(400),
+        )
+        .into_response());
+    }
+
+    // The prices of the products are in the store currency
+    if db
+        .get_products_count(Some(current_user.store_id), None)
+        .await?
+        > 0
+    {
+        return Ok(ResponseBuilder::<u16>::error(
+            "StoreCurrencyLocked",
+            None,
+            Some("the currency can not be changed once the store has products"),
+            Some(409),
+        )
+        .into_response());
+    }
+
+    let store = db
+        .set_store_currency(&current_user.store_id, payload.currency)
+        .await?;
+
+    if store.is_none() {
+        return Ok(
+            ResponseBuilder::<u16>::error("", None, Some("store not found"), Some(400))
+                .into_response(),
+        );
+    }
+
+    Ok(ResponseBuilder::success(store, None, None).into_response())
+}
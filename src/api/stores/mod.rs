@@ -7,10 +7,13 @@ pub fn router() -> Router {
         .nest("/me", handlers::me::router())
         .nest("/logout", handlers::logout::router())
         .nest("/products", handlers::products::router())
+        .nest("/promotions", handlers::promotions::router())
+        .nest("/reviews", handlers::reviews::router())
         .nest("/variants", handlers::variants::router())
         .nest("/store", handlers::store::router())
         .nest("/invoices", handlers::invoices::router())
         .nest("/orders", handlers::orders::router())
+        .nest("/ledger", handlers::ledger::router())
         .route_layer(middleware::from_fn(middlewares::login_required))
         .nest("/login", handlers::login::router())
         .nest("/registration", handlers::registration::router())
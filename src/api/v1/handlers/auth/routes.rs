@@ -1,7 +1,10 @@
 use super::types::{LoginPayload, SignupPayload};
 use crate::api::v1::middlewares::CurrentUser;
 use crate::{
-    db::{AxumDBExtansion, OrderFunctions, UserAsGetMe, UserFunctions},
+    db::{
+        AxumDBExtansion, AxumLocalDBExtansion, OrderFunctions, UserAsGetMe, UserFunctions,
+        WishlistFunctions,
+    },
     helpers::cookies::CookieManager,
     prelude::*,
 };
@@ -17,6 +20,7 @@ use tower_cookies::Cookies;
 
 pub async fn login(
     db: AxumDBExtansion,
+    local_db: AxumLocalDBExtansion,
     cookies: Cookies,
     Extension(current_user): Extension<Option<CurrentUser>>,
     JsonWithValidation(payload): JsonWithValidation<LoginPayload>,
@@ -69,6 +73,12 @@ pub async fn login(
         }
     };
 
+    // The guest wishlists are merged into the account like the cart,
+    // if this fails the guest lists are just left behind
+    if let Some(current_user_id) = &current_user_id {
+        let _ = local_db.merge_wishlists(current_user_id, user.id()?).await;
+    }
+
     cookies.set_access_cookie(&user)?;
 
     let user_id = user.id()?.clone();
@@ -98,6 +108,7 @@ pub async fn logout(
 
 pub async fn signup(
     db: AxumDBExtansion,
+    local_db: AxumLocalDBExtansion,
     cookies: Cookies,
     Extension(current_user): Extension<Option<CurrentUser>>,
     JsonWithValidation(payload): JsonWithValidation<SignupPayload>,
@@ -130,6 +141,10 @@ pub async fn signup(
 
     let user_id = get_me.id.clone();
 
+    if let Some(current_user_id) = &current_user_id {
+        let _ = local_db.merge_wishlists(current_user_id, &user_id).await;
+    }
+
     if had_first_order && current_user_id.is_some() {
         tokio::spawn(async move {
             let _ = db
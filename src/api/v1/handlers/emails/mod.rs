@@ -0,0 +1,7 @@
+use axum::{routing, Router};
+mod routes;
+mod types;
+
+pub fn router() -> Router {
+    Router::new().route("/unsubscribe", routing::post(routes::unsubscribe))
+}
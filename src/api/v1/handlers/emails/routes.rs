@@ -0,0 +1,27 @@
+use super::types;
+use crate::{
+    db::{AxumDBExtansion, CartReminderFunctions},
+    prelude::*,
+    tokens::EMAIL_UNSUBSCRIBE_TOKEN_MANAGER,
+};
+use axum::response::IntoResponse;
+use shoppa_core::{extractors::JsonWithValidation, ResponseBuilder};
+
+pub async fn unsubscribe(
+    db: AxumDBExtansion,
+    JsonWithValidation(payload): JsonWithValidation<types::UnsubscribePayload>,
+) -> HandlerResult {
+    let token_data = match EMAIL_UNSUBSCRIBE_TOKEN_MANAGER.decode_token(&payload.token) {
+        Ok(token_data) => token_data,
+        Err(_) => {
+            return Ok(
+                ResponseBuilder::<()>::error("InvalidToken", None, None, Some(401)).into_response(),
+            );
+        }
+    };
+
+    db.unsubscribe_from_cart_reminders(&token_data.user_id)
+        .await?;
+
+    Ok(ResponseBuilder::<u16>::success(None, None, None).into_response())
+}
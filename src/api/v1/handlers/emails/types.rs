@@ -0,0 +1,6 @@
+use crate::prelude::types::*;
+
+#[derive(Debug, Deserialize, Clone, Validate)]
+pub struct UnsubscribePayload {
+    pub token: String,
+}
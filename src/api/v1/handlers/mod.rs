@@ -2,6 +2,8 @@ pub mod analytics;
 pub mod blog;
 pub mod categories;
 pub mod contact_us;
+pub mod emails;
+pub mod orders;
 pub mod products;
 pub mod stores;
 pub mod users;
@@ -0,0 +1,9 @@
+use axum::{routing, Router};
+mod routes;
+mod types;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/lookup", routing::post(routes::lookup_guest_order))
+        .route("/status", routing::get(routes::get_order_status))
+}
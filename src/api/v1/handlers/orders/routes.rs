@@ -0,0 +1,92 @@
+use super::types;
+use crate::{
+    db::{AxumDBExtansion, OrderFunctions},
+    helpers::rate_limit::{client_ip, RateLimiter},
+    prelude::*,
+    tokens::{GUEST_ORDER_TOKEN_MANAGER, ORDER_EMAIL_TOKEN_MANAGER},
+};
+use axum::{
+    extract::{ConnectInfo, Query},
+    http::HeaderMap,
+    response::IntoResponse,
+};
+use serde_json::json;
+use shoppa_core::{extractors::JsonWithValidation, ResponseBuilder};
+use std::net::SocketAddr;
+
+lazy_static! {
+    // 10 lookups per 15 minutes from the same ip
+    static ref LOOKUP_BY_IP_LIMITER: RateLimiter = RateLimiter::new(10, 15 * 60);
+    // 5 lookups per 15 minutes for the same order number from the same ip, to slow down
+    // guessing the email without letting anyone lock the customer out of their order
+    static ref LOOKUP_BY_ORDER_LIMITER: RateLimiter = RateLimiter::new(5, 15 * 60);
+}
+
+pub async fn lookup_guest_order(
+    db: AxumDBExtansion,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    JsonWithValidation(payload): JsonWithValidation<types::GuestOrderLookupPayload>,
+) -> HandlerResult {
+    let ip = client_ip(&headers, &addr);
+
+    if !LOOKUP_BY_IP_LIMITER.hit(&ip)
+        || !LOOKUP_BY_ORDER_LIMITER.hit(&format!("{}:{}", payload.order_number, ip))
+    {
+        return Ok(
+            ResponseBuilder::<()>::error("TooManyRequests", None, None, Some(429)).into_response(),
+        );
+    }
+
+    let order = db
+        .get_order_by_number_and_email(&payload.order_number, &payload.email)
+        .await?;
+
+    // The same response for a missing order and a wrong email,
+    // so the lookup can't tell if an order number exists
+    let order = match order {
+        Some(order) => order,
+        None => {
+            return Ok(
+                ResponseBuilder::<()>::error("OrderNotFound", None, None, Some(404))
+                    .into_response(),
+            );
+        }
+    };
+
+    let token = GUEST_ORDER_TOKEN_MANAGER.generate_urlsafe_token(&order, None)?;
+
+    let link = format!("{}/orders/status?token={}", ENV_VARS.SHOPPA_URL, token);
+
+    Ok(ResponseBuilder::success(Some(json!({ "link": link })), None, None).into_response())
+}
+
+pub async fn get_order_status(
+    db: AxumDBExtansion,
+    Query(query): Query<types::OrderStatusQuery>,
+) -> HandlerResult {
+    // a link from the lookup or from an order email
+    let token_data = match GUEST_ORDER_TOKEN_MANAGER
+        .decode_token(&query.token)
+        .or_else(|_| ORDER_EMAIL_TOKEN_MANAGER.decode_token(&query.token))
+    {
+        Ok(token_data) => token_data,
+        Err(_) => {
+            return Ok(
+                ResponseBuilder::<()>::error("InvalidToken", None, None, Some(401)).into_response(),
+            );
+        }
+    };
+
+    let order = db
+        .get_order_status_by_id(&token_data.order_id, None)
+        .await?;
+
+    if order.is_none() {
+        return Ok(
+            ResponseBuilder::<()>::error("OrderNotFound", None, None, Some(404)).into_response(),
+        );
+    }
+
+    Ok(ResponseBuilder::success(order, None, None).into_response())
+}
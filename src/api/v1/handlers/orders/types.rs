@@ -0,0 +1,14 @@
+use crate::prelude::types::*;
+
+#[derive(Debug, Deserialize, Clone, Validate)]
+pub struct GuestOrderLookupPayload {
+    #[validate(length(min = 1, max = 32))]
+    pub order_number: String,
+    #[validate(email)]
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct OrderStatusQuery {
+    pub token: String,
+}
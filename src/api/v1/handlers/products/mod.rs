@@ -12,6 +12,10 @@ pub fn router() -> Router {
             "/:product_id/view",
             routing::put(routes::add_view_to_product),
         )
+        .route(
+            "/:product_id/reviews",
+            routing::get(routes::get_product_reviews),
+        )
         .route("/:product_id", routing::get(routes::get_product))
         .route(
             "/autocomplete",
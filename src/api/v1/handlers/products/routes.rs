@@ -1,24 +1,72 @@
 use super::types;
 use crate::{
-    db::{AxumDBExtansion, ProductFunctions, ProductSortBy},
+    db::{
+        localize_products_prices, AxumDBExtansion, AxumLocalDBExtansion, ProductFunctions,
+        ProductSortBy, ProductsFeed, ReviewFunctions,
+    },
+    helpers::money::{Currency, EXCHANGE_RATES},
     prelude::*,
+    tokens::PRODUCTS_FEED_TOKEN_MANAGER,
 };
 use axum::{
     extract::{Path, Query},
     response::IntoResponse,
 };
 use bson::oid::ObjectId;
+use serde_json::json;
 use shoppa_core::{
     db::{OptionalSorter, Pagination},
     ResponseBuilder,
 };
 
 pub async fn get_products_infinite(
-    _db: AxumDBExtansion,
-    _pagination: Pagination,
-    Query(_query): Query<types::GetProductsInfiniteQueryParams>,
+    db: AxumDBExtansion,
+    pagination: Pagination,
+    Query(query): Query<types::GetProductsInfiniteQueryParams>,
 ) -> HandlerResult {
-    todo!()
+    let mut feed = match query.cursor {
+        Some(cursor) => match PRODUCTS_FEED_TOKEN_MANAGER.decode_token(&cursor) {
+            Ok(feed) => feed,
+            Err(_) => {
+                return Ok(ResponseBuilder::<()>::error(
+                    "InvalidCursor",
+                    None,
+                    Some("the cursor is invalid or expired, start a new feed"),
+                    Some(400),
+                )
+                .into_response());
+            }
+        },
+        None => ProductsFeed::new(),
+    };
+
+    let products = db
+        .get_products_feed(
+            &mut feed,
+            pagination.amount,
+            query.store_id,
+            query.category_id,
+            query.product_ids.unwrap_or_default(),
+            None,
+        )
+        .await?;
+
+    // a short page is the end of the feed
+    let cursor = if (products.len() as i64) < pagination.amount {
+        None
+    } else {
+        Some(PRODUCTS_FEED_TOKEN_MANAGER.generate_urlsafe_token(feed, None)?)
+    };
+
+    Ok(ResponseBuilder::success(
+        Some(json!({
+            "products": products,
+            "cursor": cursor,
+        })),
+        None,
+        None,
+    )
+    .into_response())
 }
 
 pub async fn get_products(
@@ -27,28 +75,84 @@ pub async fn get_products(
     OptionalSorter(sorting): OptionalSorter<ProductSortBy>,
     Query(query): Query<types::GetProductQueryParams>,
 ) -> HandlerResult {
-    let products = db
+    if let Some(response) = unsupported_currency(query.currency) {
+        return Ok(response);
+    }
+
+    let mut products = db
         .get_products_for_extarnel(
             Some(pagination),
             sorting,
             query.free_text,
             query.store_id,
             query.category_id,
+            query.min_rating,
             None,
         )
         .await?;
 
+    if let Some(currency) = query.currency {
+        localize_products_prices(&db, &EXCHANGE_RATES, &mut products.0, currency).await?;
+    }
+
     Ok(ResponseBuilder::paginated_response(&products).into_response())
 }
 
-pub async fn get_product(db: AxumDBExtansion, Path(product_id): Path<ObjectId>) -> HandlerResult {
+pub async fn get_product(
+    db: AxumDBExtansion,
+    Path(product_id): Path<ObjectId>,
+    Query(query): Query<types::GetOneProductQueryParams>,
+) -> HandlerResult {
+    if let Some(response) = unsupported_currency(query.currency) {
+        return Ok(response);
+    }
+
     let product = db.get_one_product_for_extarnel(&product_id, None).await?;
 
     if product.is_none() {
         return Ok(ResponseBuilder::error("", Some(""), None, Some(404)).into_response());
     }
 
-    Ok(ResponseBuilder::success(product, None, None).into_response())
+    let mut product = product.unwrap();
+
+    if let Some(currency) = query.currency {
+        localize_products_prices(
+            &db,
+            &EXCHANGE_RATES,
+            std::slice::from_mut(&mut product),
+            currency,
+        )
+        .await?;
+    }
+
+    Ok(ResponseBuilder::success(Some(product), None, None).into_response())
+}
+
+pub async fn get_product_reviews(
+    local_db: AxumLocalDBExtansion,
+    pagination: Pagination,
+    Path(product_id): Path<ObjectId>,
+) -> HandlerResult {
+    let reviews = local_db
+        .get_product_reviews(&product_id, Some(pagination))
+        .await?;
+
+    Ok(ResponseBuilder::paginated_response(&reviews).into_response())
+}
+
+fn unsupported_currency(currency: Option<Currency>) -> Option<axum::response::Response> {
+    match currency {
+        Some(currency) if !EXCHANGE_RATES.supports(currency) => Some(
+            ResponseBuilder::<u16>::error(
+                "CurrencyNotSupported",
+                None,
+                Some("there is no exchange rate for this currency"),
+                Some(400),
+            )
+            .into_response(),
+        ),
+        _ => None,
+    }
 }
 
 pub async fn products_autocomplete(
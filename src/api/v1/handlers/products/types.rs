@@ -1,4 +1,7 @@
-use crate::prelude::{types::*, *};
+use crate::{
+    helpers::money::Currency,
+    prelude::{types::*, *},
+};
 use axum::{async_trait, extract::Multipart};
 use shoppa_core::{
     constans::MAX_IMAGE_SIZE,
@@ -16,6 +19,18 @@ pub struct GetProductQueryParams {
     pub store_id: Option<ObjectId>,
     #[serde(default, deserialize_with = "empty_string_as_none")]
     pub category_id: Option<ObjectId>,
+    // Only products with at least this average rating
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub min_rating: Option<f64>,
+    // The prices are returned in it, the store currency when missing
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub currency: Option<Currency>,
+}
+
+#[derive(Deserialize, Debug, Clone, Validate)]
+pub struct GetOneProductQueryParams {
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub currency: Option<Currency>,
 }
 
 #[derive(Deserialize, Debug, Clone, Validate)]
@@ -46,6 +61,9 @@ pub struct GetProductsInfiniteQueryParams {
     pub category_id: Option<ObjectId>,
     #[serde(default, deserialize_with = "empty_string_as_none")]
     pub store_id: Option<ObjectId>,
+    // From the previous page, a new feed is started without it
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub cursor: Option<String>,
 }
 
 #[derive(Debug, Clone, Validate)]
@@ -3,6 +3,9 @@ use axum::{middleware, routing, Router};
 mod routes;
 mod types;
 
+pub(super) use routes::add_item_to_cart;
+pub(super) use types::AddProductToCartPayload;
+
 pub fn router() -> Router {
     Router::new()
         // .route_layer(middleware::from_fn(middlewares::guest_user_not_allowed))
@@ -15,6 +18,8 @@ pub fn router() -> Router {
                 .route_layer(middleware::from_fn(middlewares::checkout_session_required)),
         )
         .route("/checkout", routing::patch(routes::start_checkout))
+        .route("/checkout", routing::delete(routes::cancel_checkout))
+        .route("/checkout/requote", routing::post(routes::requote_checkout))
         .route_layer(middleware::from_fn(middlewares::login_required))
         .route(
             "/",
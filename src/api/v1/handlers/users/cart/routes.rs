@@ -1,22 +1,32 @@
 use super::types::{
-    AddProductToCartPayload, EditProductInCartPayload, PayCartPayload, RemoveProductFromCartQuery,
+    AddProductToCartPayload, DeliveryStrategyKind, EditProductInCartPayload, PayCartPayload,
+    RemoveProductFromCartQuery, StartCheckoutPayload,
 };
 use crate::{
     api::v1::middlewares::{CurrentCheckOutSession, CurrentUser},
     db::{
-        AxumDBExtansion, CheckoutSessionFunctions, OrderFunctions, ProductFunctions, UserFunctions,
+        AxumDBExtansion, AxumLocalDBExtansion, ChargeCurrency, CheckoutDiscount,
+        CheckoutSessionFunctions, CommissionFunctions, CommissionItem, CurrencyFunctions,
+        DiscountFunctions, DiscountablePart, OrderFunctions, OrderPartCommission, OrderPartTax,
+        OrderPaymentStatus, PaymentMethodFunctions, PaymentStateFunctions, PostPaymentFunctions,
+        PostPaymentJob, ProductFunctions, PromotionDiscount, PromotionFunctions,
+        SavedPaymentMethod, StoreCommission, StoreTax, TaxFunctions, TaxableItem, UserFunctions,
     },
     helpers::{
         cookies::CookieManager,
+        money::{self, Currency, Money, EXCHANGE_RATES},
         types::{
-            AxumInvoiceClientExtension, AxumPaymentClientExtension, AxumStorgeClientExtension,
+            AxumEmailClientExtension, AxumInvoiceClientExtension, AxumPaymentClientExtension,
+            AxumStorgeClientExtension,
         },
     },
+    payments::{self, CheckoutPayment, CheckoutSettlement, PaymentSource},
     prelude::*,
+    workers,
 };
 use axum::{
     extract::{Json, Query},
-    response::IntoResponse,
+    response::{IntoResponse, Response},
 };
 use bson::{doc, oid::ObjectId};
 use serde_json::json;
@@ -24,14 +34,13 @@ use shoppa_core::{
     db::{
         models::{
             CheckOutSession, CheckOutSessionPart, CheckOutSessionPartItem, DBModel,
-            EmbeddedDocument, InvoiceType, Order, OrderInfo, ProductItemStatus, ProductStatus,
-            Store,
+            EmbeddedDocument, Order, OrderInfo, Product, ProductItemStatus, ProductStatus, Store,
+            User,
         },
-        populate::{FieldPopulate, OrderPopulate, UsersPopulate},
+        populate::{FieldPopulate, UsersPopulate},
     },
     extractors::JsonWithValidation,
-    file_storage::StorageFolders,
-    payments::types::{ChargeCreditCard, ChargeResult},
+    payments::types::TransactionInfo,
     ResponseBuilder,
 };
 use std::collections::HashMap;
@@ -52,14 +61,30 @@ pub async fn add_product_to_cart(
         );
     }
 
+    if let Some(response) =
+        add_item_to_cart(&db, current_user.get_user_unchecked(), payload).await?
+    {
+        return Ok(response);
+    }
+
+    Ok(ResponseBuilder::<()>::success(None, None, None).into_response())
+}
+
+/// Adds the item to the user cart, or sets its quantity if it's already there.
+/// Returns the response to send back if the item can't be added.
+pub async fn add_item_to_cart(
+    db: &AxumDBExtansion,
+    user: &User,
+    payload: AddProductToCartPayload,
+) -> Result<Option<Response>> {
     let product = db
         .get_product_by_id(&payload.product_id, None, None, None)
         .await?;
 
     if product.is_none() {
-        return Ok(
+        return Ok(Some(
             ResponseBuilder::<()>::error("Product not found", None, None, None).into_response(),
-        );
+        ));
     }
 
     let product = product.unwrap();
@@ -67,19 +92,21 @@ pub async fn add_product_to_cart(
     if product.status != ProductStatus::Active {
         match product.status {
             ProductStatus::Inactive => {
-                return Ok(ResponseBuilder::<()>::error(
-                    "Product is currently not availble",
-                    None,
-                    None,
-                    None,
-                )
-                .into_response());
+                return Ok(Some(
+                    ResponseBuilder::<()>::error(
+                        "Product is currently not availble",
+                        None,
+                        None,
+                        None,
+                    )
+                    .into_response(),
+                ));
             }
             _ => {
-                return Ok(
+                return Ok(Some(
                     ResponseBuilder::<()>::error("Product not found", None, None, None)
                         .into_response(),
-                )
+                ))
             }
         };
     };
@@ -91,36 +118,39 @@ pub async fn add_product_to_cart(
         .find(|item| *item.id() == payload.item_id && item.status != ProductItemStatus::Deleted);
 
     if item.is_none() {
-        return Ok(
+        return Ok(Some(
             ResponseBuilder::<()>::error("Product item not found", None, None, None)
                 .into_response(),
-        );
+        ));
     }
 
     let item = item.unwrap();
 
     if item.status != ProductItemStatus::Active {
-        return Ok(ResponseBuilder::<()>::error(
-            "Product item is currently not availble",
-            None,
-            None,
-            None,
-        )
-        .into_response());
+        return Ok(Some(
+            ResponseBuilder::<()>::error(
+                "Product item is currently not availble",
+                None,
+                None,
+                None,
+            )
+            .into_response(),
+        ));
     };
 
     if item.in_storage < payload.quantity as u64 {
-        return Ok(ResponseBuilder::error(
-            "Not enough items in storage",
-            Some(item.in_storage),
-            None,
-            None,
-        )
-        .into_response());
+        return Ok(Some(
+            ResponseBuilder::error(
+                "Not enough items in storage",
+                Some(item.in_storage),
+                None,
+                None,
+            )
+            .into_response(),
+        ));
     }
 
-    let update_quantity = current_user
-        .get_user_unchecked()
+    let update_quantity = user
         .cart
         .items
         .iter()
@@ -132,7 +162,7 @@ pub async fn add_product_to_cart(
     if update_quantity {
         updated_res = db
             .edit_product_in_cart(
-                &current_user.user_id,
+                user.id()?,
                 &payload.product_id,
                 &payload.item_id,
                 payload.quantity,
@@ -140,29 +170,24 @@ pub async fn add_product_to_cart(
             )
             .await?;
     } else {
-        updated_res = db
-            .add_product_to_cart(&current_user.user_id, payload, None)
-            .await?
+        updated_res = db.add_product_to_cart(user.id()?, payload, None).await?
     }
 
     if updated_res.modified_count == 0 {
         if updated_res.matched_count == 0 {
-            return Ok(ResponseBuilder::<()>::error(
-                "Maybe item is in cart already?",
-                None,
-                None,
-                None,
-            )
-            .into_response());
+            return Ok(Some(
+                ResponseBuilder::<()>::error("Maybe item is in cart already?", None, None, None)
+                    .into_response(),
+            ));
         }
 
-        return Ok(
+        return Ok(Some(
             ResponseBuilder::<()>::error("Failed to add product to cart", None, None, None)
                 .into_response(),
-        );
+        ));
     }
 
-    Ok(ResponseBuilder::<()>::success(None, None, None).into_response())
+    Ok(None)
 }
 
 pub async fn get_full_cart(db: AxumDBExtansion, current_user: CurrentUser) -> HandlerResult {
@@ -234,8 +259,18 @@ pub async fn edit_product_in_cart(
 pub async fn start_checkout(
     db: AxumDBExtansion,
     mut current_user: CurrentUser,
+    local_db: AxumLocalDBExtansion,
     cookies: Cookies,
+    payload: Option<Json<StartCheckoutPayload>>,
 ) -> HandlerResult {
+    let payload = payload.map(|Json(payload)| payload).unwrap_or_default();
+
+    if payload.validate().is_err() {
+        return Ok(
+            ResponseBuilder::<()>::error("InvalidPayload", None, None, Some(400)).into_response(),
+        );
+    }
+
     let populate = UsersPopulate {
         cart_products: FieldPopulate::Field,
         options: None,
@@ -250,20 +285,38 @@ pub async fn start_checkout(
         );
     }
 
+    match create_checkout_session(&db, &local_db, &current_user, &cookies, payload).await? {
+        Ok(res) => Ok(ResponseBuilder::success(Some(res), None, None).into_response()),
+        Err(rejection) => Ok(rejection.into_response()),
+    }
+}
+
+/// Builds a checkout session from the user's cart, reserves its stock and sets the cookie.
+/// The previous checkout session of the user is released and replaced.
+async fn create_checkout_session(
+    db: &AxumDBExtansion,
+    local_db: &AxumLocalDBExtansion,
+    current_user: &CurrentUser,
+    cookies: &Cookies,
+    payload: StartCheckoutPayload,
+) -> Result<StdResult<serde_json::Value, CheckoutRejection>> {
+    let delivery_strategies = payload.delivery_strategies;
+
     let mut checkout_session = CheckOutSession::new(current_user.user_id.clone());
 
-    let user = current_user.user().unwrap();
+    let user = current_user.get_user_unchecked();
 
     if user.cart.items.is_empty() {
-        return Ok(ResponseBuilder::<()>::error("Cart is empty", None, None, None).into_response());
+        return Ok(Err(CheckoutRejection::new("Cart is empty", None, None)));
     }
     // If one is populated, all are populated
     if user.cart.items.get(0).unwrap().product.is_not_populated() {
         // This is not supposed to happen
-        return Ok(
-            ResponseBuilder::<()>::error("CartItemNotPopulated", None, None, Some(500))
-                .into_response(),
-        );
+        return Ok(Err(CheckoutRejection::new(
+            "CartItemNotPopulated",
+            None,
+            Some(500),
+        )));
     }
 
     let mut checkout_parts: HashMap<&ObjectId, CheckOutSessionPart> = HashMap::new();
@@ -290,8 +343,11 @@ pub async fn start_checkout(
                     items_total: 0.0,
                     delivery_cost: 0.0,
                     items: Vec::new(),
-                    // In the future the user will send the desired delivery strategy
-                    delivery_strategy: "default".to_string(),
+                    delivery_strategy: delivery_strategies
+                        .get(product.store_id())
+                        .copied()
+                        .unwrap_or_default()
+                        .to_string(),
                 });
 
         if product.status != ProductStatus::Active {
@@ -327,12 +383,10 @@ pub async fn start_checkout(
             quantity: item.quantity,
             price: product_item.price,
         });
-
-        checkout_part.items_total += product_item.price * item.quantity as f64;
     });
 
     if !errors.is_empty() {
-        return Ok(ResponseBuilder::error("", Some(errors), None, None).into_response());
+        return Ok(Err(CheckoutRejection::new("", Some(errors), None)));
     }
 
     // getting stores from db
@@ -351,37 +405,54 @@ pub async fn start_checkout(
 
     // checking if all stores were found
     if stores.len() != checkout_parts.len() {
-        return Ok(
-            ResponseBuilder::<()>::error("Some stores not found", None, None, None).into_response(),
-        );
+        return Ok(Err(CheckoutRejection::new(
+            "Some stores not found",
+            None,
+            None,
+        )));
     }
 
-    // making sure all stores have a default delivery strategy
-    for store in &stores {
-        if store.delivery_strategies.default.is_none() {
-            return Ok(ResponseBuilder::<()>::error(
-                "Some stores have no delivery strategies",
-                None,
-                None,
+    let store_ids: Vec<ObjectId> = checkout_parts.keys().map(|store_id| **store_id).collect();
+
+    let store_currencies = db.get_stores_currency(&store_ids).await?;
+    let stores_tax = db.get_stores_tax(&store_ids).await?;
+
+    // The store rules are checked in the store currency, then the prices are converted to it
+    let charge_currency = match ChargeCurrency::quote(
+        &EXCHANGE_RATES,
+        payload.currency.unwrap_or_default(),
+        store_currencies.values().copied(),
+    ) {
+        Some(charge_currency) => charge_currency,
+        None => {
+            return Ok(Err(CheckoutRejection::new(
+                "CurrencyNotSupported",
                 None,
-            )
-            .into_response());
+                Some(400),
+            )));
         }
-    }
+    };
 
     // Includes all products in cart + delivery
-    let mut total_price = 0.0;
 
-    // Adding delivery cost to each part
+    let now = chrono::Utc::now();
+
+    let mut delivery_dates = Vec::with_capacity(checkout_parts.len());
+
+    // Adding delivery cost to each part, by the delivery strategy chosen for its store
     let checkout_parts: Vec<CheckOutSessionPart> = checkout_parts
         .into_iter()
-        .map(|(store_id, mut part)| {
+        .map(|(store_id, mut part)| -> Result<CheckOutSessionPart> {
             // Not possible to fail
             let store = stores
                 .iter()
                 .find(|store| store.id().unwrap() == store_id)
                 .expect("Store not found");
 
+            let store_currency = store_currencies.get(store_id).copied().unwrap_or_default();
+
+            part.items_total = part_items_total(&part.items, store_currency).to_major();
+
             // checking if items total is above store min order
             if part.items_total < store.min_order as f64 {
                 errors.push(json!({
@@ -392,54 +463,357 @@ pub async fn start_checkout(
                 }));
             };
 
-            // checking if store as a free above policy
-            if let Some(free_above) = store
-                .delivery_strategies
-                .default
-                .as_ref()
-                .unwrap()
-                .free_above
-            {
-                // if it does, check if the items total is above it
-                if part.items_total < free_above as f64 {
-                    part.delivery_cost = store.delivery_strategies.default.as_ref().unwrap().price;
-                } else {
-                    part.delivery_cost = 0.0;
+            let strategy = delivery_strategies
+                .get(store_id)
+                .copied()
+                .unwrap_or_default();
+
+            // price, free above, from days, to days
+            let delivery = match strategy {
+                DeliveryStrategyKind::Default => {
+                    store.delivery_strategies.default.as_ref().map(|delivery| {
+                        (
+                            delivery.price,
+                            delivery.free_above.map(|free_above| free_above as f64),
+                            delivery.from_days as i64,
+                            delivery.to_days as i64,
+                        )
+                    })
                 }
+                // fast delivery is never free
+                DeliveryStrategyKind::Fast => {
+                    store.delivery_strategies.fast.as_ref().map(|delivery| {
+                        (
+                            delivery.price,
+                            None,
+                            delivery.from_days as i64,
+                            delivery.to_days as i64,
+                        )
+                    })
+                }
+            };
+
+            let (price, free_above, from_days, to_days) = match delivery {
+                Some(delivery) => delivery,
+                None => {
+                    errors.push(json!({
+                        "store": store.id().unwrap(),
+                        "delivery_strategy": strategy,
+                        "error": "Store does not offer this delivery strategy"
+                    }));
+                    return Ok(part);
+                }
+            };
+
+            part.delivery_cost = match free_above {
+                Some(free_above) if part.items_total >= free_above => 0.0,
+                _ => price,
+            };
+
+            delivery_dates.push(json!({
+                "store": store.id().unwrap(),
+                "delivery_strategy": strategy,
+                "from": (now + chrono::Duration::days(from_days)).date_naive(),
+                "to": (now + chrono::Duration::days(to_days)).date_naive(),
+            }));
+
+            let store_tax = stores_tax.get(store_id).cloned().unwrap_or_default();
+
+            for item in part.items.iter_mut() {
+                item.price = customer_price(
+                    item.price,
+                    store_currency,
+                    &store_tax,
+                    &charge_currency,
+                    now.date_naive(),
+                )?;
             }
-            // if it doesn't, set the delivery cost to the provided price
-            else {
-                part.delivery_cost = store.delivery_strategies.default.as_ref().unwrap().price;
-            }
 
-            // adding delivery cost and total part items to total price
-            total_price += part.items_total + part.delivery_cost;
+            let items_total = part_items_total(&part.items, charge_currency.currency);
+            let delivery_cost = Money::from_major(
+                customer_price(
+                    part.delivery_cost,
+                    store_currency,
+                    &store_tax,
+                    &charge_currency,
+                    now.date_naive(),
+                )?,
+                charge_currency.currency,
+            );
+
+            part.items_total = items_total.to_major();
+            part.delivery_cost = delivery_cost.to_major();
 
-            part
+            Ok(part)
         })
-        .collect();
+        .collect::<Result<_>>()?;
 
     if !errors.is_empty() {
-        return Ok(ResponseBuilder::error("", Some(errors), None, None).into_response());
+        return Ok(Err(CheckoutRejection::new("", Some(errors), None)));
     }
 
+    let discount = match payload.coupon {
+        Some(code) => {
+            let promotion = match local_db.get_valid_promotion_by_code(&code).await? {
+                Some(promotion) => promotion,
+                None => {
+                    return Ok(Err(CheckoutRejection::new(
+                        "CouponNotFound",
+                        None,
+                        Some(404),
+                    )));
+                }
+            };
+
+            let user_uses = local_db
+                .count_user_redemptions(&promotion.id, &current_user.user_id)
+                .await?;
+
+            if !promotion.can_be_used_by(user_uses) {
+                return Ok(Err(CheckoutRejection::new(
+                    "CouponUsageLimitReached",
+                    None,
+                    None,
+                )));
+            }
+
+            let promotion = promotion.in_currency(&charge_currency)?;
+
+            let parts: Vec<DiscountablePart> = checkout_parts
+                .iter()
+                .map(|part| DiscountablePart {
+                    store: part.store,
+                    items_total: Money::from_major(part.items_total, charge_currency.currency),
+                    delivery_cost: Money::from_major(part.delivery_cost, charge_currency.currency),
+                })
+                .collect();
+
+            match promotion.apply(&parts, charge_currency.currency) {
+                Ok(discount) => Some(discount),
+                Err(code) => {
+                    return Ok(Err(CheckoutRejection::new(code, None, None)));
+                }
+            }
+        }
+        None => None,
+    };
+
+    let currency = charge_currency.currency;
+
+    checkout_session.total = money::checkout_total(
+        currency,
+        checkout_parts.iter().map(|part| {
+            (
+                Money::from_major(part.items_total, currency),
+                Money::from_major(part.delivery_cost, currency),
+            )
+        }),
+        discount
+            .as_ref()
+            .map(|discount| Money::from_major(discount.total, currency))
+            .unwrap_or(Money::zero(currency)),
+    )
+    .to_major();
     checkout_session.parts = checkout_parts;
-    checkout_session.total = total_price;
+
+    // the previous checkout session is replaced, so its stock is given back first
+    if let Some(previous_session) = db
+        .get_checkout_session_by_user(&current_user.user_id, None, None)
+        .await?
+    {
+        db.release_checkout_session_stock(&previous_session, false)
+            .await?;
+    }
 
     let checkout_session = db
         .insert_new_checkout_session(checkout_session, None)
         .await?;
 
+    if let Some(discount) = &discount {
+        db.set_checkout_session_discount(checkout_session.id()?, Some(discount))
+            .await?;
+    }
+
+    db.set_checkout_session_currency(checkout_session.id()?, &charge_currency)
+        .await?;
+
+    let failed_items = db.reserve_checkout_session_stock(&checkout_session).await?;
+
+    if !failed_items.is_empty() {
+        let errors: Vec<_> = failed_items
+            .iter()
+            .map(|(product_id, item_id)| {
+                json!({
+                    "product": product_id,
+                    "item": item_id,
+                    "error": "Not enough items in storage"
+                })
+            })
+            .collect();
+
+        return Ok(Err(CheckoutRejection::new("", Some(errors), Some(409))));
+    }
+
     cookies.set_checkout_session_cookie(&checkout_session)?;
 
-    Ok(ResponseBuilder::success(Some(checkout_session), None, None).into_response())
+    let mut res = serde_json::to_value(&checkout_session).map_err(|_| Error::Desrilaztion)?;
+
+    res["delivery_dates"] = json!(delivery_dates);
+    res["discount"] = json!(discount);
+    res["currency"] = json!(charge_currency);
+
+    Ok(Ok(res))
+}
+
+pub async fn cancel_checkout(
+    db: AxumDBExtansion,
+    current_user: CurrentUser,
+    cookies: Cookies,
+) -> HandlerResult {
+    let checkout_session = db
+        .get_checkout_session_by_user(&current_user.user_id, None, None)
+        .await?;
+
+    if let Some(checkout_session) = checkout_session {
+        db.release_checkout_session_stock(&checkout_session, false)
+            .await?;
+    }
+
+    cookies.delete_checkout_session_cookie();
+
+    Ok(ResponseBuilder::<()>::success(None, None, None).into_response())
+}
+
+/// Re-quotes the current checkout after the cart was edited, the previous session is
+/// replaced by a new one with the current prices and the differences are reported.
+pub async fn requote_checkout(
+    db: AxumDBExtansion,
+    mut current_user: CurrentUser,
+    local_db: AxumLocalDBExtansion,
+    cookies: Cookies,
+    payload: Option<Json<StartCheckoutPayload>>,
+) -> HandlerResult {
+    let previous_session = match db
+        .get_checkout_session_by_user(&current_user.user_id, None, None)
+        .await?
+    {
+        Some(checkout_session) => checkout_session,
+        None => {
+            cookies.delete_checkout_session_cookie();
+            return Ok(ResponseBuilder::<()>::error(
+                "Checkout session not found",
+                None,
+                None,
+                Some(404),
+            )
+            .into_response());
+        }
+    };
+
+    let previous_currency = db
+        .get_checkout_session_currency(previous_session.id()?)
+        .await?
+        .unwrap_or_else(ChargeCurrency::platform);
+
+    // Without a payload the delivery strategies, coupon and currency
+    // of the previous session are kept
+    let payload = match payload {
+        Some(Json(payload)) => payload,
+        None => StartCheckoutPayload {
+            delivery_strategies: previous_session
+                .parts
+                .iter()
+                .filter_map(|part| {
+                    part.delivery_strategy
+                        .parse::<DeliveryStrategyKind>()
+                        .ok()
+                        .map(|strategy| (part.store, strategy))
+                })
+                .collect(),
+            coupon: db
+                .get_checkout_session_discount(previous_session.id()?)
+                .await?
+                .map(|discount| discount.code),
+            currency: Some(previous_currency.currency),
+        },
+    };
+
+    if payload.validate().is_err() {
+        return Ok(
+            ResponseBuilder::<()>::error("InvalidPayload", None, None, Some(400)).into_response(),
+        );
+    }
+
+    let populate = UsersPopulate {
+        cart_products: FieldPopulate::Field,
+        options: None,
+    };
+
+    current_user.force_fetch(&db, Some(populate)).await?;
+
+    if !current_user.user_exists() {
+        cookies.delete_access_cookie();
+        return Ok(
+            ResponseBuilder::<()>::error("User not found", None, None, None).into_response(),
+        );
+    }
+
+    let user = current_user.get_user_unchecked();
+
+    let store_ids: Vec<ObjectId> = user
+        .cart
+        .items
+        .iter()
+        .filter_map(|item| item.product.as_populated())
+        .map(|product| *product.store_id())
+        .collect();
+
+    let store_currencies = db.get_stores_currency(&store_ids).await?;
+    let stores_tax = db.get_stores_tax(&store_ids).await?;
+
+    let changes = checkout_session_changes(
+        &previous_session,
+        user,
+        &previous_currency,
+        &store_currencies,
+        &stores_tax,
+    )?;
+
+    // The stock held by the previous session is given back first,
+    // so it counts as available for the new one
+    db.release_checkout_session_stock(&previous_session, false)
+        .await?;
+
+    match create_checkout_session(&db, &local_db, &current_user, &cookies, payload).await? {
+        Ok(mut res) => {
+            res["changes"] = json!(changes);
+            Ok(ResponseBuilder::success(Some(res), None, None).into_response())
+        }
+        Err(rejection) => {
+            // the previous session was released, so it can't be paid anymore
+            cookies.delete_checkout_session_cookie();
+            Ok(ResponseBuilder::error(
+                "CheckoutRequoteFailed",
+                Some(json!({
+                    "reason": rejection.code,
+                    "changes": changes,
+                    "errors": rejection.errors,
+                })),
+                None,
+                Some(rejection.status.unwrap_or(409)),
+            )
+            .into_response())
+        }
+    }
 }
 
 pub async fn checkout_pay(
     db: AxumDBExtansion,
+    local_db: AxumLocalDBExtansion,
     payment_client: AxumPaymentClientExtension,
     storage_client: AxumStorgeClientExtension,
     invoice_client: AxumInvoiceClientExtension,
+    email_client: AxumEmailClientExtension,
     mut current_user: CurrentUser,
     current_checkout_session: CurrentCheckOutSession,
     cookies: Cookies,
@@ -467,6 +841,27 @@ pub async fn checkout_pay(
         );
     }
 
+    // Sessions from before checkouts had a currency were charged in the platform currency
+    let charge_currency = db
+        .get_checkout_session_currency(checkout_session.id()?)
+        .await?
+        .unwrap_or_else(ChargeCurrency::platform);
+
+    // The session keeps the prices it was quoted with,
+    // a price that changed since has to be re-quoted before paying
+    let price_changes =
+        checkout_session_price_changes(&db, &checkout_session, &charge_currency).await?;
+
+    if !price_changes.is_empty() {
+        return Ok(ResponseBuilder::error(
+            "CheckoutPricesChanged",
+            Some(price_changes),
+            None,
+            Some(409),
+        )
+        .into_response());
+    }
+
     current_user.fetch(&db, None).await?;
 
     if !current_user.user_exists() {
@@ -476,7 +871,7 @@ pub async fn checkout_pay(
         );
     }
 
-    let user = current_user.user().unwrap();
+    let user = current_user.get_user_unchecked();
 
     let address = match user
         .addresses
@@ -491,6 +886,112 @@ pub async fn checkout_pay(
         }
     };
 
+    // Either a new card or one the user saved from a previous order
+    let (payment_source, card_holder_name) =
+        match (payload.credit_card.take(), payload.payment_method_id) {
+            (Some(credit_card), None) => match payload.card_holder_name.take() {
+                Some(card_holder_name) => (PaymentSource::NewCard(credit_card), card_holder_name),
+                None => {
+                    return Ok(ResponseBuilder::<()>::error(
+                        "CardHolderNameRequired",
+                        None,
+                        None,
+                        Some(400),
+                    )
+                    .into_response());
+                }
+            },
+            (None, Some(_)) if !payment_client.supports_saved_cards() => {
+                return Ok(ResponseBuilder::<()>::error(
+                    "SavedCardsNotSupported",
+                    None,
+                    None,
+                    Some(400),
+                )
+                .into_response());
+            }
+            (None, Some(payment_method_id)) => {
+                match db
+                    .get_user_payment_method(&current_user.user_id, &payment_method_id)
+                    .await?
+                {
+                    Some(payment_method) => (
+                        PaymentSource::SavedCard(payment_method.card.token),
+                        payment_method.card.holder_name,
+                    ),
+                    None => {
+                        return Ok(ResponseBuilder::<()>::error(
+                            "PaymentMethodNotFound",
+                            None,
+                            None,
+                            Some(404),
+                        )
+                        .into_response());
+                    }
+                }
+            }
+            _ => {
+                return Ok(ResponseBuilder::<()>::error(
+                    "CreditCardOrPaymentMethodRequired",
+                    None,
+                    None,
+                    Some(400),
+                )
+                .into_response());
+            }
+        };
+
+    // a card is never saved if the provider can't charge it again
+    if payload.save_payment_method && !payment_client.supports_saved_cards() {
+        return Ok(
+            ResponseBuilder::<()>::error("SavedCardsNotSupported", None, None, Some(400))
+                .into_response(),
+        );
+    }
+
+    // only a new card can be saved
+    let save_payment_method =
+        payload.save_payment_method && matches!(payment_source, PaymentSource::NewCard(_));
+
+    let discount = db
+        .get_checkout_session_discount(checkout_session.id()?)
+        .await?;
+
+    let promotion = match &discount {
+        Some(discount) => match local_db.get_promotion_by_id(&discount.promotion).await? {
+            Some(promotion) => Some(promotion),
+            None => {
+                cookies.delete_checkout_session_cookie();
+                return Ok(ResponseBuilder::<()>::error(
+                    "CouponNoLongerValid",
+                    None,
+                    None,
+                    Some(409),
+                )
+                .into_response());
+            }
+        },
+        None => None,
+    };
+
+    let free_delivery = promotion
+        .as_ref()
+        .map(|promotion| promotion.discount == PromotionDiscount::FreeDelivery)
+        .unwrap_or(false);
+
+    let part_taxes =
+        order_parts_tax(&db, &checkout_session, &charge_currency, discount.as_ref()).await?;
+
+    // Recorded with the order, so later changes to the store terms don't affect it
+    let part_commissions = order_parts_commission(
+        &db,
+        &checkout_session,
+        &charge_currency,
+        discount.as_ref(),
+        free_delivery,
+    )
+    .await?;
+
     let mut db_session = db.start_session().await?;
 
     if db_session.start_transaction(None).await.is_err() {
@@ -510,10 +1011,50 @@ pub async fn checkout_pay(
         order_number
     });
 
+    let checkout_session_id = checkout_session.id().unwrap().clone();
+
+    // kept aside for the error response, the session parts are moved into the order
+    let reserved_items: Vec<(ObjectId, ObjectId, u32)> = checkout_session
+        .parts
+        .iter()
+        .flat_map(|part| {
+            part.items
+                .iter()
+                .map(|item| (item.product.clone(), item.item_id.clone(), item.quantity))
+        })
+        .collect();
+
+    let order_parts: Vec<_> = checkout_session
+        .parts
+        .into_iter()
+        .map(|part| {
+            let utm = payload.utms.remove(&part.store);
+            let total = money::order_part_total(
+                part.items_total,
+                part.delivery_cost,
+                charge_currency.currency,
+            );
+
+            let mut order_part = part.into_order_part(utm);
+            order_part.total = total.to_major();
+            order_part
+        })
+        .collect();
+
+    // the same as the session total, see `money::checkout_total`
+    let order_total = money::order_total(
+        order_parts.iter().map(|part| part.total),
+        discount
+            .as_ref()
+            .map(|discount| discount.total)
+            .unwrap_or(0.0),
+        charge_currency.currency,
+    );
+
     let order = Order::new(
         order_number,
         Default::default(),
-        checkout_session.total,
+        order_total.to_major(),
         user.id().unwrap().clone(),
         address.clone(),
         OrderInfo {
@@ -521,14 +1062,7 @@ pub async fn checkout_pay(
             phone_number: payload.phone_number,
             customer_id: payload.customer_id,
         },
-        checkout_session
-            .parts
-            .into_iter()
-            .map(|part| {
-                let utm = payload.utms.remove(&part.store);
-                part.into_order_part(utm)
-            })
-            .collect(),
+        order_parts,
     );
 
     let order = match db
@@ -545,36 +1079,145 @@ pub async fn checkout_pay(
         }
     };
 
-    let charge_res = match payment_client
-        .charge_credit_card(ChargeCreditCard {
-            order_number: order.order_number.clone(),
-            amount: checkout_session.total,
-            // user is not guest so he must have a name
-            credit_card: payload.credit_card,
-            currency_code: None,
-        })
+    // The stock was taken when the checkout started, the reservation is confirmed
+    // before charging so a lapsed reservation is never paid for
+    let confirmed = match db
+        .confirm_checkout_session_reservation(
+            &checkout_session_id,
+            order.id().unwrap(),
+            Some(&mut db_session),
+        )
         .await
     {
-        Ok(res) => res,
+        Ok(res) => res.modified_count == 1,
         Err(e) => {
-            tracing::error!("Failed to charge credit card1: {}", e);
-            // same comment as above
             let _ = db_session.abort_transaction().await;
+            return Err(e);
+        }
+    };
+
+    if !confirmed {
+        let _ = db_session.abort_transaction().await;
+        // the stock is given back by the reservations worker if it wasn't already
+        cookies.delete_checkout_session_cookie();
+        return reservation_lapsed_response(&db, reserved_items).await;
+    }
+
+    if let (Some(discount), Some(promotion)) = (&discount, &promotion) {
+        if let Err(e) = db
+            .set_order_discount(order.id().unwrap(), discount, Some(&mut db_session))
+            .await
+        {
+            let _ = db_session.abort_transaction().await;
+            return Err(e);
+        }
+
+        // The promotion limits are checked again here, the redemption is given back
+        // if the charge fails
+        let redeemed = match local_db
+            .redeem_promotion(promotion, user.id().unwrap(), order.id().unwrap())
+            .await
+        {
+            Ok(redeemed) => redeemed,
+            Err(e) => {
+                let _ = db_session.abort_transaction().await;
+                return Err(e);
+            }
+        };
+
+        if !redeemed {
+            let _ = db_session.abort_transaction().await;
+            return Ok(
+                ResponseBuilder::<()>::error("CouponNoLongerValid", None, None, Some(409))
+                    .into_response(),
+            );
+        }
+    }
+
+    let part_amounts =
+        payments::order_part_amounts(&order, discount.as_ref(), charge_currency.currency);
+
+    // Everything the order needs is saved before the card is charged, with the payment
+    // pending and a post payment job that waits for it, so a charge always has an order
+    let saved: Result<()> = async {
+        db.set_order_parts_commission(
+            order.id().unwrap(),
+            &part_commissions,
+            Some(&mut db_session),
+        )
+        .await?;
+
+        db.set_order_currency(order.id().unwrap(), &charge_currency, Some(&mut db_session))
+            .await?;
+
+        db.set_order_parts_tax(order.id().unwrap(), &part_taxes, Some(&mut db_session))
+            .await?;
+
+        db.init_order_payment(
+            order.id().unwrap(),
+            OrderPaymentStatus::Pending,
+            &[],
+            Some(&mut db_session),
+        )
+        .await?;
+
+        db.insert_post_payment_job(
+            order.id().unwrap(),
+            &PostPaymentJob::awaiting_payment(),
+            Some(&mut db_session),
+        )
+        .await?;
+
+        Ok(())
+    }
+    .await;
+
+    // The promotion was redeemed outside the transaction,
+    // it's given back on every exit that doesn't leave an order behind
+    if let Err(e) = saved {
+        let _ = db_session.abort_transaction().await;
+        release_promotion(&local_db, &discount, order.id().unwrap()).await;
+        return Err(e);
+    }
+
+    if let Err(e) = db.commit_transaction(&mut db_session, Some(16)).await {
+        release_promotion(&local_db, &discount, order.id().unwrap()).await;
+        return Err(e);
+    }
+
+    let settlement = CheckoutOrderSettlement {
+        db: &db,
+        local_db: &local_db,
+        order: &order,
+        checkout_session_id: &checkout_session_id,
+        discount: &discount,
+        card_holder_name,
+        part_amounts: &part_amounts,
+    };
+
+    match payments::pay_checkout_order(
+        payment_client.0.as_ref(),
+        &settlement,
+        &order.order_number,
+        Money::from_major(order.total, charge_currency.currency),
+        payment_source,
+    )
+    .await
+    {
+        CheckoutPayment::Paid(_) => {}
+        CheckoutPayment::Failed(err) => {
+            // the declined order keeps its number
+            cookies.delete_order_number_cookie();
             return Ok(ResponseBuilder::error(
                 "Failed to charge credit card",
-                Some(e.to_string()),
+                Some(err),
                 None,
                 Some(500),
             )
             .into_response());
         }
-    };
-
-    let transaction_info = match charge_res {
-        ChargeResult::Failure(err) => {
-            // same comment as above
-            tracing::error!("Failed to charge credit card2: {}", err);
-            let _ = db_session.abort_transaction().await;
+        CheckoutPayment::Declined(err) => {
+            cookies.delete_order_number_cookie();
             return Ok(ResponseBuilder::<()>::error(
                 "Failed to charge credit card",
                 None,
@@ -583,157 +1226,592 @@ pub async fn checkout_pay(
             )
             .into_response());
         }
-        ChargeResult::Success(data) => data,
+        CheckoutPayment::Unrecorded => {
+            // the reservation is used by the order, paying again would charge twice
+            cookies.delete_checkout_session_cookie();
+            cookies.delete_order_number_cookie();
+            return Ok(
+                ResponseBuilder::<()>::error("Failed to save order", None, None, Some(500))
+                    .into_response(),
+            );
+        }
+    }
+
+    // The job is persisted with the order, if this fails or the process
+    // restarts the post payment worker will pick it up
+    tokio::spawn(workers::process_post_payment_job(
+        db.0.clone(),
+        local_db.0.clone(),
+        storage_client.0.clone(),
+        invoice_client.0.clone(),
+        email_client.0.clone(),
+        order.id().unwrap().clone(),
+    ));
+
+    cookies.delete_checkout_session_cookie();
+    cookies.delete_order_number_cookie();
+
+    // The card is saved from the transaction of this order, a failure here doesn't fail the order
+    if save_payment_method && !current_user.guest {
+        save_payment_method_from_order(&db, &current_user.user_id, order.id().unwrap()).await;
+    }
+
+    Ok(ResponseBuilder::<()>::success(None, None, Some(201)).into_response())
+}
+
+// The order of a declined charge is kept as declined (it's not shown anywhere),
+// and the stock reservation goes back to the checkout session so it can be paid again.
+// The stock itself is only given back when the reservation is released.
+async fn discard_unpaid_order(
+    db: &AxumDBExtansion,
+    order_id: &ObjectId,
+    checkout_session_id: &ObjectId,
+) {
+    let mut db_session = match db.start_session().await {
+        Ok(db_session) => db_session,
+        Err(_) => {
+            tracing::error!("Failed to discard unpaid order {}", order_id);
+            return;
+        }
     };
 
-    db.commit_transaction(&mut db_session, Some(16)).await?;
+    if db_session.start_transaction(None).await.is_err() {
+        tracing::error!("Failed to discard unpaid order {}", order_id);
+        return;
+    }
 
-    tokio::spawn(async move {
-        let full_order: Order = match db
-            .get_order_by_id(
-                order.id().unwrap(),
-                None,
-                Some(OrderPopulate {
-                    stores: FieldPopulate::Field,
-                    products: FieldPopulate::Field,
-                    user: FieldPopulate::None,
-                    options: None,
-                }),
-                None,
-            )
+    let declined = match db
+        .decline_order_payment(order_id, Some(&mut db_session))
+        .await
+    {
+        Ok(res) => res.modified_count == 1,
+        Err(_) => {
+            tracing::error!("Failed to discard unpaid order {}", order_id);
+            let _ = db_session.abort_transaction().await;
+            return;
+        }
+    };
+
+    // The processor already failed the payment, its webhook gives the stock back
+    if !declined {
+        let _ = db_session.abort_transaction().await;
+        return;
+    }
+
+    if db
+        .unconfirm_checkout_session_reservation(
+            checkout_session_id,
+            order_id,
+            Some(&mut db_session),
+        )
+        .await
+        .is_err()
+    {
+        tracing::error!("Failed to discard unpaid order {}", order_id);
+        let _ = db_session.abort_transaction().await;
+        return;
+    }
+
+    if db
+        .commit_transaction(&mut db_session, Some(16))
+        .await
+        .is_err()
+    {
+        tracing::error!("Failed to discard unpaid order {}", order_id);
+    }
+}
+
+async fn reservation_lapsed_response(
+    db: &AxumDBExtansion,
+    reserved_items: Vec<(ObjectId, ObjectId, u32)>,
+) -> HandlerResult {
+    let products = db
+        .get_products(
+            doc! {
+                Product::fields().id: {
+                    "$in": reserved_items.iter().map(|(product_id, _, _)| product_id).collect::<Vec<_>>()
+                }
+            },
+            None,
+            None,
+            None,
+        )
+        .await?;
+
+    let errors: Vec<_> = reserved_items
+        .iter()
+        .map(|(product_id, item_id, quantity)| {
+            let in_storage = products
+                .iter()
+                .find(|product| product.id().unwrap() == product_id)
+                .and_then(|product| product.items.iter().find(|item| item.id() == item_id))
+                .map(|item| item.in_storage)
+                .unwrap_or(0);
+
+            json!({
+                "product": product_id,
+                "item": item_id,
+                "quantity": quantity,
+                "in_storage": in_storage,
+                "error": "Reservation expired"
+            })
+        })
+        .collect();
+
+    Ok(
+        ResponseBuilder::error("CheckoutReservationExpired", Some(errors), None, Some(409))
+            .into_response(),
+    )
+}
+
+async fn release_promotion(
+    local_db: &AxumLocalDBExtansion,
+    discount: &Option<CheckoutDiscount>,
+    order_id: &ObjectId,
+) {
+    if let Some(discount) = discount {
+        if local_db
+            .release_promotion_redemption(&discount.promotion, order_id)
             .await
+            .is_err()
         {
-            Ok(order) => order.unwrap(),
-            Err(_) => return,
+            tracing::error!("Failed to release promotion of order {}", order_id);
+        }
+    }
+}
+
+// A checkout that could not be created, the caller decides how it's reported
+struct CheckoutRejection {
+    code: &'static str,
+    errors: Option<Vec<serde_json::Value>>,
+    status: Option<u16>,
+}
+
+impl CheckoutRejection {
+    fn new(
+        code: &'static str,
+        errors: Option<Vec<serde_json::Value>>,
+        status: Option<u16>,
+    ) -> Self {
+        Self {
+            code,
+            errors,
+            status,
+        }
+    }
+
+    fn into_response(self) -> Response {
+        ResponseBuilder::error(self.code, self.errors, None, self.status).into_response()
+    }
+}
+
+/// What the customer pays for a store price, in the charge currency
+fn customer_price(
+    price: f64,
+    store_currency: Currency,
+    store_tax: &StoreTax,
+    charge_currency: &ChargeCurrency,
+    date: chrono::NaiveDate,
+) -> Result<f64> {
+    charge_currency.from_store(
+        store_tax.gross_price(price, store_currency, date),
+        store_currency,
+    )
+}
+
+/// The items total of a checkout session part, summed in minor units
+fn part_items_total(items: &[CheckOutSessionPartItem], currency: Currency) -> Money {
+    Money::sum(
+        currency,
+        items
+            .iter()
+            .map(|item| Money::from_major(item.price, currency).times(item.quantity)),
+    )
+}
+
+/// Diffs the user's cart against the items and prices of a checkout session.
+/// The cart prices are compared as the customer pays them, by the exchange rates
+/// the session was quoted with, like the pay check does.
+fn checkout_session_changes(
+    checkout_session: &CheckOutSession,
+    user: &User,
+    session_currency: &ChargeCurrency,
+    store_currencies: &HashMap<ObjectId, Currency>,
+    stores_tax: &HashMap<ObjectId, StoreTax>,
+) -> Result<Vec<serde_json::Value>> {
+    let today = chrono::Utc::now().date_naive();
+
+    let mut session_items: HashMap<(&ObjectId, &ObjectId), &CheckOutSessionPartItem> =
+        checkout_session
+            .parts
+            .iter()
+            .flat_map(|part| part.items.iter())
+            .map(|item| ((&item.product, &item.item_id), item))
+            .collect();
+
+    let mut changes = Vec::new();
+
+    for cart_item in &user.cart.items {
+        let product = match cart_item.product.as_populated() {
+            Some(product) => product,
+            None => continue,
         };
 
-        // adding payment info to order
-        let fn1 = db.update_order_after_payment(&order, transaction_info, payload.card_holder_name);
-        // clear user cart + update his phone number if needed
-        let fn2 = db.update_user_after_order(&user, &order);
-        // update products storage
-        let fn3 = db.update_products_storage_by_order(&order, None);
+        let product_item = match product.items.get(0) {
+            Some(product_item) => product_item,
+            None => continue,
+        };
 
-        let mut data: Vec<shoppa_core::invoice_service::InvoicePart> = Vec::new();
+        let product_id = product.id().unwrap();
+
+        let store_currency = store_currencies
+            .get(product.store_id())
+            .copied()
+            .unwrap_or_default();
+
+        let price = customer_price(
+            product_item.price,
+            store_currency,
+            &stores_tax
+                .get(product.store_id())
+                .cloned()
+                .unwrap_or_default(),
+            session_currency,
+            today,
+        )?;
+
+        let session_item = match session_items.remove(&(product_id, product_item.id())) {
+            Some(session_item) => session_item,
+            None => {
+                changes.push(json!({
+                    "product": product_id,
+                    "item": product_item.id(),
+                    "quantity": cart_item.quantity,
+                    "price": price,
+                    "change": "added"
+                }));
+                continue;
+            }
+        };
 
-        for part in &full_order.parts {
-            let keys = StorageFolders::generate_invoice_keys(
-                order.id().unwrap(),
-                part.store.ref_doc_id(),
-                &InvoiceType::Reciept,
-            );
+        if session_item.price != price {
+            changes.push(json!({
+                "product": product_id,
+                "item": product_item.id(),
+                "old_price": session_item.price,
+                "new_price": price,
+                "change": "price_changed"
+            }));
+        }
 
-            let url1 = storage_client
-                .generate_secure_upload_url(
-                    keys[0].as_str(),
-                    // 5 hours
-                    18000,
-                    "application/pdf",
-                    shoppa_core::file_storage::Buckets::Invoice,
-                )
-                .await
-                .unwrap();
-
-            let url2 = storage_client
-                .generate_secure_upload_url(
-                    keys[1].as_str(),
-                    18000,
-                    "application/pdf",
-                    shoppa_core::file_storage::Buckets::Invoice,
+        if session_item.quantity != cart_item.quantity {
+            changes.push(json!({
+                "product": product_id,
+                "item": product_item.id(),
+                "old_quantity": session_item.quantity,
+                "new_quantity": cart_item.quantity,
+                "change": "quantity_changed"
+            }));
+        }
+    }
+
+    // left in the session but not in the cart anymore
+    for ((product_id, item_id), session_item) in session_items {
+        changes.push(json!({
+            "product": product_id,
+            "item": item_id,
+            "quantity": session_item.quantity,
+            "change": "removed"
+        }));
+    }
+
+    Ok(changes)
+}
+
+/// Compares the prices a checkout session was quoted with against the catalog,
+/// as the customer pays them by the exchange rates the session was quoted with.
+async fn checkout_session_price_changes(
+    db: &AxumDBExtansion,
+    checkout_session: &CheckOutSession,
+    charge_currency: &ChargeCurrency,
+) -> Result<Vec<serde_json::Value>> {
+    let items: Vec<(&ObjectId, &CheckOutSessionPartItem)> = checkout_session
+        .parts
+        .iter()
+        .flat_map(|part| part.items.iter().map(move |item| (&part.store, item)))
+        .collect();
+
+    let store_ids: Vec<ObjectId> = checkout_session
+        .parts
+        .iter()
+        .map(|part| part.store)
+        .collect();
+
+    let store_currencies = db.get_stores_currency(&store_ids).await?;
+    let stores_tax = db.get_stores_tax(&store_ids).await?;
+    let today = chrono::Utc::now().date_naive();
+
+    let products = db
+        .get_products(
+            doc! {
+                Product::fields().id: {
+                    "$in": items.iter().map(|(_, item)| &item.product).collect::<Vec<_>>()
+                }
+            },
+            None,
+            None,
+            None,
+        )
+        .await?;
+
+    let errors = items
+        .iter()
+        .filter_map(|(store_id, item)| {
+            let product_item = products
+                .iter()
+                .find(|product| product.id().unwrap() == &item.product)
+                .and_then(|product| {
+                    product
+                        .items
+                        .iter()
+                        .find(|product_item| product_item.id() == &item.item_id)
+                });
+
+            match product_item {
+                None => Some(Ok(json!({
+                    "product": item.product,
+                    "item": item.item_id,
+                    "error": "Product item not found"
+                }))),
+                Some(product_item) => {
+                    let store_currency =
+                        store_currencies.get(*store_id).copied().unwrap_or_default();
+
+                    let price = match customer_price(
+                        product_item.price,
+                        store_currency,
+                        &stores_tax.get(*store_id).cloned().unwrap_or_default(),
+                        charge_currency,
+                        today,
+                    ) {
+                        Ok(price) => price,
+                        Err(e) => return Some(Err(e)),
+                    };
+
+                    (price != item.price).then(|| {
+                        Ok(json!({
+                            "product": item.product,
+                            "item": item.item_id,
+                            "old_price": item.price,
+                            "new_price": price,
+                            "error": "Price changed"
+                        }))
+                    })
+                }
+            }
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(errors)
+}
+
+/// The commission of each session part by the store terms in effect now,
+/// in the order of the parts.
+/// A free delivery discount leaves the items total, and so the commission, as it is.
+async fn order_parts_commission(
+    db: &AxumDBExtansion,
+    checkout_session: &CheckOutSession,
+    charge_currency: &ChargeCurrency,
+    discount: Option<&CheckoutDiscount>,
+    free_delivery: bool,
+) -> Result<Vec<OrderPartCommission>> {
+    let store_ids: Vec<ObjectId> = checkout_session
+        .parts
+        .iter()
+        .map(|part| part.store.clone())
+        .collect();
+
+    let product_ids: Vec<ObjectId> = checkout_session
+        .parts
+        .iter()
+        .flat_map(|part| part.items.iter().map(|item| item.product.clone()))
+        .collect();
+
+    let commissions = db.get_stores_commission(&store_ids).await?;
+    let categories = db.get_products_category_paths(&product_ids).await?;
+    let store_currencies = db.get_stores_currency(&store_ids).await?;
+
+    let now = bson::DateTime::now();
+    let default_commission = StoreCommission::default();
+
+    checkout_session
+        .parts
+        .iter()
+        .map(|part| {
+            let items: Vec<CommissionItem> = part
+                .items
+                .iter()
+                .map(|item| CommissionItem {
+                    total: Money::from_major(item.price, charge_currency.currency)
+                        .times(item.quantity),
+                    categories: categories
+                        .get(&item.product)
+                        .map(|paths| paths.as_slice())
+                        .unwrap_or(&[]),
+                })
+                .collect();
+
+            let items_discount = match discount {
+                Some(discount) if !free_delivery => {
+                    discount.for_store(&part.store, charge_currency.currency)
+                }
+                _ => Money::zero(charge_currency.currency),
+            };
+
+            commissions
+                .get(&part.store)
+                .unwrap_or(&default_commission)
+                .order_part_commission(&items, items_discount, now)
+                .in_currency(
+                    charge_currency,
+                    store_currencies
+                        .get(&part.store)
+                        .copied()
+                        .unwrap_or_default(),
                 )
-                .await
-                .unwrap();
-
-            let counter = db
-                .get_and_increase_counter_by1("reciept", None)
-                .await
-                .unwrap();
-
-            let invoice_doc = shoppa_core::db::models::Invoice::new(
-                shoppa_core::db::models::InvoiceType::Reciept,
-                full_order.id().unwrap().clone(),
-                part.store.ref_doc_id().clone(),
-                shoppa_core::db::models::InvoiceType::Reciept.generate_number(counter.value),
-                shoppa_core::db::models::FileDocument::new(
-                    false,
-                    "original-reciept".to_string(),
-                    keys[0].clone(),
-                    0,
-                    "application/pdf".to_string(),
-                    shoppa_core::db::models::FileTypes::Document,
-                ),
-                shoppa_core::db::models::FileDocument::new(
-                    false,
-                    "copy-reciept".to_string(),
-                    keys[1].clone(),
-                    0,
-                    "application/pdf".to_string(),
-                    shoppa_core::db::models::FileTypes::Document,
-                ),
-            );
+        })
+        .collect()
+}
+
+/// The VAT breakdown of each session part by the VAT rate in force today,
+/// in the order of the parts. The session prices are what the customer pays, so VAT is in them.
+async fn order_parts_tax(
+    db: &AxumDBExtansion,
+    checkout_session: &CheckOutSession,
+    charge_currency: &ChargeCurrency,
+    discount: Option<&CheckoutDiscount>,
+) -> Result<Vec<OrderPartTax>> {
+    let store_ids: Vec<ObjectId> = checkout_session
+        .parts
+        .iter()
+        .map(|part| part.store)
+        .collect();
+
+    let stores_tax = db.get_stores_tax(&store_ids).await?;
+    let today = chrono::Utc::now().date_naive();
 
-            let number = invoice_doc.number.clone();
-
-            let _ = db.insert_new_invoice(invoice_doc, None, None).await;
-
-            let store = part.store.as_populated().unwrap();
-
-            data.push(shoppa_core::invoice_service::InvoicePart {
-                store: shoppa_core::invoice_service::InvoiceStore {
-                    display_name: store.name.clone(),
-                    legal_name: store.legal_information.name.clone(),
-                    legal_id: store.legal_information.legal_id.clone(),
-                    address: store.legal_information.address.clone(),
-                    store_user_email: store.contact.email.clone(),
-                },
-                order_id: full_order.id().unwrap().clone().to_string(),
-                invoice_number: number,
-                upload_urls: shoppa_core::invoice_service::InvoicePartUploadUrls {
-                    original_url: url1,
-                    copy_url: url2,
-                },
-                cc_hint: order.transaction.gen_cc_hint(),
-                sum: part.total,
-                customer: shoppa_core::invoice_service::InvoiceCustomer {
-                    name: user.name.clone().unwrap_or("לקוח כללי".to_string()),
-                    id: order.info.customer_id.clone(),
-                    address: order.address.clone().to_invoice(),
-                    phone_number: order.info.phone_number.clone(),
-                    email: order.info.email.clone(),
-                },
-                items: part
-                    .items
-                    .iter()
-                    .map(|item| {
-                        let product = item.product.as_populated().unwrap();
-                        let product_image = product.assets.get(0);
-                        let path;
-                        if product_image.is_none() {
-                            path = String::new();
-                        } else {
-                            path = product_image.unwrap().path.clone();
-                        }
-                        shoppa_core::invoice_service::InvoicePartItem {
-                            name: product.name.clone(),
-                            price: item.price,
-                            quantity: item.quantity,
-                            _id: product.id().unwrap().clone().to_string(),
-                            image: path,
-                        }
+    Ok(checkout_session
+        .parts
+        .iter()
+        .map(|part| {
+            let items: Vec<TaxableItem> = part
+                .items
+                .iter()
+                .map(|item| TaxableItem {
+                    item_id: item.item_id,
+                    price: item.price,
+                    quantity: item.quantity,
+                })
+                .collect();
+
+            OrderPartTax::new(
+                &stores_tax.get(&part.store).cloned().unwrap_or_default(),
+                &items,
+                part.delivery_cost,
+                discount
+                    .map(|discount| {
+                        discount
+                            .for_store(&part.store, charge_currency.currency)
+                            .to_major()
                     })
-                    .collect(),
-            });
+                    .unwrap_or(0.0),
+                charge_currency.currency,
+                today,
+            )
+        })
+        .collect())
+}
+
+// How the order is paid for
+// The order of a checkout that is being charged, see `payments::pay_checkout_order`
+struct CheckoutOrderSettlement<'a> {
+    db: &'a AxumDBExtansion,
+    local_db: &'a AxumLocalDBExtansion,
+    order: &'a Order,
+    checkout_session_id: &'a ObjectId,
+    discount: &'a Option<CheckoutDiscount>,
+    card_holder_name: String,
+    // the store share of each part, what is captured or voided for it later
+    part_amounts: &'a [f64],
+}
+
+#[async_trait]
+impl CheckoutSettlement for CheckoutOrderSettlement<'_> {
+    async fn record_payment(
+        &self,
+        transaction_info: TransactionInfo,
+        status: OrderPaymentStatus,
+    ) -> Result<()> {
+        let res = self
+            .db
+            .update_order_after_payment(
+                self.order,
+                transaction_info,
+                self.card_holder_name.clone(),
+                status,
+                self.part_amounts,
+            )
+            .await?;
+
+        if res.modified_count == 1 {
+            return Ok(());
+        }
+
+        // the webhook recorded the payment first, or the payment failed in the meantime
+        match self
+            .db
+            .get_order_payment_state(self.order.id().unwrap())
+            .await?
+        {
+            Some(state)
+                if matches!(
+                    state.status,
+                    OrderPaymentStatus::Authorized | OrderPaymentStatus::Captured
+                ) =>
+            {
+                Ok(())
+            }
+            _ => Err(Error::Static("Order payment was taken back")),
         }
+    }
 
-        let _ = invoice_client.create_invoices(data).await;
+    async fn discard_order(&self) {
+        let order_id = self.order.id().unwrap();
 
-        let _ = fn1.await;
-        let _ = fn2.await;
-        let _ = fn3.await;
-    });
+        discard_unpaid_order(self.db, order_id, self.checkout_session_id).await;
+        // The promotion was redeemed outside the transaction of the order
+        release_promotion(self.local_db, self.discount, order_id).await;
+    }
+}
 
-    cookies.delete_checkout_session_cookie();
-    cookies.delete_order_number_cookie();
+async fn save_payment_method_from_order(
+    db: &AxumDBExtansion,
+    user_id: &ObjectId,
+    order_id: &ObjectId,
+) {
+    let order = match db.get_order_by_id(order_id, None, None, None).await {
+        Ok(Some(order)) => order,
+        _ => {
+            tracing::error!("Failed to get order {} to save its card", order_id);
+            return;
+        }
+    };
 
-    Ok(ResponseBuilder::<()>::success(None, None, Some(201)).into_response())
+    if db
+        .add_user_payment_method(user_id, SavedPaymentMethod::new(order.transaction))
+        .await
+        .is_err()
+    {
+        tracing::error!("Failed to save the card of order {}", order_id);
+    }
 }
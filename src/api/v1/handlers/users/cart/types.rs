@@ -1,6 +1,8 @@
-use crate::prelude::types::*;
+use crate::{helpers::money::Currency, prelude::types::*};
 use shoppa_core::{db::models::CartItem, payments::types::CreditCard, validators, parser::hashmap_with_k_as_key};
 use std::collections::HashMap;
+use strum_macros::{Display, EnumString};
+
 #[derive(Deserialize, Serialize, Validate)]
 pub struct AddProductToCartPayload {
     pub product_id: ObjectId,
@@ -32,12 +34,41 @@ pub struct PayCartPayload {
     #[validate(email)]
     pub email: String,
     #[validate]
-    pub credit_card: CreditCard,
-    pub card_holder_name: String,
+    pub credit_card: Option<CreditCard>,
+    // A card the user saved from a previous order, instead of `credit_card`.
+    // Rejected with SavedCardsNotSupported when the payment provider can't charge saved cards
+    pub payment_method_id: Option<ObjectId>,
+    // Required with `credit_card`, a saved card has its own
+    pub card_holder_name: Option<String>,
+    // Rejected the same way as `payment_method_id`
+    #[serde(default)]
+    pub save_payment_method: bool,
     #[serde(default, deserialize_with = "hashmap_with_k_as_key")]
     pub utms: HashMap<ObjectId, String>,
 }
 
+#[derive(
+    Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default, Display, EnumString,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum DeliveryStrategyKind {
+    #[default]
+    Default,
+    Fast,
+}
+
+#[derive(Deserialize, Serialize, Validate, Default)]
+pub struct StartCheckoutPayload {
+    // store id -> delivery strategy, stores that are missing get the default one
+    #[serde(default, deserialize_with = "hashmap_with_k_as_key")]
+    pub delivery_strategies: HashMap<ObjectId, DeliveryStrategyKind>,
+    #[validate(length(min = 1, max = 32))]
+    pub coupon: Option<String>,
+    // The checkout is charged in it, the platform currency when missing
+    pub currency: Option<Currency>,
+}
+
 impl From<AddProductToCartPayload> for CartItem {
     fn from(payload: AddProductToCartPayload) -> Self {
         Self::new(payload.product_id, payload.item_id, payload.quantity)
@@ -2,8 +2,13 @@ use crate::api::v1::middlewares;
 use axum::{middleware, routing, Router};
 mod address;
 mod cart;
+mod orders;
 mod password;
+mod payment_methods;
+mod reviews;
+mod stock_alerts;
 mod types;
+mod wishlists;
 
 pub fn router() -> Router {
     Router::new()
@@ -11,8 +16,15 @@ pub fn router() -> Router {
             "/update-password",
             routing::patch(password::change_password),
         )
+        .nest("/payment-methods", payment_methods::router())
+        .nest("/reviews", reviews::router())
+        // the alerts are sent by email, so guests can't subscribe
+        .nest("/stock-alerts", stock_alerts::router())
         .route_layer(middleware::from_fn(middlewares::guest_user_not_allowed))
         .nest("/addresses", address::router())
+        // guests can see the orders they made too
+        .nest("/orders", orders::router())
         .route_layer(middleware::from_fn(middlewares::login_required))
         .nest("/cart", cart::router())
+        .nest("/wishlists", wishlists::router())
 }
@@ -0,0 +1,9 @@
+use axum::{routing, Router};
+mod routes;
+mod types;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/:order_oid", routing::get(routes::get_order))
+        .route("/", routing::get(routes::get_orders))
+}
@@ -0,0 +1,43 @@
+use super::types;
+use crate::{
+    api::v1::middlewares::CurrentUser,
+    db::{AxumDBExtansion, OrderFunctions},
+    prelude::*,
+};
+use axum::{
+    extract::{Path, Query},
+    response::IntoResponse,
+};
+use bson::oid::ObjectId;
+use shoppa_core::{db::Pagination, ResponseBuilder};
+
+pub async fn get_orders(
+    db: AxumDBExtansion,
+    current_user: CurrentUser,
+    pagination: Pagination,
+    Query(query): Query<types::OrdersQuery>,
+) -> HandlerResult {
+    let orders = db
+        .get_orders_for_user(Some(pagination), &current_user.user_id, query.status, None)
+        .await?;
+
+    Ok(ResponseBuilder::paginated_response(&orders).into_response())
+}
+
+pub async fn get_order(
+    db: AxumDBExtansion,
+    current_user: CurrentUser,
+    Path(order_oid): Path<ObjectId>,
+) -> HandlerResult {
+    let order = db
+        .get_order_by_id_for_user(&current_user.user_id, &order_oid, None)
+        .await?;
+
+    if order.is_none() {
+        return Ok(
+            ResponseBuilder::<()>::error("OrderNotFound", None, None, Some(404)).into_response(),
+        );
+    }
+
+    Ok(ResponseBuilder::success(order, None, None).into_response())
+}
@@ -0,0 +1,7 @@
+use crate::prelude::types::*;
+use shoppa_core::db::models::OrderPartStatus;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct OrdersQuery {
+    pub status: Option<OrderPartStatus>,
+}
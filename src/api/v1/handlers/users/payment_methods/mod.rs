@@ -0,0 +1,11 @@
+use axum::{routing, Router};
+mod routes;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/", routing::get(routes::get_payment_methods))
+        .route(
+            "/:payment_method_oid",
+            routing::delete(routes::delete_payment_method),
+        )
+}
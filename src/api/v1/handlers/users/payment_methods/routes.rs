@@ -0,0 +1,47 @@
+use crate::{
+    api::v1::middlewares::CurrentUser,
+    db::{AxumDBExtansion, PaymentMethodFunctions},
+    helpers::types::AxumPaymentClientExtension,
+    prelude::*,
+};
+use axum::{extract::Path, response::IntoResponse};
+use bson::oid::ObjectId;
+use shoppa_core::ResponseBuilder;
+
+pub async fn get_payment_methods(
+    db: AxumDBExtansion,
+    payment_client: AxumPaymentClientExtension,
+    current_user: CurrentUser,
+) -> HandlerResult {
+    // The provider can't charge a saved card again (the core payment client can't),
+    // so saved cards are turned off rather than listed and then rejected at checkout
+    if !payment_client.supports_saved_cards() {
+        return Ok(
+            ResponseBuilder::<()>::error("SavedCardsNotSupported", None, None, Some(400))
+                .into_response(),
+        );
+    }
+
+    let payment_methods = db.get_user_payment_methods(&current_user.user_id).await?;
+
+    Ok(ResponseBuilder::success(Some(payment_methods), None, None).into_response())
+}
+
+pub async fn delete_payment_method(
+    db: AxumDBExtansion,
+    current_user: CurrentUser,
+    Path(payment_method_id): Path<ObjectId>,
+) -> HandlerResult {
+    let update_res = db
+        .delete_user_payment_method(&current_user.user_id, &payment_method_id)
+        .await?;
+
+    if update_res.modified_count == 0 {
+        return Ok(
+            ResponseBuilder::<()>::error("PaymentMethodNotFound", None, None, Some(404))
+                .into_response(),
+        );
+    }
+
+    Ok(ResponseBuilder::<()>::success(None, None, None).into_response())
+}
@@ -0,0 +1,6 @@
+use axum::{routing, Router};
+mod routes;
+
+pub fn router() -> Router {
+    Router::new().route("/", routing::post(routes::create_review))
+}
@@ -0,0 +1,54 @@
+use crate::{
+    api::v1::middlewares::CurrentUser,
+    db::{
+        refresh_product_rating, AxumDBExtansion, AxumLocalDBExtansion, CreateReviewPayload,
+        ProductRatingFunctions, Review, ReviewFunctions,
+    },
+    prelude::*,
+};
+use axum::response::IntoResponse;
+use shoppa_core::{extractors::JsonWithValidation, ResponseBuilder};
+
+pub async fn create_review(
+    db: AxumDBExtansion,
+    local_db: AxumLocalDBExtansion,
+    mut current_user: CurrentUser,
+    JsonWithValidation(payload): JsonWithValidation<CreateReviewPayload>,
+) -> HandlerResult {
+    // only a user the product was delivered to can review it
+    let purchase = match db
+        .get_delivered_purchase(&current_user.user_id, &payload.product)
+        .await?
+    {
+        Some(purchase) => purchase,
+        None => {
+            return Ok(
+                ResponseBuilder::<()>::error("ProductNotDelivered", None, None, Some(403))
+                    .into_response(),
+            );
+        }
+    };
+
+    current_user.fetch(&db, None).await?;
+
+    let user_name = current_user.get_user().and_then(|user| user.name.clone());
+
+    let review = Review::new(payload, purchase, current_user.user_id, user_name);
+
+    if !local_db.insert_new_review(&review).await? {
+        return Ok(
+            ResponseBuilder::<()>::error("ProductAlreadyReviewed", None, None, Some(409))
+                .into_response(),
+        );
+    }
+
+    // the review is saved, a stale rating is fixed by the next change to the product reviews
+    if refresh_product_rating(&db, &local_db, &review.product)
+        .await
+        .is_err()
+    {
+        tracing::error!("Failed to update rating of product {}", review.product);
+    }
+
+    Ok(ResponseBuilder::success(Some(review), None, Some(201)).into_response())
+}
@@ -0,0 +1,9 @@
+use axum::{routing, Router};
+mod routes;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/", routing::get(routes::get_stock_alerts))
+        .route("/", routing::post(routes::create_stock_alert))
+        .route("/:alert_id", routing::delete(routes::delete_stock_alert))
+}
@@ -0,0 +1,118 @@
+use crate::{
+    api::v1::middlewares::CurrentUser,
+    db::{
+        is_item_available, AxumDBExtansion, AxumLocalDBExtansion, CreateStockAlertPayload,
+        StockAlert, StockAlertFunctions, StockAlertKind, StockAlertRecipientFunctions,
+    },
+    prelude::*,
+};
+use axum::{extract::Path, response::IntoResponse};
+use bson::oid::ObjectId;
+use shoppa_core::{
+    db::models::{EmbeddedDocument, ProductItemStatus, ProductStatus},
+    extractors::JsonWithValidation,
+    ResponseBuilder,
+};
+
+pub async fn get_stock_alerts(
+    local_db: AxumLocalDBExtansion,
+    current_user: CurrentUser,
+) -> HandlerResult {
+    let alerts = local_db
+        .get_user_stock_alerts(&current_user.user_id)
+        .await?;
+
+    Ok(ResponseBuilder::success(Some(alerts), None, None).into_response())
+}
+
+pub async fn create_stock_alert(
+    db: AxumDBExtansion,
+    local_db: AxumLocalDBExtansion,
+    current_user: CurrentUser,
+    JsonWithValidation(payload): JsonWithValidation<CreateStockAlertPayload>,
+) -> HandlerResult {
+    if db
+        .get_stock_alert_recipient(&current_user.user_id)
+        .await?
+        .is_none()
+    {
+        return Ok(
+            ResponseBuilder::<()>::error("EmailRequired", None, None, Some(400)).into_response(),
+        );
+    }
+
+    let product = db
+        .get_product_by_id(&payload.product_id, None, None, None)
+        .await?
+        .filter(|product| {
+            matches!(
+                product.status,
+                ProductStatus::Active | ProductStatus::Inactive
+            )
+        });
+
+    let product = match product {
+        Some(product) => product,
+        None => {
+            return Ok(
+                ResponseBuilder::<()>::error("Product not found", None, None, Some(404))
+                    .into_response(),
+            );
+        }
+    };
+
+    let item = product
+        .items
+        .iter()
+        .find(|item| item.id() == &payload.item_id && item.status != ProductItemStatus::Deleted);
+
+    let item = match item {
+        Some(item) => item,
+        None => {
+            return Ok(ResponseBuilder::<()>::error(
+                "Product item not found",
+                None,
+                None,
+                Some(404),
+            )
+            .into_response());
+        }
+    };
+
+    if payload.kind == StockAlertKind::BackInStock && is_item_available(&product, item) {
+        return Ok(
+            ResponseBuilder::<()>::error("ProductItemAvailable", None, None, Some(409))
+                .into_response(),
+        );
+    }
+
+    let alert = StockAlert::new(current_user.user_id, payload, item.price);
+
+    if !local_db.insert_new_stock_alert(&alert).await? {
+        return Ok(
+            ResponseBuilder::<()>::error("AlreadySubscribed", None, None, Some(409))
+                .into_response(),
+        );
+    }
+
+    Ok(ResponseBuilder::success(Some(alert), None, Some(201)).into_response())
+}
+
+pub async fn delete_stock_alert(
+    local_db: AxumLocalDBExtansion,
+    current_user: CurrentUser,
+    Path(alert_id): Path<ObjectId>,
+) -> HandlerResult {
+    let res = local_db
+        .delete_stock_alert(&current_user.user_id, &alert_id)
+        .await?;
+
+    if res.deleted_count == 0 {
+        return Ok(
+            ResponseBuilder::<()>::error("StockAlertNotFound", None, None, Some(404))
+                .into_response(),
+        );
+    }
+
+    Ok(ResponseBuilder::<()>::success(None, None, None).into_response())
+}
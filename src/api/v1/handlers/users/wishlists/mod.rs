@@ -0,0 +1,41 @@
+use crate::api::v1::middlewares;
+use axum::{middleware, routing, Router};
+mod routes;
+mod types;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/", routing::get(routes::get_wishlists))
+        .route("/:wishlist_id", routing::get(routes::get_wishlist))
+        .route("/:wishlist_id", routing::delete(routes::delete_wishlist))
+        .route(
+            "/:wishlist_id/items",
+            routing::post(routes::add_item_to_wishlist),
+        )
+        .route(
+            "/:wishlist_id/items",
+            routing::delete(routes::remove_item_from_wishlist),
+        )
+        .route(
+            "/:wishlist_id/move-to-cart",
+            routing::post(routes::move_to_cart),
+        )
+        .route(
+            "/:wishlist_id/move-from-cart",
+            routing::post(routes::move_from_cart),
+        )
+        .route("/:wishlist_id/share", routing::post(routes::share_wishlist))
+        .route(
+            "/:wishlist_id/share",
+            routing::delete(routes::unshare_wishlist),
+        )
+        .route_layer(middleware::from_fn(middlewares::login_required))
+        .route(
+            "/",
+            routing::post(routes::create_wishlist).layer(middleware::from_fn(
+                middlewares::login_required_or_create_guest,
+            )),
+        )
+        // read only, anyone with the link can see the list
+        .route("/shared", routing::get(routes::get_shared_wishlist))
+}
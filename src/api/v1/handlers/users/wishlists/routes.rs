@@ -0,0 +1,393 @@
+use super::super::cart::{add_item_to_cart, AddProductToCartPayload};
+use super::types;
+use crate::{
+    api::v1::middlewares::CurrentUser,
+    db::{
+        AxumDBExtansion, AxumLocalDBExtansion, CreateWishlistPayload, UserFunctions, Wishlist,
+        WishlistFunctions, WishlistItemPayload, MAX_WISHLISTS_PER_USER,
+    },
+    helpers::cookies::CookieManager,
+    prelude::*,
+    tokens::{WishlistShareTokenData, WISHLIST_SHARE_TOKEN_MANAGER},
+};
+use axum::{
+    extract::{Path, Query},
+    response::{IntoResponse, Response},
+};
+use bson::oid::ObjectId;
+use serde_json::json;
+use shoppa_core::{
+    db::models::{EmbeddedDocument, ProductItemStatus, ProductStatus},
+    extractors::JsonWithValidation,
+    ResponseBuilder,
+};
+use tower_cookies::Cookies;
+
+fn wishlist_not_found() -> Response {
+    ResponseBuilder::<()>::error("WishlistNotFound", None, None, Some(404)).into_response()
+}
+
+pub async fn get_wishlists(
+    local_db: AxumLocalDBExtansion,
+    current_user: CurrentUser,
+) -> HandlerResult {
+    let wishlists = local_db.get_user_wishlists(&current_user.user_id).await?;
+
+    Ok(ResponseBuilder::success(Some(wishlists), None, None).into_response())
+}
+
+pub async fn get_wishlist(
+    local_db: AxumLocalDBExtansion,
+    current_user: CurrentUser,
+    Path(wishlist_id): Path<ObjectId>,
+) -> HandlerResult {
+    match local_db
+        .get_user_wishlist(&current_user.user_id, &wishlist_id)
+        .await?
+    {
+        Some(wishlist) => Ok(ResponseBuilder::success(Some(wishlist), None, None).into_response()),
+        None => Ok(wishlist_not_found()),
+    }
+}
+
+pub async fn create_wishlist(
+    local_db: AxumLocalDBExtansion,
+    current_user: CurrentUser,
+    JsonWithValidation(payload): JsonWithValidation<CreateWishlistPayload>,
+) -> HandlerResult {
+    let wishlists = local_db.get_user_wishlists(&current_user.user_id).await?;
+
+    if wishlists.len() as u64 >= MAX_WISHLISTS_PER_USER {
+        return Ok(
+            ResponseBuilder::<()>::error("TooManyWishlists", None, None, Some(409)).into_response(),
+        );
+    }
+
+    let wishlist = Wishlist::new(current_user.user_id, payload.name);
+
+    if wishlists
+        .iter()
+        .any(|existing| existing.name.to_lowercase() == wishlist.name.to_lowercase())
+    {
+        return Ok(
+            ResponseBuilder::<()>::error("WishlistNameTaken", None, None, Some(409))
+                .into_response(),
+        );
+    }
+
+    local_db.insert_new_wishlist(&wishlist).await?;
+
+    Ok(ResponseBuilder::success(Some(wishlist), None, Some(201)).into_response())
+}
+
+pub async fn delete_wishlist(
+    local_db: AxumLocalDBExtansion,
+    current_user: CurrentUser,
+    Path(wishlist_id): Path<ObjectId>,
+) -> HandlerResult {
+    let res = local_db
+        .delete_wishlist(&current_user.user_id, &wishlist_id)
+        .await?;
+
+    if res.deleted_count == 0 {
+        return Ok(wishlist_not_found());
+    }
+
+    Ok(ResponseBuilder::<()>::success(None, None, None).into_response())
+}
+
+/// Adds the item to the list, a product doesn't have to be available to be saved for later.
+/// Returns the response to send back if the item can't be added.
+async fn add_to_wishlist(
+    db: &AxumDBExtansion,
+    local_db: &AxumLocalDBExtansion,
+    wishlist: &Wishlist,
+    product_id: &ObjectId,
+    item_id: &ObjectId,
+) -> Result<Option<Response>> {
+    if wishlist.has_item(product_id, item_id) {
+        return Ok(None);
+    }
+
+    let product = db.get_product_by_id(product_id, None, None, None).await?;
+
+    let item_exists = product
+        .filter(|product| {
+            matches!(
+                product.status,
+                ProductStatus::Active | ProductStatus::Inactive
+            )
+        })
+        .map(|product| {
+            product
+                .items
+                .iter()
+                .any(|item| item.id() == item_id && item.status != ProductItemStatus::Deleted)
+        })
+        .unwrap_or(false);
+
+    if !item_exists {
+        return Ok(Some(
+            ResponseBuilder::<()>::error("Product item not found", None, None, Some(404))
+                .into_response(),
+        ));
+    }
+
+    let res = local_db
+        .add_item_to_wishlist(&wishlist.user, &wishlist.id, product_id, item_id)
+        .await?;
+
+    if res.modified_count == 0 {
+        return Ok(Some(
+            ResponseBuilder::<()>::error("WishlistFull", None, None, Some(409)).into_response(),
+        ));
+    }
+
+    Ok(None)
+}
+
+pub async fn add_item_to_wishlist(
+    db: AxumDBExtansion,
+    local_db: AxumLocalDBExtansion,
+    current_user: CurrentUser,
+    Path(wishlist_id): Path<ObjectId>,
+    JsonWithValidation(payload): JsonWithValidation<WishlistItemPayload>,
+) -> HandlerResult {
+    let wishlist = match local_db
+        .get_user_wishlist(&current_user.user_id, &wishlist_id)
+        .await?
+    {
+        Some(wishlist) => wishlist,
+        None => return Ok(wishlist_not_found()),
+    };
+
+    if let Some(response) = add_to_wishlist(
+        &db,
+        &local_db,
+        &wishlist,
+        &payload.product_id,
+        &payload.item_id,
+    )
+    .await?
+    {
+        return Ok(response);
+    }
+
+    Ok(ResponseBuilder::<()>::success(None, None, None).into_response())
+}
+
+pub async fn remove_item_from_wishlist(
+    local_db: AxumLocalDBExtansion,
+    current_user: CurrentUser,
+    Path(wishlist_id): Path<ObjectId>,
+    Query(query): Query<types::WishlistItemQuery>,
+) -> HandlerResult {
+    let res = local_db
+        .remove_item_from_wishlist(
+            &current_user.user_id,
+            &wishlist_id,
+            &query.product_id,
+            &query.item_id,
+        )
+        .await?;
+
+    if res.matched_count == 0 {
+        return Ok(wishlist_not_found());
+    }
+
+    Ok(ResponseBuilder::<()>::success(None, None, None).into_response())
+}
+
+pub async fn move_to_cart(
+    db: AxumDBExtansion,
+    local_db: AxumLocalDBExtansion,
+    cookies: Cookies,
+    mut current_user: CurrentUser,
+    Path(wishlist_id): Path<ObjectId>,
+    JsonWithValidation(payload): JsonWithValidation<types::MoveToCartPayload>,
+) -> HandlerResult {
+    let wishlist = match local_db
+        .get_user_wishlist(&current_user.user_id, &wishlist_id)
+        .await?
+    {
+        Some(wishlist) => wishlist,
+        None => return Ok(wishlist_not_found()),
+    };
+
+    if !wishlist.has_item(&payload.product_id, &payload.item_id) {
+        return Ok(
+            ResponseBuilder::<()>::error("Product item not found", None, None, Some(404))
+                .into_response(),
+        );
+    }
+
+    current_user.fetch(&db, None).await?;
+
+    if !current_user.user_exists() {
+        cookies.delete_access_cookie();
+        return Ok(
+            ResponseBuilder::<()>::error("User not found", None, None, None).into_response(),
+        );
+    }
+
+    // the same checks as adding to the cart from the product page
+    let cart_payload = AddProductToCartPayload {
+        product_id: payload.product_id,
+        item_id: payload.item_id,
+        quantity: payload.quantity,
+    };
+
+    if let Some(response) =
+        add_item_to_cart(&db, current_user.get_user_unchecked(), cart_payload).await?
+    {
+        return Ok(response);
+    }
+
+    local_db
+        .remove_item_from_wishlist(
+            &current_user.user_id,
+            &wishlist_id,
+            &payload.product_id,
+            &payload.item_id,
+        )
+        .await?;
+
+    Ok(ResponseBuilder::<()>::success(None, None, None).into_response())
+}
+
+pub async fn move_from_cart(
+    db: AxumDBExtansion,
+    local_db: AxumLocalDBExtansion,
+    cookies: Cookies,
+    mut current_user: CurrentUser,
+    Path(wishlist_id): Path<ObjectId>,
+    JsonWithValidation(payload): JsonWithValidation<WishlistItemPayload>,
+) -> HandlerResult {
+    let wishlist = match local_db
+        .get_user_wishlist(&current_user.user_id, &wishlist_id)
+        .await?
+    {
+        Some(wishlist) => wishlist,
+        None => return Ok(wishlist_not_found()),
+    };
+
+    current_user.fetch(&db, None).await?;
+
+    if !current_user.user_exists() {
+        cookies.delete_access_cookie();
+        return Ok(
+            ResponseBuilder::<()>::error("User not found", None, None, None).into_response(),
+        );
+    }
+
+    let in_cart = current_user
+        .get_user_unchecked()
+        .cart
+        .items
+        .iter()
+        .any(|item| item.product_id() == &payload.product_id && item.item_id == payload.item_id);
+
+    if !in_cart {
+        return Ok(
+            ResponseBuilder::<()>::error("Product item not in cart", None, None, Some(404))
+                .into_response(),
+        );
+    }
+
+    if let Some(response) = add_to_wishlist(
+        &db,
+        &local_db,
+        &wishlist,
+        &payload.product_id,
+        &payload.item_id,
+    )
+    .await?
+    {
+        return Ok(response);
+    }
+
+    db.remove_product_from_cart(
+        &current_user.user_id,
+        &payload.product_id,
+        &payload.item_id,
+        None,
+    )
+    .await?;
+
+    Ok(ResponseBuilder::<()>::success(None, None, None).into_response())
+}
+
+pub async fn share_wishlist(
+    local_db: AxumLocalDBExtansion,
+    current_user: CurrentUser,
+    Path(wishlist_id): Path<ObjectId>,
+) -> HandlerResult {
+    let wishlist = match local_db
+        .share_wishlist(&current_user.user_id, &wishlist_id)
+        .await?
+    {
+        Some(wishlist) => wishlist,
+        None => return Ok(wishlist_not_found()),
+    };
+
+    let token = WISHLIST_SHARE_TOKEN_MANAGER.generate_urlsafe_token(
+        WishlistShareTokenData {
+            wishlist_id: wishlist.id,
+            secret: wishlist.share_secret.unwrap_or_default(),
+        },
+        None,
+    )?;
+
+    let link = format!("{}/wishlists/shared?token={}", ENV_VARS.SHOPPA_URL, token);
+
+    Ok(ResponseBuilder::success(Some(json!({ "link": link })), None, None).into_response())
+}
+
+pub async fn unshare_wishlist(
+    local_db: AxumLocalDBExtansion,
+    current_user: CurrentUser,
+    Path(wishlist_id): Path<ObjectId>,
+) -> HandlerResult {
+    let res = local_db
+        .unshare_wishlist(&current_user.user_id, &wishlist_id)
+        .await?;
+
+    if res.matched_count == 0 {
+        return Ok(wishlist_not_found());
+    }
+
+    Ok(ResponseBuilder::<()>::success(None, None, None).into_response())
+}
+
+pub async fn get_shared_wishlist(
+    local_db: AxumLocalDBExtansion,
+    Query(query): Query<types::SharedWishlistQuery>,
+) -> HandlerResult {
+    let token_data = match WISHLIST_SHARE_TOKEN_MANAGER.decode_token(&query.token) {
+        Ok(token_data) => token_data,
+        Err(_) => {
+            return Ok(
+                ResponseBuilder::<()>::error("InvalidToken", None, None, Some(401)).into_response(),
+            );
+        }
+    };
+
+    let wishlist = local_db
+        .get_wishlist_by_id(&token_data.wishlist_id)
+        .await?
+        // a list that was unshared or shared again doesn't match old links
+        .filter(|wishlist| wishlist.share_secret.as_ref() == Some(&token_data.secret));
+
+    match wishlist {
+        Some(wishlist) => Ok(ResponseBuilder::success(
+            Some(json!({
+                "name": wishlist.name,
+                "items": wishlist.items,
+            })),
+            None,
+            None,
+        )
+        .into_response()),
+        None => Ok(wishlist_not_found()),
+    }
+}
@@ -0,0 +1,20 @@
+use crate::prelude::types::*;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct WishlistItemQuery {
+    pub product_id: ObjectId,
+    pub item_id: ObjectId,
+}
+
+#[derive(Debug, Deserialize, Clone, Validate)]
+pub struct MoveToCartPayload {
+    pub product_id: ObjectId,
+    pub item_id: ObjectId,
+    #[validate(range(min = 1))]
+    pub quantity: u32,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SharedWishlistQuery {
+    pub token: String,
+}
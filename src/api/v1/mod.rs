@@ -11,6 +11,8 @@ pub fn router() -> Router {
         .nest("/analytics", handlers::analytics::router())
         .nest("/blog", handlers::blog::router())
         .nest("/contact-us", handlers::contact_us::router())
+        .nest("/emails", handlers::emails::router())
+        .nest("/orders", handlers::orders::router())
         .nest("/stores", handlers::stores::router())
         .nest("/auth", handlers::auth::router())
 }
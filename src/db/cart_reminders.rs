@@ -0,0 +1,206 @@
+use crate::prelude::{types::*, *};
+use axum::async_trait;
+use bson::{doc, oid::ObjectId, DateTime as BsonDateTime, Document};
+use mongodb::results::UpdateResult;
+use shoppa_core::db::{
+    aggregations,
+    models::{Order, User, UserStatus},
+    DBConection,
+};
+use std::collections::HashMap;
+
+// How many cart reminders the user got and when, stored on the user document
+// (under `cart_reminders`)
+pub const CART_REMINDERS_FIELD: &str = "cart_reminders";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AbandonedCart {
+    #[serde(rename = "_id")]
+    pub user_id: ObjectId,
+    pub email: String,
+    pub name: Option<String>,
+    pub items_count: u32,
+    pub last_updated: BsonDateTime,
+}
+
+/// A user is reminded once for each time the cart was left,
+/// touching the cart again makes it count as a new one.
+fn not_reminded_since_last_update() -> Document {
+    doc! {
+        "$or": [
+            {
+                format!("{}.last_sent_at", CART_REMINDERS_FIELD): {
+                    "$exists": false
+                }
+            },
+            {
+                "$expr": {
+                    "$lt": [
+                        format!("${}.last_sent_at", CART_REMINDERS_FIELD),
+                        format!("${}", User::fields().cart(true).last_updated),
+                    ]
+                }
+            }
+        ]
+    }
+}
+
+#[async_trait]
+pub trait CartReminderFunctions {
+    /// Registered users with items in the cart that nobody touched since `untouched_since`
+    /// and that did not order since, who can still get a reminder
+    async fn get_abandoned_carts(
+        &self,
+        untouched_since: BsonDateTime,
+        max_reminders: u32,
+        limit: i64,
+    ) -> Result<Vec<AbandonedCart>>;
+    /// Returns false if the cart was already handled, so the reminder must not be sent.
+    /// A cart that is skipped without a reminder is marked with `sent` false,
+    /// it doesn't count towards the limit.
+    async fn mark_cart_reminder(&self, cart: &AbandonedCart, sent: bool) -> Result<bool>;
+    async fn unsubscribe_from_cart_reminders(&self, user_id: &ObjectId) -> Result<UpdateResult>;
+}
+
+#[async_trait]
+impl CartReminderFunctions for DBConection {
+    async fn get_abandoned_carts(
+        &self,
+        untouched_since: BsonDateTime,
+        max_reminders: u32,
+        limit: i64,
+    ) -> Result<Vec<AbandonedCart>> {
+        let mut filters = doc! {
+            User::fields().status: {
+                "$nin": [UserStatus::Deleted, UserStatus::Banned, UserStatus::Guest]
+            },
+            User::fields().email: {
+                "$type": "string"
+            },
+            format!("{}.0", User::fields().cart(true).items): {
+                "$exists": true
+            },
+            User::fields().cart(true).last_updated: {
+                "$lte": untouched_since
+            },
+            format!("{}.unsubscribed", CART_REMINDERS_FIELD): {
+                "$ne": true
+            },
+            format!("{}.sent", CART_REMINDERS_FIELD): {
+                "$not": {
+                    "$gte": max_reminders
+                }
+            },
+        };
+
+        filters.extend(not_reminded_since_last_update());
+
+        let pipeline = [
+            aggregations::match_query(&filters),
+            aggregations::sort(doc! {
+                User::fields().cart(true).last_updated: 1
+            }),
+            aggregations::limit(limit),
+            doc! {
+                "$project": {
+                    User::fields().id: 1,
+                    User::fields().email: 1,
+                    User::fields().name: 1,
+                    "items_count": {
+                        "$size": format!("${}", User::fields().cart(true).items)
+                    },
+                    "last_updated": format!("${}", User::fields().cart(true).last_updated),
+                }
+            },
+        ];
+
+        let carts = self
+            .aggregate_users(pipeline, None, None)
+            .await?
+            .into_iter()
+            .map(bson::from_document::<AbandonedCart>)
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|_| Error::Desrilaztion)?;
+
+        if carts.is_empty() {
+            return Ok(carts);
+        }
+
+        let user_ids: Vec<ObjectId> = carts.iter().map(|cart| cart.user_id).collect();
+
+        // The latest order of each of the users
+        let pipeline = [
+            aggregations::match_query(&doc! {
+                Order::fields().user: {
+                    "$in": user_ids
+                }
+            }),
+            doc! {
+                "$group": {
+                    "_id": format!("${}", Order::fields().user),
+                    "last_order_at": {
+                        "$max": format!("${}", Order::fields().created_at)
+                    }
+                }
+            },
+        ];
+
+        let last_orders: HashMap<ObjectId, BsonDateTime> = self
+            .aggregate_orders(pipeline, None, None)
+            .await?
+            .iter()
+            .filter_map(|order| {
+                Some((
+                    order.get_object_id("_id").ok()?,
+                    *order.get_datetime("last_order_at").ok()?,
+                ))
+            })
+            .collect();
+
+        let mut abandoned = Vec::with_capacity(carts.len());
+
+        for cart in carts {
+            match last_orders.get(&cart.user_id) {
+                Some(last_order_at) if *last_order_at >= cart.last_updated => {
+                    // ordered since, mark it so the cart isn't picked again
+                    self.mark_cart_reminder(&cart, false).await?;
+                }
+                _ => abandoned.push(cart),
+            }
+        }
+
+        Ok(abandoned)
+    }
+
+    async fn mark_cart_reminder(&self, cart: &AbandonedCart, sent: bool) -> Result<bool> {
+        let mut filters = doc! {
+            User::fields().id: cart.user_id,
+            User::fields().cart(true).last_updated: cart.last_updated,
+        };
+
+        filters.extend(not_reminded_since_last_update());
+
+        let update = doc! {
+            "$set": {
+                format!("{}.last_sent_at", CART_REMINDERS_FIELD): BsonDateTime::now()
+            },
+            "$inc": {
+                format!("{}.sent", CART_REMINDERS_FIELD): if sent { 1 } else { 0 }
+            }
+        };
+
+        let res = self.update_user(filters, update, None, None).await?;
+
+        Ok(res.modified_count == 1)
+    }
+
+    async fn unsubscribe_from_cart_reminders(&self, user_id: &ObjectId) -> Result<UpdateResult> {
+        let update = doc! {
+            "$set": {
+                format!("{}.unsubscribed", CART_REMINDERS_FIELD): true
+            }
+        };
+
+        self.update_user_by_id(user_id, update, None, None).await
+    }
+}
@@ -1,8 +1,33 @@
-use crate::prelude::*;
+use crate::{db::ProductFunctions, prelude::*};
 use axum::async_trait;
-use bson::{doc, oid::ObjectId};
-use mongodb::options::FindOneOptions;
+use bson::{doc, oid::ObjectId, DateTime as BsonDateTime, Document};
+use mongodb::{options::FindOneOptions, results::UpdateResult, ClientSession};
+use serde::{Deserialize, Serialize};
+use shoppa_core::db::{aggregations, models::DBModel};
 use shoppa_core::db::{models::CheckOutSession, populate::CheckoutSessionPopulate, DBConection};
+use strum_macros::Display;
+
+// 30 minutes, the checkout session cookie and the stock reservation share it
+pub const CHECKOUT_SESSION_EXP: i64 = 60 * 30;
+
+// The stock reservation is stored on the checkout session document (under `reservation`)
+pub const RESERVATION_FIELD: &str = "reservation";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum CheckoutReservationStatus {
+    Active,
+    Confirmed,
+    Released,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckoutReservation {
+    pub status: CheckoutReservationStatus,
+    pub expires_at: BsonDateTime,
+    pub order: Option<ObjectId>,
+}
 
 #[async_trait]
 pub trait CheckoutSessionFunctions {
@@ -12,6 +37,107 @@ pub trait CheckoutSessionFunctions {
         options: Option<FindOneOptions>,
         populate: Option<CheckoutSessionPopulate>,
     ) -> Result<Option<CheckOutSession>>;
+
+    /// Takes the stock of all the session items, all or nothing.
+    /// Returns the items that could not be reserved (product id, item id).
+    async fn reserve_checkout_session_stock(
+        &self,
+        checkout_session: &CheckOutSession,
+    ) -> Result<Vec<(ObjectId, ObjectId)>>;
+
+    /// Gives the reserved stock back, returns false if there was no active reservation.
+    /// With `only_expired` a reservation that is still valid is left as is.
+    async fn release_checkout_session_stock(
+        &self,
+        checkout_session: &CheckOutSession,
+        only_expired: bool,
+    ) -> Result<bool>;
+
+    /// Marks the reservation as used by the order, the stock stays taken.
+    /// Matches nothing if the reservation has lapsed.
+    async fn confirm_checkout_session_reservation(
+        &self,
+        checkout_session_id: &ObjectId,
+        order_id: &ObjectId,
+        session: Option<&mut ClientSession>,
+    ) -> Result<UpdateResult>;
+
+    /// Gives the reservation back to the session when its order was not paid,
+    /// so the checkout can be paid again until the reservation lapses
+    async fn unconfirm_checkout_session_reservation(
+        &self,
+        checkout_session_id: &ObjectId,
+        order_id: &ObjectId,
+        session: Option<&mut ClientSession>,
+    ) -> Result<UpdateResult>;
+
+    /// Gives the stock of an order that was never paid back, when the processor failed
+    /// its payment. Returns false if the reservation is not held by the order anymore.
+    async fn release_order_reservation(&self, order_id: &ObjectId) -> Result<bool>;
+
+    async fn get_expired_checkout_reservations(&self, limit: i64) -> Result<Vec<ObjectId>>;
+}
+
+fn reservation_field(field: &str) -> String {
+    format!("{}.{}", RESERVATION_FIELD, field)
+}
+
+/// The only way reserved stock is given back. The reservation is claimed first,
+/// by the state `filters` release it from, so the stock is given back only once.
+async fn release_reserved_stock(
+    db: &DBConection,
+    checkout_session: &CheckOutSession,
+    filters: Document,
+) -> Result<bool> {
+    let mut db_session = db.start_session().await?;
+
+    db_session
+        .start_transaction(None)
+        .await
+        .map_err(|_| Error::Static("Failed to start transaction"))?;
+
+    let update = doc! {
+        "$set": {
+            reservation_field("status"): CheckoutReservationStatus::Released.to_string(),
+        }
+    };
+
+    let res = match db
+        .update_checkout_session(filters, update, None, Some(&mut db_session))
+        .await
+    {
+        Ok(res) => res,
+        Err(e) => {
+            let _ = db_session.abort_transaction().await;
+            return Err(e);
+        }
+    };
+
+    if res.modified_count == 0 {
+        let _ = db_session.abort_transaction().await;
+        return Ok(false);
+    }
+
+    for part in &checkout_session.parts {
+        for item in &part.items {
+            if let Err(e) = db
+                .release_product_item_stock(
+                    &item.product,
+                    &item.item_id,
+                    item.quantity as i64,
+                    Some(&mut db_session),
+                )
+                .await
+            {
+                let _ = db_session.abort_transaction().await;
+                return Err(e);
+            }
+        }
+    }
+
+    db.commit_transaction(&mut db_session, Some(16)).await?;
+
+    Ok(true)
 }
 
 #[async_trait]
@@ -26,6 +152,188 @@ impl CheckoutSessionFunctions for DBConection {
             CheckOutSession::fields().user: user_id,
         };
 
-        self.get_checkout_session(query, options, populate, None).await
+        self.get_checkout_session(query, options, populate, None)
+            .await
+    }
+
+    async fn reserve_checkout_session_stock(
+        &self,
+        checkout_session: &CheckOutSession,
+    ) -> Result<Vec<(ObjectId, ObjectId)>> {
+        let mut db_session = self.start_session().await?;
+
+        db_session
+            .start_transaction(None)
+            .await
+            .map_err(|_| Error::Static("Failed to start transaction"))?;
+
+        let mut failed = Vec::new();
+
+        for part in &checkout_session.parts {
+            for item in &part.items {
+                let res = self
+                    .reserve_product_item_stock(
+                        &item.product,
+                        &item.item_id,
+                        item.quantity as i64,
+                        Some(&mut db_session),
+                    )
+                    .await;
+
+                match res {
+                    Ok(res) if res.modified_count == 1 => {}
+                    Ok(_) => failed.push((item.product.clone(), item.item_id.clone())),
+                    Err(e) => {
+                        let _ = db_session.abort_transaction().await;
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        if !failed.is_empty() {
+            let _ = db_session.abort_transaction().await;
+            return Ok(failed);
+        }
+
+        let reservation = CheckoutReservation {
+            status: CheckoutReservationStatus::Active,
+            expires_at: BsonDateTime::from_millis(
+                BsonDateTime::now().timestamp_millis() + CHECKOUT_SESSION_EXP * 1000,
+            ),
+            order: None,
+        };
+
+        let update = doc! {
+            "$set": {
+                RESERVATION_FIELD: bson::to_bson(&reservation).map_err(|_| Error::Desrilaztion)?
+            }
+        };
+
+        if let Err(e) = self
+            .update_checkout_session_by_id(
+                checkout_session.id()?,
+                update,
+                None,
+                Some(&mut db_session),
+            )
+            .await
+        {
+            let _ = db_session.abort_transaction().await;
+            return Err(e);
+        }
+
+        self.commit_transaction(&mut db_session, Some(16)).await?;
+
+        Ok(failed)
+    }
+
+    async fn release_checkout_session_stock(
+        &self,
+        checkout_session: &CheckOutSession,
+        only_expired: bool,
+    ) -> Result<bool> {
+        let mut filters = doc! {
+            CheckOutSession::fields().id: checkout_session.id()?,
+            reservation_field("status"): CheckoutReservationStatus::Active.to_string(),
+        };
+
+        if only_expired {
+            filters.insert(
+                reservation_field("expires_at"),
+                doc! { "$lte": BsonDateTime::now() },
+            );
+        }
+
+        release_reserved_stock(self, checkout_session, filters).await
+    }
+
+    async fn confirm_checkout_session_reservation(
+        &self,
+        checkout_session_id: &ObjectId,
+        order_id: &ObjectId,
+        session: Option<&mut ClientSession>,
+    ) -> Result<UpdateResult> {
+        let filters = doc! {
+            CheckOutSession::fields().id: checkout_session_id,
+            reservation_field("status"): CheckoutReservationStatus::Active.to_string(),
+            reservation_field("expires_at"): {
+                "$gt": BsonDateTime::now()
+            },
+        };
+
+        let update = doc! {
+            "$set": {
+                reservation_field("status"): CheckoutReservationStatus::Confirmed.to_string(),
+                reservation_field("order"): order_id,
+            }
+        };
+
+        self.update_checkout_session(filters, update, None, session)
+            .await
+    }
+
+    async fn unconfirm_checkout_session_reservation(
+        &self,
+        checkout_session_id: &ObjectId,
+        order_id: &ObjectId,
+        session: Option<&mut ClientSession>,
+    ) -> Result<UpdateResult> {
+        let filters = doc! {
+            CheckOutSession::fields().id: checkout_session_id,
+            reservation_field("status"): CheckoutReservationStatus::Confirmed.to_string(),
+            reservation_field("order"): order_id,
+        };
+
+        let update = doc! {
+            "$set": {
+                reservation_field("status"): CheckoutReservationStatus::Active.to_string(),
+                reservation_field("order"): None::<ObjectId>,
+            }
+        };
+
+        self.update_checkout_session(filters, update, None, session)
+            .await
+    }
+
+    async fn release_order_reservation(&self, order_id: &ObjectId) -> Result<bool> {
+        let mut filters = doc! {
+            reservation_field("status"): CheckoutReservationStatus::Confirmed.to_string(),
+            reservation_field("order"): order_id,
+        };
+
+        let checkout_session = match self
+            .get_checkout_session(filters.clone(), None, None, None)
+            .await?
+        {
+            Some(checkout_session) => checkout_session,
+            None => return Ok(false),
+        };
+
+        filters.insert(CheckOutSession::fields().id, checkout_session.id()?);
+
+        release_reserved_stock(self, &checkout_session, filters).await
+    }
+
+    async fn get_expired_checkout_reservations(&self, limit: i64) -> Result<Vec<ObjectId>> {
+        let pipeline = [
+            aggregations::match_query(&doc! {
+                reservation_field("status"): CheckoutReservationStatus::Active.to_string(),
+                reservation_field("expires_at"): {
+                    "$lte": BsonDateTime::now()
+                },
+            }),
+            aggregations::limit(limit),
+            doc! { "$project": { CheckOutSession::fields().id: 1 } },
+        ];
+
+        let sessions = self
+            .aggregate_checkout_sessions(pipeline, None, None)
+            .await?;
+
+        Ok(sessions
+            .iter()
+            .filter_map(|session| session.get_object_id("_id").ok())
+            .collect())
     }
 }
@@ -0,0 +1,438 @@
+use crate::{
+    db::ChargeCurrency,
+    helpers::money::{Currency, Money},
+    prelude::{types::*, *},
+};
+use axum::async_trait;
+use bson::{doc, oid::ObjectId, Bson, DateTime as BsonDateTime};
+use mongodb::{options::FindOneAndUpdateOptions, results::UpdateResult, ClientSession};
+use shoppa_core::db::{
+    aggregations,
+    models::{Order, Product, Store},
+    DBConection,
+};
+use std::collections::HashMap;
+
+// The commission terms are stored on the store document (under `commission`),
+// they are only ever appended to so the terms of past orders can still be found
+pub const STORE_COMMISSION_FIELD: &str = "commission";
+// The commission of each order part is recorded on the part (under `commission`) at payment time
+pub const ORDER_PART_COMMISSION_FIELD: &str = "commission";
+
+// Used when the store has no commission rate in effect
+pub const DEFAULT_COMMISSION_PERCENT: f64 = 10.0;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommissionRate {
+    pub percent: f64,
+    // Charged once for each order part of the store
+    pub fixed_fee: Option<f64>,
+    pub effective_from: BsonDateTime,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CategoryCommissionRate {
+    pub category: ObjectId,
+    pub percent: f64,
+    pub effective_from: BsonDateTime,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StoreCommission {
+    #[serde(default)]
+    pub rates: Vec<CommissionRate>,
+    #[serde(default)]
+    pub categories: Vec<CategoryCommissionRate>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderPartCommission {
+    // Of the items, after the overrides of their categories
+    pub percent: f64,
+    pub fixed_fee: f64,
+    pub amount: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderPartCommissionEntry {
+    pub store: ObjectId,
+    pub commission: Option<OrderPartCommission>,
+}
+
+/// What the commission of one order item is calculated from
+pub struct CommissionItem<'a> {
+    // In the charge currency
+    pub total: Money,
+    // The category paths of the product, from the root category down
+    pub categories: &'a [Vec<ObjectId>],
+}
+
+#[derive(Debug, Clone, Deserialize, Validate)]
+pub struct AddCommissionRatePayload {
+    #[validate(range(min = 0.0, max = 100.0))]
+    pub percent: f64,
+    #[validate(range(min = 0.0))]
+    pub fixed_fee: Option<f64>,
+    // Now when missing, never in the past so recorded orders keep their terms
+    pub effective_from: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Clone, Deserialize, Validate)]
+pub struct AddCategoryCommissionPayload {
+    pub category: ObjectId,
+    #[validate(range(min = 0.0, max = 100.0))]
+    pub percent: f64,
+    pub effective_from: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl StoreCommission {
+    /// The base rate in effect at the given time
+    pub fn rate_at(&self, at: BsonDateTime) -> Option<&CommissionRate> {
+        self.rates
+            .iter()
+            .filter(|rate| rate.effective_from <= at)
+            .max_by_key(|rate| rate.effective_from)
+    }
+
+    /// The most specific category override in effect wins,
+    /// an override of a parent category applies to all of its sub categories.
+    pub fn percent_at(&self, categories: &[Vec<ObjectId>], at: BsonDateTime) -> f64 {
+        let base = self
+            .rate_at(at)
+            .map(|rate| rate.percent)
+            .unwrap_or(DEFAULT_COMMISSION_PERCENT);
+
+        let mut best: Option<(usize, &CategoryCommissionRate)> = None;
+
+        for path in categories {
+            for (depth, category) in path.iter().enumerate() {
+                let rate = self
+                    .categories
+                    .iter()
+                    .filter(|rate| &rate.category == category && rate.effective_from <= at)
+                    .max_by_key(|rate| rate.effective_from);
+
+                if let Some(rate) = rate {
+                    if best
+                        .map(|(best_depth, _)| depth > best_depth)
+                        .unwrap_or(true)
+                    {
+                        best = Some((depth, rate));
+                    }
+                }
+            }
+        }
+
+        best.map(|(_, rate)| rate.percent).unwrap_or(base)
+    }
+
+    /// The item discount is spread over the items by their share of the items total.
+    /// The amounts are in the currency of the discount (the charge currency).
+    pub fn order_part_commission(
+        &self,
+        items: &[CommissionItem],
+        items_discount: Money,
+        at: BsonDateTime,
+    ) -> OrderPartCommission {
+        let currency = items_discount.currency;
+
+        let items_total = Money::sum(currency, items.iter().map(|item| item.total));
+
+        let full_amount = Money::sum(
+            currency,
+            items
+                .iter()
+                .map(|item| item.total.percent(self.percent_at(item.categories, at))),
+        );
+
+        let (percent, items_amount) = if items_total.is_positive() {
+            let charged = (items_total - items_discount).max(Money::zero(currency));
+            (
+                full_amount.amount as f64 / items_total.amount as f64 * 100.0,
+                full_amount.share(charged, items_total),
+            )
+        } else {
+            (0.0, Money::zero(currency))
+        };
+
+        let fixed_fee = self
+            .rate_at(at)
+            .and_then(|rate| rate.fixed_fee)
+            .unwrap_or(0.0);
+
+        OrderPartCommission {
+            // the effective percent of the items, kept to two decimals
+            percent: (percent * 100.0).round() / 100.0,
+            fixed_fee,
+            amount: (items_amount + Money::from_major(fixed_fee, currency)).to_major(),
+        }
+    }
+}
+
+impl OrderPartCommission {
+    /// The order amounts are in the charge currency, the fixed fee of the store terms
+    /// is in the store currency
+    pub fn in_currency(
+        mut self,
+        currency: &ChargeCurrency,
+        store_currency: Currency,
+    ) -> Result<Self> {
+        let charged = currency.currency;
+        let fixed_fee = currency.from_store(self.fixed_fee, store_currency)?;
+
+        self.amount = (Money::from_major(self.amount, charged)
+            - Money::from_major(self.fixed_fee, charged)
+            + Money::from_major(fixed_fee, charged))
+        .to_major();
+        self.fixed_fee = fixed_fee;
+
+        Ok(self)
+    }
+
+    /// For orders that were paid before commissions were recorded
+    pub fn default_for(items_total: Money) -> Self {
+        Self {
+            percent: DEFAULT_COMMISSION_PERCENT,
+            fixed_fee: 0.0,
+            amount: items_total.percent(DEFAULT_COMMISSION_PERCENT).to_major(),
+        }
+    }
+}
+
+/// Returns None if the date is in the past
+pub fn commission_effective_from(
+    effective_from: Option<chrono::DateTime<chrono::Utc>>,
+) -> Option<BsonDateTime> {
+    let now = BsonDateTime::now();
+
+    match effective_from {
+        Some(effective_from) => {
+            let effective_from = BsonDateTime::from_millis(effective_from.timestamp_millis());
+            (effective_from >= now).then_some(effective_from)
+        }
+        None => Some(now),
+    }
+}
+
+fn category_paths(product: &bson::Document) -> Vec<Vec<ObjectId>> {
+    product
+        .get_array(Product::fields().categories)
+        .map(|paths| {
+            paths
+                .iter()
+                .filter_map(|path| match path {
+                    Bson::Document(path) => path.get_array("ids").ok(),
+                    _ => None,
+                })
+                .map(|ids| ids.iter().filter_map(|id| id.as_object_id()).collect())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[async_trait]
+pub trait CommissionFunctions {
+    /// Returns None if the store was not found
+    async fn get_store_commission(&self, store_id: &ObjectId) -> Result<Option<StoreCommission>>;
+    async fn get_stores_commission(
+        &self,
+        store_ids: &[ObjectId],
+    ) -> Result<HashMap<ObjectId, StoreCommission>>;
+    async fn add_store_commission_rate(
+        &self,
+        store_id: &ObjectId,
+        rate: &CommissionRate,
+    ) -> Result<Option<Store>>;
+    async fn add_store_category_commission(
+        &self,
+        store_id: &ObjectId,
+        rate: &CategoryCommissionRate,
+    ) -> Result<Option<Store>>;
+    async fn get_products_category_paths(
+        &self,
+        product_ids: &[ObjectId],
+    ) -> Result<HashMap<ObjectId, Vec<Vec<ObjectId>>>>;
+    /// One commission for each order part, in the order of the parts
+    async fn set_order_parts_commission(
+        &self,
+        order_id: &ObjectId,
+        commissions: &[OrderPartCommission],
+        session: Option<&mut ClientSession>,
+    ) -> Result<UpdateResult>;
+    async fn get_order_parts_commission(
+        &self,
+        order_id: &ObjectId,
+    ) -> Result<Vec<OrderPartCommissionEntry>>;
+}
+
+#[async_trait]
+impl CommissionFunctions for DBConection {
+    async fn get_store_commission(&self, store_id: &ObjectId) -> Result<Option<StoreCommission>> {
+        Ok(self
+            .get_stores_commission(&[store_id.clone()])
+            .await?
+            .remove(store_id))
+    }
+
+    async fn get_stores_commission(
+        &self,
+        store_ids: &[ObjectId],
+    ) -> Result<HashMap<ObjectId, StoreCommission>> {
+        let pipeline = [
+            aggregations::match_query(&doc! {
+                Store::fields().id: {
+                    "$in": store_ids.to_vec()
+                }
+            }),
+            doc! {
+                "$project": {
+                    Store::fields().id: 1,
+                    STORE_COMMISSION_FIELD: 1,
+                }
+            },
+        ];
+
+        let stores = self.aggregate_stores(pipeline, None, None).await?;
+
+        let mut commissions = HashMap::with_capacity(stores.len());
+
+        for store in stores {
+            let store_id = store
+                .get_object_id("_id")
+                .map_err(|_| Error::Desrilaztion)?;
+
+            let commission = match store.get_document(STORE_COMMISSION_FIELD) {
+                Ok(commission) => {
+                    bson::from_document(commission.clone()).map_err(|_| Error::Desrilaztion)?
+                }
+                Err(_) => StoreCommission::default(),
+            };
+
+            commissions.insert(store_id, commission);
+        }
+
+        Ok(commissions)
+    }
+
+    async fn add_store_commission_rate(
+        &self,
+        store_id: &ObjectId,
+        rate: &CommissionRate,
+    ) -> Result<Option<Store>> {
+        let update = doc! {
+            "$push": {
+                format!("{}.rates", STORE_COMMISSION_FIELD):
+                    bson::to_bson(rate).map_err(|_| Error::Desrilaztion)?
+            }
+        };
+
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(mongodb::options::ReturnDocument::After)
+            .build();
+
+        self.find_and_update_store_by_id(store_id, update, Some(options), None)
+            .await
+    }
+
+    async fn add_store_category_commission(
+        &self,
+        store_id: &ObjectId,
+        rate: &CategoryCommissionRate,
+    ) -> Result<Option<Store>> {
+        let update = doc! {
+            "$push": {
+                format!("{}.categories", STORE_COMMISSION_FIELD):
+                    bson::to_bson(rate).map_err(|_| Error::Desrilaztion)?
+            }
+        };
+
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(mongodb::options::ReturnDocument::After)
+            .build();
+
+        self.find_and_update_store_by_id(store_id, update, Some(options), None)
+            .await
+    }
+
+    async fn get_products_category_paths(
+        &self,
+        product_ids: &[ObjectId],
+    ) -> Result<HashMap<ObjectId, Vec<Vec<ObjectId>>>> {
+        let pipeline = [
+            aggregations::match_query(&doc! {
+                Product::fields().id: {
+                    "$in": product_ids.to_vec()
+                }
+            }),
+            doc! {
+                "$project": {
+                    Product::fields().id: 1,
+                    Product::fields().categories(true).ids: 1,
+                }
+            },
+        ];
+
+        let products = self.aggregate_products(pipeline, None, None).await?;
+
+        Ok(products
+            .iter()
+            .filter_map(|product| {
+                let product_id = product.get_object_id("_id").ok()?;
+                Some((product_id, category_paths(product)))
+            })
+            .collect())
+    }
+
+    async fn set_order_parts_commission(
+        &self,
+        order_id: &ObjectId,
+        commissions: &[OrderPartCommission],
+        session: Option<&mut ClientSession>,
+    ) -> Result<UpdateResult> {
+        let mut set = doc! {};
+
+        for (index, commission) in commissions.iter().enumerate() {
+            set.insert(
+                format!(
+                    "{}.{}.{}",
+                    Order::fields().parts,
+                    index,
+                    ORDER_PART_COMMISSION_FIELD
+                ),
+                bson::to_bson(commission).map_err(|_| Error::Desrilaztion)?,
+            );
+        }
+
+        self.update_order_by_id(order_id, doc! { "$set": set }, None, session)
+            .await
+    }
+
+    async fn get_order_parts_commission(
+        &self,
+        order_id: &ObjectId,
+    ) -> Result<Vec<OrderPartCommissionEntry>> {
+        let pipeline = [
+            aggregations::match_query(&doc! {
+                Order::fields().id: order_id,
+            }),
+            aggregations::unwind(Order::fields().parts, false),
+            doc! {
+                "$project": {
+                    "_id": 0,
+                    "store": format!("${}", Order::fields().parts(true).store),
+                    "commission": format!(
+                        "${}.{}",
+                        Order::fields().parts,
+                        ORDER_PART_COMMISSION_FIELD
+                    ),
+                }
+            },
+        ];
+
+        self.aggregate_orders(pipeline, None, None)
+            .await?
+            .into_iter()
+            .map(|entry| bson::from_document(entry).map_err(|_| Error::Desrilaztion))
+            .collect()
+    }
+}
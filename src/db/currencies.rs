@@ -0,0 +1,318 @@
+use crate::{
+    helpers::money::{Currency, ExchangeRates, Money},
+    prelude::{types::*, *},
+};
+use axum::async_trait;
+use bson::{doc, oid::ObjectId, Bson, DateTime as BsonDateTime, Document};
+use mongodb::{options::FindOneAndUpdateOptions, results::UpdateResult, ClientSession};
+use shoppa_core::db::{
+    aggregations,
+    models::{CheckOutSession, Order, Product, Store},
+    DBConection,
+};
+use std::collections::HashMap;
+
+// The currency the store prices are in, stored on the store document (under `currency`)
+pub const STORE_CURRENCY_FIELD: &str = "currency";
+
+// The currency a checkout is charged in, stored on the checkout session
+// and then on the order (under `charge_currency`)
+pub const CHARGE_CURRENCY_FIELD: &str = "charge_currency";
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct CurrencyRate {
+    pub from: Currency,
+    // How many units of the charge currency one unit of `from` was worth
+    pub rate: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChargeCurrency {
+    pub currency: Currency,
+    // One for each store currency in the checkout
+    pub rates: Vec<CurrencyRate>,
+    pub quoted_at: BsonDateTime,
+}
+
+#[derive(Debug, Clone, Deserialize, Validate)]
+pub struct SetStoreCurrencyPayload {
+    pub currency: Currency,
+}
+
+impl ChargeCurrency {
+    /// Returns None if one of the currencies has no exchange rate.
+    /// The platform currency is always quoted, promotion amounts are in it.
+    pub fn quote(
+        rates: &ExchangeRates,
+        currency: Currency,
+        store_currencies: impl IntoIterator<Item = Currency>,
+    ) -> Option<Self> {
+        let mut quoted: Vec<CurrencyRate> = Vec::new();
+
+        for from in std::iter::once(Currency::default()).chain(store_currencies) {
+            if quoted.iter().any(|quoted| quoted.from == from) {
+                continue;
+            }
+
+            quoted.push(CurrencyRate {
+                from,
+                rate: rates.rate(from, currency)?,
+            });
+        }
+
+        Some(Self {
+            currency,
+            rates: quoted,
+            quoted_at: BsonDateTime::now(),
+        })
+    }
+
+    /// Checkout sessions and orders from before currencies were charged in the platform currency
+    pub fn platform() -> Self {
+        Self {
+            currency: Currency::default(),
+            rates: Vec::new(),
+            quoted_at: BsonDateTime::now(),
+        }
+    }
+
+    /// Every currency of the checkout is quoted, a currency that was not
+    /// is an error rather than a price at the wrong rate
+    fn rate_from(&self, from: Currency) -> Result<f64> {
+        if from == self.currency {
+            return Ok(1.0);
+        }
+
+        self.rates
+            .iter()
+            .find(|rate| rate.from == from)
+            .map(|rate| rate.rate)
+            .ok_or(Error::ApiErrorWithCode("CurrencyNotQuoted", 409))
+    }
+
+    /// From a store price to the charge currency
+    pub fn from_store(&self, amount: f64, from: Currency) -> Result<f64> {
+        Ok(Money::from_major(amount, from)
+            .convert(self.currency, self.rate_from(from)?)
+            .to_major())
+    }
+
+    /// From the charge currency back to the store currency, by the rate that was charged
+    pub fn to_store(&self, amount: f64, to: Currency) -> Result<f64> {
+        Ok(Money::from_major(amount, self.currency)
+            .convert(to, 1.0 / self.rate_from(to)?)
+            .to_major())
+    }
+}
+
+fn product_store_id(product: &Document) -> Option<ObjectId> {
+    match product.get(Product::fields().store) {
+        Some(Bson::Document(store)) => store.get_object_id("_id").ok(),
+        Some(Bson::ObjectId(store_id)) => Some(*store_id),
+        _ => None,
+    }
+}
+
+fn convert_price(item: &mut Document, rates: &ExchangeRates, from: Currency, to: Currency) {
+    let price = match item.get("price").and_then(Bson::as_f64) {
+        Some(price) => price,
+        None => return,
+    };
+
+    if let Some(price) = rates.convert(price, from, to) {
+        item.insert("price", price);
+    }
+}
+
+/// Converts the item prices of public product documents to the display currency,
+/// and adds the currency they are in.
+pub async fn localize_products_prices(
+    db: &DBConection,
+    rates: &ExchangeRates,
+    products: &mut [Document],
+    currency: Currency,
+) -> Result<()> {
+    let store_ids: Vec<ObjectId> = products.iter().filter_map(product_store_id).collect();
+
+    let store_currencies = db.get_stores_currency(&store_ids).await?;
+
+    for product in products.iter_mut() {
+        let from = product_store_id(product)
+            .and_then(|store_id| store_currencies.get(&store_id).copied())
+            .unwrap_or_default();
+
+        // a single product has all of its items, a list has the matching one
+        if let Ok(items) = product.get_array_mut(Product::fields().items) {
+            for item in items.iter_mut() {
+                if let Bson::Document(item) = item {
+                    convert_price(item, rates, from, currency);
+                }
+            }
+        }
+
+        if let Ok(item) = product.get_document_mut("item") {
+            convert_price(item, rates, from, currency);
+        }
+
+        product.insert("currency", currency.to_string());
+    }
+
+    Ok(())
+}
+
+#[async_trait]
+pub trait CurrencyFunctions {
+    /// Stores that did not declare a currency are in the platform currency
+    async fn get_stores_currency(
+        &self,
+        store_ids: &[ObjectId],
+    ) -> Result<HashMap<ObjectId, Currency>>;
+    async fn set_store_currency(
+        &self,
+        store_id: &ObjectId,
+        currency: Currency,
+    ) -> Result<Option<Store>>;
+    async fn set_checkout_session_currency(
+        &self,
+        checkout_session_id: &ObjectId,
+        currency: &ChargeCurrency,
+    ) -> Result<UpdateResult>;
+    async fn get_checkout_session_currency(
+        &self,
+        checkout_session_id: &ObjectId,
+    ) -> Result<Option<ChargeCurrency>>;
+    async fn set_order_currency(
+        &self,
+        order_id: &ObjectId,
+        currency: &ChargeCurrency,
+        session: Option<&mut ClientSession>,
+    ) -> Result<UpdateResult>;
+    async fn get_order_currency(&self, order_id: &ObjectId) -> Result<Option<ChargeCurrency>>;
+}
+
+fn currency_from_documents(mut docs: Vec<Document>) -> Result<Option<ChargeCurrency>> {
+    match docs.pop() {
+        Some(currency) => bson::from_document::<ChargeCurrency>(currency)
+            .map(Some)
+            .map_err(|_| Error::Desrilaztion),
+        None => Ok(None),
+    }
+}
+
+#[async_trait]
+impl CurrencyFunctions for DBConection {
+    async fn get_stores_currency(
+        &self,
+        store_ids: &[ObjectId],
+    ) -> Result<HashMap<ObjectId, Currency>> {
+        let pipeline = [
+            aggregations::match_query(&doc! {
+                Store::fields().id: {
+                    "$in": store_ids.to_vec()
+                }
+            }),
+            doc! {
+                "$project": {
+                    Store::fields().id: 1,
+                    STORE_CURRENCY_FIELD: 1,
+                }
+            },
+        ];
+
+        let stores = self.aggregate_stores(pipeline, None, None).await?;
+
+        Ok(stores
+            .iter()
+            .filter_map(|store| {
+                let store_id = store.get_object_id("_id").ok()?;
+                let currency = store
+                    .get_str(STORE_CURRENCY_FIELD)
+                    .ok()
+                    .and_then(|currency| currency.parse::<Currency>().ok())
+                    .unwrap_or_default();
+
+                Some((store_id, currency))
+            })
+            .collect())
+    }
+
+    async fn set_store_currency(
+        &self,
+        store_id: &ObjectId,
+        currency: Currency,
+    ) -> Result<Option<Store>> {
+        let update = doc! {
+            "$set": {
+                STORE_CURRENCY_FIELD: currency.to_string()
+            }
+        };
+
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(mongodb::options::ReturnDocument::After)
+            .build();
+
+        self.find_and_update_store_by_id(store_id, update, Some(options), None)
+            .await
+    }
+
+    async fn set_checkout_session_currency(
+        &self,
+        checkout_session_id: &ObjectId,
+        currency: &ChargeCurrency,
+    ) -> Result<UpdateResult> {
+        let update = doc! {
+            "$set": {
+                CHARGE_CURRENCY_FIELD: bson::to_bson(currency).map_err(|_| Error::Desrilaztion)?
+            }
+        };
+
+        self.update_checkout_session_by_id(checkout_session_id, update, None, None)
+            .await
+    }
+
+    async fn get_checkout_session_currency(
+        &self,
+        checkout_session_id: &ObjectId,
+    ) -> Result<Option<ChargeCurrency>> {
+        let pipeline = [
+            aggregations::match_query(&doc! {
+                CheckOutSession::fields().id: checkout_session_id,
+                CHARGE_CURRENCY_FIELD: { "$type": "object" },
+            }),
+            aggregations::replace_root(CHARGE_CURRENCY_FIELD),
+        ];
+
+        currency_from_documents(
+            self.aggregate_checkout_sessions(pipeline, None, None)
+                .await?,
+        )
+    }
+
+    async fn set_order_currency(
+        &self,
+        order_id: &ObjectId,
+        currency: &ChargeCurrency,
+        session: Option<&mut ClientSession>,
+    ) -> Result<UpdateResult> {
+        let update = doc! {
+            "$set": {
+                CHARGE_CURRENCY_FIELD: bson::to_bson(currency).map_err(|_| Error::Desrilaztion)?
+            }
+        };
+
+        self.update_order_by_id(order_id, update, None, session)
+            .await
+    }
+
+    async fn get_order_currency(&self, order_id: &ObjectId) -> Result<Option<ChargeCurrency>> {
+        let pipeline = [
+            aggregations::match_query(&doc! {
+                Order::fields().id: order_id,
+                CHARGE_CURRENCY_FIELD: { "$type": "object" },
+            }),
+            aggregations::replace_root(CHARGE_CURRENCY_FIELD),
+        ];
+
+        currency_from_documents(self.aggregate_orders(pipeline, None, None).await?)
+    }
+}
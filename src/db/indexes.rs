@@ -0,0 +1,167 @@
+use crate::{
+    db::{
+        LocalDBConection, SearchBackend, LEDGER_TRANSACTIONS_COLLECTION, PROMOTIONS_COLLECTION,
+        PROMOTION_REDEMPTIONS_COLLECTION, SEARCH_BACKEND, STOCK_ALERTS_COLLECTION,
+    },
+    prelude::*,
+};
+use bson::{doc, Document};
+use mongodb::{options::IndexOptions, IndexModel};
+use shoppa_core::db::models::{DBModel, Product, Store};
+
+const PRODUCTS_TEXT_INDEX: &str = "products_text_search";
+const STORES_TEXT_INDEX: &str = "stores_text_search";
+
+const PROMOTIONS_CODE_INDEX: &str = "promotions_code_unique";
+const PROMOTION_REDEMPTIONS_SLOT_INDEX: &str = "promotion_redemptions_slot_unique";
+const PROMOTION_REDEMPTIONS_ORDER_INDEX: &str = "promotion_redemptions_order";
+const LEDGER_KEY_INDEX: &str = "ledger_transactions_key_unique";
+const STOCK_ALERTS_ACTIVE_INDEX: &str = "stock_alerts_active_unique";
+
+/// Creates the unique indexes the local collections rely on, so a value that is
+/// checked before it's inserted (or upserted by) can't be written twice by concurrent requests.
+pub async fn ensure_unique_indexes(local_db: &LocalDBConection) -> Result<()> {
+    let promotions_code_index = IndexModel::builder()
+        .keys(doc! { "code": 1 })
+        .options(
+            IndexOptions::builder()
+                .name(PROMOTIONS_CODE_INDEX.to_string())
+                .unique(true)
+                .build(),
+        )
+        .build();
+
+    local_db
+        .collection::<Document>(PROMOTIONS_COLLECTION)
+        .create_index(promotions_code_index, None)
+        .await
+        .map_err(|_| Error::Static("Failed to create promotions code index"))?;
+
+    // a user slot of a promotion is taken once, only promotions with a per user limit have slots
+    let redemptions_slot_index = IndexModel::builder()
+        .keys(doc! { "promotion": 1, "user": 1, "slot": 1 })
+        .options(
+            IndexOptions::builder()
+                .name(PROMOTION_REDEMPTIONS_SLOT_INDEX.to_string())
+                .unique(true)
+                .partial_filter_expression(doc! { "slot": { "$exists": true } })
+                .build(),
+        )
+        .build();
+
+    // redemptions are released by their order
+    let redemptions_order_index = IndexModel::builder()
+        .keys(doc! { "promotion": 1, "order": 1 })
+        .options(
+            IndexOptions::builder()
+                .name(PROMOTION_REDEMPTIONS_ORDER_INDEX.to_string())
+                .build(),
+        )
+        .build();
+
+    local_db
+        .collection::<Document>(PROMOTION_REDEMPTIONS_COLLECTION)
+        .create_indexes([redemptions_slot_index, redemptions_order_index], None)
+        .await
+        .map_err(|_| Error::Static("Failed to create promotion redemptions indexes"))?;
+
+    // a ledger transaction is written once per source event
+    let ledger_key_index = IndexModel::builder()
+        .keys(doc! { "key": 1 })
+        .options(
+            IndexOptions::builder()
+                .name(LEDGER_KEY_INDEX.to_string())
+                .unique(true)
+                .build(),
+        )
+        .build();
+
+    local_db
+        .collection::<Document>(LEDGER_TRANSACTIONS_COLLECTION)
+        .create_index(ledger_key_index, None)
+        .await
+        .map_err(|_| Error::Static("Failed to create ledger key index"))?;
+
+    // a user has one active alert of a kind for an item, inactive ones are kept as history
+    let stock_alerts_active_index = IndexModel::builder()
+        .keys(doc! { "user": 1, "product": 1, "item_id": 1, "kind": 1 })
+        .options(
+            IndexOptions::builder()
+                .name(STOCK_ALERTS_ACTIVE_INDEX.to_string())
+                .unique(true)
+                .partial_filter_expression(doc! { "active": true })
+                .build(),
+        )
+        .build();
+
+    local_db
+        .collection::<Document>(STOCK_ALERTS_COLLECTION)
+        .create_index(stock_alerts_active_index, None)
+        .await
+        .map_err(|_| Error::Static("Failed to create stock alerts index"))?;
+
+    Ok(())
+}
+
+/// Creates the text indexes the text backend searches with, does nothing with atlas.
+/// The index language is `none`, words are split on spaces and punctuation
+/// without stemming, which is what works for Hebrew.
+pub async fn ensure_search_indexes(local_db: &LocalDBConection) -> Result<()> {
+    if *SEARCH_BACKEND != SearchBackend::Text {
+        return Ok(());
+    }
+
+    let products_index = IndexModel::builder()
+        .keys(doc! {
+            Product::fields().name: "text",
+            Product::fields().keywords: "text",
+            Product::fields().brand(true).name: "text",
+            Product::fields().description: "text",
+        })
+        .options(
+            IndexOptions::builder()
+                .name(PRODUCTS_TEXT_INDEX.to_string())
+                .default_language("none".to_string())
+                .weights(doc! {
+                    Product::fields().name: 10,
+                    Product::fields().keywords: 5,
+                    Product::fields().brand(true).name: 3,
+                    Product::fields().description: 1,
+                })
+                .build(),
+        )
+        .build();
+
+    local_db
+        .collection::<Document>(Product::get_collection_name())
+        .create_index(products_index, None)
+        .await
+        .map_err(|_| Error::Static("Failed to create products text index"))?;
+
+    let stores_index = IndexModel::builder()
+        .keys(doc! {
+            Store::fields().name: "text",
+            Store::fields().description: "text",
+            Store::fields().slogan: "text",
+        })
+        .options(
+            IndexOptions::builder()
+                .name(STORES_TEXT_INDEX.to_string())
+                .default_language("none".to_string())
+                .weights(doc! {
+                    Store::fields().name: 10,
+                    Store::fields().slogan: 2,
+                    Store::fields().description: 1,
+                })
+                .build(),
+        )
+        .build();
+
+    local_db
+        .collection::<Document>(Store::get_collection_name())
+        .create_index(stores_index, None)
+        .await
+        .map_err(|_| Error::Static("Failed to create stores text index"))?;
+
+    Ok(())
+}
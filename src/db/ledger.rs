@@ -0,0 +1,1008 @@
+use crate::{
+    db::{collect_cursor, is_duplicate_key_error, LocalDBConection},
+    helpers::money::{Currency, Money},
+    prelude::*,
+};
+use axum::async_trait;
+use bson::{doc, oid::ObjectId, DateTime as BsonDateTime, Document};
+use mongodb::{options::UpdateOptions, Collection};
+use serde::{Deserialize, Serialize};
+use shoppa_core::db::{aggregations, Pagination};
+use std::collections::BTreeMap;
+use strum_macros::Display;
+
+// The amounts of a store account are in the store currency, which is locked
+// once the store has products
+pub const LEDGER_TRANSACTIONS_COLLECTION: &str = "ledger_transactions";
+pub const PAYOUTS_COLLECTION: &str = "payouts";
+
+// Where the customer money sits until it is paid out to the stores
+pub const PLATFORM_CLEARING_ACCOUNT: &str = "platform:clearing";
+pub const PLATFORM_COMMISSION_ACCOUNT: &str = "platform:commission";
+// What the platform pays for the discounts of its own promotions
+pub const PLATFORM_PROMOTIONS_ACCOUNT: &str = "platform:promotions";
+
+// How long the money of a sale or a refund is held before it can be paid out
+pub const PAYOUT_HOLD_DAYS: i64 = 14;
+
+// A payout that is still being created after this was left behind by a failure,
+// its transactions go back to the store balance
+const PAYOUT_CREATION_TIMEOUT_SECS: i64 = 5 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum LedgerTransactionKind {
+    Sale,
+    // Takes back a sale whose payment was voided or failed
+    Reversal,
+    // Takes back the share of a sale the customer charged back
+    Chargeback,
+    Refund,
+    Payout,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum LedgerLineKind {
+    Charge,
+    Gross,
+    Delivery,
+    Commission,
+    Discount,
+    Refund,
+    Payout,
+}
+
+/// A positive amount is a credit and a negative one is a debit,
+/// so the balance of a store account is what the platform owes the store.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LedgerLine {
+    pub account: String,
+    pub kind: LedgerLineKind,
+    pub amount: f64,
+}
+
+/// One balanced entry, the amounts of its lines always add up to zero.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LedgerTransaction {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    // Unique per source event, so writing the same event twice has no effect
+    pub key: String,
+    pub kind: LedgerTransactionKind,
+    pub store: ObjectId,
+    pub order: Option<ObjectId>,
+    // The store currency, the amounts of the lines are in it
+    #[serde(default)]
+    pub currency: Currency,
+    pub lines: Vec<LedgerLine>,
+    pub created_at: BsonDateTime,
+    // Not part of the store balance that can be paid out before that
+    pub available_at: BsonDateTime,
+    // The payout batch the transaction was paid in
+    pub payout: Option<ObjectId>,
+    pub paid_at: Option<BsonDateTime>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum PayoutStatus {
+    // Saved before its transactions are tagged, so a tagged transaction always has a payout
+    Creating,
+    Pending,
+    Paid,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Payout {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub store: ObjectId,
+    pub amount: f64,
+    // The store currency
+    #[serde(default)]
+    pub currency: Currency,
+    pub transactions: u64,
+    pub status: PayoutStatus,
+    // The bank transfer reference, given when the payout is marked as paid
+    pub reference: Option<String>,
+    pub created_at: BsonDateTime,
+    pub paid_at: Option<BsonDateTime>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LedgerPeriod {
+    // yyyy-mm
+    pub period: String,
+    pub gross: f64,
+    pub delivery: f64,
+    pub commission: f64,
+    pub refunds: f64,
+    pub net: f64,
+    pub paid_out: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoreLedgerSummary {
+    pub currency: Currency,
+    // Can be paid out, including what is already in a payout that was not paid yet
+    pub balance: f64,
+    pub in_payout: f64,
+    // Still on hold
+    pub pending: f64,
+    pub paid_out: f64,
+    pub periods: Vec<LedgerPeriod>,
+}
+
+/// What the store part of an order is credited with at payment time,
+/// the amounts are in the store currency
+pub struct LedgerSale {
+    pub order: ObjectId,
+    pub store: ObjectId,
+    // After the discount of a store promotion
+    pub items_total: Money,
+    pub delivery_cost: Money,
+    // As recorded on the order part at payment time
+    pub commission: Money,
+    // The store share of the discount of a platform promotion, the store is paid in full
+    pub platform_discount: Money,
+}
+
+pub fn store_account(store_id: &ObjectId) -> String {
+    format!("store:{}", store_id)
+}
+
+fn after_days(days: i64) -> BsonDateTime {
+    BsonDateTime::from_millis(BsonDateTime::now().timestamp_millis() + days * 24 * 60 * 60 * 1000)
+}
+
+impl LedgerTransaction {
+    fn new(
+        key: String,
+        kind: LedgerTransactionKind,
+        store: ObjectId,
+        order: Option<ObjectId>,
+        currency: Currency,
+        lines: Vec<LedgerLine>,
+        available_at: BsonDateTime,
+    ) -> Self {
+        Self {
+            id: ObjectId::new(),
+            key,
+            kind,
+            store,
+            order,
+            currency,
+            lines,
+            created_at: BsonDateTime::now(),
+            available_at,
+            payout: None,
+            paid_at: None,
+        }
+    }
+
+    pub fn sale_key(order_id: &ObjectId, store_id: &ObjectId) -> String {
+        format!("sale:{}:{}", order_id, store_id)
+    }
+
+    /// The customer charge goes to the clearing account, the store is credited
+    /// with the items and the delivery and debited with the platform commission.
+    /// A discount of a platform promotion is debited from the platform promotions account.
+    pub fn sale(sale: LedgerSale) -> Self {
+        let account = store_account(&sale.store);
+
+        let mut lines = vec![
+            LedgerLine {
+                account: PLATFORM_CLEARING_ACCOUNT.to_string(),
+                kind: LedgerLineKind::Charge,
+                amount: -(sale.items_total + sale.delivery_cost - sale.platform_discount)
+                    .to_major(),
+            },
+            LedgerLine {
+                account: account.clone(),
+                kind: LedgerLineKind::Gross,
+                amount: sale.items_total.to_major(),
+            },
+            LedgerLine {
+                account: account.clone(),
+                kind: LedgerLineKind::Delivery,
+                amount: sale.delivery_cost.to_major(),
+            },
+            LedgerLine {
+                account,
+                kind: LedgerLineKind::Commission,
+                amount: -sale.commission.to_major(),
+            },
+            LedgerLine {
+                account: PLATFORM_COMMISSION_ACCOUNT.to_string(),
+                kind: LedgerLineKind::Commission,
+                amount: sale.commission.to_major(),
+            },
+        ];
+
+        if sale.platform_discount.is_positive() {
+            lines.push(LedgerLine {
+                account: PLATFORM_PROMOTIONS_ACCOUNT.to_string(),
+                kind: LedgerLineKind::Discount,
+                amount: -sale.platform_discount.to_major(),
+            });
+        }
+
+        Self::new(
+            Self::sale_key(&sale.order, &sale.store),
+            LedgerTransactionKind::Sale,
+            sale.store,
+            Some(sale.order),
+            sale.items_total.currency,
+            lines,
+            after_days(PAYOUT_HOLD_DAYS),
+        )
+    }
+
+    /// The same lines as the sale with the opposite amounts
+    pub fn reversal(sale: &LedgerTransaction) -> Self {
+        let lines = sale
+            .lines
+            .iter()
+            .map(|line| LedgerLine {
+                account: line.account.clone(),
+                kind: line.kind,
+                amount: -line.amount,
+            })
+            .collect();
+
+        Self::new(
+            format!("reversal:{}", sale.key),
+            LedgerTransactionKind::Reversal,
+            sale.store,
+            sale.order,
+            sale.currency,
+            lines,
+            sale.available_at,
+        )
+    }
+
+    /// The sale lines with the opposite amounts, scaled by the share of the order
+    /// that was charged back. The clearing line takes the rounding, so it stays balanced.
+    pub fn chargeback(sale: &LedgerTransaction, event_id: &str, share: f64) -> Self {
+        let mut lines: Vec<LedgerLine> = sale
+            .lines
+            .iter()
+            .map(|line| LedgerLine {
+                account: line.account.clone(),
+                kind: line.kind,
+                amount: -Money::round_major(line.amount * share, sale.currency),
+            })
+            .collect();
+
+        let others = Money::sum(
+            sale.currency,
+            lines
+                .iter()
+                .filter(|line| line.kind != LedgerLineKind::Charge)
+                .map(|line| Money::from_major(line.amount, sale.currency)),
+        );
+
+        if let Some(charge) = lines
+            .iter_mut()
+            .find(|line| line.kind == LedgerLineKind::Charge)
+        {
+            charge.amount = -others.to_major();
+        }
+
+        // a chargeback is taken into account right away
+        Self::new(
+            format!("chargeback:{}:{}", event_id, sale.key),
+            LedgerTransactionKind::Chargeback,
+            sale.store,
+            sale.order,
+            sale.currency,
+            lines,
+            BsonDateTime::now(),
+        )
+    }
+
+    /// The refund is taken from the store, the commission of the sale is kept.
+    /// `amount` is in the store currency
+    pub fn refund(
+        order_id: ObjectId,
+        store_id: ObjectId,
+        refund_id: &ObjectId,
+        amount: Money,
+    ) -> Self {
+        let lines = vec![
+            LedgerLine {
+                account: store_account(&store_id),
+                kind: LedgerLineKind::Refund,
+                amount: -amount.to_major(),
+            },
+            LedgerLine {
+                account: PLATFORM_CLEARING_ACCOUNT.to_string(),
+                kind: LedgerLineKind::Refund,
+                amount: amount.to_major(),
+            },
+        ];
+
+        // refunds are taken into account right away, so they are never paid out first
+        Self::new(
+            format!("refund:{}", refund_id),
+            LedgerTransactionKind::Refund,
+            store_id,
+            Some(order_id),
+            amount.currency,
+            lines,
+            BsonDateTime::now(),
+        )
+    }
+
+    pub fn payout(payout: &Payout) -> Self {
+        let lines = vec![
+            LedgerLine {
+                account: store_account(&payout.store),
+                kind: LedgerLineKind::Payout,
+                amount: -payout.amount,
+            },
+            LedgerLine {
+                account: PLATFORM_CLEARING_ACCOUNT.to_string(),
+                kind: LedgerLineKind::Payout,
+                amount: payout.amount,
+            },
+        ];
+
+        let mut transaction = Self::new(
+            format!("payout:{}", payout.id),
+            LedgerTransactionKind::Payout,
+            payout.store,
+            None,
+            payout.currency,
+            lines,
+            BsonDateTime::now(),
+        );
+
+        transaction.payout = Some(payout.id);
+        transaction.paid_at = Some(BsonDateTime::now());
+
+        transaction
+    }
+
+    pub fn is_balanced(&self) -> bool {
+        Money::sum(
+            self.currency,
+            self.lines
+                .iter()
+                .map(|line| Money::from_major(line.amount, self.currency)),
+        )
+        .amount
+            == 0
+    }
+}
+
+impl Payout {
+    fn new(store: ObjectId, currency: Currency) -> Self {
+        Self {
+            id: ObjectId::new(),
+            store,
+            amount: 0.0,
+            currency,
+            transactions: 0,
+            status: PayoutStatus::Creating,
+            reference: None,
+            created_at: BsonDateTime::now(),
+            paid_at: None,
+        }
+    }
+}
+
+// The totals of a period in minor units, see `LedgerPeriod`
+struct PeriodTotals {
+    gross: Money,
+    delivery: Money,
+    commission: Money,
+    refunds: Money,
+    paid_out: Money,
+}
+
+impl PeriodTotals {
+    fn new(currency: Currency) -> Self {
+        Self {
+            gross: Money::zero(currency),
+            delivery: Money::zero(currency),
+            commission: Money::zero(currency),
+            refunds: Money::zero(currency),
+            paid_out: Money::zero(currency),
+        }
+    }
+
+    fn into_period(self, period: String) -> LedgerPeriod {
+        LedgerPeriod {
+            period,
+            gross: self.gross.to_major(),
+            delivery: self.delivery.to_major(),
+            commission: self.commission.to_major(),
+            refunds: self.refunds.to_major(),
+            net: (self.gross + self.delivery - self.commission - self.refunds).to_major(),
+            paid_out: self.paid_out.to_major(),
+        }
+    }
+}
+
+fn date_range_filter(
+    from: Option<chrono::NaiveDate>,
+    to: Option<chrono::NaiveDate>,
+) -> Option<Document> {
+    if from.is_none() && to.is_none() {
+        return None;
+    }
+
+    let mut d = doc! {};
+
+    if let Some(from) = from {
+        let from = chrono::DateTime::parse_from_rfc3339(&format!("{}T00:00:00Z", from)).unwrap();
+        d.insert("$gte", from);
+    }
+
+    if let Some(to) = to {
+        let to = chrono::DateTime::parse_from_rfc3339(&format!("{}T23:59:59Z", to)).unwrap();
+        d.insert("$lte", to);
+    }
+
+    Some(d)
+}
+
+// Sales and refunds of the store that can be paid out and are not in a payout yet
+fn payable_filter(store_id: &ObjectId) -> Document {
+    doc! {
+        "store": store_id,
+        "kind": { "$ne": LedgerTransactionKind::Payout.to_string() },
+        "payout": None::<ObjectId>,
+        "available_at": { "$lte": BsonDateTime::now() },
+    }
+}
+
+/// Gives the transactions of payouts that were never finished back to the store balance,
+/// from a payout left in creation or a payout that was never saved
+async fn release_stranded_payout_transactions(
+    local_db: &LocalDBConection,
+    store_id: &ObjectId,
+) -> Result<()> {
+    let payout_ids: Vec<ObjectId> = local_db
+        .ledger_transactions()
+        .distinct(
+            "payout",
+            doc! {
+                "store": store_id,
+                "kind": { "$ne": LedgerTransactionKind::Payout.to_string() },
+                "payout": { "$ne": None::<ObjectId> },
+                "paid_at": None::<BsonDateTime>,
+            },
+            None,
+        )
+        .await
+        .map_err(|_| Error::Static("Failed to get payout transactions"))?
+        .into_iter()
+        .filter_map(|payout_id| payout_id.as_object_id())
+        .collect();
+
+    if payout_ids.is_empty() {
+        return Ok(());
+    }
+
+    let cursor = local_db
+        .payouts()
+        .find(doc! { "_id": { "$in": &payout_ids } }, None)
+        .await
+        .map_err(|_| Error::Static("Failed to get payouts"))?;
+
+    let payouts = collect_cursor(cursor).await?;
+
+    let stale_before = BsonDateTime::now().timestamp_millis() - PAYOUT_CREATION_TIMEOUT_SECS * 1000;
+
+    let stranded: Vec<ObjectId> = payout_ids
+        .into_iter()
+        .filter(
+            |payout_id| match payouts.iter().find(|payout| &payout.id == payout_id) {
+                Some(payout) => {
+                    payout.status == PayoutStatus::Creating
+                        && payout.created_at.timestamp_millis() < stale_before
+                }
+                None => true,
+            },
+        )
+        .collect();
+
+    if stranded.is_empty() {
+        return Ok(());
+    }
+
+    tracing::warn!(
+        "Releasing transactions of {} unfinished payouts of store {}",
+        stranded.len(),
+        store_id
+    );
+
+    local_db
+        .ledger_transactions()
+        .update_many(
+            doc! { "payout": { "$in": &stranded } },
+            doc! { "$set": { "payout": None::<ObjectId> } },
+            None,
+        )
+        .await
+        .map_err(|_| Error::Static("Failed to release payout transactions"))?;
+
+    local_db
+        .payouts()
+        .delete_many(
+            doc! {
+                "_id": { "$in": &stranded },
+                "status": PayoutStatus::Creating.to_string(),
+            },
+            None,
+        )
+        .await
+        .map_err(|_| Error::Static("Failed to delete unfinished payouts"))?;
+
+    Ok(())
+}
+
+// Releases the transactions before the payout, a payout left behind is cleaned up later
+async fn discard_payout(local_db: &LocalDBConection, payout_id: &ObjectId) -> Result<()> {
+    local_db
+        .ledger_transactions()
+        .update_many(
+            doc! { "payout": payout_id },
+            doc! { "$set": { "payout": None::<ObjectId> } },
+            None,
+        )
+        .await
+        .map_err(|_| Error::Static("Failed to release payout transactions"))?;
+
+    local_db
+        .payouts()
+        .delete_one(doc! { "_id": payout_id }, None)
+        .await
+        .map_err(|_| Error::Static("Failed to delete empty payout"))?;
+
+    Ok(())
+}
+
+async fn get_ledger_sale(
+    local_db: &LocalDBConection,
+    order_id: &ObjectId,
+    store_id: &ObjectId,
+) -> Result<Option<LedgerTransaction>> {
+    local_db
+        .ledger_transactions()
+        .find_one(
+            doc! { "key": LedgerTransaction::sale_key(order_id, store_id) },
+            None,
+        )
+        .await
+        .map_err(|_| Error::Static("Failed to get ledger transaction"))
+}
+
+#[async_trait]
+pub trait LedgerFunctions {
+    fn ledger_transactions(&self) -> Collection<LedgerTransaction>;
+    fn payouts(&self) -> Collection<Payout>;
+    /// Returns false if a transaction with the same key was already written
+    async fn insert_ledger_transaction(&self, transaction: &LedgerTransaction) -> Result<bool>;
+    /// Returns false if the sale of the store part was not written
+    async fn reverse_ledger_sale(&self, order_id: &ObjectId, store_id: &ObjectId) -> Result<bool>;
+    /// Takes `share` (0 to 1) of the sale of the store part back for the chargeback event.
+    /// Returns false if the sale of the store part was not written
+    async fn charge_back_ledger_sale(
+        &self,
+        order_id: &ObjectId,
+        store_id: &ObjectId,
+        event_id: &str,
+        share: f64,
+    ) -> Result<bool>;
+    async fn get_store_ledger_summary(
+        &self,
+        store_id: &ObjectId,
+        currency: Currency,
+        from: Option<chrono::NaiveDate>,
+        to: Option<chrono::NaiveDate>,
+    ) -> Result<StoreLedgerSummary>;
+    /// Puts everything the store can be paid into a new payout.
+    /// Returns None if there is nothing to pay.
+    /// Transactions of a payout that was never finished are paid in the new one.
+    async fn create_payout(
+        &self,
+        store_id: &ObjectId,
+        currency: Currency,
+    ) -> Result<Option<Payout>>;
+    async fn get_payout_by_id(&self, payout_id: &ObjectId) -> Result<Option<Payout>>;
+    async fn get_payouts(
+        &self,
+        store_id: Option<&ObjectId>,
+        pagination: Option<Pagination>,
+    ) -> Result<(Vec<Payout>, u64)>;
+    /// Returns false if the payout is not pending
+    async fn mark_payout_paid(&self, payout: &Payout, reference: Option<String>) -> Result<bool>;
+}
+
+#[async_trait]
+impl LedgerFunctions for LocalDBConection {
+    fn ledger_transactions(&self) -> Collection<LedgerTransaction> {
+        self.collection(LEDGER_TRANSACTIONS_COLLECTION)
+    }
+
+    fn payouts(&self) -> Collection<Payout> {
+        self.collection(PAYOUTS_COLLECTION)
+    }
+
+    async fn insert_ledger_transaction(&self, transaction: &LedgerTransaction) -> Result<bool> {
+        if !transaction.is_balanced() {
+            return Err(Error::Static("Ledger transaction is not balanced"));
+        }
+
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        let update = doc! {
+            "$setOnInsert": bson::to_document(transaction).map_err(|_| Error::Desrilaztion)?
+        };
+
+        // The key has a unique index, two concurrent upserts of the same key
+        // can't both insert and the one that loses fails with a duplicate key
+        let res = match self
+            .ledger_transactions()
+            .update_one(doc! { "key": &transaction.key }, update, options)
+            .await
+        {
+            Ok(res) => res,
+            Err(e) if is_duplicate_key_error(&e) => return Ok(false),
+            Err(_) => return Err(Error::Static("Failed to insert ledger transaction")),
+        };
+
+        Ok(res.upserted_id.is_some())
+    }
+
+    async fn reverse_ledger_sale(&self, order_id: &ObjectId, store_id: &ObjectId) -> Result<bool> {
+        match get_ledger_sale(self, order_id, store_id).await? {
+            Some(sale) => {
+                self.insert_ledger_transaction(&LedgerTransaction::reversal(&sale))
+                    .await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn charge_back_ledger_sale(
+        &self,
+        order_id: &ObjectId,
+        store_id: &ObjectId,
+        event_id: &str,
+        share: f64,
+    ) -> Result<bool> {
+        match get_ledger_sale(self, order_id, store_id).await? {
+            Some(sale) => {
+                self.insert_ledger_transaction(&LedgerTransaction::chargeback(
+                    &sale, event_id, share,
+                ))
+                .await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn get_store_ledger_summary(
+        &self,
+        store_id: &ObjectId,
+        currency: Currency,
+        from: Option<chrono::NaiveDate>,
+        to: Option<chrono::NaiveDate>,
+    ) -> Result<StoreLedgerSummary> {
+        let account = store_account(store_id);
+
+        let totals_pipeline = [
+            aggregations::match_query(&doc! { "store": store_id }),
+            aggregations::unwind("lines", false),
+            aggregations::match_query(&doc! { "lines.account": &account }),
+            doc! {
+                "$group": {
+                    "_id": None::<String>,
+                    "balance": { "$sum": "$lines.amount" },
+                    "pending": {
+                        "$sum": {
+                            "$cond": [
+                                { "$gt": ["$available_at", BsonDateTime::now()] },
+                                "$lines.amount",
+                                0.0
+                            ]
+                        }
+                    },
+                    "in_payout": {
+                        "$sum": {
+                            "$cond": [
+                                {
+                                    "$and": [
+                                        {
+                                            "$ne": [
+                                                { "$ifNull": ["$payout", None::<ObjectId>] },
+                                                None::<ObjectId>
+                                            ]
+                                        },
+                                        {
+                                            "$eq": [
+                                                { "$ifNull": ["$paid_at", None::<BsonDateTime>] },
+                                                None::<BsonDateTime>
+                                            ]
+                                        },
+                                    ]
+                                },
+                                "$lines.amount",
+                                0.0
+                            ]
+                        }
+                    },
+                    "paid_out": {
+                        "$sum": {
+                            "$cond": [
+                                { "$eq": ["$lines.kind", LedgerLineKind::Payout.to_string()] },
+                                "$lines.amount",
+                                0.0
+                            ]
+                        }
+                    },
+                }
+            },
+        ];
+
+        let cursor = self
+            .ledger_transactions()
+            .aggregate(totals_pipeline, None)
+            .await
+            .map_err(|_| Error::Static("Failed to get ledger summary"))?;
+
+        let totals = collect_cursor(cursor).await?.pop().unwrap_or_default();
+        let total = |field: &str| Money::from_major(totals.get_f64(field).unwrap_or(0.0), currency);
+
+        let mut filters = doc! { "store": store_id };
+
+        if let Some(range) = date_range_filter(from, to) {
+            filters.insert("created_at", range);
+        }
+
+        let periods_pipeline = [
+            aggregations::match_query(&filters),
+            aggregations::unwind("lines", false),
+            aggregations::match_query(&doc! { "lines.account": &account }),
+            doc! {
+                "$group": {
+                    "_id": {
+                        "period": {
+                            "$dateToString": { "format": "%Y-%m", "date": "$created_at" }
+                        },
+                        "kind": "$lines.kind",
+                    },
+                    "amount": { "$sum": "$lines.amount" },
+                }
+            },
+        ];
+
+        let cursor = self
+            .ledger_transactions()
+            .aggregate(periods_pipeline, None)
+            .await
+            .map_err(|_| Error::Static("Failed to get ledger summary"))?;
+
+        let mut periods: BTreeMap<String, PeriodTotals> = BTreeMap::new();
+
+        for row in collect_cursor(cursor).await? {
+            let id = row.get_document("_id").map_err(|_| Error::Desrilaztion)?;
+            let period = id.get_str("period").map_err(|_| Error::Desrilaztion)?;
+            let kind: LedgerLineKind = bson::from_bson(id.get("kind").cloned().unwrap_or_default())
+                .map_err(|_| Error::Desrilaztion)?;
+            let amount = Money::from_major(row.get_f64("amount").unwrap_or(0.0), currency);
+
+            let entry = periods
+                .entry(period.to_string())
+                .or_insert_with(|| PeriodTotals::new(currency));
+
+            match kind {
+                LedgerLineKind::Gross => entry.gross += amount,
+                LedgerLineKind::Delivery => entry.delivery += amount,
+                LedgerLineKind::Commission => entry.commission -= amount,
+                LedgerLineKind::Refund => entry.refunds -= amount,
+                LedgerLineKind::Payout => entry.paid_out -= amount,
+                // only on platform accounts
+                LedgerLineKind::Charge => {}
+            }
+        }
+
+        let periods = periods
+            .into_iter()
+            .map(|(period, totals)| totals.into_period(period))
+            .collect();
+
+        Ok(StoreLedgerSummary {
+            currency,
+            balance: (total("balance") - total("pending")).to_major(),
+            in_payout: total("in_payout").to_major(),
+            pending: total("pending").to_major(),
+            paid_out: (Money::zero(currency) - total("paid_out")).to_major(),
+            periods,
+        })
+    }
+
+    async fn create_payout(
+        &self,
+        store_id: &ObjectId,
+        currency: Currency,
+    ) -> Result<Option<Payout>> {
+        release_stranded_payout_transactions(self, store_id).await?;
+
+        let mut payout = Payout::new(store_id.clone(), currency);
+
+        // The payout is saved before any transaction points to it,
+        // a failure after this leaves it in creation and the next payout picks it up
+        self.payouts()
+            .insert_one(&payout, None)
+            .await
+            .map_err(|_| Error::Static("Failed to insert payout"))?;
+
+        // Transactions are tagged only while they are not in any payout,
+        // so two payouts created at the same time never share a transaction
+        let res = self
+            .ledger_transactions()
+            .update_many(
+                payable_filter(store_id),
+                doc! { "$set": { "payout": payout.id } },
+                None,
+            )
+            .await
+            .map_err(|_| Error::Static("Failed to create payout"))?;
+
+        if res.modified_count == 0 {
+            discard_payout(self, &payout.id).await?;
+            return Ok(None);
+        }
+
+        let cursor = self
+            .ledger_transactions()
+            .find(doc! { "payout": payout.id }, None)
+            .await
+            .map_err(|_| Error::Static("Failed to create payout"))?;
+
+        let account = store_account(store_id);
+        let transactions = collect_cursor(cursor).await?;
+
+        payout.transactions = transactions.len() as u64;
+        payout.amount = Money::sum(
+            currency,
+            transactions
+                .iter()
+                .flat_map(|transaction| transaction.lines.iter())
+                .filter(|line| line.account == account)
+                .map(|line| Money::from_major(line.amount, currency)),
+        )
+        .to_major();
+
+        // refunds can leave nothing to pay, the transactions wait for the next payout
+        if payout.amount <= 0.0 {
+            discard_payout(self, &payout.id).await?;
+            return Ok(None);
+        }
+
+        payout.status = PayoutStatus::Pending;
+
+        self.payouts()
+            .update_one(
+                doc! {
+                    "_id": payout.id,
+                    "status": PayoutStatus::Creating.to_string(),
+                },
+                doc! {
+                    "$set": {
+                        "amount": payout.amount,
+                        "transactions": payout.transactions as i64,
+                        "status": PayoutStatus::Pending.to_string(),
+                    }
+                },
+                None,
+            )
+            .await
+            .map_err(|_| Error::Static("Failed to update payout"))?;
+
+        Ok(Some(payout))
+    }
+
+    async fn get_payout_by_id(&self, payout_id: &ObjectId) -> Result<Option<Payout>> {
+        self.payouts()
+            .find_one(doc! { "_id": payout_id }, None)
+            .await
+            .map_err(|_| Error::Static("Failed to get payout"))
+    }
+
+    async fn get_payouts(
+        &self,
+        store_id: Option<&ObjectId>,
+        pagination: Option<Pagination>,
+    ) -> Result<(Vec<Payout>, u64)> {
+        let pagination = pagination.unwrap_or_default();
+
+        // a payout in creation has no amount yet
+        let mut filters = doc! { "status": { "$ne": PayoutStatus::Creating.to_string() } };
+
+        if let Some(store_id) = store_id {
+            filters.insert("store", store_id);
+        }
+
+        let pipeline = [
+            aggregations::match_query(&filters),
+            aggregations::sort(doc! { "created_at": -1 }),
+            aggregations::skip(pagination.offset),
+            aggregations::limit(pagination.amount),
+        ];
+
+        let cursor = self
+            .payouts()
+            .aggregate(pipeline, None)
+            .await
+            .map_err(|_| Error::Static("Failed to get payouts"))?;
+
+        let payouts = collect_cursor(cursor)
+            .await?
+            .into_iter()
+            .map(|payout| bson::from_document(payout).map_err(|_| Error::Desrilaztion))
+            .collect::<Result<Vec<Payout>>>()?;
+
+        let count = self
+            .payouts()
+            .count_documents(filters, None)
+            .await
+            .map_err(|_| Error::Static("Failed to count payouts"))?;
+
+        Ok((payouts, count))
+    }
+
+    async fn mark_payout_paid(&self, payout: &Payout, reference: Option<String>) -> Result<bool> {
+        if payout.status != PayoutStatus::Pending {
+            return Ok(false);
+        }
+
+        // Written first and keyed by the payout, so a retry after a failure
+        // below never takes the payout from the store balance twice
+        self.insert_ledger_transaction(&LedgerTransaction::payout(payout))
+            .await?;
+
+        let now = BsonDateTime::now();
+
+        let res = self
+            .payouts()
+            .update_one(
+                doc! {
+                    "_id": payout.id,
+                    "status": PayoutStatus::Pending.to_string(),
+                },
+                doc! {
+                    "$set": {
+                        "status": PayoutStatus::Paid.to_string(),
+                        "reference": reference,
+                        "paid_at": now,
+                    }
+                },
+                None,
+            )
+            .await
+            .map_err(|_| Error::Static("Failed to update payout"))?;
+
+        self.ledger_transactions()
+            .update_many(
+                doc! { "payout": payout.id, "paid_at": None::<BsonDateTime> },
+                doc! { "$set": { "paid_at": now } },
+                None,
+            )
+            .await
+            .map_err(|_| Error::Static("Failed to update payout transactions"))?;
+
+        Ok(res.modified_count == 1)
+    }
+}
@@ -0,0 +1,64 @@
+use crate::prelude::*;
+use axum::extract::Extension;
+use mongodb::{
+    error::{ErrorKind, WriteFailure},
+    Client, Collection, Cursor, Database,
+};
+use serde::de::DeserializeOwned;
+use std::sync::Arc;
+
+/// Collections that are owned by this api and have no model in shoppa-core.
+/// It uses its own client, so its writes can't join a `DBConection` transaction.
+pub struct LocalDBConection {
+    db: Database,
+}
+
+pub type AxumLocalDBExtansion = Extension<Arc<LocalDBConection>>;
+
+impl LocalDBConection {
+    pub async fn connect() -> Result<Self> {
+        let client = Client::with_uri_str(&ENV_VARS.MONGODB_URI)
+            .await
+            .map_err(|_| Error::Static("Failed to connect to DB"))?;
+
+        Ok(Self {
+            db: client.database(&ENV_VARS.DB_NAME),
+        })
+    }
+
+    pub fn collection<T>(&self, name: &str) -> Collection<T> {
+        self.db.collection(name)
+    }
+}
+
+const DUPLICATE_KEY_CODE: i32 = 11000;
+
+/// A write that was rejected by a unique index
+pub fn is_duplicate_key_error(error: &mongodb::error::Error) -> bool {
+    match error.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(e)) => e.code == DUPLICATE_KEY_CODE,
+        ErrorKind::Command(e) => e.code == DUPLICATE_KEY_CODE,
+        _ => false,
+    }
+}
+
+pub async fn collect_cursor<T>(mut cursor: Cursor<T>) -> Result<Vec<T>>
+where
+    T: DeserializeOwned,
+{
+    let mut docs = Vec::new();
+
+    while cursor
+        .advance()
+        .await
+        .map_err(|_| Error::Static("Failed to read from DB"))?
+    {
+        docs.push(
+            cursor
+                .deserialize_current()
+                .map_err(|_| Error::Desrilaztion)?,
+        );
+    }
+
+    Ok(docs)
+}
@@ -1,22 +1,54 @@
+mod cart_reminders;
 mod categories;
 mod checkout_session;
+mod commissions;
+mod currencies;
+mod indexes;
 mod invoices;
+mod ledger;
+mod local;
 mod orders;
+mod payment_methods;
+mod payment_state;
+mod post_payment;
 mod products;
+mod promotions;
+mod refunds;
+mod reviews;
+mod search;
+mod stock_alerts;
 mod store_users;
 mod stores;
+mod taxes;
 mod users;
 mod variants;
+mod wishlists;
 
+pub use cart_reminders::*;
 pub use categories::*;
 pub use checkout_session::*;
+pub use commissions::*;
+pub use currencies::*;
+pub use indexes::*;
 pub use invoices::*;
+pub use ledger::*;
+pub use local::*;
 pub use orders::*;
+pub use payment_methods::*;
+pub use payment_state::*;
+pub use post_payment::*;
 pub use products::*;
+pub use promotions::*;
+pub use refunds::*;
+pub use reviews::*;
+pub use search::*;
+pub use stock_alerts::*;
 pub use store_users::*;
 pub use stores::*;
+pub use taxes::*;
 pub use users::*;
 pub use variants::*;
+pub use wishlists::*;
 
 use axum::extract::Extension;
 use shoppa_core::db::DBConection;
@@ -1,6 +1,14 @@
-use crate::prelude::*;
+use crate::{
+    db::{
+        paid_orders_filter, OrderPaymentStatus, PaymentStateFunctions, ProductFunctions,
+        CHARGE_CURRENCY_FIELD, ORDER_PART_COMMISSION_FIELD, PAYMENT_STATE_FIELD,
+        POST_PAYMENT_FIELD, STORE_REFUNDS_FIELD,
+    },
+    helpers::money::{Currency, Money},
+    prelude::*,
+};
 use axum::async_trait;
-use bson::{doc, oid::ObjectId, Document};
+use bson::{doc, oid::ObjectId, Bson, DateTime as BsonDateTime, Document};
 use mongodb::{options::AggregateOptions, results::UpdateResult};
 use shoppa_core::{
     db::{
@@ -14,11 +22,16 @@ use shoppa_core::{
 
 #[async_trait]
 pub trait OrderFunctions {
+    /// Records the charge of an order that was saved before it was charged,
+    /// then its payment and the payment step of its post payment job (see `record_order_payment`).
+    /// Matches nothing if the payment was already recorded or was taken back.
     async fn update_order_after_payment(
         &self,
         order: &Order,
         transaction_info: TransactionInfo,
         card_holder_name: String,
+        status: OrderPaymentStatus,
+        part_amounts: &[f64],
     ) -> Result<UpdateResult>;
     async fn get_orders_for_store(
         &self,
@@ -41,6 +54,129 @@ pub trait OrderFunctions {
         old_user_owner_id: ObjectId,
         new_user_owner_id: ObjectId,
     ) -> Result<UpdateResult>;
+    async fn get_orders_for_user(
+        &self,
+        pagination: Option<Pagination>,
+        user_id: &ObjectId,
+        status: Option<OrderPartStatus>,
+        options: Option<AggregateOptions>,
+    ) -> Result<(Vec<Document>, u64)>;
+    async fn get_order_by_id_for_user(
+        &self,
+        user_id: &ObjectId,
+        order_id: &ObjectId,
+        options: Option<AggregateOptions>,
+    ) -> Result<Option<Document>>;
+    async fn get_order_by_number_and_email(
+        &self,
+        order_number: &str,
+        email: &str,
+    ) -> Result<Option<Order>>;
+    /// The read only view of the order for guest links, without personal details
+    async fn get_order_status_by_id(
+        &self,
+        order_id: &ObjectId,
+        options: Option<AggregateOptions>,
+    ) -> Result<Option<Document>>;
+    /// Fails the same way `update_order_part_status` does if the store part
+    /// can't move to the status, returns the current status of the part.
+    async fn check_order_part_status_transition(
+        &self,
+        order_id: &ObjectId,
+        store_id: &ObjectId,
+        status: &OrderPartStatus,
+    ) -> Result<OrderPartStatus>;
+    /// Moves the store part of the order to the new status if the transition is allowed,
+    /// and appends the change to the part status history.
+    async fn update_order_part_status(
+        &self,
+        order_id: &ObjectId,
+        store_id: &ObjectId,
+        status: OrderPartStatus,
+        changed_by: &ObjectId,
+        note: Option<String>,
+    ) -> Result<UpdateResult>;
+    /// Cancels the parts that were not shipped yet, for an order whose payment was taken back
+    /// by the processor. With `release_stock` their stock is given back, an order that was
+    /// never paid gives it back by releasing its checkout reservation instead.
+    /// Returns the stores whose part was canceled, running it again cancels nothing.
+    async fn cancel_unshipped_order_parts(
+        &self,
+        order: &Order,
+        note: &str,
+        release_stock: bool,
+    ) -> Result<Vec<ObjectId>>;
+}
+
+// Each store part history is stored on the part itself (under `status_history`)
+pub const ORDER_PART_STATUS_HISTORY_FIELD: &str = "status_history";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderPartStatusChange {
+    pub from: String,
+    pub to: String,
+    // None when the change was made by the payment processor
+    pub changed_by: Option<ObjectId>,
+    pub changed_at: BsonDateTime,
+    pub note: Option<String>,
+}
+
+// Fields that are kept on the order for internal use only
+fn hide_internal_order_fields() -> Document {
+    doc! {
+        "$project": {
+            format!("{}.token", Order::fields().transaction): 0,
+            format!("{}.events", PAYMENT_STATE_FIELD): 0,
+            format!("{}.{}", Order::fields().parts, ORDER_PART_COMMISSION_FIELD): 0,
+            POST_PAYMENT_FIELD: 0,
+            STORE_REFUNDS_FIELD: 0,
+        }
+    }
+}
+
+#[async_trait]
+pub trait AdminOrderFunctions {
+    /// Orders saved before amounts were summed in minor units can have amounts like
+    /// 99.89999999, they are rounded to whole minor units.
+    /// Returns how many orders were changed, running it again changes nothing.
+    async fn round_orders_money(&self) -> Result<u64>;
+}
+
+fn not_whole_minor_units(amount: &str) -> Document {
+    doc! {
+        "$ne": [amount, { "$round": [amount, 2] }]
+    }
+}
+
+fn any_element(input: &str, as_: &str, condition: Document) -> Document {
+    doc! {
+        "$anyElementTrue": [{
+            "$map": {
+                "input": input,
+                "as": as_,
+                "in": condition,
+            }
+        }]
+    }
+}
+
+/// Whether a part can move from one status to the other.
+/// pending -> processing -> shipped -> delivered,
+/// a part can be canceled until it is shipped and returned once it was shipped.
+pub fn is_order_part_transition_allowed(from: &OrderPartStatus, to: &OrderPartStatus) -> bool {
+    match from {
+        OrderPartStatus::Pending => {
+            matches!(to, OrderPartStatus::Processing | OrderPartStatus::Canceled)
+        }
+        OrderPartStatus::Processing => {
+            matches!(to, OrderPartStatus::Shipped | OrderPartStatus::Canceled)
+        }
+        OrderPartStatus::Shipped => {
+            matches!(to, OrderPartStatus::Delivered | OrderPartStatus::Returned)
+        }
+        OrderPartStatus::Delivered => matches!(to, OrderPartStatus::Returned),
+        OrderPartStatus::Canceled | OrderPartStatus::Returned => false,
+    }
 }
 
 #[async_trait]
@@ -50,6 +186,8 @@ impl OrderFunctions for DBConection {
         order: &Order,
         transaction_info: TransactionInfo,
         card_holder_name: String,
+        status: OrderPaymentStatus,
+        part_amounts: &[f64],
     ) -> Result<UpdateResult> {
         let order_transaction = OrderTransaction {
             token: transaction_info.token,
@@ -59,13 +197,16 @@ impl OrderFunctions for DBConection {
             holder_name: card_holder_name,
         };
 
-        let update = doc! {
-            "$set": {
-                Order::fields().transaction: order_transaction,
-            }
-        };
+        // the transaction is kept even if the webhook recorded the payment first
+        self.update_order_by_id(
+            order.id()?,
+            doc! { "$set": { Order::fields().transaction: order_transaction } },
+            None,
+            None,
+        )
+        .await?;
 
-        self.update_order_by_id(order.id().unwrap(), update, None, None)
+        self.record_order_payment(order.id()?, status, part_amounts)
             .await
     }
 
@@ -81,9 +222,11 @@ impl OrderFunctions for DBConection {
     ) -> Result<(Vec<Document>, u64)> {
         let pagination = pagination.unwrap_or_default();
 
-        let mut filters = aggregations::match_query(&doc! {
-            Order::fields().parts(true).store: store_id,
-        });
+        let mut query = paid_orders_filter();
+
+        query.insert(Order::fields().parts(true).store, store_id);
+
+        let mut filters = aggregations::match_query(&query);
 
         if from.is_some() || to.is_some() {
             let mut d = doc! {};
@@ -138,6 +281,7 @@ impl OrderFunctions for DBConection {
                     Order::fields().parts(false).total: "$parts.total",
                     Order::fields().parts(false).total_after_refunds: "$parts.total_after_refunds",
                     Order::fields().parts(false).items: "$parts.items",
+                    ORDER_PART_STATUS_HISTORY_FIELD: format!("$parts.{}", ORDER_PART_STATUS_HISTORY_FIELD),
                     // notes, utm
                 }),
             ),
@@ -179,10 +323,12 @@ impl OrderFunctions for DBConection {
         }
         .build_pipeline();
 
-        let mut pipeline = vec![aggregations::match_query(&doc! {
-            Order::fields().id: order_id,
-            Order::fields().parts(true).store: store_id,
-        })];
+        let mut query = paid_orders_filter();
+
+        query.insert(Order::fields().id, order_id);
+        query.insert(Order::fields().parts(true).store, store_id);
+
+        let mut pipeline = vec![aggregations::match_query(&query)];
 
         pipeline.extend(populate_pipeline);
 
@@ -207,6 +353,7 @@ impl OrderFunctions for DBConection {
                     Order::fields().parts(false).total: "$parts.total",
                     Order::fields().parts(false).total_after_refunds: "$parts.total_after_refunds",
                     Order::fields().parts(false).items: "$parts.items",
+                    ORDER_PART_STATUS_HISTORY_FIELD: format!("$parts.{}", ORDER_PART_STATUS_HISTORY_FIELD),
                     // notes, utm
                 }),
             ),
@@ -232,4 +379,428 @@ impl OrderFunctions for DBConection {
 
         self.update_many_order(filter, update, None, None).await
     }
+
+    async fn get_orders_for_user(
+        &self,
+        pagination: Option<Pagination>,
+        user_id: &ObjectId,
+        status: Option<OrderPartStatus>,
+        options: Option<AggregateOptions>,
+    ) -> Result<(Vec<Document>, u64)> {
+        let pagination = pagination.unwrap_or_default();
+
+        let mut filters = paid_orders_filter();
+
+        filters.insert(Order::fields().user, user_id);
+
+        if let Some(status) = status {
+            filters.insert(Order::fields().parts(true).status, status.to_string());
+        }
+
+        let filters = aggregations::match_query(&filters);
+
+        let pipeline = [
+            filters.clone(),
+            aggregations::sort(doc! {
+                Order::fields().created_at: -1
+            }),
+            aggregations::skip(pagination.offset),
+            aggregations::limit(pagination.amount),
+            hide_internal_order_fields(),
+        ];
+
+        let count = self
+            .count_orders_with_aggregation(
+                [filters.clone(), aggregations::count("count")],
+                options,
+                None,
+            )
+            .await
+            .unwrap_or_default();
+
+        let orders = self
+            .aggregate_orders(pipeline, None, None)
+            .await
+            .unwrap_or_default();
+
+        Ok((orders, count))
+    }
+
+    async fn get_order_by_id_for_user(
+        &self,
+        user_id: &ObjectId,
+        order_id: &ObjectId,
+        options: Option<AggregateOptions>,
+    ) -> Result<Option<Document>> {
+        let populate_pipeline = OrderPopulate {
+            stores: FieldPopulate::None,
+            products: FieldPopulate::Nested(ProductsPopulate {
+                store: false,
+                categories: FieldPopulate::None,
+                variants: true,
+                options: None,
+            }),
+            user: FieldPopulate::None,
+            options: None,
+        }
+        .build_pipeline();
+
+        let mut query = paid_orders_filter();
+
+        query.insert(Order::fields().id, order_id);
+        query.insert(Order::fields().user, user_id);
+
+        let mut pipeline = vec![aggregations::match_query(&query)];
+
+        pipeline.extend(populate_pipeline);
+
+        pipeline.push(hide_internal_order_fields());
+
+        Ok(self.aggregate_orders(pipeline, options, None).await?.pop())
+    }
+
+    async fn get_order_by_number_and_email(
+        &self,
+        order_number: &str,
+        email: &str,
+    ) -> Result<Option<Order>> {
+        let mut filters = paid_orders_filter();
+
+        filters.insert(Order::fields().order_number, order_number);
+        // emails are compared case insensitively, the order number already narrows it to one order
+        filters.insert(
+            "$expr",
+            doc! {
+                "$eq": [
+                    { "$toLower": format!("${}.email", Order::fields().info) },
+                    email.trim().to_lowercase(),
+                ]
+            },
+        );
+
+        self.get_order(filters, None, None, None).await
+    }
+
+    async fn get_order_status_by_id(
+        &self,
+        order_id: &ObjectId,
+        options: Option<AggregateOptions>,
+    ) -> Result<Option<Document>> {
+        let mut query = paid_orders_filter();
+
+        query.insert(Order::fields().id, order_id);
+
+        let pipeline = [
+            aggregations::match_query(&query),
+            doc! {
+                "$project": {
+                    Order::fields().user: 0,
+                    Order::fields().address: 0,
+                    Order::fields().info: 0,
+                    Order::fields().transaction: 0,
+                    format!("{}.events", PAYMENT_STATE_FIELD): 0,
+                    format!("{}.{}", Order::fields().parts, ORDER_PART_COMMISSION_FIELD): 0,
+                    POST_PAYMENT_FIELD: 0,
+                    STORE_REFUNDS_FIELD: 0,
+                }
+            },
+        ];
+
+        Ok(self.aggregate_orders(pipeline, options, None).await?.pop())
+    }
+
+    async fn check_order_part_status_transition(
+        &self,
+        order_id: &ObjectId,
+        store_id: &ObjectId,
+        status: &OrderPartStatus,
+    ) -> Result<OrderPartStatus> {
+        let mut query = paid_orders_filter();
+
+        query.insert(Order::fields().id, order_id);
+
+        let order = self
+            .get_order(query, None, None, None)
+            .await?
+            .ok_or(Error::ApiErrorWithCode("OrderNotFound", 404))?;
+
+        let part = order
+            .parts
+            .iter()
+            .find(|part| part.store.ref_doc_id() == store_id)
+            .ok_or(Error::ApiErrorWithCode("OrderNotFound", 404))?;
+
+        if !is_order_part_transition_allowed(&part.status, status) {
+            return Err(Error::ApiErrorWithCode("InvalidOrderStatusTransition", 400));
+        }
+
+        Ok(part.status.clone())
+    }
+
+    async fn update_order_part_status(
+        &self,
+        order_id: &ObjectId,
+        store_id: &ObjectId,
+        status: OrderPartStatus,
+        changed_by: &ObjectId,
+        note: Option<String>,
+    ) -> Result<UpdateResult> {
+        let current_status = self
+            .check_order_part_status_transition(order_id, store_id, &status)
+            .await?
+            .to_string();
+        let new_status = status.to_string();
+
+        let change = OrderPartStatusChange {
+            from: current_status.clone(),
+            to: new_status.clone(),
+            changed_by: Some(changed_by.clone()),
+            changed_at: BsonDateTime::now(),
+            note,
+        };
+
+        // the current status is part of the filter, so a concurrent change is not overridden
+        let filters = doc! {
+            Order::fields().id: order_id,
+            Order::fields().parts: {
+                "$elemMatch": {
+                    Order::fields().parts(false).store: store_id,
+                    Order::fields().parts(false).status: current_status,
+                }
+            }
+        };
+
+        let update = doc! {
+            "$set": {
+                format!(
+                    "{}.$.{}",
+                    Order::fields().parts,
+                    Order::fields().parts(false).status
+                ): new_status,
+            },
+            "$push": {
+                format!(
+                    "{}.$.{}",
+                    Order::fields().parts,
+                    ORDER_PART_STATUS_HISTORY_FIELD
+                ): bson::to_bson(&change).map_err(|_| Error::Desrilaztion)?,
+            }
+        };
+
+        let res = self.update_order(filters, update, None, None).await?;
+
+        if res.modified_count == 0 {
+            return Err(Error::ApiErrorWithCode("OrderStatusChanged", 409));
+        }
+
+        Ok(res)
+    }
+
+    async fn cancel_unshipped_order_parts(
+        &self,
+        order: &Order,
+        note: &str,
+        release_stock: bool,
+    ) -> Result<Vec<ObjectId>> {
+        let order_id = order.id()?;
+        let mut canceled = Vec::new();
+
+        let mut db_session = self.start_session().await?;
+
+        db_session
+            .start_transaction(None)
+            .await
+            .map_err(|_| Error::Static("Failed to start transaction"))?;
+
+        for part in &order.parts {
+            if !matches!(
+                part.status,
+                OrderPartStatus::Pending | OrderPartStatus::Processing
+            ) {
+                continue;
+            }
+
+            let store_id = part.store.ref_doc_id();
+
+            let change = OrderPartStatusChange {
+                from: part.status.to_string(),
+                to: OrderPartStatus::Canceled.to_string(),
+                changed_by: None,
+                changed_at: BsonDateTime::now(),
+                note: Some(note.to_string()),
+            };
+
+            // the status is part of the filter, so the stock is given back only once
+            let filters = doc! {
+                Order::fields().id: order_id,
+                Order::fields().parts: {
+                    "$elemMatch": {
+                        Order::fields().parts(false).store: store_id,
+                        Order::fields().parts(false).status: part.status.to_string(),
+                    }
+                }
+            };
+
+            let update = doc! {
+                "$set": {
+                    format!(
+                        "{}.$.{}",
+                        Order::fields().parts,
+                        Order::fields().parts(false).status
+                    ): OrderPartStatus::Canceled.to_string(),
+                },
+                "$push": {
+                    format!(
+                        "{}.$.{}",
+                        Order::fields().parts,
+                        ORDER_PART_STATUS_HISTORY_FIELD
+                    ): bson::to_bson(&change).map_err(|_| Error::Desrilaztion)?,
+                }
+            };
+
+            let res = match self
+                .update_order(filters, update, None, Some(&mut db_session))
+                .await
+            {
+                Ok(res) => res,
+                Err(e) => {
+                    let _ = db_session.abort_transaction().await;
+                    return Err(e);
+                }
+            };
+
+            if res.modified_count == 0 {
+                continue;
+            }
+
+            canceled.push(store_id.clone());
+
+            if !release_stock {
+                continue;
+            }
+
+            for item in &part.items {
+                if let Err(e) = self
+                    .release_product_item_stock(
+                        item.product_id(),
+                        &item.item_id,
+                        item.quantity as i64,
+                        Some(&mut db_session),
+                    )
+                    .await
+                {
+                    let _ = db_session.abort_transaction().await;
+                    return Err(e);
+                }
+            }
+        }
+
+        self.commit_transaction(&mut db_session, Some(16)).await?;
+
+        Ok(canceled)
+    }
+}
+
+#[async_trait]
+impl AdminOrderFunctions for DBConection {
+    async fn round_orders_money(&self) -> Result<u64> {
+        let pipeline = [
+            aggregations::match_query(&doc! {
+                "$expr": {
+                    "$or": [
+                        not_whole_minor_units(&format!("${}", Order::fields().total)),
+                        any_element(
+                            &format!("${}", Order::fields().parts),
+                            "part",
+                            doc! {
+                                "$or": [
+                                    not_whole_minor_units("$$part.total"),
+                                    any_element(
+                                        "$$part.items",
+                                        "item",
+                                        not_whole_minor_units("$$item.price"),
+                                    ),
+                                ]
+                            },
+                        ),
+                    ]
+                }
+            }),
+            doc! {
+                "$project": {
+                    Order::fields().total: 1,
+                    format!("{}.total", Order::fields().parts): 1,
+                    format!("{}.items.price", Order::fields().parts): 1,
+                    format!("{}.currency", CHARGE_CURRENCY_FIELD): 1,
+                }
+            },
+        ];
+
+        let orders = self.aggregate_orders(pipeline, None, None).await?;
+
+        let mut changed = 0;
+
+        for order in orders {
+            let order_id = order
+                .get_object_id("_id")
+                .map_err(|_| Error::Desrilaztion)?;
+
+            let currency = order
+                .get_document(CHARGE_CURRENCY_FIELD)
+                .ok()
+                .and_then(|charge_currency| charge_currency.get_str("currency").ok())
+                .and_then(|currency| currency.parse::<Currency>().ok())
+                .unwrap_or_default();
+
+            let round = |amount: f64| Bson::Double(Money::from_major(amount, currency).to_major());
+
+            let mut set = doc! {};
+
+            if let Ok(total) = order.get_f64(Order::fields().total) {
+                set.insert(Order::fields().total, round(total));
+            }
+
+            let parts = order.get_array(Order::fields().parts).ok();
+
+            for (index, part) in parts.into_iter().flatten().enumerate() {
+                let part = match part {
+                    Bson::Document(part) => part,
+                    _ => continue,
+                };
+
+                if let Ok(total) = part.get_f64("total") {
+                    set.insert(
+                        format!("{}.{}.total", Order::fields().parts, index),
+                        round(total),
+                    );
+                }
+
+                let items = part.get_array("items").ok();
+
+                for (item_index, item) in items.into_iter().flatten().enumerate() {
+                    if let Some(price) = item
+                        .as_document()
+                        .and_then(|item| item.get_f64("price").ok())
+                    {
+                        set.insert(
+                            format!(
+                                "{}.{}.items.{}.price",
+                                Order::fields().parts,
+                                index,
+                                item_index
+                            ),
+                            round(price),
+                        );
+                    }
+                }
+            }
+
+            self.update_order_by_id(&order_id, doc! { "$set": set }, None, None)
+                .await?;
+
+            changed += 1;
+        }
+
+        Ok(changed)
+    }
 }
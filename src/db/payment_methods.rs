@@ -0,0 +1,155 @@
+use crate::prelude::*;
+use axum::async_trait;
+use bson::{doc, oid::ObjectId, DateTime as BsonDateTime, Document};
+use mongodb::results::UpdateResult;
+use serde::{Deserialize, Serialize};
+use shoppa_core::db::{
+    aggregations,
+    models::{OrderTransaction, User, UserStatus},
+    DBConection,
+};
+
+// Cards saved from a previous charge are stored on the user (under `payment_methods`)
+pub const PAYMENT_METHODS_FIELD: &str = "payment_methods";
+
+pub const MAX_SAVED_PAYMENT_METHODS: usize = 5;
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedPaymentMethod {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    // The transaction of the charge the card was saved from, its token is reusable
+    pub card: OrderTransaction,
+    pub created_at: BsonDateTime,
+}
+
+impl SavedPaymentMethod {
+    pub fn new(card: OrderTransaction) -> Self {
+        Self {
+            id: ObjectId::new(),
+            card,
+            created_at: BsonDateTime::now(),
+        }
+    }
+}
+
+fn payment_method_field(field: &str) -> String {
+    format!("{}.{}", PAYMENT_METHODS_FIELD, field)
+}
+
+#[async_trait]
+pub trait PaymentMethodFunctions {
+    /// The saved cards of the user, without their tokens
+    async fn get_user_payment_methods(&self, user_id: &ObjectId) -> Result<Vec<Document>>;
+
+    async fn get_user_payment_method(
+        &self,
+        user_id: &ObjectId,
+        payment_method_id: &ObjectId,
+    ) -> Result<Option<SavedPaymentMethod>>;
+
+    /// Matches nothing if the card is already saved or the user has the max saved cards
+    async fn add_user_payment_method(
+        &self,
+        user_id: &ObjectId,
+        payment_method: SavedPaymentMethod,
+    ) -> Result<UpdateResult>;
+
+    async fn delete_user_payment_method(
+        &self,
+        user_id: &ObjectId,
+        payment_method_id: &ObjectId,
+    ) -> Result<UpdateResult>;
+}
+
+#[async_trait]
+impl PaymentMethodFunctions for DBConection {
+    async fn get_user_payment_methods(&self, user_id: &ObjectId) -> Result<Vec<Document>> {
+        let pipeline = [
+            aggregations::match_query(&doc! {
+                User::fields().id: user_id,
+            }),
+            aggregations::unwind(PAYMENT_METHODS_FIELD, false),
+            aggregations::replace_root(PAYMENT_METHODS_FIELD),
+            doc! {
+                "$project": {
+                    "card.token": 0,
+                }
+            },
+        ];
+
+        self.aggregate_users(pipeline, None, None).await
+    }
+
+    async fn get_user_payment_method(
+        &self,
+        user_id: &ObjectId,
+        payment_method_id: &ObjectId,
+    ) -> Result<Option<SavedPaymentMethod>> {
+        let pipeline = [
+            aggregations::match_query(&doc! {
+                User::fields().id: user_id,
+            }),
+            aggregations::unwind(PAYMENT_METHODS_FIELD, false),
+            aggregations::replace_root(PAYMENT_METHODS_FIELD),
+            aggregations::match_query(&doc! {
+                "_id": payment_method_id,
+            }),
+        ];
+
+        match self.aggregate_users(pipeline, None, None).await?.pop() {
+            Some(payment_method) => bson::from_document(payment_method)
+                .map(Some)
+                .map_err(|_| Error::Desrilaztion),
+            None => Ok(None),
+        }
+    }
+
+    async fn add_user_payment_method(
+        &self,
+        user_id: &ObjectId,
+        payment_method: SavedPaymentMethod,
+    ) -> Result<UpdateResult> {
+        let filters = doc! {
+            User::fields().id: user_id,
+            User::fields().status: {
+                "$nin": [UserStatus::Deleted, UserStatus::Banned, UserStatus::Guest]
+            },
+            payment_method_field("card.token"): {
+                "$ne": &payment_method.card.token
+            },
+            // an index past the max means the user already has the max saved cards
+            payment_method_field(&(MAX_SAVED_PAYMENT_METHODS - 1).to_string()): {
+                "$exists": false
+            },
+        };
+
+        let update = doc! {
+            "$push": {
+                PAYMENT_METHODS_FIELD: bson::to_bson(&payment_method).map_err(|_| Error::Desrilaztion)?
+            }
+        };
+
+        self.update_user(filters, update, None, None).await
+    }
+
+    async fn delete_user_payment_method(
+        &self,
+        user_id: &ObjectId,
+        payment_method_id: &ObjectId,
+    ) -> Result<UpdateResult> {
+        let filters = doc! {
+            User::fields().id: user_id,
+        };
+
+        let update = doc! {
+            "$pull": {
+                PAYMENT_METHODS_FIELD: {
+                    "_id": payment_method_id
+                }
+            }
+        };
+
+        self.update_user(filters, update, None, None).await
+    }
+}
@@ -0,0 +1,758 @@
+use crate::{
+    db::{
+        post_payment_paid_set, post_payment_unpaid_filter, PostPaymentJob, PostPaymentJobStatus,
+        POST_PAYMENT_FIELD,
+    },
+    prelude::*,
+};
+use axum::async_trait;
+use bson::{doc, oid::ObjectId, Bson, DateTime as BsonDateTime, Document};
+use mongodb::{results::UpdateResult, ClientSession};
+use serde::{Deserialize, Serialize};
+use shoppa_core::db::{aggregations, models::Order, DBConection};
+use strum_macros::Display;
+
+// The payment state reported by the processor is stored on the order (under `payment_state`),
+// together with every webhook event that was received for it
+pub const PAYMENT_STATE_FIELD: &str = "payment_state";
+
+// Each part keeps its share of the payment (under `parts.payment`), it is captured
+// when the store ships its part and voided when the part is canceled
+pub const ORDER_PART_PAYMENT_FIELD: &str = "payment";
+
+// Authorizations that weren't captured by then are voided
+pub const AUTHORIZATION_EXP_DAYS: i64 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum OrderPaymentStatus {
+    // The order is saved and the card is being charged
+    Pending,
+    Authorized,
+    Captured,
+    // The card was declined at checkout, the order was never paid
+    Declined,
+    Failed,
+    ChargedBack,
+    Voided,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum OrderPartPaymentStatus {
+    Authorized,
+    // Claimed while the provider is called, so a part is never captured or voided twice
+    Capturing,
+    Voiding,
+    Captured,
+    CaptureFailed,
+    Voided,
+    Expired,
+    // The processor took the payment back (a failed payment or a chargeback)
+    ChargedBack,
+}
+
+/// A store sale to take back from the ledger. The ledger is in another database,
+/// so it's kept on the part payment, set together with the status that takes
+/// the sale back, until the ledger entry is written.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum PendingLedgerReversal {
+    // The whole sale, the payment was voided or failed
+    Reversal,
+    // The share of the sale that the chargeback event took back
+    Chargeback { event_id: String, share: f64 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderPartPayment {
+    pub status: OrderPartPaymentStatus,
+    // The store share of the order total, after its share of the discount
+    pub amount: f64,
+    pub updated_at: BsonDateTime,
+    pub reference: Option<String>,
+    pub last_error: Option<String>,
+    #[serde(default)]
+    pub ledger_reversal: Option<PendingLedgerReversal>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderPartPaymentEntry {
+    pub store: ObjectId,
+    pub payment: OrderPartPayment,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PaymentEvent {
+    // The processor id of the event, a replayed delivery has the same id
+    pub id: String,
+    pub status: OrderPaymentStatus,
+    pub amount: Option<f64>,
+    pub reason: Option<String>,
+    // false when the event arrived out of order and didn't change the status
+    pub applied: bool,
+    pub received_at: BsonDateTime,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderPaymentState {
+    pub status: OrderPaymentStatus,
+    pub updated_at: BsonDateTime,
+    #[serde(default)]
+    pub authorization_expires_at: Option<BsonDateTime>,
+    #[serde(default)]
+    pub events: Vec<PaymentEvent>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum PaymentEventOutcome {
+    Applied,
+    // Recorded, but the order was not in a status the event can follow
+    Ignored,
+    // The event was already received
+    Duplicate,
+}
+
+impl OrderPaymentStatus {
+    /// The statuses an order can move to this one from, `None` is an order the
+    /// processor didn't report on yet.
+    pub fn previous_statuses(&self) -> &'static [Option<OrderPaymentStatus>] {
+        match self {
+            // only set by the checkout
+            Self::Pending => &[],
+            Self::Authorized => &[None, Some(Self::Pending)],
+            Self::Captured | Self::Failed => &[None, Some(Self::Pending), Some(Self::Authorized)],
+            Self::Declined => &[Some(Self::Pending)],
+            Self::ChargedBack => &[Some(Self::Captured)],
+            Self::Voided => &[Some(Self::Authorized)],
+        }
+    }
+}
+
+impl OrderPaymentStatus {
+    // The previous statuses as a filter value, `None` also matches a missing status
+    fn previous_statuses_bson(&self) -> Vec<Bson> {
+        self.previous_statuses()
+            .iter()
+            .map(|status| match status {
+                Some(status) => Bson::String(status.to_string()),
+                None => Bson::Null,
+            })
+            .collect()
+    }
+}
+
+impl PaymentEvent {
+    pub fn new(
+        id: String,
+        status: OrderPaymentStatus,
+        amount: Option<f64>,
+        reason: Option<String>,
+    ) -> Self {
+        Self {
+            id,
+            status,
+            amount,
+            reason,
+            applied: true,
+            received_at: BsonDateTime::now(),
+        }
+    }
+}
+
+impl OrderPartPaymentStatus {
+    /// The statuses a part moves from when the processor takes the payment back,
+    /// a part that is being captured or voided is left to that call
+    pub fn reversible() -> [OrderPartPaymentStatus; 3] {
+        [Self::Authorized, Self::Captured, Self::CaptureFailed]
+    }
+
+    // The statuses that still hold money on the card
+    fn open() -> [OrderPartPaymentStatus; 5] {
+        [
+            Self::Authorized,
+            Self::Capturing,
+            Self::Voiding,
+            Self::Captured,
+            Self::CaptureFailed,
+        ]
+    }
+}
+
+fn payment_state_field(field: &str) -> String {
+    format!("{}.{}", PAYMENT_STATE_FIELD, field)
+}
+
+fn part_payment_field(field: &str) -> String {
+    format!("{}.{}", ORDER_PART_PAYMENT_FIELD, field)
+}
+
+fn positional_part_payment_field(field: &str) -> String {
+    format!(
+        "{}.$.{}.{}",
+        Order::fields().parts,
+        ORDER_PART_PAYMENT_FIELD,
+        field
+    )
+}
+
+fn statuses_to_bson(statuses: &[OrderPartPaymentStatus]) -> Vec<String> {
+    statuses.iter().map(|status| status.to_string()).collect()
+}
+
+/// Matches the orders that were paid, or are from before the payment state was kept.
+/// Orders of a checkout that is still charging or was declined are not shown anywhere,
+/// neither are orders whose payment failed or was charged back.
+pub fn paid_orders_filter() -> Document {
+    doc! {
+        payment_state_field("status"): {
+            "$nin": [
+                OrderPaymentStatus::Pending.to_string(),
+                OrderPaymentStatus::Declined.to_string(),
+                OrderPaymentStatus::Failed.to_string(),
+                OrderPaymentStatus::ChargedBack.to_string(),
+            ]
+        }
+    }
+}
+
+/// The `$set` fields of the order payment, `part_amounts` are by the order parts index.
+/// An authorized order gets an expiry for its uncaptured parts.
+pub fn order_payment_set(status: OrderPaymentStatus, part_amounts: &[f64]) -> Result<Document> {
+    let now = BsonDateTime::now();
+
+    let part_status = match status {
+        OrderPaymentStatus::Authorized => OrderPartPaymentStatus::Authorized,
+        _ => OrderPartPaymentStatus::Captured,
+    };
+
+    let mut set = doc! {
+        payment_state_field("status"): status.to_string(),
+        payment_state_field("updated_at"): now,
+    };
+
+    if status == OrderPaymentStatus::Authorized {
+        set.insert(
+            payment_state_field("authorization_expires_at"),
+            BsonDateTime::from_millis(
+                now.timestamp_millis() + AUTHORIZATION_EXP_DAYS * 24 * 60 * 60 * 1000,
+            ),
+        );
+    }
+
+    for (index, amount) in part_amounts.iter().enumerate() {
+        let payment = OrderPartPayment {
+            status: part_status,
+            amount: *amount,
+            updated_at: now,
+            reference: None,
+            last_error: None,
+            ledger_reversal: None,
+        };
+
+        set.insert(
+            format!(
+                "{}.{}.{}",
+                Order::fields().parts,
+                index,
+                ORDER_PART_PAYMENT_FIELD
+            ),
+            bson::to_bson(&payment).map_err(|_| Error::Desrilaztion)?,
+        );
+    }
+
+    Ok(set)
+}
+
+#[async_trait]
+pub trait PaymentStateFunctions {
+    /// Records a processor event on the order, an event id is applied only once.
+    async fn apply_payment_event(
+        &self,
+        order_id: &ObjectId,
+        event: PaymentEvent,
+    ) -> Result<PaymentEventOutcome>;
+
+    async fn get_order_payment_state(
+        &self,
+        order_id: &ObjectId,
+    ) -> Result<Option<OrderPaymentState>>;
+    /// Sets the payment of a new order, see `order_payment_set`
+    async fn init_order_payment(
+        &self,
+        order_id: &ObjectId,
+        status: OrderPaymentStatus,
+        part_amounts: &[f64],
+        session: Option<&mut ClientSession>,
+    ) -> Result<UpdateResult>;
+
+    /// Records an approved payment with the share of each part and completes the payment
+    /// step of the post payment job, creating the job if the order has none.
+    /// The checkout and the processor webhook both record the payment with it,
+    /// the first one records it and the other matches nothing.
+    async fn record_order_payment(
+        &self,
+        order_id: &ObjectId,
+        status: OrderPaymentStatus,
+        part_amounts: &[f64],
+    ) -> Result<UpdateResult>;
+
+    /// Marks a pending order as declined and cancels its post payment job
+    async fn decline_order_payment(
+        &self,
+        order_id: &ObjectId,
+        session: Option<&mut ClientSession>,
+    ) -> Result<UpdateResult>;
+
+    async fn get_order_part_payments(
+        &self,
+        order_id: &ObjectId,
+    ) -> Result<Vec<OrderPartPaymentEntry>>;
+
+    /// Moves a part payment from one of the `from` statuses to `to`,
+    /// returns the payment only if this call moved it.
+    async fn claim_order_part_payment(
+        &self,
+        order_id: &ObjectId,
+        store_id: &ObjectId,
+        from: &[OrderPartPaymentStatus],
+        to: OrderPartPaymentStatus,
+    ) -> Result<Option<OrderPartPayment>>;
+
+    async fn set_order_part_payment_status(
+        &self,
+        order_id: &ObjectId,
+        store_id: &ObjectId,
+        from: OrderPartPaymentStatus,
+        to: OrderPartPaymentStatus,
+        reference: Option<String>,
+        error: Option<String>,
+        ledger_reversal: Option<PendingLedgerReversal>,
+    ) -> Result<UpdateResult>;
+
+    /// Called once the ledger reversal of the part was written
+    async fn clear_order_part_ledger_reversal(
+        &self,
+        order_id: &ObjectId,
+        store_id: &ObjectId,
+    ) -> Result<UpdateResult>;
+
+    /// Orders with a part whose ledger reversal was not written yet
+    async fn get_pending_ledger_reversals(&self, limit: i64) -> Result<Vec<ObjectId>>;
+
+    /// An authorized order is captured once one of its parts is captured,
+    /// and voided once none of its parts hold money anymore.
+    async fn settle_order_payment(&self, order_id: &ObjectId) -> Result<()>;
+
+    /// Authorized orders that expired or have a capture to retry
+    async fn get_open_authorizations(&self, limit: i64) -> Result<Vec<ObjectId>>;
+}
+
+#[async_trait]
+impl PaymentStateFunctions for DBConection {
+    async fn apply_payment_event(
+        &self,
+        order_id: &ObjectId,
+        mut event: PaymentEvent,
+    ) -> Result<PaymentEventOutcome> {
+        let previous_statuses = event.status.previous_statuses_bson();
+
+        let filters = doc! {
+            Order::fields().id: order_id,
+            payment_state_field("events.id"): {
+                "$ne": &event.id
+            },
+            payment_state_field("status"): {
+                "$in": previous_statuses
+            },
+        };
+
+        let update = doc! {
+            "$set": {
+                payment_state_field("status"): event.status.to_string(),
+                payment_state_field("updated_at"): BsonDateTime::now(),
+            },
+            "$push": {
+                payment_state_field("events"): bson::to_bson(&event).map_err(|_| Error::Desrilaztion)?
+            }
+        };
+
+        let res = self.update_order(filters, update, None, None).await?;
+
+        if res.modified_count == 1 {
+            return Ok(PaymentEventOutcome::Applied);
+        }
+
+        // Out of order events are kept for reference only
+        event.applied = false;
+
+        let filters = doc! {
+            Order::fields().id: order_id,
+            payment_state_field("events.id"): {
+                "$ne": &event.id
+            },
+        };
+
+        let update = doc! {
+            "$push": {
+                payment_state_field("events"): bson::to_bson(&event).map_err(|_| Error::Desrilaztion)?
+            }
+        };
+
+        let res = self.update_order(filters, update, None, None).await?;
+
+        if res.modified_count == 1 {
+            Ok(PaymentEventOutcome::Ignored)
+        } else {
+            Ok(PaymentEventOutcome::Duplicate)
+        }
+    }
+
+    async fn get_order_payment_state(
+        &self,
+        order_id: &ObjectId,
+    ) -> Result<Option<OrderPaymentState>> {
+        let pipeline = [
+            aggregations::match_query(&doc! {
+                Order::fields().id: order_id,
+                payment_state_field("status"): {
+                    "$exists": true
+                },
+            }),
+            doc! {
+                "$project": {
+                    PAYMENT_STATE_FIELD: 1
+                }
+            },
+        ];
+
+        let order = match self.aggregate_orders(pipeline, None, None).await?.pop() {
+            Some(order) => order,
+            None => return Ok(None),
+        };
+
+        let state = order
+            .get_document(PAYMENT_STATE_FIELD)
+            .map_err(|_| Error::Desrilaztion)?;
+
+        bson::from_document(state.clone())
+            .map(Some)
+            .map_err(|_| Error::Desrilaztion)
+    }
+
+    async fn init_order_payment(
+        &self,
+        order_id: &ObjectId,
+        status: OrderPaymentStatus,
+        part_amounts: &[f64],
+        session: Option<&mut ClientSession>,
+    ) -> Result<UpdateResult> {
+        let set = order_payment_set(status, part_amounts)?;
+
+        self.update_order_by_id(order_id, doc! { "$set": set }, None, session)
+            .await
+    }
+
+    async fn record_order_payment(
+        &self,
+        order_id: &ObjectId,
+        status: OrderPaymentStatus,
+        part_amounts: &[f64],
+    ) -> Result<UpdateResult> {
+        // the webhook sets the status before it records the payment,
+        // a payment that failed in the meantime is not recorded
+        let mut statuses = status.previous_statuses_bson();
+        statuses.push(Bson::String(status.to_string()));
+
+        let mut filters = post_payment_unpaid_filter();
+
+        filters.insert(Order::fields().id, order_id);
+        filters.insert(payment_state_field("status"), doc! { "$in": &statuses });
+
+        let mut set = order_payment_set(status, part_amounts)?;
+
+        set.extend(post_payment_paid_set());
+
+        let res = self
+            .update_order(filters, doc! { "$set": set }, None, None)
+            .await?;
+
+        if res.modified_count == 1 {
+            return Ok(res);
+        }
+
+        // orders that were not created by the checkout have no job yet
+        let filters = doc! {
+            Order::fields().id: order_id,
+            payment_state_field("status"): { "$in": statuses },
+            POST_PAYMENT_FIELD: { "$exists": false },
+        };
+
+        let mut set = order_payment_set(status, part_amounts)?;
+
+        set.insert(
+            POST_PAYMENT_FIELD,
+            bson::to_bson(&PostPaymentJob::new()).map_err(|_| Error::Desrilaztion)?,
+        );
+
+        self.update_order(filters, doc! { "$set": set }, None, None)
+            .await
+    }
+
+    async fn decline_order_payment(
+        &self,
+        order_id: &ObjectId,
+        session: Option<&mut ClientSession>,
+    ) -> Result<UpdateResult> {
+        let filters = doc! {
+            Order::fields().id: order_id,
+            payment_state_field("status"): OrderPaymentStatus::Pending.to_string(),
+        };
+
+        let update = doc! {
+            "$set": {
+                payment_state_field("status"): OrderPaymentStatus::Declined.to_string(),
+                payment_state_field("updated_at"): BsonDateTime::now(),
+                format!("{}.status", POST_PAYMENT_FIELD): PostPaymentJobStatus::Canceled.to_string(),
+            }
+        };
+
+        self.update_order(filters, update, None, session).await
+    }
+
+    async fn get_order_part_payments(
+        &self,
+        order_id: &ObjectId,
+    ) -> Result<Vec<OrderPartPaymentEntry>> {
+        let pipeline = [
+            aggregations::match_query(&doc! {
+                Order::fields().id: order_id,
+            }),
+            aggregations::unwind(Order::fields().parts, false),
+            aggregations::match_query(&doc! {
+                format!("{}.{}", Order::fields().parts, part_payment_field("status")): {
+                    "$exists": true
+                },
+            }),
+            doc! {
+                "$project": {
+                    "_id": 0,
+                    "store": format!("${}", Order::fields().parts(true).store),
+                    "payment": format!("${}.{}", Order::fields().parts, ORDER_PART_PAYMENT_FIELD),
+                }
+            },
+        ];
+
+        let entries = self.aggregate_orders(pipeline, None, None).await?;
+
+        entries
+            .into_iter()
+            .map(|entry| bson::from_document(entry).map_err(|_| Error::Desrilaztion))
+            .collect()
+    }
+
+    async fn claim_order_part_payment(
+        &self,
+        order_id: &ObjectId,
+        store_id: &ObjectId,
+        from: &[OrderPartPaymentStatus],
+        to: OrderPartPaymentStatus,
+    ) -> Result<Option<OrderPartPayment>> {
+        let filters = doc! {
+            Order::fields().id: order_id,
+            Order::fields().parts: {
+                "$elemMatch": {
+                    Order::fields().parts(false).store: store_id,
+                    part_payment_field("status"): {
+                        "$in": statuses_to_bson(from)
+                    },
+                }
+            }
+        };
+
+        let update = doc! {
+            "$set": {
+                positional_part_payment_field("status"): to.to_string(),
+                positional_part_payment_field("updated_at"): BsonDateTime::now(),
+            }
+        };
+
+        let res = self.update_order(filters, update, None, None).await?;
+
+        if res.modified_count == 0 {
+            return Ok(None);
+        }
+
+        Ok(self
+            .get_order_part_payments(order_id)
+            .await?
+            .into_iter()
+            .find(|entry| &entry.store == store_id)
+            .map(|entry| entry.payment))
+    }
+
+    async fn set_order_part_payment_status(
+        &self,
+        order_id: &ObjectId,
+        store_id: &ObjectId,
+        from: OrderPartPaymentStatus,
+        to: OrderPartPaymentStatus,
+        reference: Option<String>,
+        error: Option<String>,
+        ledger_reversal: Option<PendingLedgerReversal>,
+    ) -> Result<UpdateResult> {
+        let filters = doc! {
+            Order::fields().id: order_id,
+            Order::fields().parts: {
+                "$elemMatch": {
+                    Order::fields().parts(false).store: store_id,
+                    part_payment_field("status"): from.to_string(),
+                }
+            }
+        };
+
+        let mut set = doc! {
+            positional_part_payment_field("status"): to.to_string(),
+            positional_part_payment_field("updated_at"): BsonDateTime::now(),
+            positional_part_payment_field("last_error"): error,
+        };
+
+        if let Some(reference) = reference {
+            set.insert(positional_part_payment_field("reference"), reference);
+        }
+
+        if let Some(ledger_reversal) = ledger_reversal {
+            set.insert(
+                positional_part_payment_field("ledger_reversal"),
+                bson::to_bson(&ledger_reversal).map_err(|_| Error::Desrilaztion)?,
+            );
+        }
+
+        self.update_order(filters, doc! { "$set": set }, None, None)
+            .await
+    }
+
+    async fn clear_order_part_ledger_reversal(
+        &self,
+        order_id: &ObjectId,
+        store_id: &ObjectId,
+    ) -> Result<UpdateResult> {
+        let filters = doc! {
+            Order::fields().id: order_id,
+            Order::fields().parts: {
+                "$elemMatch": {
+                    Order::fields().parts(false).store: store_id,
+                }
+            }
+        };
+
+        let update = doc! {
+            "$set": {
+                positional_part_payment_field("ledger_reversal"): Bson::Null,
+            }
+        };
+
+        self.update_order(filters, update, None, None).await
+    }
+
+    async fn get_pending_ledger_reversals(&self, limit: i64) -> Result<Vec<ObjectId>> {
+        let pipeline = [
+            aggregations::match_query(&doc! {
+                format!("{}.{}", Order::fields().parts, part_payment_field("ledger_reversal")): {
+                    "$type": "object"
+                },
+            }),
+            aggregations::limit(limit),
+            doc! { "$project": { Order::fields().id: 1 } },
+        ];
+
+        let orders = self.aggregate_orders(pipeline, None, None).await?;
+
+        Ok(orders
+            .iter()
+            .filter_map(|order| order.get_object_id("_id").ok())
+            .collect())
+    }
+
+    async fn settle_order_payment(&self, order_id: &ObjectId) -> Result<()> {
+        let update = |status: OrderPaymentStatus| {
+            doc! {
+                "$set": {
+                    payment_state_field("status"): status.to_string(),
+                    payment_state_field("updated_at"): BsonDateTime::now(),
+                }
+            }
+        };
+
+        let captured_filters = doc! {
+            Order::fields().id: order_id,
+            payment_state_field("status"): OrderPaymentStatus::Authorized.to_string(),
+            format!("{}.{}", Order::fields().parts, part_payment_field("status")):
+                OrderPartPaymentStatus::Captured.to_string(),
+        };
+
+        self.update_order(
+            captured_filters,
+            update(OrderPaymentStatus::Captured),
+            None,
+            None,
+        )
+        .await?;
+
+        let voided_filters = doc! {
+            Order::fields().id: order_id,
+            payment_state_field("status"): OrderPaymentStatus::Authorized.to_string(),
+            Order::fields().parts: {
+                "$not": {
+                    "$elemMatch": {
+                        part_payment_field("status"): {
+                            "$in": statuses_to_bson(&OrderPartPaymentStatus::open())
+                        }
+                    }
+                }
+            }
+        };
+
+        self.update_order(
+            voided_filters,
+            update(OrderPaymentStatus::Voided),
+            None,
+            None,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_open_authorizations(&self, limit: i64) -> Result<Vec<ObjectId>> {
+        let pipeline = [
+            aggregations::match_query(&doc! {
+                payment_state_field("status"): OrderPaymentStatus::Authorized.to_string(),
+                "$or": [
+                    {
+                        payment_state_field("authorization_expires_at"): {
+                            "$lte": BsonDateTime::now()
+                        }
+                    },
+                    {
+                        format!("{}.{}", Order::fields().parts, part_payment_field("status")):
+                            OrderPartPaymentStatus::CaptureFailed.to_string()
+                    },
+                ]
+            }),
+            aggregations::limit(limit),
+            doc! { "$project": { Order::fields().id: 1 } },
+        ];
+
+        let orders = self.aggregate_orders(pipeline, None, None).await?;
+
+        Ok(orders
+            .iter()
+            .filter_map(|order| order.get_object_id("_id").ok())
+            .collect())
+    }
+}
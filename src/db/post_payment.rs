@@ -0,0 +1,382 @@
+use crate::prelude::*;
+use axum::async_trait;
+use bson::{doc, oid::ObjectId, DateTime as BsonDateTime, Document};
+use mongodb::{results::UpdateResult, ClientSession};
+use serde::{Deserialize, Serialize};
+use shoppa_core::db::{aggregations, models::Order, DBConection};
+use strum_macros::Display;
+
+// The post payment job is stored on the order document itself (under `post_payment`),
+// so it is created in the same transaction as the order and can't get lost.
+pub const POST_PAYMENT_FIELD: &str = "post_payment";
+
+// How long the checkout has to charge the card and record the payment
+// before the worker starts looking at the job
+const AWAITING_PAYMENT_SECS: i64 = 10 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum PostPaymentJobStatus {
+    Pending,
+    Done,
+    Failed,
+    // The order was never paid, so there is nothing to do
+    Canceled,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum PostPaymentStepStatus {
+    Pending,
+    Done,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum PostPaymentStepKind {
+    Payment,
+    Cart,
+    Storage,
+    Invoices,
+    Ledger,
+    Emails,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PostPaymentStep {
+    pub status: PostPaymentStepStatus,
+    pub completed_at: Option<BsonDateTime>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PostPaymentSteps {
+    pub payment: PostPaymentStep,
+    pub cart: PostPaymentStep,
+    pub storage: PostPaymentStep,
+    pub invoices: PostPaymentStep,
+    // Jobs that were created before the ledger existed still have to write it
+    #[serde(default = "PostPaymentStep::pending")]
+    pub ledger: PostPaymentStep,
+    #[serde(default = "PostPaymentStep::pending")]
+    pub emails: PostPaymentStep,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PostPaymentJob {
+    pub status: PostPaymentJobStatus,
+    pub steps: PostPaymentSteps,
+    pub attempts: u32,
+    pub next_attempt_at: BsonDateTime,
+    pub locked_until: Option<BsonDateTime>,
+    pub last_error: Option<String>,
+    pub created_at: BsonDateTime,
+}
+
+#[async_trait]
+pub trait PostPaymentFunctions {
+    /// Does nothing if the order already has a job, so the post payment steps run only once
+    async fn insert_post_payment_job(
+        &self,
+        order_id: &ObjectId,
+        job: &PostPaymentJob,
+        session: Option<&mut ClientSession>,
+    ) -> Result<UpdateResult>;
+    async fn get_due_post_payment_jobs(&self, limit: i64) -> Result<Vec<ObjectId>>;
+    async fn get_post_payment_job(&self, order_id: &ObjectId) -> Result<Option<PostPaymentJob>>;
+    /// Returns true only if the lock was acquired by this call
+    async fn lock_post_payment_job(&self, order_id: &ObjectId, lease_secs: i64) -> Result<bool>;
+    async fn complete_post_payment_step(
+        &self,
+        order_id: &ObjectId,
+        step: PostPaymentStepKind,
+        session: Option<&mut ClientSession>,
+    ) -> Result<UpdateResult>;
+    async fn fail_post_payment_attempt(
+        &self,
+        order_id: &ObjectId,
+        step: PostPaymentStepKind,
+        error: &str,
+        attempts: u32,
+        next_attempt_in_secs: Option<i64>,
+    ) -> Result<UpdateResult>;
+    async fn complete_post_payment_job(&self, order_id: &ObjectId) -> Result<UpdateResult>;
+}
+
+impl PostPaymentStep {
+    fn pending() -> Self {
+        Self {
+            status: PostPaymentStepStatus::Pending,
+            completed_at: None,
+            last_error: None,
+        }
+    }
+
+    fn done() -> Self {
+        Self {
+            status: PostPaymentStepStatus::Done,
+            completed_at: Some(BsonDateTime::now()),
+            last_error: None,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.status == PostPaymentStepStatus::Done
+    }
+}
+
+impl PostPaymentJob {
+    /// A job of an order that is already paid, the payment step starts as done.
+    /// The stock is taken when the checkout starts (see `reserve_checkout_session_stock`),
+    /// so the storage step starts as done as well.
+    pub fn new() -> Self {
+        Self::with_payment(PostPaymentStep::done(), BsonDateTime::now())
+    }
+
+    /// The job of an order that is saved before its card is charged.
+    /// The payment step is done when the charge is recorded (see `update_order_after_payment`),
+    /// until then the worker doesn't run the other steps.
+    pub fn awaiting_payment() -> Self {
+        Self::with_payment(
+            PostPaymentStep::pending(),
+            after_secs(AWAITING_PAYMENT_SECS),
+        )
+    }
+
+    fn with_payment(payment: PostPaymentStep, next_attempt_at: BsonDateTime) -> Self {
+        let now = BsonDateTime::now();
+        Self {
+            status: PostPaymentJobStatus::Pending,
+            steps: PostPaymentSteps {
+                payment,
+                cart: PostPaymentStep::pending(),
+                storage: PostPaymentStep::done(),
+                invoices: PostPaymentStep::pending(),
+                ledger: PostPaymentStep::pending(),
+                emails: PostPaymentStep::pending(),
+            },
+            attempts: 0,
+            next_attempt_at,
+            locked_until: None,
+            last_error: None,
+            created_at: now,
+        }
+    }
+
+    pub fn step(&self, step: PostPaymentStepKind) -> &PostPaymentStep {
+        match step {
+            PostPaymentStepKind::Payment => &self.steps.payment,
+            PostPaymentStepKind::Cart => &self.steps.cart,
+            PostPaymentStepKind::Storage => &self.steps.storage,
+            PostPaymentStepKind::Invoices => &self.steps.invoices,
+            PostPaymentStepKind::Ledger => &self.steps.ledger,
+            PostPaymentStepKind::Emails => &self.steps.emails,
+        }
+    }
+}
+
+impl Default for PostPaymentJob {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn job_field(field: &str) -> String {
+    format!("{}.{}", POST_PAYMENT_FIELD, field)
+}
+
+fn step_field(step: PostPaymentStepKind, field: &str) -> String {
+    format!("{}.steps.{}.{}", POST_PAYMENT_FIELD, step, field)
+}
+
+fn after_secs(secs: i64) -> BsonDateTime {
+    BsonDateTime::from_millis(BsonDateTime::now().timestamp_millis() + secs * 1000)
+}
+
+/// The `$set` fields that complete the payment step of a job and make it due right away
+pub fn post_payment_paid_set() -> Document {
+    doc! {
+        step_field(PostPaymentStepKind::Payment, "status"): PostPaymentStepStatus::Done.to_string(),
+        step_field(PostPaymentStepKind::Payment, "completed_at"): BsonDateTime::now(),
+        step_field(PostPaymentStepKind::Payment, "last_error"): None::<String>,
+        job_field("status"): PostPaymentJobStatus::Pending.to_string(),
+        job_field("attempts"): 0,
+        job_field("next_attempt_at"): BsonDateTime::now(),
+        job_field("last_error"): None::<String>,
+    }
+}
+
+/// Matches a job that is still waiting for its payment to be recorded
+pub fn post_payment_unpaid_filter() -> Document {
+    doc! {
+        step_field(PostPaymentStepKind::Payment, "status"): PostPaymentStepStatus::Pending.to_string(),
+    }
+}
+
+fn unlocked_filter() -> Document {
+    doc! {
+        "$or": [
+            { job_field("locked_until"): None::<BsonDateTime> },
+            { job_field("locked_until"): { "$lte": BsonDateTime::now() } },
+        ]
+    }
+}
+
+#[async_trait]
+impl PostPaymentFunctions for DBConection {
+    async fn insert_post_payment_job(
+        &self,
+        order_id: &ObjectId,
+        job: &PostPaymentJob,
+        session: Option<&mut ClientSession>,
+    ) -> Result<UpdateResult> {
+        let job = bson::to_bson(job).map_err(|_| Error::Desrilaztion)?;
+
+        let filters = doc! {
+            Order::fields().id: order_id,
+            POST_PAYMENT_FIELD: {
+                "$exists": false
+            }
+        };
+
+        let update = doc! {
+            "$set": {
+                POST_PAYMENT_FIELD: job
+            }
+        };
+
+        self.update_order(filters, update, None, session).await
+    }
+
+    async fn get_due_post_payment_jobs(&self, limit: i64) -> Result<Vec<ObjectId>> {
+        let mut filters = unlocked_filter();
+
+        filters.insert(
+            job_field("status"),
+            PostPaymentJobStatus::Pending.to_string(),
+        );
+        filters.insert(
+            job_field("next_attempt_at"),
+            doc! { "$lte": BsonDateTime::now() },
+        );
+
+        let pipeline = [
+            aggregations::match_query(&filters),
+            aggregations::sort(doc! {
+                job_field("next_attempt_at"): 1
+            }),
+            aggregations::limit(limit),
+            doc! { "$project": { Order::fields().id: 1 } },
+        ];
+
+        let orders = self.aggregate_orders(pipeline, None, None).await?;
+
+        Ok(orders
+            .iter()
+            .filter_map(|order| order.get_object_id("_id").ok())
+            .collect())
+    }
+
+    async fn get_post_payment_job(&self, order_id: &ObjectId) -> Result<Option<PostPaymentJob>> {
+        let pipeline = [
+            aggregations::match_query(&doc! {
+                Order::fields().id: order_id,
+                POST_PAYMENT_FIELD: { "$exists": true },
+            }),
+            aggregations::replace_root(POST_PAYMENT_FIELD),
+        ];
+
+        let job = self.aggregate_orders(pipeline, None, None).await?.pop();
+
+        match job {
+            Some(job) => bson::from_document::<PostPaymentJob>(job)
+                .map(Some)
+                .map_err(|_| Error::Desrilaztion),
+            None => Ok(None),
+        }
+    }
+
+    async fn lock_post_payment_job(&self, order_id: &ObjectId, lease_secs: i64) -> Result<bool> {
+        let mut filters = unlocked_filter();
+
+        filters.insert(Order::fields().id, order_id);
+        filters.insert(
+            job_field("status"),
+            PostPaymentJobStatus::Pending.to_string(),
+        );
+
+        let update = doc! {
+            "$set": {
+                job_field("locked_until"): after_secs(lease_secs)
+            }
+        };
+
+        let res = self.update_order(filters, update, None, None).await?;
+
+        Ok(res.modified_count == 1)
+    }
+
+    async fn complete_post_payment_step(
+        &self,
+        order_id: &ObjectId,
+        step: PostPaymentStepKind,
+        session: Option<&mut ClientSession>,
+    ) -> Result<UpdateResult> {
+        let update = doc! {
+            "$set": {
+                step_field(step, "status"): PostPaymentStepStatus::Done.to_string(),
+                step_field(step, "completed_at"): BsonDateTime::now(),
+                step_field(step, "last_error"): None::<String>,
+            }
+        };
+
+        self.update_order_by_id(order_id, update, None, session)
+            .await
+    }
+
+    async fn fail_post_payment_attempt(
+        &self,
+        order_id: &ObjectId,
+        step: PostPaymentStepKind,
+        error: &str,
+        attempts: u32,
+        next_attempt_in_secs: Option<i64>,
+    ) -> Result<UpdateResult> {
+        let mut set = doc! {
+            job_field("attempts"): attempts,
+            job_field("last_error"): error,
+            job_field("locked_until"): None::<BsonDateTime>,
+            step_field(step, "last_error"): error,
+        };
+
+        // no next attempt means we gave up on this job
+        match next_attempt_in_secs {
+            Some(secs) => {
+                set.insert(job_field("next_attempt_at"), after_secs(secs));
+            }
+            None => {
+                set.insert(
+                    job_field("status"),
+                    PostPaymentJobStatus::Failed.to_string(),
+                );
+            }
+        }
+
+        self.update_order_by_id(order_id, doc! { "$set": set }, None, None)
+            .await
+    }
+
+    async fn complete_post_payment_job(&self, order_id: &ObjectId) -> Result<UpdateResult> {
+        let update = doc! {
+            "$set": {
+                job_field("status"): PostPaymentJobStatus::Done.to_string(),
+                job_field("locked_until"): None::<BsonDateTime>,
+                job_field("last_error"): None::<String>,
+            }
+        };
+
+        self.update_order_by_id(order_id, update, None, None).await
+    }
+}
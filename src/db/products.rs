@@ -1,11 +1,18 @@
 use std::str::FromStr;
 
-use crate::prelude::*;
+use crate::{
+    db::{
+        autocomplete_products_stages, filter_products_stages, product_name_search_stages,
+        search_products_stages, SearchBackend, SearchFilter, PRODUCT_RATING_FIELD, SEARCH_BACKEND,
+    },
+    prelude::*,
+};
 use axum::async_trait;
 use bson::{doc, oid::ObjectId, Bson, Document};
 use mongodb::{
     options::{AggregateOptions, FindOneAndUpdateOptions, FindOneOptions, UpdateOptions},
     results::UpdateResult,
+    ClientSession,
 };
 use serde::{Deserialize, Deserializer};
 use shoppa_core::{
@@ -19,6 +26,7 @@ use shoppa_core::{
         DBConection, Pagination, Sorter,
     },
     parser::FieldPatch,
+    random::random_string,
 };
 
 #[derive(Debug, Clone, PartialEq)]
@@ -26,6 +34,7 @@ pub enum ProductSortBy {
     Popularity,
     Date,
     Relevance,
+    Rating,
     Random(Vec<u8>),
 }
 
@@ -43,6 +52,7 @@ impl FromStr for ProductSortBy {
             "popularity" | "pop" | "p" | "Popularity" => Ok(Self::Popularity),
             "date" | "da" | "d" | "Date" => Ok(Self::Date),
             "relevance" | "rel" | "r" | "Relevance" => Ok(Self::Relevance),
+            "rating" | "ra" | "Rating" => Ok(Self::Rating),
             _ => serde_json::from_str::<Vec<u8>>(s)
                 .map_err(|_| Error::Desrilaztion)
                 .map(Self::Random),
@@ -61,6 +71,69 @@ impl<'de> Deserialize<'de> for ProductSortBy {
     }
 }
 
+// The feed keys are a shuffle of the products creation time, modulo a prime
+const PRODUCTS_FEED_KEY_MODULUS: i64 = 2_147_483_647;
+
+/// Where an infinite products feed is at, the client gets it back as an opaque cursor.
+/// The feed goes over the products ordered by a key that is shuffled once for each feed
+/// and never changes, so a product is never returned twice.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProductsFeed {
+    multiplier: i64,
+    increment: i64,
+    // Each page is sorted with it, so the shuffle is mixed with the random sort
+    randomizar: Vec<u8>,
+    // The key and id of the last product that was returned
+    last_key: Option<i64>,
+    last_id: Option<ObjectId>,
+}
+
+impl Default for ProductsFeed {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProductsFeed {
+    pub fn new() -> Self {
+        let random_number = |modulus: i64| {
+            random_string(12)
+                .bytes()
+                .fold(0, |acc, byte| (acc * 31 + byte as i64) % modulus)
+        };
+
+        Self {
+            multiplier: random_number(PRODUCTS_FEED_KEY_MODULUS - 1) + 1,
+            increment: random_number(PRODUCTS_FEED_KEY_MODULUS),
+            // two of the random sort fields, the first one from the most telling ones
+            randomizar: vec![random_number(4) as u8, random_number(9) as u8],
+            last_key: None,
+            last_id: None,
+        }
+    }
+
+    /// Moves the feed past the products of a page, the page is not in the feed order.
+    /// Takes the feed key off the products, it is not part of the response
+    fn advance(&mut self, products: &mut [Document]) -> Result<()> {
+        for product in products {
+            let key = product
+                .remove("feed_key")
+                .and_then(|key| key.as_i64())
+                .ok_or(Error::Desrilaztion)?;
+            let id = product
+                .get_object_id("_id")
+                .map_err(|_| Error::Desrilaztion)?;
+
+            if (self.last_key, self.last_id) < (Some(key), Some(id)) {
+                self.last_key = Some(key);
+                self.last_id = Some(id);
+            }
+        }
+
+        Ok(())
+    }
+}
+
 #[async_trait]
 pub trait ProductFunctions {
     async fn add_view_to_product(
@@ -87,8 +160,22 @@ pub trait ProductFunctions {
         free_text: Option<String>,
         store_id: Option<ObjectId>,
         category_id: Option<ObjectId>,
+        min_rating: Option<f64>,
         options: Option<AggregateOptions>,
     ) -> Result<(Vec<Document>, u64)>;
+    /// The next page of the feed, without the products in `exclude`, and moves the feed past it.
+    /// The feed key can't be indexed, it is different for each feed, so every page reads
+    /// all the active products that match the filters. The sort is followed by the limit,
+    /// so only `amount` of them are kept in memory
+    async fn get_products_feed(
+        &self,
+        feed: &mut ProductsFeed,
+        amount: i64,
+        store_id: Option<ObjectId>,
+        category_id: Option<ObjectId>,
+        exclude: Vec<ObjectId>,
+        options: Option<AggregateOptions>,
+    ) -> Result<Vec<Document>>;
     async fn random_autocomplete_products_search(
         &self,
         amount: Option<u8>,
@@ -105,6 +192,23 @@ pub trait ProductFunctions {
         &self,
         order: &Order,
         options: Option<UpdateOptions>,
+        session: Option<&mut ClientSession>,
+    ) -> Result<UpdateResult>;
+    /// Takes the quantity out of the item storage, only if there is enough of it.
+    /// Nothing is modified if the item can't be reserved.
+    async fn reserve_product_item_stock(
+        &self,
+        product_id: &ObjectId,
+        item_id: &ObjectId,
+        quantity: i64,
+        session: Option<&mut ClientSession>,
+    ) -> Result<UpdateResult>;
+    async fn release_product_item_stock(
+        &self,
+        product_id: &ObjectId,
+        item_id: &ObjectId,
+        quantity: i64,
+        session: Option<&mut ClientSession>,
     ) -> Result<UpdateResult>;
 }
 
@@ -277,45 +381,13 @@ impl ProductFunctions for DBConection {
         category_id: Option<ObjectId>,
         options: Option<AggregateOptions>,
     ) -> Result<Vec<Document>> {
-        let mut filters = vec![
-            doc! {
-                "text": {
-                    "path": Product::fields().status,
-                    "query": ProductStatus::Active
-                }
-
-            },
-            doc! {
-                "text": {
-                    "path": Product::fields().items(true).status,
-                    "query": ProductItemStatus::Active
-                }
-            },
-        ];
-
-        if let Some(store_id) = store_id {
-            filters.push(doc! {
-                "equals": {
-                    "value": store_id,
-                    "path": Product::fields().store(true).id
-                }
-            });
-        };
-
-        if let Some(category_id) = category_id {
-            filters.push(doc! {
-                "equals": {
-                    "value": category_id,
-                    "path": Product::fields().categories(true).ids
-                }
-            });
-        }
+        let filters = active_products_filters(store_id, category_id);
 
         // TODO in the future we need to use the embeddeddocuments search to return the must
         // relevant product item and not the first one
-        let pipeline = [
-            aggregations::autocomplete_products_search(&free_text, filters),
-            aggregations::add_score_meta(),
+        let mut pipeline = autocomplete_products_stages(&free_text, &filters);
+
+        pipeline.extend([
             aggregations::sort_by_score(),
             aggregations::limit(10),
             aggregations::project(
@@ -329,7 +401,7 @@ impl ProductFunctions for DBConection {
                     "views": format!("${}", Product::fields().analytics(true).views),
                 }),
             ),
-        ];
+        ]);
 
         self.aggregate_products(pipeline, options, None).await
     }
@@ -339,34 +411,21 @@ impl ProductFunctions for DBConection {
         product_id: &ObjectId,
         options: Option<AggregateOptions>,
     ) -> Result<Option<Document>> {
-        let filters = vec![
-            doc! {
-                "equals": {
-                    "path": Product::fields().id,
-                    "value": product_id,
-                }
-            },
-            doc! {
-                "text": {
-                    "path": Product::fields().status,
-                    "query": ProductStatus::Active
-                }
+        let mut filters = active_products_filters(None, None);
 
-            },
-            doc! {
-                "text": {
-                    "path": Product::fields().items(true).status,
-                    "query": ProductItemStatus::Active
-                }
-            },
-        ];
+        filters.push(SearchFilter::equals(Product::fields().id, *product_id));
 
-        let pipeline = [
-            aggregations::search(doc! {
+        let search_stage = match *SEARCH_BACKEND {
+            SearchBackend::Atlas => aggregations::search(doc! {
                 "compound": {
-                    "filter": filters
+                    "filter": SearchFilter::to_atlas(&filters)
                 }
             }),
+            SearchBackend::Text => aggregations::match_query(&SearchFilter::to_match(&filters)),
+        };
+
+        let pipeline = [
+            search_stage,
             aggregations::lookup_product_variants(Some(vec![aggregations::project(
                 ProjectIdOptions::Keep,
                 [
@@ -429,6 +488,7 @@ impl ProductFunctions for DBConection {
                     Product::fields().categories,
                     Product::fields().variants,
                     Product::fields().analytics,
+                    PRODUCT_RATING_FIELD,
                     Product::fields().feature_bullet_points,
                     Product::fields().warranty,
                     // Product items fields to return
@@ -468,6 +528,7 @@ impl ProductFunctions for DBConection {
         free_text: Option<String>,
         store_id: Option<ObjectId>,
         category_id: Option<ObjectId>,
+        min_rating: Option<f64>,
         options: Option<AggregateOptions>,
     ) -> Result<(Vec<Document>, u64)> {
         let pagination = pagination.unwrap_or_default();
@@ -480,6 +541,10 @@ impl ProductFunctions for DBConection {
             ProductSortBy::Popularity => aggregations::sort(doc! {
                 Product::fields().analytics(true).views: &sorting.direction
             }),
+            ProductSortBy::Rating => aggregations::sort(doc! {
+                format!("{}.average", PRODUCT_RATING_FIELD): &sorting.direction,
+                format!("{}.count", PRODUCT_RATING_FIELD): &sorting.direction,
+            }),
             ProductSortBy::Relevance => {
                 if free_text.is_some() {
                     aggregations::sort(doc! {
@@ -497,44 +562,20 @@ impl ProductFunctions for DBConection {
         };
 
         let filters = {
-            let mut f = vec![
-                doc! {
-                    "text": {
-                        "path": Product::fields().status,
-                        "query": ProductStatus::Active
-                    }
-                },
-                doc! {
-                    "text": {
-                        "path": Product::fields().items(true).status,
-                        "query": ProductItemStatus::Active
-                    }
-                },
-            ];
+            let mut f = active_products_filters(store_id, category_id);
 
-            if let Some(store_id) = store_id {
-                f.push(doc! {
-                    "equals": {
-                        "value": store_id,
-                        "path": Product::fields().store(true).id
-                    }
-                });
-            };
-
-            if let Some(category_id) = category_id {
-                f.push(doc! {
-                    "equals": {
-                        "value": category_id,
-                        "path": Product::fields().categories(true).ids
-                    }
-                });
+            if let Some(min_rating) = min_rating {
+                f.push(SearchFilter::gte(
+                    format!("{}.average", PRODUCT_RATING_FIELD),
+                    min_rating,
+                ));
             }
             f
         };
 
-        let pipeline = [
-            aggregations::search_products(&free_text, &filters, Some(1)),
-            aggregations::add_score_meta(),
+        let mut pipeline = search_products_stages(&free_text, &filters);
+
+        pipeline.extend([
             sort_stage,
             aggregations::skip(pagination.offset),
             aggregations::limit(pagination.amount),
@@ -565,6 +606,7 @@ impl ProductFunctions for DBConection {
                     Product::fields().name,
                     Product::fields().keywords,
                     Product::fields().analytics,
+                    PRODUCT_RATING_FIELD,
                     Product::fields().categories,
                     Product::fields().created_at,
                     Product::fields().store,
@@ -588,7 +630,7 @@ impl ProductFunctions for DBConection {
                 ],
                 None,
             ),
-        ];
+        ]);
 
         let products = self
             .aggregate_products(pipeline, options.clone(), None)
@@ -600,20 +642,155 @@ impl ProductFunctions for DBConection {
             return Ok((products, pagination.calculate_total(count)));
         }
 
+        let mut count_pipeline = search_products_stages(&free_text, &filters);
+
+        count_pipeline.push(aggregations::count("count"));
+
         let count = self
-            .count_products_with_aggregation(
-                [
-                    aggregations::search_products(&free_text, &filters, Some(1)),
-                    aggregations::count("count"),
-                ],
-                options,
-                None,
-            )
+            .count_products_with_aggregation(count_pipeline, options, None)
             .await?;
 
         Ok((products, count))
     }
 
+    async fn get_products_feed(
+        &self,
+        feed: &mut ProductsFeed,
+        amount: i64,
+        store_id: Option<ObjectId>,
+        category_id: Option<ObjectId>,
+        exclude: Vec<ObjectId>,
+        options: Option<AggregateOptions>,
+    ) -> Result<Vec<Document>> {
+        let filters = active_products_filters(store_id, category_id);
+
+        let mut after = doc! {
+            Product::fields().id: {
+                "$nin": exclude
+            }
+        };
+
+        if let (Some(last_key), Some(last_id)) = (feed.last_key, feed.last_id) {
+            after.insert(
+                "$or",
+                vec![
+                    doc! {
+                        "feed_key": {
+                            "$gt": last_key
+                        }
+                    },
+                    doc! {
+                        "feed_key": last_key,
+                        Product::fields().id: {
+                            "$gt": last_id
+                        }
+                    },
+                ],
+            );
+        }
+
+        let mut pipeline = filter_products_stages(&filters);
+
+        pipeline.extend([
+            // the creation time of the product is the part of the id that doesn't change
+            aggregations::add_fields(doc! {
+                "feed_key": {
+                    "$mod": [
+                        {
+                            "$add": [
+                                {
+                                    "$multiply": [
+                                        {
+                                            "$toLong": {
+                                                "$divide": [
+                                                    {
+                                                        "$toLong": {
+                                                            "$toDate": format!("${}", Product::fields().id)
+                                                        }
+                                                    },
+                                                    1000
+                                                ]
+                                            }
+                                        },
+                                        feed.multiplier
+                                    ]
+                                },
+                                feed.increment
+                            ]
+                        },
+                        PRODUCTS_FEED_KEY_MODULUS
+                    ]
+                }
+            }),
+            aggregations::match_query(&after),
+            // with the limit right after it the sort keeps only the top of the page
+            aggregations::sort(doc! {
+                "feed_key": 1,
+                Product::fields().id: 1,
+            }),
+            aggregations::limit(amount),
+            // the page is taken in the feed order and shown in a random order
+            aggregations::sort(generate_products_random_sort(feed.randomizar.clone())),
+            aggregations::add_fields(doc! {
+                "item": {
+                    "$arrayElemAt": [
+                        {
+                            "$filter": {
+                                "input": format!("${}", Product::fields().items),
+                                "as": "item",
+                                "cond": {
+                                    "$eq": [
+                                        format!("$$item.{}", Product::fields().items(false).status),
+                                        ProductItemStatus::Active
+                                    ]
+                                }
+                            }
+                        },
+                        0
+                    ]
+                }
+            }),
+            aggregations::project(
+                ProjectIdOptions::Keep,
+                vec![
+                    "feed_key",
+                    Product::fields().brand,
+                    Product::fields().name,
+                    Product::fields().keywords,
+                    Product::fields().analytics,
+                    PRODUCT_RATING_FIELD,
+                    Product::fields().categories,
+                    Product::fields().created_at,
+                    Product::fields().store,
+                    // Product items fields to return
+                    format!("item.{}", Product::fields().items(false).id).as_str(),
+                    format!("item.{}", Product::fields().items(false).price).as_str(),
+                    format!("item.{}", Product::fields().items(false).in_storage).as_str(),
+                    format!("item.{}", Product::fields().items(false).variants).as_str(),
+                    format!("item.{}", Product::fields().items(false).name).as_str(),
+                    format!("item.{}", Product::fields().items(false).assets_refs).as_str(),
+                    format!("item.{}", Product::fields().items(false).sku).as_str(),
+                    format!("item.{}", Product::fields().items(false).info).as_str(),
+                    format!("item.{}", Product::fields().items(false).status).as_str(),
+                    // Product assets fields to return
+                    Product::fields().assets(true).id,
+                    Product::fields().assets(true).file_name,
+                    Product::fields().assets(true).path,
+                    Product::fields().assets(true).size,
+                    Product::fields().assets(true).mime_type,
+                    Product::fields().assets(true).file_type,
+                ],
+                None,
+            ),
+        ]);
+
+        let mut products = self.aggregate_products(pipeline, options, None).await?;
+
+        feed.advance(&mut products)?;
+
+        Ok(products)
+    }
+
     async fn random_autocomplete_products_search(
         &self,
         amount: Option<u8>,
@@ -625,45 +802,11 @@ impl ProductFunctions for DBConection {
 
         let from_pool = amount * 10;
 
-        let filters = {
-            let mut f = vec![
-                doc! {
-                    "text": {
-                        "path": Product::fields().status,
-                        "query": ProductStatus::Active
-                    }
-
-                },
-                doc! {
-                    "text": {
-                        "path": Product::fields().items(true).status,
-                        "query": ProductItemStatus::Active
-                    }
-                },
-            ];
-
-            if let Some(store_id) = store_id {
-                f.push(doc! {
-                    "equals": {
-                        "value": store_id,
-                        "path": Product::fields().store(true).id
-                    }
-                });
-            };
+        let filters = active_products_filters(store_id, category_id);
 
-            if let Some(category_id) = category_id {
-                f.push(doc! {
-                    "equals": {
-                        "value": category_id,
-                        "path": Product::fields().categories(true).ids
-                    }
-                });
-            }
-            f
-        };
+        let mut pipeline = filter_products_stages(&filters);
 
-        let pipeline = [
-            aggregations::search_products(&None, &filters, Some(0)),
+        pipeline.extend([
             aggregations::sort(doc! {
                 Product::fields().analytics(true).views: -1
             }),
@@ -682,7 +825,7 @@ impl ProductFunctions for DBConection {
                     "views": format!("${}", Product::fields().analytics(true).views),
                 }),
             ),
-        ];
+        ]);
 
         self.aggregate_products(pipeline, options, None).await
     }
@@ -692,47 +835,11 @@ impl ProductFunctions for DBConection {
         store_id: Option<ObjectId>,
         category_id: Option<ObjectId>,
     ) -> Result<u64> {
-        let filters = {
-            let mut f = vec![
-                doc! {
-                    "text": {
-                        "path": Product::fields().status,
-                        "query": ProductStatus::Active
-                    }
+        let filters = active_products_filters(store_id, category_id);
 
-                },
-                doc! {
-                    "text": {
-                        "path": Product::fields().items(true).status,
-                        "query": ProductItemStatus::Active
-                    }
-                },
-            ];
+        let mut pipeline = filter_products_stages(&filters);
 
-            if let Some(store_id) = store_id {
-                f.push(doc! {
-                    "equals": {
-                        "value": store_id,
-                        "path": Product::fields().store(true).id
-                    }
-                });
-            };
-
-            if let Some(category_id) = category_id {
-                f.push(doc! {
-                    "equals": {
-                        "value": category_id,
-                        "path": Product::fields().categories(true).ids
-                    }
-                });
-            }
-            f
-        };
-
-        let pipeline = [
-            aggregations::search_products(&None, &filters, Some(0)),
-            aggregations::count("count"),
-        ];
+        pipeline.push(aggregations::count("count"));
 
         self.count_products_with_aggregation(pipeline, None, None)
             .await
@@ -742,6 +849,7 @@ impl ProductFunctions for DBConection {
         &self,
         order: &Order,
         options: Option<UpdateOptions>,
+        session: Option<&mut ClientSession>,
     ) -> Result<UpdateResult> {
         let mut products = Vec::new();
 
@@ -832,9 +940,72 @@ impl ProductFunctions for DBConection {
             }
         };
 
-        self.update_many_products(filters, update, options, None)
+        self.update_many_products(filters, update, options, session)
             .await
     }
+
+    async fn reserve_product_item_stock(
+        &self,
+        product_id: &ObjectId,
+        item_id: &ObjectId,
+        quantity: i64,
+        session: Option<&mut ClientSession>,
+    ) -> Result<UpdateResult> {
+        let filters = doc! {
+            Product::fields().id: product_id,
+            Product::fields().status: ProductStatus::Active,
+            Product::fields().items: {
+                "$elemMatch": {
+                    Product::fields().items(false).id: item_id,
+                    Product::fields().items(false).status: ProductItemStatus::Active,
+                    Product::fields().items(false).in_storage: {
+                        "$gte": quantity
+                    }
+                }
+            }
+        };
+
+        let field = format!(
+            "{}.$.{}",
+            Product::fields().items,
+            Product::fields().items(false).in_storage
+        );
+
+        let update = doc! {
+            "$inc": {
+                field: -quantity
+            }
+        };
+
+        self.update_product(filters, update, None, session).await
+    }
+
+    async fn release_product_item_stock(
+        &self,
+        product_id: &ObjectId,
+        item_id: &ObjectId,
+        quantity: i64,
+        session: Option<&mut ClientSession>,
+    ) -> Result<UpdateResult> {
+        let filters = doc! {
+            Product::fields().id: product_id,
+            Product::fields().items(true).id: item_id
+        };
+
+        let field = format!(
+            "{}.$.{}",
+            Product::fields().items,
+            Product::fields().items(false).in_storage
+        );
+
+        let update = doc! {
+            "$inc": {
+                field: quantity
+            }
+        };
+
+        self.update_product(filters, update, None, session).await
+    }
 }
 
 #[async_trait]
@@ -1089,6 +1260,10 @@ impl AdminProductFunctions for DBConection {
             ProductSortBy::Popularity => aggregations::sort(doc! {
                 Product::fields().analytics(true).views: &sorting.direction
             }),
+            ProductSortBy::Rating => aggregations::sort(doc! {
+                format!("{}.average", PRODUCT_RATING_FIELD): &sorting.direction,
+                format!("{}.count", PRODUCT_RATING_FIELD): &sorting.direction,
+            }),
             ProductSortBy::Relevance => {
                 if product_name.is_some() {
                     aggregations::sort(doc! {
@@ -1102,50 +1277,31 @@ impl AdminProductFunctions for DBConection {
             }
         };
 
-        let filters = {
-            let mut f = vec![];
+        let mut filters = vec![];
 
-            if let Some(store_id) = store_id {
-                f.push(doc! {
-                    "equals": {
-                        "value": store_id,
-                        "path": Product::fields().store(true).id
-                    }
-                });
-            };
+        if let Some(store_id) = store_id {
+            filters.push(SearchFilter::equals(
+                Product::fields().store(true).id,
+                store_id,
+            ));
+        };
 
-            if let Some(category_id) = category_id {
-                f.push(doc! {
-                    "equals": {
-                        "value": category_id,
-                        "path": Product::fields().categories(true).ids
-                    }
-                });
-            }
+        if let Some(category_id) = category_id {
+            filters.push(SearchFilter::equals(
+                Product::fields().categories(true).ids,
+                category_id,
+            ));
+        }
 
-            if let Some(status) = status {
-                f.push(doc! {
-                    "text": {
-                        "query": status,
-                        "path": Product::fields().status
-                    }
-                });
-            }
+        if let Some(status) = status {
+            filters.push(SearchFilter::text(Product::fields().status, status));
+        }
 
-            f
-        };
+        let search_stages = product_name_search_stages(&product_name, &filters);
 
-        let search_stage = {
-            if filters.is_empty() && product_name.is_none() {
-                aggregations::match_all()
-            } else {
-                aggregations::product_name_search(product_name, filters)
-            }
-        };
+        let mut pipeline = search_stages.clone();
 
-        let pipeline = [
-            search_stage.clone(),
-            aggregations::add_score_meta(),
+        pipeline.extend([
             sort_stage,
             aggregations::skip(pagination.offset),
             aggregations::limit(pagination.amount),
@@ -1174,7 +1330,7 @@ impl AdminProductFunctions for DBConection {
                 ],
                 None,
             ),
-        ];
+        ]);
 
         let products = self
             .aggregate_products(pipeline, options.clone(), None)
@@ -1186,12 +1342,12 @@ impl AdminProductFunctions for DBConection {
             return Ok((products, pagination.calculate_total(count)));
         }
 
+        let mut count_pipeline = search_stages;
+
+        count_pipeline.push(aggregations::count("count"));
+
         let count = self
-            .count_products_with_aggregation(
-                [search_stage, aggregations::count("count")],
-                options,
-                None,
-            )
+            .count_products_with_aggregation(count_pipeline, options, None)
             .await?;
 
         Ok((products, count))
@@ -1566,6 +1722,10 @@ impl StoreProductFunctions for DBConection {
             ProductSortBy::Popularity => aggregations::sort(doc! {
                 Product::fields().analytics(true).views: &sorting.direction
             }),
+            ProductSortBy::Rating => aggregations::sort(doc! {
+                format!("{}.average", PRODUCT_RATING_FIELD): &sorting.direction,
+                format!("{}.count", PRODUCT_RATING_FIELD): &sorting.direction,
+            }),
             ProductSortBy::Relevance => {
                 if product_name.is_some() {
                     aggregations::sort(doc! {
@@ -1579,46 +1739,36 @@ impl StoreProductFunctions for DBConection {
             }
         };
 
-        let filters = {
-            let mut f = vec![
-                doc! {
-                "equals": {
-                    "value": store_id,
-                    "path": Product::fields().store(true).id
-                }},
-                doc! {"text": {
-                        "query": [ProductStatus::Active, ProductStatus::Draft, ProductStatus::Pending, ProductStatus::Inactive, ProductStatus::Banned],
-                        "path": Product::fields().status
-                    }
-                },
-            ];
+        let mut filters = vec![
+            SearchFilter::equals(Product::fields().store(true).id, *store_id),
+            SearchFilter::text(
+                Product::fields().status,
+                vec![
+                    ProductStatus::Active,
+                    ProductStatus::Draft,
+                    ProductStatus::Pending,
+                    ProductStatus::Inactive,
+                    ProductStatus::Banned,
+                ],
+            ),
+        ];
 
-            if let Some(category_id) = category_id {
-                f.push(doc! {
-                    "equals": {
-                        "value": category_id,
-                        "path": Product::fields().categories(true).ids
-                    }
-                });
-            }
+        if let Some(category_id) = category_id {
+            filters.push(SearchFilter::equals(
+                Product::fields().categories(true).ids,
+                category_id,
+            ));
+        }
 
-            if let Some(status) = status {
-                f.push(doc! {
-                    "text": {
-                        "query": status,
-                        "path": Product::fields().status
-                    }
-                });
-            }
+        if let Some(status) = status {
+            filters.push(SearchFilter::text(Product::fields().status, status));
+        }
 
-            f
-        };
+        let search_stages = product_name_search_stages(&product_name, &filters);
 
-        let search_stage = aggregations::product_name_search(product_name, filters);
+        let mut pipeline = search_stages.clone();
 
-        let pipeline = [
-            search_stage.clone(),
-            aggregations::add_score_meta(),
+        pipeline.extend([
             sort_stage,
             aggregations::skip(pagination.offset),
             aggregations::limit(pagination.amount),
@@ -1688,7 +1838,7 @@ impl StoreProductFunctions for DBConection {
                 ],
                 None,
             ),
-        ];
+        ]);
 
         let products = self
             .aggregate_products(pipeline, options.clone(), None)
@@ -1700,12 +1850,12 @@ impl StoreProductFunctions for DBConection {
             return Ok((products, pagination.calculate_total(count)));
         }
 
+        let mut count_pipeline = search_stages;
+
+        count_pipeline.push(aggregations::count("count"));
+
         let count = self
-            .count_products_with_aggregation(
-                [search_stage, aggregations::count("count")],
-                options,
-                None,
-            )
+            .count_products_with_aggregation(count_pipeline, options, None)
             .await?;
 
         Ok((products, count))
@@ -1822,6 +1972,36 @@ fn product_status_update() -> Document {
     }
 }
 
+/// Active products with an active item, of the store and category if given
+fn active_products_filters(
+    store_id: Option<ObjectId>,
+    category_id: Option<ObjectId>,
+) -> Vec<SearchFilter> {
+    let mut filters = vec![
+        SearchFilter::text(Product::fields().status, ProductStatus::Active),
+        SearchFilter::text(
+            Product::fields().items(true).status,
+            ProductItemStatus::Active,
+        ),
+    ];
+
+    if let Some(store_id) = store_id {
+        filters.push(SearchFilter::equals(
+            Product::fields().store(true).id,
+            store_id,
+        ));
+    };
+
+    if let Some(category_id) = category_id {
+        filters.push(SearchFilter::equals(
+            Product::fields().categories(true).ids,
+            category_id,
+        ));
+    }
+
+    filters
+}
+
 pub fn generate_products_random_sort(indexes: Vec<u8>) -> Document {
     let set_indexes = std::collections::BTreeSet::from_iter(indexes);
 
@@ -0,0 +1,677 @@
+use crate::{
+    db::{collect_cursor, is_duplicate_key_error, ChargeCurrency, LocalDBConection},
+    helpers::money::{Currency, Money},
+    prelude::{types::*, *},
+};
+use axum::async_trait;
+use bson::{doc, oid::ObjectId, DateTime as BsonDateTime, Document};
+use mongodb::{
+    results::{DeleteResult, UpdateResult},
+    ClientSession, Collection,
+};
+use serde::{Deserialize, Serialize};
+use shoppa_core::db::{
+    aggregations,
+    models::{CheckOutSession, Order},
+    DBConection, Pagination,
+};
+
+pub const PROMOTIONS_COLLECTION: &str = "promotions";
+pub const PROMOTION_REDEMPTIONS_COLLECTION: &str = "promotion_redemptions";
+
+// The applied discount is stored on the checkout session and then on the order (under `discount`)
+pub const DISCOUNT_FIELD: &str = "discount";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum PromotionDiscount {
+    Percentage { percent: f64 },
+    Fixed { amount: f64 },
+    FreeDelivery,
+}
+
+/// A use of a promotion, kept out of the promotion document so a popular code
+/// never grows it and the customers who used it are not shown to the store
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromotionRedemption {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub promotion: ObjectId,
+    pub user: ObjectId,
+    pub order: ObjectId,
+    // Which of the user uses this is, set only when the promotion limits the uses per user.
+    // The unique index on it keeps the user under the limit when redeeming at the same time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slot: Option<u32>,
+    pub created_at: BsonDateTime,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Promotion {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub code: String,
+    // None means the promotion is platform wide
+    pub store: Option<ObjectId>,
+    pub discount: PromotionDiscount,
+    // Of the items the promotion applies to, delivery not included
+    pub min_order: Option<f64>,
+    pub max_uses: Option<u32>,
+    pub max_uses_per_user: Option<u32>,
+    pub starts_at: Option<BsonDateTime>,
+    pub ends_at: Option<BsonDateTime>,
+    pub active: bool,
+    // How many times the promotion was redeemed, see `PromotionRedemption`
+    #[serde(default)]
+    pub uses: u32,
+    pub created_at: BsonDateTime,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PartDiscount {
+    pub store: ObjectId,
+    pub amount: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckoutDiscount {
+    pub promotion: ObjectId,
+    pub code: String,
+    pub parts: Vec<PartDiscount>,
+    pub total: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct CreatePromotionPayload {
+    #[validate(length(min = 3, max = 32))]
+    pub code: String,
+    pub discount: PromotionDiscount,
+    #[validate(range(min = 0.0))]
+    pub min_order: Option<f64>,
+    #[validate(range(min = 1))]
+    pub max_uses: Option<u32>,
+    #[validate(range(min = 1))]
+    pub max_uses_per_user: Option<u32>,
+    pub starts_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub ends_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct UpdatePromotionPayload {
+    pub active: bool,
+}
+
+/// What the discount is calculated from, one for each store part, in the charge currency
+pub struct DiscountablePart {
+    pub store: ObjectId,
+    pub items_total: Money,
+    pub delivery_cost: Money,
+}
+
+impl PromotionDiscount {
+    pub fn is_valid(&self) -> bool {
+        match self {
+            Self::Percentage { percent } => *percent > 0.0 && *percent <= 100.0,
+            Self::Fixed { amount } => *amount > 0.0,
+            Self::FreeDelivery => true,
+        }
+    }
+}
+
+impl Promotion {
+    pub fn new(
+        code: String,
+        store: Option<ObjectId>,
+        discount: PromotionDiscount,
+        min_order: Option<f64>,
+        max_uses: Option<u32>,
+        max_uses_per_user: Option<u32>,
+        starts_at: Option<BsonDateTime>,
+        ends_at: Option<BsonDateTime>,
+    ) -> Self {
+        Self {
+            id: ObjectId::new(),
+            code: Self::normalize_code(&code),
+            store,
+            discount,
+            min_order,
+            max_uses,
+            max_uses_per_user,
+            starts_at,
+            ends_at,
+            active: true,
+            uses: 0,
+            created_at: BsonDateTime::now(),
+        }
+    }
+
+    /// Returns None if the payload discount or validity window make no sense
+    pub fn from_payload(payload: CreatePromotionPayload, store: Option<ObjectId>) -> Option<Self> {
+        if !payload.discount.is_valid() {
+            return None;
+        }
+
+        if let (Some(starts_at), Some(ends_at)) = (payload.starts_at, payload.ends_at) {
+            if starts_at >= ends_at {
+                return None;
+            }
+        }
+
+        Some(Self::new(
+            payload.code,
+            store,
+            payload.discount,
+            payload.min_order,
+            payload.max_uses,
+            payload.max_uses_per_user,
+            payload.starts_at.map(BsonDateTime::from_chrono),
+            payload.ends_at.map(BsonDateTime::from_chrono),
+        ))
+    }
+
+    pub fn normalize_code(code: &str) -> String {
+        code.trim().to_uppercase()
+    }
+
+    /// Checks the usage limits against the uses of the user,
+    /// the redemption itself checks them again atomically
+    pub fn can_be_used_by(&self, user_uses: u32) -> bool {
+        let under_max_uses = self.max_uses.map_or(true, |max_uses| self.uses < max_uses);
+
+        let under_max_user_uses = self
+            .max_uses_per_user
+            .map_or(true, |max_uses| user_uses < max_uses);
+
+        under_max_uses && under_max_user_uses
+    }
+
+    /// The amounts of promotions are in the platform currency
+    pub fn in_currency(mut self, currency: &ChargeCurrency) -> Result<Self> {
+        let platform = Currency::default();
+
+        self.min_order = self
+            .min_order
+            .map(|min_order| currency.from_store(min_order, platform))
+            .transpose()?;
+
+        if let PromotionDiscount::Fixed { amount } = self.discount {
+            self.discount = PromotionDiscount::Fixed {
+                amount: currency.from_store(amount, platform)?,
+            };
+        }
+
+        Ok(self)
+    }
+
+    /// Splits the discount between the parts it applies to.
+    /// A store promotion only applies to the part of that store.
+    /// The promotion amounts must already be in `currency`, see `in_currency`
+    pub fn apply(
+        &self,
+        parts: &[DiscountablePart],
+        currency: Currency,
+    ) -> StdResult<CheckoutDiscount, &'static str> {
+        let eligible: Vec<&DiscountablePart> = parts
+            .iter()
+            .filter(|part| self.store.map_or(true, |store| store == part.store))
+            .collect();
+
+        if eligible.is_empty() {
+            return Err("CouponNotValidForCartStores");
+        }
+
+        let items_total = Money::sum(currency, eligible.iter().map(|part| part.items_total));
+
+        if let Some(min_order) = self.min_order {
+            if items_total < Money::from_major(min_order, currency) {
+                return Err("CouponMinOrderNotReached");
+            }
+        }
+
+        let mut discounts: Vec<(ObjectId, Money)> = match self.discount {
+            PromotionDiscount::Percentage { percent } => eligible
+                .iter()
+                .map(|part| (part.store, part.items_total.percent(percent)))
+                .collect(),
+            PromotionDiscount::Fixed { amount } => {
+                // by the part share of the items total, never more than the items
+                let amount = Money::from_major(amount, currency).min(items_total);
+                eligible
+                    .iter()
+                    .map(|part| (part.store, amount.share(part.items_total, items_total)))
+                    .collect()
+            }
+            PromotionDiscount::FreeDelivery => eligible
+                .iter()
+                .map(|part| (part.store, part.delivery_cost))
+                .collect(),
+        };
+
+        discounts.retain(|(_, amount)| amount.is_positive());
+
+        if discounts.is_empty() {
+            return Err("CouponHasNothingToDiscount");
+        }
+
+        Ok(CheckoutDiscount {
+            promotion: self.id,
+            code: self.code.clone(),
+            total: Money::sum(currency, discounts.iter().map(|(_, amount)| *amount)).to_major(),
+            parts: discounts
+                .into_iter()
+                .map(|(store, amount)| PartDiscount {
+                    store,
+                    amount: amount.to_major(),
+                })
+                .collect(),
+        })
+    }
+}
+
+impl CheckoutDiscount {
+    /// The discount of the store part, `currency` is the one the discount was applied in
+    pub fn for_store(&self, store_id: &ObjectId, currency: Currency) -> Money {
+        self.parts
+            .iter()
+            .find(|part| &part.store == store_id)
+            .map(|part| Money::from_major(part.amount, currency))
+            .unwrap_or(Money::zero(currency))
+    }
+}
+
+fn valid_now_filter() -> Document {
+    let now = BsonDateTime::now();
+    doc! {
+        "active": true,
+        "$and": [
+            { "$or": [{ "starts_at": None::<BsonDateTime> }, { "starts_at": { "$lte": now } }] },
+            { "$or": [{ "ends_at": None::<BsonDateTime> }, { "ends_at": { "$gt": now } }] },
+        ]
+    }
+}
+
+#[async_trait]
+pub trait PromotionFunctions {
+    fn promotions(&self) -> Collection<Promotion>;
+    fn promotion_redemptions(&self) -> Collection<PromotionRedemption>;
+    async fn insert_new_promotion(&self, promotion: &Promotion) -> Result<()>;
+    async fn get_promotion_by_id(&self, promotion_id: &ObjectId) -> Result<Option<Promotion>>;
+    async fn get_promotion_by_code(&self, code: &str) -> Result<Option<Promotion>>;
+    /// Only a promotion that is active and inside its validity window
+    async fn get_valid_promotion_by_code(&self, code: &str) -> Result<Option<Promotion>>;
+    async fn get_promotions(
+        &self,
+        store_id: Option<&ObjectId>,
+        pagination: Option<Pagination>,
+    ) -> Result<(Vec<Promotion>, u64)>;
+    async fn set_promotion_active(
+        &self,
+        promotion_id: &ObjectId,
+        store_id: Option<&ObjectId>,
+        active: bool,
+    ) -> Result<UpdateResult>;
+    async fn count_user_redemptions(
+        &self,
+        promotion_id: &ObjectId,
+        user_id: &ObjectId,
+    ) -> Result<u32>;
+    /// Returns false if the promotion is no longer valid or one of its limits was reached
+    async fn redeem_promotion(
+        &self,
+        promotion: &Promotion,
+        user_id: &ObjectId,
+        order_id: &ObjectId,
+    ) -> Result<bool>;
+    /// Running it again for the same order gives back nothing
+    async fn release_promotion_redemption(
+        &self,
+        promotion_id: &ObjectId,
+        order_id: &ObjectId,
+    ) -> Result<DeleteResult>;
+}
+
+#[async_trait]
+impl PromotionFunctions for LocalDBConection {
+    fn promotions(&self) -> Collection<Promotion> {
+        self.collection(PROMOTIONS_COLLECTION)
+    }
+
+    fn promotion_redemptions(&self) -> Collection<PromotionRedemption> {
+        self.collection(PROMOTION_REDEMPTIONS_COLLECTION)
+    }
+
+    async fn insert_new_promotion(&self, promotion: &Promotion) -> Result<()> {
+        self.promotions()
+            .insert_one(promotion, None)
+            .await
+            .map_err(|e| {
+                // the code was free when checked, another promotion took it since
+                if is_duplicate_key_error(&e) {
+                    Error::ApiErrorWithCode("CouponCodeTaken", 409)
+                } else {
+                    Error::Static("Failed to insert promotion")
+                }
+            })?;
+
+        Ok(())
+    }
+
+    async fn get_promotion_by_id(&self, promotion_id: &ObjectId) -> Result<Option<Promotion>> {
+        self.promotions()
+            .find_one(doc! { "_id": promotion_id }, None)
+            .await
+            .map_err(|_| Error::Static("Failed to get promotion"))
+    }
+
+    async fn get_promotion_by_code(&self, code: &str) -> Result<Option<Promotion>> {
+        self.promotions()
+            .find_one(doc! { "code": Promotion::normalize_code(code) }, None)
+            .await
+            .map_err(|_| Error::Static("Failed to get promotion"))
+    }
+
+    async fn get_valid_promotion_by_code(&self, code: &str) -> Result<Option<Promotion>> {
+        let mut filters = valid_now_filter();
+
+        filters.insert("code", Promotion::normalize_code(code));
+
+        self.promotions()
+            .find_one(filters, None)
+            .await
+            .map_err(|_| Error::Static("Failed to get promotion"))
+    }
+
+    async fn get_promotions(
+        &self,
+        store_id: Option<&ObjectId>,
+        pagination: Option<Pagination>,
+    ) -> Result<(Vec<Promotion>, u64)> {
+        let pagination = pagination.unwrap_or_default();
+
+        let filters = match store_id {
+            Some(store_id) => doc! { "store": store_id },
+            None => doc! {},
+        };
+
+        let pipeline = [
+            aggregations::match_query(&filters),
+            aggregations::sort(doc! { "created_at": -1 }),
+            aggregations::skip(pagination.offset),
+            aggregations::limit(pagination.amount),
+        ];
+
+        let cursor = self
+            .promotions()
+            .aggregate(pipeline, None)
+            .await
+            .map_err(|_| Error::Static("Failed to get promotions"))?;
+
+        let promotions = collect_cursor(cursor)
+            .await?
+            .into_iter()
+            .map(|promotion| bson::from_document(promotion).map_err(|_| Error::Desrilaztion))
+            .collect::<Result<Vec<Promotion>>>()?;
+
+        let count = self
+            .promotions()
+            .count_documents(filters, None)
+            .await
+            .map_err(|_| Error::Static("Failed to count promotions"))?;
+
+        Ok((promotions, count))
+    }
+
+    async fn set_promotion_active(
+        &self,
+        promotion_id: &ObjectId,
+        store_id: Option<&ObjectId>,
+        active: bool,
+    ) -> Result<UpdateResult> {
+        let mut filters = doc! { "_id": promotion_id };
+
+        // store users can only change their own store promotions
+        if let Some(store_id) = store_id {
+            filters.insert("store", store_id);
+        }
+
+        self.promotions()
+            .update_one(filters, doc! { "$set": { "active": active } }, None)
+            .await
+            .map_err(|_| Error::Static("Failed to update promotion"))
+    }
+
+    async fn count_user_redemptions(
+        &self,
+        promotion_id: &ObjectId,
+        user_id: &ObjectId,
+    ) -> Result<u32> {
+        let count = self
+            .promotion_redemptions()
+            .count_documents(doc! { "promotion": promotion_id, "user": user_id }, None)
+            .await
+            .map_err(|_| Error::Static("Failed to count promotion redemptions"))?;
+
+        Ok(count as u32)
+    }
+
+    async fn redeem_promotion(
+        &self,
+        promotion: &Promotion,
+        user_id: &ObjectId,
+        order_id: &ObjectId,
+    ) -> Result<bool> {
+        let mut filters = valid_now_filter();
+
+        filters.insert("_id", promotion.id);
+
+        if let Some(max_uses) = promotion.max_uses {
+            filters.insert(
+                "$or",
+                vec![
+                    doc! { "uses": { "$lt": max_uses } },
+                    doc! { "uses": { "$exists": false } },
+                ],
+            );
+        }
+
+        let res = self
+            .promotions()
+            .update_one(filters, doc! { "$inc": { "uses": 1 } }, None)
+            .await
+            .map_err(|_| Error::Static("Failed to redeem promotion"))?;
+
+        if res.modified_count != 1 {
+            return Ok(false);
+        }
+
+        let give_back_use = || async {
+            if self
+                .promotions()
+                .update_one(
+                    doc! { "_id": promotion.id },
+                    doc! { "$inc": { "uses": -1 } },
+                    None,
+                )
+                .await
+                .is_err()
+            {
+                tracing::error!("Failed to give back a use of promotion {}", promotion.id);
+            }
+        };
+
+        // the first of the user slots that is free, a released redemption frees its slot
+        let slot = match promotion.max_uses_per_user {
+            Some(max_uses) => {
+                let cursor = self
+                    .promotion_redemptions()
+                    .find(doc! { "promotion": promotion.id, "user": user_id }, None)
+                    .await
+                    .map_err(|_| Error::Static("Failed to get promotion redemptions"))?;
+
+                let taken: Vec<Option<u32>> = collect_cursor(cursor)
+                    .await?
+                    .into_iter()
+                    .map(|redemption| redemption.slot)
+                    .collect();
+
+                match (0..max_uses).find(|slot| !taken.contains(&Some(*slot))) {
+                    Some(slot) => Some(slot),
+                    None => {
+                        give_back_use().await;
+                        return Ok(false);
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let redemption = PromotionRedemption {
+            id: ObjectId::new(),
+            promotion: promotion.id,
+            user: user_id.clone(),
+            order: order_id.clone(),
+            slot,
+            created_at: BsonDateTime::now(),
+        };
+
+        match self
+            .promotion_redemptions()
+            .insert_one(&redemption, None)
+            .await
+        {
+            Ok(_) => Ok(true),
+            // another redemption of the user took the slot at the same time
+            Err(e) if is_duplicate_key_error(&e) => {
+                give_back_use().await;
+                Ok(false)
+            }
+            Err(_) => {
+                give_back_use().await;
+                Err(Error::Static("Failed to redeem promotion"))
+            }
+        }
+    }
+
+    async fn release_promotion_redemption(
+        &self,
+        promotion_id: &ObjectId,
+        order_id: &ObjectId,
+    ) -> Result<DeleteResult> {
+        let res = self
+            .promotion_redemptions()
+            .delete_one(doc! { "promotion": promotion_id, "order": order_id }, None)
+            .await
+            .map_err(|_| Error::Static("Failed to release promotion"))?;
+
+        if res.deleted_count == 1 {
+            self.promotions()
+                .update_one(
+                    doc! { "_id": promotion_id },
+                    doc! { "$inc": { "uses": -1 } },
+                    None,
+                )
+                .await
+                .map_err(|_| Error::Static("Failed to release promotion"))?;
+        }
+
+        Ok(res)
+    }
+}
+
+#[async_trait]
+pub trait DiscountFunctions {
+    async fn set_checkout_session_discount(
+        &self,
+        checkout_session_id: &ObjectId,
+        discount: Option<&CheckoutDiscount>,
+    ) -> Result<UpdateResult>;
+    async fn get_checkout_session_discount(
+        &self,
+        checkout_session_id: &ObjectId,
+    ) -> Result<Option<CheckoutDiscount>>;
+    async fn set_order_discount(
+        &self,
+        order_id: &ObjectId,
+        discount: &CheckoutDiscount,
+        session: Option<&mut ClientSession>,
+    ) -> Result<UpdateResult>;
+    async fn get_order_discount(&self, order_id: &ObjectId) -> Result<Option<CheckoutDiscount>>;
+}
+
+fn discount_from_documents(mut docs: Vec<Document>) -> Result<Option<CheckoutDiscount>> {
+    match docs.pop() {
+        Some(discount) => bson::from_document::<CheckoutDiscount>(discount)
+            .map(Some)
+            .map_err(|_| Error::Desrilaztion),
+        None => Ok(None),
+    }
+}
+
+#[async_trait]
+impl DiscountFunctions for DBConection {
+    async fn set_checkout_session_discount(
+        &self,
+        checkout_session_id: &ObjectId,
+        discount: Option<&CheckoutDiscount>,
+    ) -> Result<UpdateResult> {
+        let discount = match discount {
+            Some(discount) => bson::to_bson(discount).map_err(|_| Error::Desrilaztion)?,
+            None => bson::Bson::Null,
+        };
+
+        let update = doc! {
+            "$set": {
+                DISCOUNT_FIELD: discount
+            }
+        };
+
+        self.update_checkout_session_by_id(checkout_session_id, update, None, None)
+            .await
+    }
+
+    async fn get_checkout_session_discount(
+        &self,
+        checkout_session_id: &ObjectId,
+    ) -> Result<Option<CheckoutDiscount>> {
+        let pipeline = [
+            aggregations::match_query(&doc! {
+                CheckOutSession::fields().id: checkout_session_id,
+                DISCOUNT_FIELD: { "$type": "object" },
+            }),
+            aggregations::replace_root(DISCOUNT_FIELD),
+        ];
+
+        discount_from_documents(
+            self.aggregate_checkout_sessions(pipeline, None, None)
+                .await?,
+        )
+    }
+
+    async fn set_order_discount(
+        &self,
+        order_id: &ObjectId,
+        discount: &CheckoutDiscount,
+        session: Option<&mut ClientSession>,
+    ) -> Result<UpdateResult> {
+        let update = doc! {
+            "$set": {
+                DISCOUNT_FIELD: bson::to_bson(discount).map_err(|_| Error::Desrilaztion)?
+            }
+        };
+
+        self.update_order_by_id(order_id, update, None, session)
+            .await
+    }
+
+    async fn get_order_discount(&self, order_id: &ObjectId) -> Result<Option<CheckoutDiscount>> {
+        let pipeline = [
+            aggregations::match_query(&doc! {
+                Order::fields().id: order_id,
+                DISCOUNT_FIELD: { "$type": "object" },
+            }),
+            aggregations::replace_root(DISCOUNT_FIELD),
+        ];
+
+        discount_from_documents(self.aggregate_orders(pipeline, None, None).await?)
+    }
+}
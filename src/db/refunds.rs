@@ -0,0 +1,213 @@
+use crate::prelude::*;
+use axum::async_trait;
+use bson::{doc, oid::ObjectId, DateTime as BsonDateTime};
+use mongodb::{results::UpdateResult, ClientSession};
+use serde::{Deserialize, Serialize};
+use shoppa_core::db::{aggregations, models::Order, DBConection};
+use strum_macros::Display;
+
+// The refunds made by stores are stored on the order document (under `store_refunds`)
+pub const STORE_REFUNDS_FIELD: &str = "store_refunds";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum StoreRefundStatus {
+    Pending,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoreRefundItem {
+    pub product: ObjectId,
+    pub item_id: ObjectId,
+    pub quantity: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoreRefund {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub store: ObjectId,
+    pub items: Vec<StoreRefundItem>,
+    pub amount: f64,
+    pub reason: Option<String>,
+    pub status: StoreRefundStatus,
+    pub created_by: ObjectId,
+    pub created_at: BsonDateTime,
+    pub provider_reference: Option<String>,
+    pub credit_note_number: Option<String>,
+    pub last_error: Option<String>,
+}
+
+impl StoreRefund {
+    pub fn new(
+        store: ObjectId,
+        items: Vec<StoreRefundItem>,
+        amount: f64,
+        reason: Option<String>,
+        created_by: ObjectId,
+    ) -> Self {
+        Self {
+            id: ObjectId::new(),
+            store,
+            items,
+            amount,
+            reason,
+            status: StoreRefundStatus::Pending,
+            created_by,
+            created_at: BsonDateTime::now(),
+            provider_reference: None,
+            credit_note_number: None,
+            last_error: None,
+        }
+    }
+
+    /// Failed refunds gave nothing back, so they don't count toward the part total
+    pub fn is_counted(&self) -> bool {
+        self.status != StoreRefundStatus::Failed
+    }
+}
+
+#[async_trait]
+pub trait RefundFunctions {
+    async fn get_order_refunds(&self, order_id: &ObjectId) -> Result<Vec<StoreRefund>>;
+    /// Adds the refund only if no other refund was added since the refunds were read,
+    /// so two refunds of the same part can't both pass the refundable amount check.
+    async fn insert_pending_refund(
+        &self,
+        order_id: &ObjectId,
+        refund: &StoreRefund,
+        known_refunds: usize,
+    ) -> Result<bool>;
+    async fn complete_refund(
+        &self,
+        order_id: &ObjectId,
+        refund: &StoreRefund,
+        provider_reference: String,
+        credit_note_number: String,
+        total_after_refunds: f64,
+        session: Option<&mut ClientSession>,
+    ) -> Result<UpdateResult>;
+    async fn fail_refund(
+        &self,
+        order_id: &ObjectId,
+        refund_id: &ObjectId,
+        error: &str,
+    ) -> Result<UpdateResult>;
+}
+
+fn refund_field(field: &str) -> String {
+    format!("{}.$.{}", STORE_REFUNDS_FIELD, field)
+}
+
+#[async_trait]
+impl RefundFunctions for DBConection {
+    async fn get_order_refunds(&self, order_id: &ObjectId) -> Result<Vec<StoreRefund>> {
+        let pipeline = [
+            aggregations::match_query(&doc! {
+                Order::fields().id: order_id,
+            }),
+            aggregations::unwind(STORE_REFUNDS_FIELD, false),
+            aggregations::replace_root(STORE_REFUNDS_FIELD),
+        ];
+
+        let refunds = self.aggregate_orders(pipeline, None, None).await?;
+
+        refunds
+            .into_iter()
+            .map(|refund| {
+                bson::from_document::<StoreRefund>(refund).map_err(|_| Error::Desrilaztion)
+            })
+            .collect()
+    }
+
+    async fn insert_pending_refund(
+        &self,
+        order_id: &ObjectId,
+        refund: &StoreRefund,
+        known_refunds: usize,
+    ) -> Result<bool> {
+        let filters = doc! {
+            Order::fields().id: order_id,
+            format!("{}.{}", STORE_REFUNDS_FIELD, known_refunds): {
+                "$exists": false
+            }
+        };
+
+        let update = doc! {
+            "$push": {
+                STORE_REFUNDS_FIELD: bson::to_bson(refund).map_err(|_| Error::Desrilaztion)?
+            }
+        };
+
+        let res = self.update_order(filters, update, None, None).await?;
+
+        Ok(res.modified_count == 1)
+    }
+
+    async fn complete_refund(
+        &self,
+        order_id: &ObjectId,
+        refund: &StoreRefund,
+        provider_reference: String,
+        credit_note_number: String,
+        total_after_refunds: f64,
+        mut session: Option<&mut ClientSession>,
+    ) -> Result<UpdateResult> {
+        let filters = doc! {
+            Order::fields().id: order_id,
+            format!("{}._id", STORE_REFUNDS_FIELD): refund.id,
+        };
+
+        let update = doc! {
+            "$set": {
+                refund_field("status"): StoreRefundStatus::Done.to_string(),
+                refund_field("provider_reference"): provider_reference,
+                refund_field("credit_note_number"): credit_note_number,
+            }
+        };
+
+        self.update_order(filters, update, None, session.as_deref_mut())
+            .await?;
+
+        let filters = doc! {
+            Order::fields().id: order_id,
+            Order::fields().parts(true).store: refund.store,
+        };
+
+        let update = doc! {
+            "$set": {
+                format!(
+                    "{}.$.{}",
+                    Order::fields().parts,
+                    Order::fields().parts(false).total_after_refunds
+                ): total_after_refunds,
+            }
+        };
+
+        self.update_order(filters, update, None, session).await
+    }
+
+    async fn fail_refund(
+        &self,
+        order_id: &ObjectId,
+        refund_id: &ObjectId,
+        error: &str,
+    ) -> Result<UpdateResult> {
+        let filters = doc! {
+            Order::fields().id: order_id,
+            format!("{}._id", STORE_REFUNDS_FIELD): refund_id,
+        };
+
+        let update = doc! {
+            "$set": {
+                refund_field("status"): StoreRefundStatus::Failed.to_string(),
+                refund_field("last_error"): error,
+            }
+        };
+
+        self.update_order(filters, update, None, None).await
+    }
+}
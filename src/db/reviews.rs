@@ -0,0 +1,382 @@
+use crate::{
+    db::{collect_cursor, LocalDBConection},
+    prelude::{types::*, *},
+};
+use axum::async_trait;
+use bson::{doc, oid::ObjectId, DateTime as BsonDateTime, Document};
+use mongodb::{
+    options::{FindOneAndUpdateOptions, ReturnDocument, UpdateOptions},
+    results::UpdateResult,
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use shoppa_core::db::{
+    aggregations,
+    models::{Order, OrderPartStatus},
+    DBConection, Pagination,
+};
+
+pub const REVIEWS_COLLECTION: &str = "reviews";
+
+// The average rating of the visible reviews of a product, stored on the product document
+// (under `rating`) so the products search can sort and filter by it
+pub const PRODUCT_RATING_FIELD: &str = "rating";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReviewReply {
+    pub text: String,
+    pub replied_by: ObjectId,
+    pub created_at: BsonDateTime,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Review {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub product: ObjectId,
+    pub store: ObjectId,
+    pub user: ObjectId,
+    pub user_name: Option<String>,
+    // The order the product was delivered in
+    pub order: ObjectId,
+    pub rating: u8,
+    pub text: String,
+    pub reply: Option<ReviewReply>,
+    // Hidden reviews are not shown and don't count towards the product rating
+    pub hidden: bool,
+    pub created_at: BsonDateTime,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct ProductRating {
+    pub average: f64,
+    pub count: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct CreateReviewPayload {
+    pub product: ObjectId,
+    #[validate(range(min = 1, max = 5))]
+    pub rating: u8,
+    #[validate(length(max = 2000))]
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct ReplyToReviewPayload {
+    #[validate(length(min = 1, max = 2000))]
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct UpdateReviewPayload {
+    pub hidden: bool,
+}
+
+/// Where a product was delivered to the user
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeliveredPurchase {
+    #[serde(rename = "_id")]
+    pub order: ObjectId,
+    pub store: ObjectId,
+}
+
+impl Review {
+    pub fn new(
+        payload: CreateReviewPayload,
+        purchase: DeliveredPurchase,
+        user: ObjectId,
+        user_name: Option<String>,
+    ) -> Self {
+        Self {
+            id: ObjectId::new(),
+            product: payload.product,
+            store: purchase.store,
+            user,
+            user_name,
+            order: purchase.order,
+            rating: payload.rating,
+            text: payload.text,
+            reply: None,
+            hidden: false,
+            created_at: BsonDateTime::now(),
+        }
+    }
+}
+
+#[async_trait]
+pub trait ReviewFunctions {
+    fn reviews(&self) -> Collection<Review>;
+    /// Returns false if the user already reviewed the product
+    async fn insert_new_review(&self, review: &Review) -> Result<bool>;
+    async fn get_product_reviews(
+        &self,
+        product_id: &ObjectId,
+        pagination: Option<Pagination>,
+    ) -> Result<(Vec<Review>, u64)>;
+    /// With the hidden reviews
+    async fn get_reviews(
+        &self,
+        store_id: Option<&ObjectId>,
+        pagination: Option<Pagination>,
+    ) -> Result<(Vec<Review>, u64)>;
+    async fn reply_to_review(
+        &self,
+        review_id: &ObjectId,
+        store_id: &ObjectId,
+        reply: ReviewReply,
+    ) -> Result<UpdateResult>;
+    async fn set_review_hidden(&self, review_id: &ObjectId, hidden: bool)
+        -> Result<Option<Review>>;
+    /// Of the visible reviews only
+    async fn get_product_rating(&self, product_id: &ObjectId) -> Result<ProductRating>;
+}
+
+#[async_trait]
+impl ReviewFunctions for LocalDBConection {
+    fn reviews(&self) -> Collection<Review> {
+        self.collection(REVIEWS_COLLECTION)
+    }
+
+    async fn insert_new_review(&self, review: &Review) -> Result<bool> {
+        // one review for each user and product, the upsert only inserts if there is none
+        let filters = doc! {
+            "product": review.product,
+            "user": review.user,
+        };
+
+        let update = doc! {
+            "$setOnInsert": bson::to_bson(review).map_err(|_| Error::Desrilaztion)?
+        };
+
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        let res = self
+            .reviews()
+            .update_one(filters, update, options)
+            .await
+            .map_err(|_| Error::Static("Failed to insert review"))?;
+
+        Ok(res.upserted_id.is_some())
+    }
+
+    async fn get_product_reviews(
+        &self,
+        product_id: &ObjectId,
+        pagination: Option<Pagination>,
+    ) -> Result<(Vec<Review>, u64)> {
+        let filters = doc! {
+            "product": product_id,
+            "hidden": false,
+        };
+
+        paginated_reviews(self, filters, pagination).await
+    }
+
+    async fn get_reviews(
+        &self,
+        store_id: Option<&ObjectId>,
+        pagination: Option<Pagination>,
+    ) -> Result<(Vec<Review>, u64)> {
+        let filters = match store_id {
+            Some(store_id) => doc! { "store": store_id },
+            None => doc! {},
+        };
+
+        paginated_reviews(self, filters, pagination).await
+    }
+
+    async fn reply_to_review(
+        &self,
+        review_id: &ObjectId,
+        store_id: &ObjectId,
+        reply: ReviewReply,
+    ) -> Result<UpdateResult> {
+        // stores can only reply to the reviews of their own products
+        let filters = doc! {
+            "_id": review_id,
+            "store": store_id,
+        };
+
+        let update = doc! {
+            "$set": {
+                "reply": bson::to_bson(&reply).map_err(|_| Error::Desrilaztion)?
+            }
+        };
+
+        self.reviews()
+            .update_one(filters, update, None)
+            .await
+            .map_err(|_| Error::Static("Failed to reply to review"))
+    }
+
+    async fn set_review_hidden(
+        &self,
+        review_id: &ObjectId,
+        hidden: bool,
+    ) -> Result<Option<Review>> {
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+
+        self.reviews()
+            .find_one_and_update(
+                doc! { "_id": review_id },
+                doc! { "$set": { "hidden": hidden } },
+                options,
+            )
+            .await
+            .map_err(|_| Error::Static("Failed to update review"))
+    }
+
+    async fn get_product_rating(&self, product_id: &ObjectId) -> Result<ProductRating> {
+        let pipeline = [
+            aggregations::match_query(&doc! {
+                "product": product_id,
+                "hidden": false,
+            }),
+            doc! {
+                "$group": {
+                    "_id": null,
+                    "average": { "$avg": "$rating" },
+                    "count": { "$sum": 1 },
+                }
+            },
+        ];
+
+        let cursor = self
+            .reviews()
+            .aggregate(pipeline, None)
+            .await
+            .map_err(|_| Error::Static("Failed to get product rating"))?;
+
+        match collect_cursor(cursor).await?.pop() {
+            Some(rating) => bson::from_document(rating).map_err(|_| Error::Desrilaztion),
+            None => Ok(ProductRating::default()),
+        }
+    }
+}
+
+async fn paginated_reviews(
+    local_db: &LocalDBConection,
+    filters: Document,
+    pagination: Option<Pagination>,
+) -> Result<(Vec<Review>, u64)> {
+    let pagination = pagination.unwrap_or_default();
+
+    let pipeline = [
+        aggregations::match_query(&filters),
+        aggregations::sort(doc! { "created_at": -1 }),
+        aggregations::skip(pagination.offset),
+        aggregations::limit(pagination.amount),
+    ];
+
+    let cursor = local_db
+        .reviews()
+        .aggregate(pipeline, None)
+        .await
+        .map_err(|_| Error::Static("Failed to get reviews"))?;
+
+    let reviews = collect_cursor(cursor)
+        .await?
+        .into_iter()
+        .map(|review| bson::from_document(review).map_err(|_| Error::Desrilaztion))
+        .collect::<Result<Vec<Review>>>()?;
+
+    let count = local_db
+        .reviews()
+        .count_documents(filters, None)
+        .await
+        .map_err(|_| Error::Static("Failed to count reviews"))?;
+
+    Ok((reviews, count))
+}
+
+#[async_trait]
+pub trait ProductRatingFunctions {
+    /// The latest order where a store delivered the product to the user
+    async fn get_delivered_purchase(
+        &self,
+        user_id: &ObjectId,
+        product_id: &ObjectId,
+    ) -> Result<Option<DeliveredPurchase>>;
+    async fn set_product_rating(
+        &self,
+        product_id: &ObjectId,
+        rating: &ProductRating,
+    ) -> Result<UpdateResult>;
+}
+
+#[async_trait]
+impl ProductRatingFunctions for DBConection {
+    async fn get_delivered_purchase(
+        &self,
+        user_id: &ObjectId,
+        product_id: &ObjectId,
+    ) -> Result<Option<DeliveredPurchase>> {
+        let pipeline = [
+            aggregations::match_query(&doc! {
+                Order::fields().user: user_id,
+                Order::fields().parts: {
+                    "$elemMatch": {
+                        Order::fields().parts(false).status: OrderPartStatus::Delivered.to_string(),
+                        Order::fields().parts(false).items(true).product: product_id,
+                    }
+                }
+            }),
+            aggregations::sort(doc! { Order::fields().created_at: -1 }),
+            aggregations::limit(1),
+            aggregations::unwind(Order::fields().parts, false),
+            aggregations::match_query(&doc! {
+                Order::fields().parts(true).status: OrderPartStatus::Delivered.to_string(),
+                format!(
+                    "{}.{}",
+                    Order::fields().parts,
+                    Order::fields().parts(false).items(true).product
+                ): product_id,
+            }),
+            doc! {
+                "$project": {
+                    Order::fields().id: 1,
+                    "store": format!("${}", Order::fields().parts(true).store),
+                }
+            },
+            aggregations::limit(1),
+        ];
+
+        match self.aggregate_orders(pipeline, None, None).await?.pop() {
+            Some(purchase) => bson::from_document(purchase)
+                .map(Some)
+                .map_err(|_| Error::Desrilaztion),
+            None => Ok(None),
+        }
+    }
+
+    async fn set_product_rating(
+        &self,
+        product_id: &ObjectId,
+        rating: &ProductRating,
+    ) -> Result<UpdateResult> {
+        let update = doc! {
+            "$set": {
+                PRODUCT_RATING_FIELD: bson::to_bson(rating).map_err(|_| Error::Desrilaztion)?
+            }
+        };
+
+        self.update_product_by_id(product_id, update, None, None)
+            .await
+    }
+}
+
+/// Recalculates the rating stored on the product from its visible reviews
+pub async fn refresh_product_rating(
+    db: &DBConection,
+    local_db: &LocalDBConection,
+    product_id: &ObjectId,
+) -> Result<()> {
+    let rating = local_db.get_product_rating(product_id).await?;
+
+    db.set_product_rating(product_id, &rating).await?;
+
+    Ok(())
+}
@@ -0,0 +1,364 @@
+use crate::prelude::*;
+use bson::{doc, Bson, Document, Regex};
+use shoppa_core::db::{
+    aggregations,
+    models::{Product, Store},
+};
+use strum_macros::{Display, EnumString};
+
+lazy_static! {
+    pub static ref SEARCH_BACKEND: SearchBackend = ENV_VARS
+        .SEARCH_BACKEND
+        .parse()
+        .expect("SEARCH_BACKEND must be atlas or text");
+}
+
+/// Where the free text search runs.
+/// Atlas Search needs the Atlas indexes, the text backend works on any MongoDB
+/// (dev and CI) with the text indexes from `ensure_search_indexes` in `indexes.rs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum SearchBackend {
+    Atlas,
+    Text,
+}
+
+// Niqqud and cantillation marks, and the geresh and gershayim (also typed as quotes)
+// that can be between the letters of a word in the stored text
+const HEBREW_MARKS_PATTERN: &str =
+    "[\u{0591}-\u{05BD}\u{05BF}\u{05C1}\u{05C2}\u{05C4}\u{05C5}\u{05C7}\u{05F3}\u{05F4}'\"]*";
+
+/// A filter of the search that both backends understand
+#[derive(Debug, Clone)]
+pub enum SearchFilter {
+    /// A string field (or one of the array items) is the value
+    Text { path: String, query: Bson },
+    /// An id field (or one of the array items) is the value
+    Equals { path: String, value: Bson },
+    /// A number field is at least the value
+    Gte { path: String, value: f64 },
+}
+
+impl SearchFilter {
+    pub fn text(path: impl Into<String>, query: impl Into<Bson>) -> Self {
+        Self::Text {
+            path: path.into(),
+            query: query.into(),
+        }
+    }
+
+    pub fn equals(path: impl Into<String>, value: impl Into<Bson>) -> Self {
+        Self::Equals {
+            path: path.into(),
+            value: value.into(),
+        }
+    }
+
+    pub fn gte(path: impl Into<String>, value: f64) -> Self {
+        Self::Gte {
+            path: path.into(),
+            value,
+        }
+    }
+
+    /// As the operators of an Atlas Search compound filter
+    pub fn to_atlas(filters: &[Self]) -> Vec<Document> {
+        filters
+            .iter()
+            .map(|filter| match filter {
+                Self::Text { path, query } => doc! {
+                    "text": {
+                        "path": path,
+                        "query": query
+                    }
+                },
+                Self::Equals { path, value } => doc! {
+                    "equals": {
+                        "value": value,
+                        "path": path
+                    }
+                },
+                Self::Gte { path, value } => doc! {
+                    "range": {
+                        "path": path,
+                        "gte": value
+                    }
+                },
+            })
+            .collect()
+    }
+
+    /// As a `$match` query
+    pub fn to_match(filters: &[Self]) -> Document {
+        let mut query = doc! {};
+
+        for filter in filters {
+            match filter {
+                // any of the values, like the query of an atlas filter can be
+                Self::Text { path, query: value } | Self::Equals { path, value } => match value {
+                    Bson::Array(values) => {
+                        query.insert(path, doc! { "$in": values.clone() });
+                    }
+                    value => {
+                        query.insert(path, value.clone());
+                    }
+                },
+                Self::Gte { path, value } => {
+                    query.insert(path, doc! { "$gte": value });
+                }
+            }
+        }
+
+        query
+    }
+}
+
+/// Lowercases the text and takes out the marks that are not part of the words,
+/// so "שָׁלוֹם" finds "שלום" and "צה\"ל" finds "צהל"
+pub fn normalize_search_text(text: &str) -> String {
+    let mut normalized = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '\u{0591}'..='\u{05BD}'
+            | '\u{05BF}'
+            | '\u{05C1}'..='\u{05C2}'
+            | '\u{05C4}'..='\u{05C5}'
+            | '\u{05C7}'
+            | '\u{05F3}'
+            | '\u{05F4}'
+            | '\''
+            | '"' => {}
+            c if c.is_alphanumeric() => normalized.extend(c.to_lowercase()),
+            // the maqaf and any other punctuation separate words
+            _ => normalized.push(' '),
+        }
+    }
+
+    normalized.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Matches a word that starts with the (normalized) token.
+/// Hebrew final letters match their regular form, a prefix can end before the final letter.
+fn word_prefix_pattern(token: &str) -> String {
+    let letters = token
+        .chars()
+        .map(|c| match c {
+            'כ' | 'ך' => "[כך]".to_string(),
+            'מ' | 'ם' => "[מם]".to_string(),
+            'נ' | 'ן' => "[נן]".to_string(),
+            'פ' | 'ף' => "[פף]".to_string(),
+            'צ' | 'ץ' => "[צץ]".to_string(),
+            c => c.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(HEBREW_MARKS_PATTERN);
+
+    format!("(^|[^\\p{{L}}\\p{{M}}\\p{{N}}]){}", letters)
+}
+
+/// Every token of the text is the start of a word in the field
+fn prefix_match_query(path: &str, text: &str) -> Document {
+    let tokens = normalize_search_text(text);
+
+    // nothing to look for, so nothing is found like with atlas
+    if tokens.is_empty() {
+        return doc! { "_id": { "$exists": false } };
+    }
+
+    let words = tokens
+        .split(' ')
+        .map(|token| {
+            doc! {
+                path: Regex {
+                    pattern: word_prefix_pattern(token),
+                    options: "i".to_string(),
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+
+    doc! { "$and": words }
+}
+
+/// Names that start with the text come before names that only have a word starting with it
+fn prefix_score(path: &str, text: &str) -> Document {
+    let first_token = normalize_search_text(text)
+        .split(' ')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+
+    doc! {
+        "score": {
+            "$cond": [
+                {
+                    "$regexMatch": {
+                        "input": format!("${}", path),
+                        "regex": format!("^{}", word_prefix_pattern(&first_token)),
+                        "options": "i"
+                    }
+                },
+                2,
+                1
+            ]
+        }
+    }
+}
+
+fn text_search_stages(free_text: &Option<String>, filters: Document) -> Vec<Document> {
+    match free_text {
+        Some(free_text) => {
+            let mut query = filters;
+
+            query.insert(
+                "$text",
+                doc! {
+                    "$search": normalize_search_text(free_text),
+                    "$language": "none"
+                },
+            );
+
+            vec![
+                aggregations::match_query(&query),
+                aggregations::add_fields(doc! {
+                    "score": { "$meta": "textScore" }
+                }),
+            ]
+        }
+        None => vec![
+            aggregations::match_query(&filters),
+            aggregations::add_fields(doc! { "score": 0 }),
+        ],
+    }
+}
+
+/// Finds the products that match the free text (if any) and the filters,
+/// the relevance of each product is in its `score` field
+pub fn search_products_stages(
+    free_text: &Option<String>,
+    filters: &[SearchFilter],
+) -> Vec<Document> {
+    match *SEARCH_BACKEND {
+        SearchBackend::Atlas => {
+            let free_text = free_text.as_deref().map(normalize_search_text);
+
+            vec![
+                aggregations::search_products(
+                    &free_text,
+                    &SearchFilter::to_atlas(filters),
+                    Some(1),
+                ),
+                aggregations::add_score_meta(),
+            ]
+        }
+        SearchBackend::Text => text_search_stages(free_text, SearchFilter::to_match(filters)),
+    }
+}
+
+/// Finds the products that match the filters, without a score
+pub fn filter_products_stages(filters: &[SearchFilter]) -> Vec<Document> {
+    match *SEARCH_BACKEND {
+        SearchBackend::Atlas => vec![aggregations::search_products(
+            &None,
+            &SearchFilter::to_atlas(filters),
+            Some(0),
+        )],
+        SearchBackend::Text => vec![aggregations::match_query(&SearchFilter::to_match(filters))],
+    }
+}
+
+/// Products with a name word that starts with each word of the text, with a `score`
+pub fn autocomplete_products_stages(free_text: &str, filters: &[SearchFilter]) -> Vec<Document> {
+    match *SEARCH_BACKEND {
+        SearchBackend::Atlas => vec![
+            aggregations::autocomplete_products_search(
+                &normalize_search_text(free_text),
+                SearchFilter::to_atlas(filters),
+            ),
+            aggregations::add_score_meta(),
+        ],
+        SearchBackend::Text => {
+            let mut query = SearchFilter::to_match(filters);
+
+            query.extend(prefix_match_query(Product::fields().name, free_text));
+
+            vec![
+                aggregations::match_query(&query),
+                aggregations::add_fields(prefix_score(Product::fields().name, free_text)),
+            ]
+        }
+    }
+}
+
+/// The products of the admin and store panels that match the filters,
+/// with a name word that starts with each word of the name (if any) and a `score`
+pub fn product_name_search_stages(
+    product_name: &Option<String>,
+    filters: &[SearchFilter],
+) -> Vec<Document> {
+    match *SEARCH_BACKEND {
+        SearchBackend::Atlas => {
+            let search_stage = if filters.is_empty() && product_name.is_none() {
+                aggregations::match_all()
+            } else {
+                aggregations::product_name_search(
+                    product_name.clone(),
+                    SearchFilter::to_atlas(filters),
+                )
+            };
+
+            vec![search_stage, aggregations::add_score_meta()]
+        }
+        SearchBackend::Text => {
+            let mut query = SearchFilter::to_match(filters);
+
+            match product_name {
+                Some(product_name) => {
+                    query.extend(prefix_match_query(Product::fields().name, product_name));
+
+                    vec![
+                        aggregations::match_query(&query),
+                        aggregations::add_fields(prefix_score(
+                            Product::fields().name,
+                            product_name,
+                        )),
+                    ]
+                }
+                None => vec![
+                    aggregations::match_query(&query),
+                    aggregations::add_fields(doc! { "score": 0 }),
+                ],
+            }
+        }
+    }
+}
+
+/// Stores with a name word that starts with each word of the text, with a `score`
+pub fn autocomplete_stores_stages(free_text: &str) -> Vec<Document> {
+    match *SEARCH_BACKEND {
+        SearchBackend::Atlas => vec![
+            aggregations::autocomplete_store_search(&normalize_search_text(free_text)),
+            aggregations::add_score_meta(),
+        ],
+        SearchBackend::Text => vec![
+            aggregations::match_query(&prefix_match_query(Store::fields().name, free_text)),
+            aggregations::add_fields(prefix_score(Store::fields().name, free_text)),
+        ],
+    }
+}
+
+/// Finds the stores that match the free text (all of them without it), with a `score`
+pub fn search_stores_stages(free_text: &Option<String>) -> Vec<Document> {
+    match *SEARCH_BACKEND {
+        SearchBackend::Atlas => {
+            let free_text = free_text.as_deref().map(normalize_search_text);
+
+            vec![
+                aggregations::search_store(&free_text, &vec![], None),
+                aggregations::add_score_meta(),
+            ]
+        }
+        SearchBackend::Text => text_search_stages(free_text, doc! {}),
+    }
+}
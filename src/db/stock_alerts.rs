@@ -0,0 +1,344 @@
+use crate::{
+    db::{collect_cursor, is_duplicate_key_error, LocalDBConection},
+    prelude::{types::*, *},
+};
+use axum::async_trait;
+use bson::{doc, oid::ObjectId, DateTime as BsonDateTime};
+use mongodb::{
+    options::{FindOneAndUpdateOptions, FindOptions, UpdateOptions},
+    results::{DeleteResult, UpdateResult},
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use shoppa_core::db::{
+    aggregations,
+    models::{
+        EmbeddedDocument, Product, ProductItem, ProductItemStatus, ProductStatus, User, UserStatus,
+    },
+    DBConection,
+};
+use strum_macros::Display;
+
+pub const STOCK_ALERTS_COLLECTION: &str = "stock_alerts";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum StockAlertKind {
+    BackInStock,
+    PriceDrop,
+}
+
+impl StockAlertKind {
+    pub fn template_id(&self) -> &'static str {
+        match self {
+            Self::BackInStock => &ENV_VARS.BACK_IN_STOCK_TEMPLATE_ID,
+            Self::PriceDrop => &ENV_VARS.PRICE_DROP_TEMPLATE_ID,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StockAlert {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub user: ObjectId,
+    pub product: ObjectId,
+    pub item_id: ObjectId,
+    pub kind: StockAlertKind,
+    // The item price when the user subscribed
+    pub price: f64,
+    // Set when a change to the item matched the alert, the alert is then waiting to be sent
+    pub triggered_at: Option<BsonDateTime>,
+    // An alert is sent at most once, it is deactivated once it's sent
+    pub active: bool,
+    pub created_at: BsonDateTime,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct CreateStockAlertPayload {
+    pub product_id: ObjectId,
+    pub item_id: ObjectId,
+    pub kind: StockAlertKind,
+}
+
+/// Where to send the alerts of a user
+#[derive(Debug, Clone, Deserialize)]
+pub struct StockAlertRecipient {
+    pub email: String,
+    pub name: Option<String>,
+}
+
+impl StockAlert {
+    pub fn new(user: ObjectId, payload: CreateStockAlertPayload, price: f64) -> Self {
+        Self {
+            id: ObjectId::new(),
+            user,
+            product: payload.product_id,
+            item_id: payload.item_id,
+            kind: payload.kind,
+            price,
+            triggered_at: None,
+            active: true,
+            created_at: BsonDateTime::now(),
+        }
+    }
+}
+
+/// Whether a customer can buy the item right now
+pub fn is_item_available(product: &Product, item: &ProductItem) -> bool {
+    product.status == ProductStatus::Active
+        && item.status == ProductItemStatus::Active
+        && item.in_storage > 0
+}
+
+#[async_trait]
+pub trait StockAlertFunctions {
+    fn stock_alerts(&self) -> Collection<StockAlert>;
+    /// Returns false if the user already has the same active alert
+    async fn insert_new_stock_alert(&self, alert: &StockAlert) -> Result<bool>;
+    async fn get_user_stock_alerts(&self, user_id: &ObjectId) -> Result<Vec<StockAlert>>;
+    async fn delete_stock_alert(
+        &self,
+        user_id: &ObjectId,
+        alert_id: &ObjectId,
+    ) -> Result<DeleteResult>;
+    /// Marks the active alerts of the item as waiting to be sent.
+    /// Price drop alerts are only triggered if the price is lower than when the user subscribed.
+    async fn trigger_stock_alerts(
+        &self,
+        product_id: &ObjectId,
+        item_id: &ObjectId,
+        kind: StockAlertKind,
+        price: f64,
+    ) -> Result<UpdateResult>;
+    /// Resets the trigger of the oldest triggered alert of the kinds and returns it to be sent.
+    /// The alert stays active until `deactivate_stock_alert`, so an alert that could not be sent
+    /// is sent the next time it's triggered
+    async fn take_triggered_stock_alert(
+        &self,
+        kinds: &[StockAlertKind],
+    ) -> Result<Option<StockAlert>>;
+    async fn deactivate_stock_alert(&self, alert_id: &ObjectId) -> Result<UpdateResult>;
+}
+
+#[async_trait]
+impl StockAlertFunctions for LocalDBConection {
+    fn stock_alerts(&self) -> Collection<StockAlert> {
+        self.collection(STOCK_ALERTS_COLLECTION)
+    }
+
+    async fn insert_new_stock_alert(&self, alert: &StockAlert) -> Result<bool> {
+        let filters = doc! {
+            "user": alert.user,
+            "product": alert.product,
+            "item_id": alert.item_id,
+            "kind": alert.kind.to_string(),
+            "active": true,
+        };
+
+        let update = doc! {
+            "$setOnInsert": bson::to_bson(alert).map_err(|_| Error::Desrilaztion)?
+        };
+
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        // two upserts at the same time can both miss, the unique index rejects the second
+        match self
+            .stock_alerts()
+            .update_one(filters, update, options)
+            .await
+        {
+            Ok(res) => Ok(res.upserted_id.is_some()),
+            Err(e) if is_duplicate_key_error(&e) => Ok(false),
+            Err(_) => Err(Error::Static("Failed to insert stock alert")),
+        }
+    }
+
+    async fn get_user_stock_alerts(&self, user_id: &ObjectId) -> Result<Vec<StockAlert>> {
+        let options = FindOptions::builder()
+            .sort(doc! { "created_at": -1 })
+            .build();
+
+        let cursor = self
+            .stock_alerts()
+            .find(doc! { "user": user_id, "active": true }, options)
+            .await
+            .map_err(|_| Error::Static("Failed to get stock alerts"))?;
+
+        collect_cursor(cursor).await
+    }
+
+    async fn delete_stock_alert(
+        &self,
+        user_id: &ObjectId,
+        alert_id: &ObjectId,
+    ) -> Result<DeleteResult> {
+        self.stock_alerts()
+            .delete_one(doc! { "_id": alert_id, "user": user_id }, None)
+            .await
+            .map_err(|_| Error::Static("Failed to delete stock alert"))
+    }
+
+    async fn trigger_stock_alerts(
+        &self,
+        product_id: &ObjectId,
+        item_id: &ObjectId,
+        kind: StockAlertKind,
+        price: f64,
+    ) -> Result<UpdateResult> {
+        let mut filters = doc! {
+            "product": product_id,
+            "item_id": item_id,
+            "kind": kind.to_string(),
+            "active": true,
+            "triggered_at": null,
+        };
+
+        if kind == StockAlertKind::PriceDrop {
+            filters.insert("price", doc! { "$gt": price });
+        }
+
+        let update = doc! {
+            "$set": {
+                "triggered_at": BsonDateTime::now()
+            }
+        };
+
+        self.stock_alerts()
+            .update_many(filters, update, None)
+            .await
+            .map_err(|_| Error::Static("Failed to trigger stock alerts"))
+    }
+
+    async fn take_triggered_stock_alert(
+        &self,
+        kinds: &[StockAlertKind],
+    ) -> Result<Option<StockAlert>> {
+        let kinds = kinds
+            .iter()
+            .map(|kind| kind.to_string())
+            .collect::<Vec<_>>();
+
+        let filters = doc! {
+            "kind": {
+                "$in": kinds
+            },
+            "active": true,
+            "triggered_at": {
+                "$ne": null
+            },
+        };
+
+        let options = FindOneAndUpdateOptions::builder()
+            .sort(doc! { "triggered_at": 1 })
+            .build();
+
+        self.stock_alerts()
+            .find_one_and_update(filters, doc! { "$set": { "triggered_at": null } }, options)
+            .await
+            .map_err(|_| Error::Static("Failed to get triggered stock alert"))
+    }
+
+    async fn deactivate_stock_alert(&self, alert_id: &ObjectId) -> Result<UpdateResult> {
+        self.stock_alerts()
+            .update_one(
+                doc! { "_id": alert_id },
+                doc! { "$set": { "active": false } },
+                None,
+            )
+            .await
+            .map_err(|_| Error::Static("Failed to deactivate stock alert"))
+    }
+}
+
+/// Triggers the alerts of the item if the edit made it available again or lowered its price.
+/// Kinds without an email template are left untouched so they can be sent once one is set.
+pub async fn queue_stock_alerts(
+    local_db: &LocalDBConection,
+    before: &Product,
+    after: &Product,
+    item_id: &ObjectId,
+) -> Result<()> {
+    fn find_item<'a>(product: &'a Product, item_id: &ObjectId) -> Option<&'a ProductItem> {
+        product.items.iter().find(|item| item.id() == item_id)
+    }
+
+    let (before_item, after_item) = match (find_item(before, item_id), find_item(after, item_id)) {
+        (Some(before_item), Some(after_item)) => (before_item, after_item),
+        _ => return Ok(()),
+    };
+
+    if after_item.status == ProductItemStatus::Deleted {
+        return Ok(());
+    }
+
+    if !StockAlertKind::BackInStock.template_id().is_empty()
+        && is_item_available(after, after_item)
+        && !is_item_available(before, before_item)
+    {
+        local_db
+            .trigger_stock_alerts(
+                after.id()?,
+                item_id,
+                StockAlertKind::BackInStock,
+                after_item.price,
+            )
+            .await?;
+    }
+
+    if !StockAlertKind::PriceDrop.template_id().is_empty() && after_item.price < before_item.price {
+        local_db
+            .trigger_stock_alerts(
+                after.id()?,
+                item_id,
+                StockAlertKind::PriceDrop,
+                after_item.price,
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
+#[async_trait]
+pub trait StockAlertRecipientFunctions {
+    /// None if the user can't get emails anymore
+    async fn get_stock_alert_recipient(
+        &self,
+        user_id: &ObjectId,
+    ) -> Result<Option<StockAlertRecipient>>;
+}
+
+#[async_trait]
+impl StockAlertRecipientFunctions for DBConection {
+    async fn get_stock_alert_recipient(
+        &self,
+        user_id: &ObjectId,
+    ) -> Result<Option<StockAlertRecipient>> {
+        let pipeline = [
+            aggregations::match_query(&doc! {
+                User::fields().id: user_id,
+                User::fields().status: {
+                    "$nin": [UserStatus::Deleted, UserStatus::Banned, UserStatus::Guest]
+                },
+                User::fields().email: {
+                    "$type": "string"
+                },
+            }),
+            doc! {
+                "$project": {
+                    User::fields().email: 1,
+                    User::fields().name: 1,
+                }
+            },
+        ];
+
+        match self.aggregate_users(pipeline, None, None).await?.pop() {
+            Some(recipient) => bson::from_document(recipient)
+                .map(Some)
+                .map_err(|_| Error::Desrilaztion),
+            None => Ok(None),
+        }
+    }
+}
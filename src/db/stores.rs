@@ -1,4 +1,7 @@
-use crate::prelude::{types::*, *};
+use crate::{
+    db::{autocomplete_stores_stages, search_stores_stages},
+    prelude::{types::*, *},
+};
 use axum::async_trait;
 use bson::{doc, oid::ObjectId, Document};
 use mongodb::options::{AggregateOptions, FindOneAndUpdateOptions};
@@ -181,13 +184,13 @@ impl StoreFunctions for DBConection {
         free_text: String,
         options: Option<AggregateOptions>,
     ) -> Result<Vec<Document>> {
-        let pipeline = [
-            aggregations::autocomplete_store_search(&free_text),
-            aggregations::add_score_meta(),
+        let mut pipeline = autocomplete_stores_stages(&free_text);
+
+        pipeline.extend([
             aggregations::sort_by_score(),
             aggregations::limit(10),
             aggregations::project(ProjectIdOptions::Keep, [models::Store::fields().name], None),
-        ];
+        ]);
 
         self.aggregate_stores(pipeline, options, None).await
     }
@@ -200,9 +203,9 @@ impl StoreFunctions for DBConection {
     ) -> Result<(Vec<Document>, u64)> {
         let pagination = pagination.unwrap_or_default();
 
-        let pipeline = [
-            aggregations::search_store(&free_text, &vec![], None),
-            aggregations::add_score_meta(),
+        let mut pipeline = search_stores_stages(&free_text);
+
+        pipeline.extend([
             aggregations::sort_by_score(),
             aggregations::skip(pagination.offset),
             aggregations::limit(pagination.amount),
@@ -241,7 +244,7 @@ impl StoreFunctions for DBConection {
                 ],
                 None,
             ),
-        ];
+        ]);
 
         let stores = self.aggregate_stores(pipeline, options, None).await?;
 
@@ -0,0 +1,365 @@
+use crate::{
+    helpers::money::{Currency, Money},
+    prelude::{types::*, *},
+};
+use axum::async_trait;
+use bson::{doc, oid::ObjectId};
+use chrono::NaiveDate;
+use mongodb::{options::FindOneAndUpdateOptions, results::UpdateResult, ClientSession};
+use shoppa_core::db::{
+    aggregations,
+    models::{Order, Store, StoreBusinessType},
+    DBConection,
+};
+use std::collections::HashMap;
+
+// The tax terms of the store that are not part of its business type,
+// stored on the store document (under `tax`)
+pub const STORE_TAX_FIELD: &str = "tax";
+// The VAT breakdown of each order part is recorded on the part (under `tax`) at payment time
+pub const ORDER_PART_TAX_FIELD: &str = "tax";
+
+// The Israeli VAT percent by the date it came into force, oldest first
+const VAT_RATES: [((i32, u32, u32), f64); 2] = [((2015, 10, 1), 17.0), ((2025, 1, 1), 18.0)];
+
+/// The VAT percent in force at the given date
+pub fn vat_percent_at(date: NaiveDate) -> f64 {
+    VAT_RATES
+        .iter()
+        .rev()
+        .find(|((year, month, day), _)| {
+            NaiveDate::from_ymd_opt(*year, *month, *day)
+                .map(|from| from <= date)
+                .unwrap_or(false)
+        })
+        .map(|(_, percent)| *percent)
+        .unwrap_or(VAT_RATES[0].1)
+}
+
+/// An exempt dealer (עוסק פטור) doesn't charge VAT
+fn is_vat_exempt_business_type(business_type: &StoreBusinessType) -> bool {
+    matches!(business_type, StoreBusinessType::ExemptDealer)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct StoreTaxTerms {
+    // Israeli consumer prices include VAT, a store can list them without it
+    pub prices_include_vat: bool,
+}
+
+impl Default for StoreTaxTerms {
+    fn default() -> Self {
+        Self {
+            prices_include_vat: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoreTax {
+    pub vat_exempt: bool,
+    pub prices_include_vat: bool,
+}
+
+impl Default for StoreTax {
+    fn default() -> Self {
+        Self {
+            vat_exempt: false,
+            prices_include_vat: true,
+        }
+    }
+}
+
+impl StoreTax {
+    pub fn vat_percent(&self, date: NaiveDate) -> f64 {
+        if self.vat_exempt {
+            0.0
+        } else {
+            vat_percent_at(date)
+        }
+    }
+
+    /// What the customer pays for a store price, VAT is added to prices that don't include it
+    pub fn gross_price(&self, price: f64, currency: Currency, date: NaiveDate) -> f64 {
+        if self.prices_include_vat || self.vat_exempt {
+            return price;
+        }
+
+        let price = Money::from_major(price, currency);
+
+        (price + vat_of_net(price, self.vat_percent(date))).to_major()
+    }
+}
+
+fn vat_of_net(net: Money, percent: f64) -> Money {
+    Money::new(
+        (net.amount as f64 * percent / 100.0).round() as i64,
+        net.currency,
+    )
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TaxLine {
+    pub net: f64,
+    pub vat: f64,
+    pub gross: f64,
+}
+
+impl TaxLine {
+    /// Takes the VAT out of a price that includes it
+    pub fn from_gross(gross: Money, percent: f64) -> Self {
+        let vat = Money::new(
+            (gross.amount as f64 * percent / (100.0 + percent)).round() as i64,
+            gross.currency,
+        );
+
+        Self {
+            net: (gross - vat).to_major(),
+            vat: vat.to_major(),
+            gross: gross.to_major(),
+        }
+    }
+
+    fn sum(currency: Currency, lines: &[TaxLine]) -> Self {
+        let sum = |amount: fn(&TaxLine) -> f64| {
+            Money::sum(
+                currency,
+                lines
+                    .iter()
+                    .map(|line| Money::from_major(amount(line), currency)),
+            )
+            .to_major()
+        };
+
+        Self {
+            net: sum(|line| line.net),
+            vat: sum(|line| line.vat),
+            gross: sum(|line| line.gross),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderItemTax {
+    pub item_id: ObjectId,
+    pub quantity: u32,
+    pub unit: TaxLine,
+    pub total: TaxLine,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderPartTax {
+    pub vat_exempt: bool,
+    pub vat_percent: f64,
+    pub items: Vec<OrderItemTax>,
+    pub delivery: TaxLine,
+    // Negative, the share of the order discount the part got
+    pub discount: TaxLine,
+    // What was charged for the part
+    pub total: TaxLine,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderPartTaxEntry {
+    pub store: ObjectId,
+    // None for orders from before the breakdown was recorded
+    pub tax: Option<OrderPartTax>,
+}
+
+/// What the VAT breakdown of one order part is calculated from, in the charge currency
+pub struct TaxableItem {
+    pub item_id: ObjectId,
+    pub price: f64,
+    pub quantity: u32,
+}
+
+impl OrderPartTax {
+    /// The VAT of each line is taken out of its gross amount,
+    /// the part total is the sum of the lines so it adds up to what was charged
+    pub fn new(
+        tax: &StoreTax,
+        items: &[TaxableItem],
+        delivery_cost: f64,
+        discount: f64,
+        currency: Currency,
+        date: NaiveDate,
+    ) -> Self {
+        let vat_percent = tax.vat_percent(date);
+
+        let items: Vec<OrderItemTax> = items
+            .iter()
+            .map(|item| {
+                let price = Money::from_major(item.price, currency);
+
+                OrderItemTax {
+                    item_id: item.item_id,
+                    quantity: item.quantity,
+                    unit: TaxLine::from_gross(price, vat_percent),
+                    total: TaxLine::from_gross(price.times(item.quantity), vat_percent),
+                }
+            })
+            .collect();
+
+        let delivery = TaxLine::from_gross(Money::from_major(delivery_cost, currency), vat_percent);
+        let discount = TaxLine::from_gross(Money::from_major(-discount, currency), vat_percent);
+
+        let mut lines: Vec<TaxLine> = items.iter().map(|item| item.total).collect();
+        lines.push(delivery);
+        lines.push(discount);
+
+        Self {
+            vat_exempt: tax.vat_exempt,
+            vat_percent,
+            total: TaxLine::sum(currency, &lines),
+            items,
+            delivery,
+            discount,
+        }
+    }
+}
+
+#[async_trait]
+pub trait TaxFunctions {
+    /// Stores without tax terms list their prices with VAT
+    async fn get_stores_tax(&self, store_ids: &[ObjectId]) -> Result<HashMap<ObjectId, StoreTax>>;
+    async fn set_store_tax_terms(
+        &self,
+        store_id: &ObjectId,
+        terms: &StoreTaxTerms,
+    ) -> Result<Option<Store>>;
+    /// One breakdown for each order part, in the order of the parts
+    async fn set_order_parts_tax(
+        &self,
+        order_id: &ObjectId,
+        taxes: &[OrderPartTax],
+        session: Option<&mut ClientSession>,
+    ) -> Result<UpdateResult>;
+    async fn get_order_parts_tax(&self, order_id: &ObjectId) -> Result<Vec<OrderPartTaxEntry>>;
+}
+
+#[async_trait]
+impl TaxFunctions for DBConection {
+    async fn get_stores_tax(&self, store_ids: &[ObjectId]) -> Result<HashMap<ObjectId, StoreTax>> {
+        let pipeline = [
+            aggregations::match_query(&doc! {
+                Store::fields().id: {
+                    "$in": store_ids.to_vec()
+                }
+            }),
+            doc! {
+                "$project": {
+                    Store::fields().id: 1,
+                    Store::fields().legal_information(true).business_type: 1,
+                    STORE_TAX_FIELD: 1,
+                }
+            },
+        ];
+
+        let stores = self.aggregate_stores(pipeline, None, None).await?;
+
+        let mut taxes = HashMap::with_capacity(stores.len());
+
+        for store in stores {
+            let store_id = store
+                .get_object_id("_id")
+                .map_err(|_| Error::Desrilaztion)?;
+
+            let business_type = store
+                .get_document(Store::fields().legal_information)
+                .ok()
+                .and_then(|legal_information| {
+                    legal_information.get(Store::fields().legal_information(false).business_type)
+                })
+                .map(|business_type| {
+                    bson::from_bson::<StoreBusinessType>(business_type.clone())
+                        .map_err(|_| Error::Desrilaztion)
+                })
+                .transpose()?;
+
+            let vat_exempt = business_type
+                .as_ref()
+                .map(is_vat_exempt_business_type)
+                .unwrap_or(false);
+
+            let terms = match store.get_document(STORE_TAX_FIELD) {
+                Ok(terms) => bson::from_document(terms.clone()).map_err(|_| Error::Desrilaztion)?,
+                Err(_) => StoreTaxTerms::default(),
+            };
+
+            taxes.insert(
+                store_id,
+                StoreTax {
+                    vat_exempt,
+                    prices_include_vat: terms.prices_include_vat,
+                },
+            );
+        }
+
+        Ok(taxes)
+    }
+
+    async fn set_store_tax_terms(
+        &self,
+        store_id: &ObjectId,
+        terms: &StoreTaxTerms,
+    ) -> Result<Option<Store>> {
+        let update = doc! {
+            "$set": {
+                STORE_TAX_FIELD: bson::to_bson(terms).map_err(|_| Error::Desrilaztion)?
+            }
+        };
+
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(mongodb::options::ReturnDocument::After)
+            .build();
+
+        self.find_and_update_store_by_id(store_id, update, Some(options), None)
+            .await
+    }
+
+    async fn set_order_parts_tax(
+        &self,
+        order_id: &ObjectId,
+        taxes: &[OrderPartTax],
+        session: Option<&mut ClientSession>,
+    ) -> Result<UpdateResult> {
+        let mut set = doc! {};
+
+        for (index, tax) in taxes.iter().enumerate() {
+            set.insert(
+                format!(
+                    "{}.{}.{}",
+                    Order::fields().parts,
+                    index,
+                    ORDER_PART_TAX_FIELD
+                ),
+                bson::to_bson(tax).map_err(|_| Error::Desrilaztion)?,
+            );
+        }
+
+        self.update_order_by_id(order_id, doc! { "$set": set }, None, session)
+            .await
+    }
+
+    async fn get_order_parts_tax(&self, order_id: &ObjectId) -> Result<Vec<OrderPartTaxEntry>> {
+        let pipeline = [
+            aggregations::match_query(&doc! {
+                Order::fields().id: order_id,
+            }),
+            aggregations::unwind(Order::fields().parts, false),
+            doc! {
+                "$project": {
+                    "_id": 0,
+                    "store": format!("${}", Order::fields().parts(true).store),
+                    "tax": format!("${}.{}", Order::fields().parts, ORDER_PART_TAX_FIELD),
+                }
+            },
+        ];
+
+        self.aggregate_orders(pipeline, None, None)
+            .await?
+            .into_iter()
+            .map(|entry| bson::from_document(entry).map_err(|_| Error::Desrilaztion))
+            .collect()
+    }
+}
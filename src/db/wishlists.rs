@@ -0,0 +1,354 @@
+use crate::{
+    db::{collect_cursor, LocalDBConection},
+    prelude::{types::*, *},
+};
+use axum::async_trait;
+use bson::{doc, oid::ObjectId, DateTime as BsonDateTime};
+use mongodb::{
+    options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument},
+    results::{DeleteResult, UpdateResult},
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use shoppa_core::random::random_string;
+
+pub const WISHLISTS_COLLECTION: &str = "wishlists";
+
+pub const MAX_WISHLISTS_PER_USER: u64 = 20;
+pub const MAX_WISHLIST_ITEMS: usize = 200;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WishlistItem {
+    pub product: ObjectId,
+    pub item_id: ObjectId,
+    pub added_at: BsonDateTime,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Wishlist {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub user: ObjectId,
+    pub name: String,
+    pub items: Vec<WishlistItem>,
+    // Set while the list is shared, the share link only works with the current secret
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub share_secret: Option<String>,
+    pub created_at: BsonDateTime,
+    pub updated_at: BsonDateTime,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct CreateWishlistPayload {
+    #[validate(length(min = 1, max = 64))]
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct WishlistItemPayload {
+    pub product_id: ObjectId,
+    pub item_id: ObjectId,
+}
+
+impl Wishlist {
+    pub fn new(user: ObjectId, name: String) -> Self {
+        let now = BsonDateTime::now();
+
+        Self {
+            id: ObjectId::new(),
+            user,
+            name: name.trim().to_string(),
+            items: Vec::new(),
+            share_secret: None,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    pub fn has_item(&self, product: &ObjectId, item_id: &ObjectId) -> bool {
+        self.items
+            .iter()
+            .any(|item| &item.product == product && &item.item_id == item_id)
+    }
+}
+
+#[async_trait]
+pub trait WishlistFunctions {
+    fn wishlists(&self) -> Collection<Wishlist>;
+    async fn insert_new_wishlist(&self, wishlist: &Wishlist) -> Result<()>;
+    async fn get_user_wishlists(&self, user_id: &ObjectId) -> Result<Vec<Wishlist>>;
+    async fn get_user_wishlist(
+        &self,
+        user_id: &ObjectId,
+        wishlist_id: &ObjectId,
+    ) -> Result<Option<Wishlist>>;
+    async fn get_wishlist_by_id(&self, wishlist_id: &ObjectId) -> Result<Option<Wishlist>>;
+    async fn delete_wishlist(
+        &self,
+        user_id: &ObjectId,
+        wishlist_id: &ObjectId,
+    ) -> Result<DeleteResult>;
+    /// Does nothing if the item is already in the list or the list is full
+    async fn add_item_to_wishlist(
+        &self,
+        user_id: &ObjectId,
+        wishlist_id: &ObjectId,
+        product: &ObjectId,
+        item_id: &ObjectId,
+    ) -> Result<UpdateResult>;
+    async fn remove_item_from_wishlist(
+        &self,
+        user_id: &ObjectId,
+        wishlist_id: &ObjectId,
+        product: &ObjectId,
+        item_id: &ObjectId,
+    ) -> Result<UpdateResult>;
+    /// Keeps the current secret if the list is already shared
+    async fn share_wishlist(
+        &self,
+        user_id: &ObjectId,
+        wishlist_id: &ObjectId,
+    ) -> Result<Option<Wishlist>>;
+    /// Links that were shared before stop working
+    async fn unshare_wishlist(
+        &self,
+        user_id: &ObjectId,
+        wishlist_id: &ObjectId,
+    ) -> Result<UpdateResult>;
+    /// Moves the wishlists of `from` (a guest) to `to`.
+    /// A list with the same name as one `to` already has is merged into it.
+    async fn merge_wishlists(&self, from: &ObjectId, to: &ObjectId) -> Result<()>;
+}
+
+#[async_trait]
+impl WishlistFunctions for LocalDBConection {
+    fn wishlists(&self) -> Collection<Wishlist> {
+        self.collection(WISHLISTS_COLLECTION)
+    }
+
+    async fn insert_new_wishlist(&self, wishlist: &Wishlist) -> Result<()> {
+        self.wishlists()
+            .insert_one(wishlist, None)
+            .await
+            .map_err(|_| Error::Static("Failed to insert wishlist"))?;
+
+        Ok(())
+    }
+
+    async fn get_user_wishlists(&self, user_id: &ObjectId) -> Result<Vec<Wishlist>> {
+        let options = FindOptions::builder()
+            .sort(doc! { "created_at": 1 })
+            .build();
+
+        let cursor = self
+            .wishlists()
+            .find(doc! { "user": user_id }, options)
+            .await
+            .map_err(|_| Error::Static("Failed to get wishlists"))?;
+
+        collect_cursor(cursor).await
+    }
+
+    async fn get_user_wishlist(
+        &self,
+        user_id: &ObjectId,
+        wishlist_id: &ObjectId,
+    ) -> Result<Option<Wishlist>> {
+        self.wishlists()
+            .find_one(doc! { "_id": wishlist_id, "user": user_id }, None)
+            .await
+            .map_err(|_| Error::Static("Failed to get wishlist"))
+    }
+
+    async fn get_wishlist_by_id(&self, wishlist_id: &ObjectId) -> Result<Option<Wishlist>> {
+        self.wishlists()
+            .find_one(doc! { "_id": wishlist_id }, None)
+            .await
+            .map_err(|_| Error::Static("Failed to get wishlist"))
+    }
+
+    async fn delete_wishlist(
+        &self,
+        user_id: &ObjectId,
+        wishlist_id: &ObjectId,
+    ) -> Result<DeleteResult> {
+        self.wishlists()
+            .delete_one(doc! { "_id": wishlist_id, "user": user_id }, None)
+            .await
+            .map_err(|_| Error::Static("Failed to delete wishlist"))
+    }
+
+    async fn add_item_to_wishlist(
+        &self,
+        user_id: &ObjectId,
+        wishlist_id: &ObjectId,
+        product: &ObjectId,
+        item_id: &ObjectId,
+    ) -> Result<UpdateResult> {
+        let filters = doc! {
+            "_id": wishlist_id,
+            "user": user_id,
+            "items": {
+                "$not": {
+                    "$elemMatch": {
+                        "product": product,
+                        "item_id": item_id,
+                    }
+                }
+            },
+            format!("items.{}", MAX_WISHLIST_ITEMS - 1): {
+                "$exists": false
+            },
+        };
+
+        let item = WishlistItem {
+            product: product.clone(),
+            item_id: item_id.clone(),
+            added_at: BsonDateTime::now(),
+        };
+
+        let update = doc! {
+            "$push": {
+                "items": bson::to_bson(&item).map_err(|_| Error::Desrilaztion)?
+            },
+            "$set": {
+                "updated_at": BsonDateTime::now()
+            }
+        };
+
+        self.wishlists()
+            .update_one(filters, update, None)
+            .await
+            .map_err(|_| Error::Static("Failed to add item to wishlist"))
+    }
+
+    async fn remove_item_from_wishlist(
+        &self,
+        user_id: &ObjectId,
+        wishlist_id: &ObjectId,
+        product: &ObjectId,
+        item_id: &ObjectId,
+    ) -> Result<UpdateResult> {
+        let update = doc! {
+            "$pull": {
+                "items": {
+                    "product": product,
+                    "item_id": item_id,
+                }
+            },
+            "$set": {
+                "updated_at": BsonDateTime::now()
+            }
+        };
+
+        self.wishlists()
+            .update_one(doc! { "_id": wishlist_id, "user": user_id }, update, None)
+            .await
+            .map_err(|_| Error::Static("Failed to remove item from wishlist"))
+    }
+
+    async fn share_wishlist(
+        &self,
+        user_id: &ObjectId,
+        wishlist_id: &ObjectId,
+    ) -> Result<Option<Wishlist>> {
+        let update = vec![doc! {
+            "$set": {
+                "share_secret": {
+                    "$ifNull": ["$share_secret", random_string(32)]
+                }
+            }
+        }];
+
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+
+        self.wishlists()
+            .find_one_and_update(
+                doc! { "_id": wishlist_id, "user": user_id },
+                update,
+                options,
+            )
+            .await
+            .map_err(|_| Error::Static("Failed to share wishlist"))
+    }
+
+    async fn unshare_wishlist(
+        &self,
+        user_id: &ObjectId,
+        wishlist_id: &ObjectId,
+    ) -> Result<UpdateResult> {
+        self.wishlists()
+            .update_one(
+                doc! { "_id": wishlist_id, "user": user_id },
+                doc! { "$unset": { "share_secret": "" } },
+                None,
+            )
+            .await
+            .map_err(|_| Error::Static("Failed to unshare wishlist"))
+    }
+
+    async fn merge_wishlists(&self, from: &ObjectId, to: &ObjectId) -> Result<()> {
+        if from == to {
+            return Ok(());
+        }
+
+        let from_lists = self.get_user_wishlists(from).await?;
+
+        if from_lists.is_empty() {
+            return Ok(());
+        }
+
+        let to_lists = self.get_user_wishlists(to).await?;
+
+        for list in from_lists {
+            match to_lists
+                .iter()
+                .find(|to_list| to_list.name.to_lowercase() == list.name.to_lowercase())
+            {
+                Some(to_list) => {
+                    let mut items = to_list.items.clone();
+
+                    for item in list.items {
+                        if items.len() >= MAX_WISHLIST_ITEMS {
+                            break;
+                        }
+
+                        if !to_list.has_item(&item.product, &item.item_id) {
+                            items.push(item);
+                        }
+                    }
+
+                    self.wishlists()
+                        .update_one(
+                            doc! { "_id": to_list.id },
+                            doc! {
+                                "$set": {
+                                    "items": bson::to_bson(&items).map_err(|_| Error::Desrilaztion)?,
+                                    "updated_at": BsonDateTime::now(),
+                                }
+                            },
+                            None,
+                        )
+                        .await
+                        .map_err(|_| Error::Static("Failed to merge wishlist"))?;
+
+                    self.delete_wishlist(from, &list.id).await?;
+                }
+                None => {
+                    self.wishlists()
+                        .update_one(
+                            doc! { "_id": list.id },
+                            doc! { "$set": { "user": to } },
+                            None,
+                        )
+                        .await
+                        .map_err(|_| Error::Static("Failed to merge wishlist"))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
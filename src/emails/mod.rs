@@ -44,3 +44,211 @@ impl AdminEmailFunctions for EmailClient {
             .set_template_args(args)
     }
 }
+
+pub trait CustomerEmailFunctions {
+    fn abandoned_cart_email(
+        &self,
+        name: String,
+        items_count: u32,
+        unsubscribe_token: String,
+    ) -> ShoppaMailBuilder;
+    fn order_confirmation_email(
+        &self,
+        name: String,
+        order_number: String,
+        total: String,
+        status_token: String,
+    ) -> ShoppaMailBuilder;
+    fn order_status_email(
+        &self,
+        name: String,
+        order_number: String,
+        store_name: String,
+        status: String,
+        status_token: String,
+    ) -> ShoppaMailBuilder;
+    fn back_in_stock_email(
+        &self,
+        name: String,
+        product_name: String,
+        product_id: String,
+        price: String,
+    ) -> ShoppaMailBuilder;
+    fn price_drop_email(
+        &self,
+        name: String,
+        product_name: String,
+        product_id: String,
+        old_price: String,
+        price: String,
+    ) -> ShoppaMailBuilder;
+}
+
+pub trait StoreEmailFunctions {
+    fn new_order_email(
+        &self,
+        store_name: String,
+        order_id: String,
+        order_number: String,
+        items_count: u32,
+        total: String,
+    ) -> ShoppaMailBuilder;
+}
+
+fn order_status_link(status_token: &str) -> String {
+    format!(
+        "{}/orders/status?token={}",
+        ENV_VARS.SHOPPA_URL, status_token
+    )
+}
+
+fn product_link(product_id: &str) -> String {
+    format!("{}/products/{}", ENV_VARS.SHOPPA_URL, product_id)
+}
+
+impl CustomerEmailFunctions for EmailClient {
+    fn abandoned_cart_email(
+        &self,
+        name: String,
+        items_count: u32,
+        unsubscribe_token: String,
+    ) -> ShoppaMailBuilder {
+        let builder = self.build_mail(None, "");
+
+        let mut args = HashMap::new();
+
+        args.insert("name".to_string(), name);
+        args.insert("items_count".to_string(), items_count.to_string());
+        args.insert(
+            "cart_link".to_string(),
+            format!("{}/cart", ENV_VARS.SHOPPA_URL),
+        );
+        args.insert(
+            "unsubscribe_link".to_string(),
+            format!(
+                "{}/unsubscribe?token={}",
+                ENV_VARS.SHOPPA_URL, unsubscribe_token
+            ),
+        );
+
+        builder
+            .set_template_id(ENV_VARS.ABANDONED_CART_TEMPLATE_ID.clone())
+            .set_template_args(args)
+    }
+
+    fn order_confirmation_email(
+        &self,
+        name: String,
+        order_number: String,
+        total: String,
+        status_token: String,
+    ) -> ShoppaMailBuilder {
+        let builder = self.build_mail(None, "");
+
+        let mut args = HashMap::new();
+
+        args.insert("name".to_string(), name);
+        args.insert("order_number".to_string(), order_number);
+        args.insert("total".to_string(), total);
+        args.insert("status_link".to_string(), order_status_link(&status_token));
+
+        builder
+            .set_template_id(ENV_VARS.ORDER_CONFIRMATION_TEMPLATE_ID.clone())
+            .set_template_args(args)
+    }
+
+    fn order_status_email(
+        &self,
+        name: String,
+        order_number: String,
+        store_name: String,
+        status: String,
+        status_token: String,
+    ) -> ShoppaMailBuilder {
+        let builder = self.build_mail(None, "");
+
+        let mut args = HashMap::new();
+
+        args.insert("name".to_string(), name);
+        args.insert("order_number".to_string(), order_number);
+        args.insert("store_name".to_string(), store_name);
+        args.insert("status".to_string(), status);
+        args.insert("status_link".to_string(), order_status_link(&status_token));
+
+        builder
+            .set_template_id(ENV_VARS.ORDER_STATUS_TEMPLATE_ID.clone())
+            .set_template_args(args)
+    }
+
+    fn back_in_stock_email(
+        &self,
+        name: String,
+        product_name: String,
+        product_id: String,
+        price: String,
+    ) -> ShoppaMailBuilder {
+        let builder = self.build_mail(None, "");
+
+        let mut args = HashMap::new();
+
+        args.insert("name".to_string(), name);
+        args.insert("product_name".to_string(), product_name);
+        args.insert("price".to_string(), price);
+        args.insert("product_link".to_string(), product_link(&product_id));
+
+        builder
+            .set_template_id(ENV_VARS.BACK_IN_STOCK_TEMPLATE_ID.clone())
+            .set_template_args(args)
+    }
+
+    fn price_drop_email(
+        &self,
+        name: String,
+        product_name: String,
+        product_id: String,
+        old_price: String,
+        price: String,
+    ) -> ShoppaMailBuilder {
+        let builder = self.build_mail(None, "");
+
+        let mut args = HashMap::new();
+
+        args.insert("name".to_string(), name);
+        args.insert("product_name".to_string(), product_name);
+        args.insert("old_price".to_string(), old_price);
+        args.insert("price".to_string(), price);
+        args.insert("product_link".to_string(), product_link(&product_id));
+
+        builder
+            .set_template_id(ENV_VARS.PRICE_DROP_TEMPLATE_ID.clone())
+            .set_template_args(args)
+    }
+}
+
+impl StoreEmailFunctions for EmailClient {
+    fn new_order_email(
+        &self,
+        store_name: String,
+        order_id: String,
+        order_number: String,
+        items_count: u32,
+        total: String,
+    ) -> ShoppaMailBuilder {
+        let builder = self.build_mail(None, "");
+
+        let mut args = HashMap::new();
+
+        args.insert("store_name".to_string(), store_name);
+        args.insert("order_number".to_string(), order_number);
+        args.insert("items_count".to_string(), items_count.to_string());
+        args.insert("total".to_string(), total);
+        args.insert(
+            "order_link".to_string(),
+            format!("{}/orders/{}", ENV_VARS.STORE_PANEL_URL, order_id),
+        );
+
+        builder
+            .set_template_id(ENV_VARS.STORE_NEW_ORDER_TEMPLATE_ID.clone())
+            .set_template_args(args)
+    }
+}
@@ -1,4 +1,5 @@
 use crate::{
+    db::CHECKOUT_SESSION_EXP,
     helpers::{env::ENV_VARS, types::Cookeys},
     prelude::*,
     tokens::{CHECKOUT_SESSION_TOKEN_MANAGER, USER_TOKEN_MANAGER},
@@ -45,8 +46,7 @@ pub trait CookieManager {
         self.set_cookie(
             &Cookeys::CheckoutSession,
             CHECKOUT_SESSION_TOKEN_MANAGER.generate_token(checkout_session, None)?,
-            // 30 minutes
-            60 * 30,
+            CHECKOUT_SESSION_EXP,
             true,
         );
 
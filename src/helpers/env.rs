@@ -17,6 +17,8 @@ pub struct EnvVariables {
     pub MONGODB_URI: String,
     pub PORT: u16,
     pub HOST: std::net::IpAddr,
+    // The proxies whose `X-Real-IP` header is trusted, nginx runs next to the api
+    pub TRUSTED_PROXIES: Vec<std::net::IpAddr>,
     #[validate(length(min = 1))]
     pub COOKIE_DOMAIN: String,
     #[validate(length(min = 1))]
@@ -36,12 +38,37 @@ pub struct EnvVariables {
     pub STORE_USER_REGISTRATION_TOKEN_SECRET: String,
     #[validate(length(equal = 32))]
     pub CHECKOUT_SESSION_TOKEN_SECRET: String,
+    #[validate(length(equal = 32))]
+    pub GUEST_ORDER_TOKEN_SECRET: String,
     #[validate(length(min = 1))]
     pub STORE_PANEL_URL: String,
     #[validate(length(min = 1))]
     pub SHOPPA_URL: String,
     #[validate(length(min = 1))]
     pub ASSETS_URL: String,
+    pub PAYMENT_PROVIDER: String,
+    pub PAYMENT_WEBHOOK_SECRET: String,
+    pub EXCHANGE_RATES: String,
+    // `atlas` for Atlas Search or `text` for a plain MongoDB text index
+    pub SEARCH_BACKEND: String,
+    // No abandoned cart reminders are sent without a template
+    pub ABANDONED_CART_TEMPLATE_ID: String,
+    // How long a cart is left untouched before it counts as abandoned
+    pub ABANDONED_CART_AFTER_MINUTES: i64,
+    // The most reminders one user ever gets
+    pub ABANDONED_CART_MAX_REMINDERS: u32,
+    #[validate(length(equal = 32))]
+    pub EMAIL_UNSUBSCRIBE_TOKEN_SECRET: String,
+    #[validate(length(equal = 32))]
+    pub WISHLIST_SHARE_TOKEN_SECRET: String,
+    #[validate(length(equal = 32))]
+    pub PRODUCTS_FEED_TOKEN_SECRET: String,
+    // Order emails of a type without a template are not sent
+    pub ORDER_CONFIRMATION_TEMPLATE_ID: String,
+    pub STORE_NEW_ORDER_TEMPLATE_ID: String,
+    pub ORDER_STATUS_TEMPLATE_ID: String,
+    pub BACK_IN_STOCK_TEMPLATE_ID: String,
+    pub PRICE_DROP_TEMPLATE_ID: String,
 }
 
 impl EnvVariables {
@@ -61,6 +88,16 @@ impl EnvVariables {
                 .unwrap_or_else(|_| String::from("127.0.0.1"))
                 .parse()
                 .expect("HOST must be a valid IP address"),
+            TRUSTED_PROXIES: env::var("TRUSTED_PROXIES")
+                .unwrap_or_else(|_| String::from("127.0.0.1"))
+                .split(",")
+                .map(|ip| ip.trim())
+                .filter(|ip| !ip.is_empty())
+                .map(|ip| {
+                    ip.parse()
+                        .expect("TRUSTED_PROXIES must be a comma separated list of IP addresses")
+                })
+                .collect(),
             COOKIE_DOMAIN: env::var("COOKIE_DOMAIN").expect("COOKIE_DOMAIN must be set"),
             CORS_DOMAIN: env::var("CORS_DOMAIN")
                 .expect("CORS_DOMAIN must be set")
@@ -111,6 +148,64 @@ impl EnvVariables {
                     println!("CHECKOUT_SESSION_TOKEN_SECRET not set, using random value",);
                     random_string(32)
                 }),
+            GUEST_ORDER_TOKEN_SECRET: env::var("GUEST_ORDER_TOKEN_SECRET")
+                .expect("GUEST_ORDER_TOKEN_SECRET must be set"),
+            PAYMENT_PROVIDER: env::var("PAYMENT_PROVIDER").unwrap_or_else(|_| "core".to_string()),
+            PAYMENT_WEBHOOK_SECRET: env::var("PAYMENT_WEBHOOK_SECRET").unwrap_or_else(|_| {
+                println!("PAYMENT_WEBHOOK_SECRET not set, payment webhooks will be rejected");
+                String::new()
+            }),
+            EXCHANGE_RATES: env::var("EXCHANGE_RATES").unwrap_or_else(|_| {
+                println!("EXCHANGE_RATES not set, only the platform currency is supported");
+                String::new()
+            }),
+            SEARCH_BACKEND: env::var("SEARCH_BACKEND").unwrap_or_else(|_| {
+                println!("SEARCH_BACKEND not set, using atlas");
+                String::from("atlas")
+            }),
+            ABANDONED_CART_TEMPLATE_ID: env::var("ABANDONED_CART_TEMPLATE_ID").unwrap_or_else(
+                |_| {
+                    println!("ABANDONED_CART_TEMPLATE_ID not set, cart reminders will not be sent");
+                    String::new()
+                },
+            ),
+            ABANDONED_CART_AFTER_MINUTES: env::var("ABANDONED_CART_AFTER_MINUTES")
+                .unwrap_or_else(|_| String::from("1440"))
+                .parse()
+                .expect("ABANDONED_CART_AFTER_MINUTES must be a valid number"),
+            ABANDONED_CART_MAX_REMINDERS: env::var("ABANDONED_CART_MAX_REMINDERS")
+                .unwrap_or_else(|_| String::from("3"))
+                .parse()
+                .expect("ABANDONED_CART_MAX_REMINDERS must be a valid number"),
+            EMAIL_UNSUBSCRIBE_TOKEN_SECRET: env::var("EMAIL_UNSUBSCRIBE_TOKEN_SECRET")
+                .expect("EMAIL_UNSUBSCRIBE_TOKEN_SECRET must be set"),
+            WISHLIST_SHARE_TOKEN_SECRET: env::var("WISHLIST_SHARE_TOKEN_SECRET")
+                .expect("WISHLIST_SHARE_TOKEN_SECRET must be set"),
+            PRODUCTS_FEED_TOKEN_SECRET: env::var("PRODUCTS_FEED_TOKEN_SECRET")
+                .expect("PRODUCTS_FEED_TOKEN_SECRET must be set"),
+            ORDER_CONFIRMATION_TEMPLATE_ID: env::var("ORDER_CONFIRMATION_TEMPLATE_ID")
+                .unwrap_or_else(|_| {
+                    println!("ORDER_CONFIRMATION_TEMPLATE_ID not set, order confirmations will not be sent");
+                    String::new()
+                }),
+            STORE_NEW_ORDER_TEMPLATE_ID: env::var("STORE_NEW_ORDER_TEMPLATE_ID").unwrap_or_else(
+                |_| {
+                    println!("STORE_NEW_ORDER_TEMPLATE_ID not set, stores will not be emailed about new orders");
+                    String::new()
+                },
+            ),
+            ORDER_STATUS_TEMPLATE_ID: env::var("ORDER_STATUS_TEMPLATE_ID").unwrap_or_else(|_| {
+                println!("ORDER_STATUS_TEMPLATE_ID not set, order status emails will not be sent");
+                String::new()
+            }),
+            BACK_IN_STOCK_TEMPLATE_ID: env::var("BACK_IN_STOCK_TEMPLATE_ID").unwrap_or_else(|_| {
+                println!("BACK_IN_STOCK_TEMPLATE_ID not set, back in stock alerts will not be sent");
+                String::new()
+            }),
+            PRICE_DROP_TEMPLATE_ID: env::var("PRICE_DROP_TEMPLATE_ID").unwrap_or_else(|_| {
+                println!("PRICE_DROP_TEMPLATE_ID not set, price drop alerts will not be sent");
+                String::new()
+            }),
         }
     }
     pub fn is_production(&self) -> bool {
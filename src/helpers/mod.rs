@@ -1,5 +1,7 @@
 pub mod cookies;
 pub mod env;
+pub mod money;
+pub mod rate_limit;
 pub mod security;
 pub mod setup;
 pub mod types;
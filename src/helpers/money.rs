@@ -0,0 +1,338 @@
+use crate::prelude::{types::*, *};
+use std::{
+    collections::HashMap,
+    ops::{Add, AddAssign, Sub, SubAssign},
+};
+use strum_macros::{Display, EnumString};
+
+lazy_static! {
+    pub static ref EXCHANGE_RATES: ExchangeRates = ExchangeRates::parse(&ENV_VARS.EXCHANGE_RATES)
+        .expect("EXCHANGE_RATES must be a comma separated list of CODE=rate");
+}
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Default,
+    Serialize,
+    Deserialize,
+    Display,
+    EnumString,
+)]
+#[serde(rename_all = "UPPERCASE")]
+#[strum(serialize_all = "UPPERCASE")]
+pub enum Currency {
+    // The platform currency, prices of stores that did not declare a currency are in it
+    #[default]
+    Ils,
+    Usd,
+    Eur,
+}
+
+impl Currency {
+    /// How many minor units make one major unit (agorot in a shekel)
+    pub fn minor_per_major(&self) -> i64 {
+        match self {
+            Self::Ils | Self::Usd | Self::Eur => 100,
+        }
+    }
+}
+
+/// An amount in the minor units of its currency.
+/// Prices are stored in major units, all arithmetic on them goes through this
+/// so totals are exact to the minor unit.
+/// Adding or subtracting amounts of different currencies is a bug and panics,
+/// `checked_add` and `checked_sub` are for when it can happen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Money {
+    pub amount: i64,
+    pub currency: Currency,
+}
+
+impl Money {
+    pub fn new(amount: i64, currency: Currency) -> Self {
+        Self { amount, currency }
+    }
+
+    pub fn zero(currency: Currency) -> Self {
+        Self::new(0, currency)
+    }
+
+    /// Rounds to the nearest minor unit
+    pub fn from_major(amount: f64, currency: Currency) -> Self {
+        Self::new(
+            (amount * currency.minor_per_major() as f64).round() as i64,
+            currency,
+        )
+    }
+
+    pub fn to_major(&self) -> f64 {
+        self.amount as f64 / self.currency.minor_per_major() as f64
+    }
+
+    /// Returns None if the currencies are different
+    pub fn checked_add(&self, other: &Money) -> Option<Money> {
+        (self.currency == other.currency)
+            .then(|| Money::new(self.amount + other.amount, self.currency))
+    }
+
+    /// Returns None if the currencies are different
+    pub fn checked_sub(&self, other: &Money) -> Option<Money> {
+        (self.currency == other.currency)
+            .then(|| Money::new(self.amount - other.amount, self.currency))
+    }
+
+    pub fn sum(currency: Currency, amounts: impl IntoIterator<Item = Money>) -> Money {
+        amounts
+            .into_iter()
+            .fold(Money::zero(currency), |total, amount| total + amount)
+    }
+
+    /// The amount of `quantity` units at this price
+    pub fn times(&self, quantity: u32) -> Money {
+        Money::new(self.amount * quantity as i64, self.currency)
+    }
+
+    pub fn is_positive(&self) -> bool {
+        self.amount > 0
+    }
+
+    /// `percent` of the amount, rounded to the nearest minor unit
+    pub fn percent(&self, percent: f64) -> Money {
+        Money::new(
+            (self.amount as f64 * percent / 100.0).round() as i64,
+            self.currency,
+        )
+    }
+
+    /// The share `part` is of `whole` of the amount, rounded to the nearest minor unit.
+    /// Zero if `whole` is zero
+    pub fn share(&self, part: Money, whole: Money) -> Money {
+        if whole.amount == 0 {
+            return Money::zero(self.currency);
+        }
+
+        Money::new(
+            (self.amount as f64 * part.amount as f64 / whole.amount as f64).round() as i64,
+            self.currency,
+        )
+    }
+
+    /// Rounds an amount in major units to whole minor units
+    pub fn round_major(amount: f64, currency: Currency) -> f64 {
+        Money::from_major(amount, currency).to_major()
+    }
+
+    /// `rate` is how many units of `to` one unit of this currency is worth
+    pub fn convert(&self, to: Currency, rate: f64) -> Money {
+        Money::from_major(self.to_major() * rate, to)
+    }
+}
+
+/// The total of a checkout session, from the items total and delivery cost of each part,
+/// less the order discount
+pub fn checkout_total(
+    currency: Currency,
+    parts: impl IntoIterator<Item = (Money, Money)>,
+    discount: Money,
+) -> Money {
+    Money::sum(
+        currency,
+        parts
+            .into_iter()
+            .map(|(items_total, delivery_cost)| items_total + delivery_cost),
+    ) - discount
+}
+
+/// The total of an order part, from the amounts its checkout session part stored
+pub fn order_part_total(items_total: f64, delivery_cost: f64, currency: Currency) -> Money {
+    Money::from_major(items_total, currency) + Money::from_major(delivery_cost, currency)
+}
+
+/// The total of an order, from the totals its parts stored less the order discount
+pub fn order_total(
+    part_totals: impl IntoIterator<Item = f64>,
+    discount: f64,
+    currency: Currency,
+) -> Money {
+    Money::sum(
+        currency,
+        part_totals
+            .into_iter()
+            .map(|part_total| Money::from_major(part_total, currency)),
+    ) - Money::from_major(discount, currency)
+}
+
+impl Add for Money {
+    type Output = Money;
+
+    fn add(self, other: Money) -> Money {
+        self.checked_add(&other)
+            .expect("Can't add amounts of different currencies")
+    }
+}
+
+impl Sub for Money {
+    type Output = Money;
+
+    fn sub(self, other: Money) -> Money {
+        self.checked_sub(&other)
+            .expect("Can't subtract amounts of different currencies")
+    }
+}
+
+impl AddAssign for Money {
+    fn add_assign(&mut self, other: Money) {
+        *self = *self + other;
+    }
+}
+
+impl SubAssign for Money {
+    fn sub_assign(&mut self, other: Money) {
+        *self = *self - other;
+    }
+}
+
+/// How many units of each currency one unit of the platform currency is worth
+#[derive(Debug, Clone)]
+pub struct ExchangeRates {
+    rates: HashMap<Currency, f64>,
+}
+
+impl ExchangeRates {
+    /// From `USD=0.27,EUR=0.25`, the platform currency is always there with a rate of 1
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut rates = HashMap::from([(Currency::default(), 1.0)]);
+
+        for pair in value
+            .split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+        {
+            let (code, rate) = pair.split_once('=')?;
+            let currency = code.trim().parse::<Currency>().ok()?;
+            let rate = rate.trim().parse::<f64>().ok().filter(|rate| *rate > 0.0)?;
+
+            rates.insert(currency, rate);
+        }
+
+        Some(Self { rates })
+    }
+
+    pub fn supports(&self, currency: Currency) -> bool {
+        self.rates.contains_key(&currency)
+    }
+
+    /// How many units of `to` one unit of `from` is worth
+    pub fn rate(&self, from: Currency, to: Currency) -> Option<f64> {
+        if from == to {
+            return Some(1.0);
+        }
+
+        Some(self.rates.get(&to)? / self.rates.get(&from)?)
+    }
+
+    /// Returns None if one of the currencies has no rate
+    pub fn convert(&self, amount: f64, from: Currency, to: Currency) -> Option<f64> {
+        let rate = self.rate(from, to)?;
+
+        Some(Money::from_major(amount, from).convert(to, rate).to_major())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn currency() -> impl Strategy<Value = Currency> {
+        prop_oneof![
+            Just(Currency::Ils),
+            Just(Currency::Usd),
+            Just(Currency::Eur)
+        ]
+    }
+
+    // A part of a checkout, the price and quantity of each item and the delivery cost,
+    // amounts in minor units
+    fn part() -> impl Strategy<Value = (Vec<(i64, u32)>, i64)> {
+        (
+            prop::collection::vec((0..1_000_000i64, 1..20u32), 1..8),
+            0..10_000i64,
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn major_round_trips(amount in -1_000_000_000_000i64..1_000_000_000_000, currency in currency()) {
+            let money = Money::new(amount, currency);
+
+            prop_assert_eq!(Money::from_major(money.to_major(), currency), money);
+        }
+
+        #[test]
+        fn checked_add_rejects_other_currencies(
+            a in any::<i32>(),
+            b in any::<i32>(),
+            (x, y) in (currency(), currency()).prop_filter("different currencies", |(x, y)| x != y),
+        ) {
+            let a = Money::new(a as i64, x);
+            let b = Money::new(b as i64, y);
+
+            prop_assert_eq!(a.checked_add(&b), None);
+            prop_assert_eq!(a.checked_sub(&b), None);
+            prop_assert_eq!(a.checked_add(&Money::new(0, x)), Some(a));
+        }
+
+        // The session total and the order total are built from the same parts in different
+        // ways, the session sums the parts as it quotes them and the order sums the stored
+        // major amounts
+        #[test]
+        fn session_and_order_totals_match(
+            parts in prop::collection::vec(part(), 1..5),
+            discount in 0..100_000i64,
+            currency in currency(),
+        ) {
+            let parts: Vec<(Money, Money)> = parts
+                .into_iter()
+                .map(|(items, delivery)| {
+                    let items_total = Money::sum(
+                        currency,
+                        items
+                            .into_iter()
+                            .map(|(price, quantity)| Money::new(price, currency).times(quantity)),
+                    );
+
+                    (items_total, Money::new(delivery, currency))
+                })
+                .collect();
+            let discount = Money::new(discount, currency);
+
+            let expected = Money::sum(
+                currency,
+                parts.iter().map(|(items_total, delivery)| *items_total + *delivery),
+            ) - discount;
+
+            let session_total = checkout_total(currency, parts.iter().copied(), discount);
+
+            let part_totals: Vec<f64> = parts
+                .iter()
+                .map(|(items_total, delivery)| {
+                    order_part_total(items_total.to_major(), delivery.to_major(), currency)
+                        .to_major()
+                })
+                .collect();
+
+            let order_total = order_total(part_totals, discount.to_major(), currency);
+
+            prop_assert_eq!(session_total, expected);
+            prop_assert_eq!(order_total, expected);
+        }
+    }
+}
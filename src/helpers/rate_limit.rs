@@ -0,0 +1,74 @@
+use crate::helpers::env::ENV_VARS;
+use axum::http::HeaderMap;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// nginx passes the address of the client in this header (see nginx/api.conf)
+const REAL_IP_HEADER: &str = "x-real-ip";
+
+// Once the map gets this big, the keys whose window is over are dropped
+const PRUNE_ABOVE_KEYS: usize = 10_000;
+
+/// A fixed window, in memory rate limiter.
+/// The limit is per process, which is enough to slow down guessing.
+pub struct RateLimiter {
+    max_hits: u32,
+    window: Duration,
+    hits: Mutex<HashMap<String, (u32, Instant)>>,
+}
+
+impl RateLimiter {
+    pub fn new(max_hits: u32, window_secs: u64) -> Self {
+        Self {
+            max_hits,
+            window: Duration::from_secs(window_secs),
+            hits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a hit for the key, returns false if the key is over the limit
+    pub fn hit(&self, key: &str) -> bool {
+        let now = Instant::now();
+
+        let mut hits = match self.hits.lock() {
+            Ok(hits) => hits,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        if hits.len() > PRUNE_ABOVE_KEYS {
+            hits.retain(|_, (_, started_at)| now.duration_since(*started_at) < self.window);
+        }
+
+        let (count, started_at) = hits.entry(key.to_string()).or_insert((0, now));
+
+        if now.duration_since(*started_at) >= self.window {
+            *count = 0;
+            *started_at = now;
+        }
+
+        *count += 1;
+
+        *count <= self.max_hits
+    }
+}
+
+/// The address of the client to rate limit by.
+/// Behind nginx every request comes from 127.0.0.1, so the address is taken
+/// from the `X-Real-IP` header, but only when the request came from one of `TRUSTED_PROXIES`.
+/// Anyone else could set the header to anything, so their own address is used.
+pub fn client_ip(headers: &HeaderMap, peer: &SocketAddr) -> String {
+    if !ENV_VARS.TRUSTED_PROXIES.contains(&peer.ip()) {
+        return peer.ip().to_string();
+    }
+
+    headers
+        .get(REAL_IP_HEADER)
+        .and_then(|ip| ip.to_str().ok())
+        .map(|ip| ip.trim().to_string())
+        .filter(|ip| !ip.is_empty())
+        .unwrap_or_else(|| peer.ip().to_string())
+}
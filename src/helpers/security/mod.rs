@@ -1,5 +1,7 @@
 mod tokens;
 mod cors;
+mod signature;
 
 pub use tokens::*;
-pub use cors::*;
\ No newline at end of file
+pub use cors::*;
+pub use signature::*;
\ No newline at end of file
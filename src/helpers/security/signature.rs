@@ -0,0 +1,26 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Checks a hex encoded HMAC-SHA256 signature of the payload (in constant time).
+/// An empty secret never verifies.
+pub fn verify_payload_signature(secret: &str, payload: &[u8], signature: &str) -> bool {
+    if secret.is_empty() {
+        return false;
+    }
+
+    let signature = match hex::decode(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+
+    let mut mac = match HmacSha256::new_from_slice(secret.as_bytes()) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+
+    mac.update(payload);
+
+    mac.verify_slice(&signature).is_ok()
+}
@@ -1,5 +1,6 @@
 pub mod error_code;
-use shoppa_core::{file_storage::StorageClient, email_sender::EmailClient, payments::PaymentClient, invoice_service::InvoiceClient};
+use shoppa_core::{file_storage::StorageClient, email_sender::EmailClient, invoice_service::InvoiceClient};
+use crate::payments::PaymentProvider;
 use strum_macros::{Display, EnumString};
 
 use axum::Extension;
@@ -7,7 +8,7 @@ use std::sync::Arc;
 
 pub type AxumStorgeClientExtension = Extension<Arc<StorageClient>>;
 pub type AxumEmailClientExtension = Extension<Arc<EmailClient>>;
-pub type AxumPaymentClientExtension = Extension<Arc<PaymentClient>>;
+pub type AxumPaymentClientExtension = Extension<Arc<dyn PaymentProvider>>;
 pub type AxumInvoiceClientExtension = Extension<Arc<InvoiceClient>>;
 
 
@@ -1,7 +1,9 @@
 pub mod api;
 pub mod db;
 pub mod helpers;
+pub mod payments;
 pub mod prelude;
+pub mod workers;
 mod tokens;
 mod emails;
 #[macro_use]
@@ -2,14 +2,15 @@ use axum::{Extension, Router};
 use dotenv::dotenv;
 use shoppa_api::{
     api,
-    helpers::{env::ENV_VARS, security::get_cors_layer, setup},
+    db::{ensure_search_indexes, ensure_unique_indexes, LocalDBConection, SEARCH_BACKEND},
+    helpers::{env::ENV_VARS, money::EXCHANGE_RATES, security::get_cors_layer, setup},
+    payments, workers,
 };
 use shoppa_core::{
     db::DBConection,
     email_sender::{EmailAddress, EmailClient},
     file_storage::StorageClient,
     invoice_service::InvoiceClient,
-    payments::PaymentClient,
 };
 use std::net::SocketAddr;
 use std::sync::Arc;
@@ -35,6 +36,10 @@ async fn main() {
         .map_err(|e| panic!("ENV validation failed: \n{:?}", e))
         .unwrap();
 
+    // fail on a bad exchange rates table now and not on the first checkout
+    lazy_static::initialize(&EXCHANGE_RATES);
+    lazy_static::initialize(&SEARCH_BACKEND);
+
     let storge_client = Arc::new(StorageClient::connect().await);
 
     let email_client = Arc::new(EmailClient::new(EmailAddress::new(
@@ -48,22 +53,65 @@ async fn main() {
             .expect("Failed to connect to DB"),
     );
 
-    let payment_client = Arc::new(PaymentClient::new());
+    let local_db = Arc::new(
+        LocalDBConection::connect()
+            .await
+            .expect("Failed to connect to DB"),
+    );
+
+    ensure_search_indexes(&local_db)
+        .await
+        .expect("Failed to create the search indexes");
+
+    ensure_unique_indexes(&local_db)
+        .await
+        .expect("Failed to create the unique indexes");
+
+    let payment_client = payments::payment_provider_from_env();
 
     let invoice_client = Arc::new(
         InvoiceClient::new()
     );
 
+    tokio::spawn(workers::run_post_payment_worker(
+        db.clone(),
+        local_db.clone(),
+        storge_client.clone(),
+        invoice_client.clone(),
+        email_client.clone(),
+    ));
+
+    tokio::spawn(workers::run_checkout_reservations_worker(db.clone()));
+
+    tokio::spawn(workers::run_authorizations_worker(
+        db.clone(),
+        local_db.clone(),
+        payment_client.clone(),
+    ));
+
+    tokio::spawn(workers::run_abandoned_carts_worker(
+        db.clone(),
+        email_client.clone(),
+    ));
+
+    tokio::spawn(workers::run_stock_alerts_worker(
+        db.clone(),
+        local_db.clone(),
+        email_client.clone(),
+    ));
+
     let app = Router::new()
         .nest("/api/v1", api::v1::router())
         .nest("/api/management", api::management::router())
         .nest("/api/stores", api::stores::router())
         .nest("/api/invoices", api::invoices::router())
+        .nest("/api/payments", api::payments::router())
         .layer(Extension(invoice_client))
         .layer(Extension(payment_client))
         .layer(Extension(email_client))
         .layer(Extension(storge_client))
         .layer(Extension(db))
+        .layer(Extension(local_db))
         .layer(CookieManagerLayer::new())
         .layer(get_cors_layer())
         .layer(TraceLayer::new_for_http());
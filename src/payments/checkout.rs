@@ -0,0 +1,254 @@
+use super::{ChargeSavedCard, PaymentProvider};
+use crate::{db::OrderPaymentStatus, helpers::money::Money, prelude::*};
+use shoppa_core::payments::types::{ChargeCreditCard, ChargeResult, CreditCard, TransactionInfo};
+
+/// Either a new card or one the user saved from a previous order
+pub enum PaymentSource {
+    NewCard(CreditCard),
+    // The token of a saved card
+    SavedCard(String),
+}
+
+/// What happens to the order around the charge. The order is saved as pending
+/// before the card is charged, and is settled by the outcome of the charge.
+#[async_trait]
+pub trait CheckoutSettlement: Send + Sync {
+    /// Records the charge on the order, if it fails the order stays pending
+    /// and its charge is reconciled by the order number
+    async fn record_payment(
+        &self,
+        transaction_info: TransactionInfo,
+        status: OrderPaymentStatus,
+    ) -> Result<()>;
+
+    /// Nothing was charged, the order is discarded and what it held is given back
+    async fn discard_order(&self);
+}
+
+#[derive(Debug)]
+pub enum CheckoutPayment {
+    Paid(OrderPaymentStatus),
+    Declined(String),
+    // The provider could not be reached
+    Failed(String),
+    // The card was charged but the payment was not recorded on the order,
+    // paying again would charge twice
+    Unrecorded,
+}
+
+/// Charges the order and settles it by the result.
+/// When the provider supports it the full amount is only authorized,
+/// each store share is captured when the store ships its part.
+pub async fn pay_checkout_order(
+    provider: &dyn PaymentProvider,
+    settlement: &dyn CheckoutSettlement,
+    order_number: &str,
+    amount: Money,
+    source: PaymentSource,
+) -> CheckoutPayment {
+    let authorize = provider.supports_authorization();
+
+    let charge_res = match source {
+        PaymentSource::NewCard(credit_card) => {
+            let charge = ChargeCreditCard {
+                order_number: order_number.to_string(),
+                amount: amount.to_major(),
+                credit_card,
+                currency_code: Some(amount.currency.to_string()),
+            };
+
+            if authorize {
+                provider.authorize_credit_card(charge).await
+            } else {
+                provider.charge_credit_card(charge).await
+            }
+        }
+        PaymentSource::SavedCard(token) => {
+            let charge = ChargeSavedCard {
+                order_number: order_number.to_string(),
+                amount: amount.to_major(),
+                token,
+                currency_code: Some(amount.currency.to_string()),
+            };
+
+            if authorize {
+                provider.authorize_saved_card(charge).await
+            } else {
+                provider.charge_saved_card(charge).await
+            }
+        }
+    };
+
+    let transaction_info = match charge_res {
+        Ok(ChargeResult::Success(transaction_info)) => transaction_info,
+        Ok(ChargeResult::Failure(err)) => {
+            tracing::error!(
+                "Credit card of order {} was declined: {}",
+                order_number,
+                err
+            );
+            settlement.discard_order().await;
+            return CheckoutPayment::Declined(err);
+        }
+        Err(e) => {
+            tracing::error!(
+                "Failed to charge credit card of order {}: {}",
+                order_number,
+                e
+            );
+            settlement.discard_order().await;
+            return CheckoutPayment::Failed(e.to_string());
+        }
+    };
+
+    let status = if authorize {
+        OrderPaymentStatus::Authorized
+    } else {
+        OrderPaymentStatus::Captured
+    };
+
+    // From here on the card is charged. If the payment can't be recorded the order stays
+    // pending, and the post payment worker fails its job once it gives up waiting
+    match settlement.record_payment(transaction_info, status).await {
+        Ok(()) => CheckoutPayment::Paid(status),
+        Err(_) => {
+            tracing::error!("Failed to save payment of order {}", order_number);
+            CheckoutPayment::Unrecorded
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        helpers::money::Currency,
+        payments::{FakePaymentProvider, VoidCharge, VoidResult},
+    };
+    use std::sync::Mutex;
+
+    const APPROVED_CARD: &str = "4580000000000001";
+    const DECLINED_CARD: &str = "4580000000000002";
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Settled {
+        Recorded(String, OrderPaymentStatus),
+        Discarded,
+    }
+
+    // Keeps what the checkout did to the order, `fail_record` is a crash
+    // between the charge and saving it
+    #[derive(Default)]
+    struct FakeSettlement {
+        fail_record: bool,
+        settled: Mutex<Vec<Settled>>,
+    }
+
+    impl FakeSettlement {
+        fn settled(&self) -> Vec<Settled> {
+            self.settled.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl CheckoutSettlement for FakeSettlement {
+        async fn record_payment(
+            &self,
+            transaction_info: TransactionInfo,
+            status: OrderPaymentStatus,
+        ) -> Result<()> {
+            if self.fail_record {
+                return Err(Error::Static("Failed to save order"));
+            }
+
+            self.settled
+                .lock()
+                .unwrap()
+                .push(Settled::Recorded(transaction_info.token, status));
+
+            Ok(())
+        }
+
+        async fn discard_order(&self) {
+            self.settled.lock().unwrap().push(Settled::Discarded);
+        }
+    }
+
+    fn ils(amount: i64) -> Money {
+        Money::new(amount, Currency::Ils)
+    }
+
+    async fn pay(
+        provider: &FakePaymentProvider,
+        settlement: &FakeSettlement,
+        card: &str,
+    ) -> CheckoutPayment {
+        let token = provider.save_card(card);
+
+        pay_checkout_order(
+            provider,
+            settlement,
+            "1000",
+            ils(12_345),
+            PaymentSource::SavedCard(token),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn approved_charge_is_recorded_as_authorized() {
+        let provider = FakePaymentProvider::new();
+        let settlement = FakeSettlement::default();
+
+        let res = pay(&provider, &settlement, APPROVED_CARD).await;
+
+        assert!(matches!(
+            res,
+            CheckoutPayment::Paid(OrderPaymentStatus::Authorized)
+        ));
+
+        let token = match settlement.settled().as_slice() {
+            [Settled::Recorded(token, OrderPaymentStatus::Authorized)] => token.clone(),
+            settled => panic!("unexpected settlement: {:?}", settled),
+        };
+
+        // the whole amount is held for the stores to capture
+        let void = provider
+            .void_charge(VoidCharge {
+                order_number: "1000".to_string(),
+                token,
+                amount: 123.45,
+            })
+            .await
+            .unwrap();
+
+        assert!(matches!(void, VoidResult::Success));
+    }
+
+    #[tokio::test]
+    async fn declined_charge_discards_the_order() {
+        let provider = FakePaymentProvider::new();
+        let settlement = FakeSettlement::default();
+
+        let res = pay(&provider, &settlement, DECLINED_CARD).await;
+
+        assert!(matches!(res, CheckoutPayment::Declined(_)));
+        assert_eq!(settlement.settled(), vec![Settled::Discarded]);
+    }
+
+    #[tokio::test]
+    async fn charge_that_was_not_saved_keeps_the_order() {
+        let provider = FakePaymentProvider::new();
+        let settlement = FakeSettlement {
+            fail_record: true,
+            ..Default::default()
+        };
+
+        let res = pay(&provider, &settlement, APPROVED_CARD).await;
+
+        // the card was charged, so the order is neither discarded nor paid again
+        assert!(matches!(res, CheckoutPayment::Unrecorded));
+        assert!(settlement.settled().is_empty());
+        assert_eq!(provider.open_charges(), 1);
+    }
+}
@@ -0,0 +1,430 @@
+use super::{
+    CaptureCharge, CaptureInfo, CaptureResult, ChargeSavedCard, PaymentProvider, RefundCreditCard,
+    RefundInfo, RefundResult, VoidCharge, VoidResult,
+};
+use crate::{
+    helpers::money::{Currency, Money},
+    prelude::*,
+};
+use serde_json::json;
+use shoppa_core::{
+    payments::types::{ChargeCreditCard, ChargeResult, CreditCard, TransactionInfo},
+    random::random_string,
+};
+use std::{collections::HashMap, str::FromStr, sync::Mutex, time::Duration};
+
+// Cards ending with these digits are declined or never answered, any other card is approved
+pub const FAKE_DECLINED_CARD_SUFFIX: &str = "0002";
+pub const FAKE_TIMEOUT_CARD_SUFFIX: &str = "0119";
+
+const FAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The amounts are kept in minor units, so partial captures and voids empty a charge exactly
+struct FakeCharge {
+    // Kept so the charge token can be charged again as a saved card
+    card_number: String,
+    // The authorized amount, a direct charge is captured in full right away
+    amount: Money,
+    captured: Money,
+    refunded: Money,
+    voided: Money,
+}
+
+impl FakeCharge {
+    fn open_amount(&self) -> Money {
+        self.amount - self.captured - self.voided
+    }
+
+    fn amount(&self, major: f64) -> Money {
+        Money::from_major(major, self.amount.currency)
+    }
+}
+
+fn charge_currency(currency_code: &Option<String>) -> Currency {
+    currency_code
+        .as_deref()
+        .and_then(|code| Currency::from_str(code).ok())
+        .unwrap_or_default()
+}
+
+/// In-process payment gateway for running the checkout offline.
+/// Charges are kept in memory, so captures, refunds and voids only work for charges of this process.
+#[derive(Default)]
+pub struct FakePaymentProvider {
+    charges: Mutex<HashMap<String, FakeCharge>>,
+}
+
+impl FakePaymentProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(super) async fn create_charge(
+        &self,
+        order_number: &str,
+        amount: Money,
+        number: String,
+        capture: bool,
+    ) -> Result<ChargeResult> {
+        if number.ends_with(FAKE_TIMEOUT_CARD_SUFFIX) {
+            tokio::time::sleep(FAKE_TIMEOUT).await;
+            return Err(Error::Static("Payment provider timed out"));
+        }
+
+        if number.ends_with(FAKE_DECLINED_CARD_SUFFIX) {
+            return Ok(ChargeResult::Failure("Card declined".to_string()));
+        }
+
+        let token = format!("fake_{}", random_string(24));
+
+        let transaction_info: TransactionInfo = serde_json::from_value(json!({
+            "token": token,
+            "cc_last4": &number[number.len() - 4..],
+            "cc_length": number.len(),
+            "cc_company": "fake",
+        }))
+        .map_err(|_| Error::Desrilaztion)?;
+
+        self.charges.lock().unwrap().insert(
+            token,
+            FakeCharge {
+                card_number: number,
+                amount,
+                captured: if capture {
+                    amount
+                } else {
+                    Money::zero(amount.currency)
+                },
+                refunded: Money::zero(amount.currency),
+                voided: Money::zero(amount.currency),
+            },
+        );
+
+        tracing::info!(
+            "Fake payment provider {} {} for order {}",
+            if capture { "charged" } else { "authorized" },
+            amount.to_major(),
+            order_number
+        );
+
+        Ok(ChargeResult::Success(transaction_info))
+    }
+
+    /// A card the tests can charge by its token, even one the provider declines
+    #[cfg(test)]
+    pub(super) fn save_card(&self, number: &str) -> String {
+        let token = format!("fake_{}", random_string(24));
+
+        self.charges.lock().unwrap().insert(
+            token.clone(),
+            FakeCharge {
+                card_number: number.to_string(),
+                amount: Money::zero(Currency::default()),
+                captured: Money::zero(Currency::default()),
+                refunded: Money::zero(Currency::default()),
+                voided: Money::zero(Currency::default()),
+            },
+        );
+
+        token
+    }
+
+    /// Charges that still hold money on the card
+    #[cfg(test)]
+    pub(super) fn open_charges(&self) -> usize {
+        self.charges
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|charge| charge.open_amount().is_positive())
+            .count()
+    }
+
+    async fn charge_card(&self, charge: ChargeCreditCard, capture: bool) -> Result<ChargeResult> {
+        let amount = Money::from_major(charge.amount, charge_currency(&charge.currency_code));
+
+        match card_number(&charge.credit_card) {
+            Some(number) => {
+                self.create_charge(&charge.order_number, amount, number, capture)
+                    .await
+            }
+            None => Ok(ChargeResult::Failure("Invalid card number".to_string())),
+        }
+    }
+
+    async fn charge_token(&self, charge: ChargeSavedCard, capture: bool) -> Result<ChargeResult> {
+        let number = self
+            .charges
+            .lock()
+            .unwrap()
+            .get(&charge.token)
+            .map(|saved| saved.card_number.clone());
+
+        let amount = Money::from_major(charge.amount, charge_currency(&charge.currency_code));
+
+        match number {
+            Some(number) => {
+                self.create_charge(&charge.order_number, amount, number, capture)
+                    .await
+            }
+            None => Ok(ChargeResult::Failure("Saved card not found".to_string())),
+        }
+    }
+}
+
+fn card_number(credit_card: &CreditCard) -> Option<String> {
+    let number = credit_card.number.trim();
+
+    if (12..=19).contains(&number.len()) && number.chars().all(|c| c.is_ascii_digit()) {
+        Some(number.to_string())
+    } else {
+        None
+    }
+}
+
+#[async_trait]
+impl PaymentProvider for FakePaymentProvider {
+    async fn charge_credit_card(&self, charge: ChargeCreditCard) -> Result<ChargeResult> {
+        self.charge_card(charge, true).await
+    }
+
+    async fn charge_saved_card(&self, charge: ChargeSavedCard) -> Result<ChargeResult> {
+        self.charge_token(charge, true).await
+    }
+
+    fn supports_saved_cards(&self) -> bool {
+        true
+    }
+
+    fn supports_refunds(&self) -> bool {
+        true
+    }
+
+    fn supports_authorization(&self) -> bool {
+        true
+    }
+
+    async fn authorize_credit_card(&self, charge: ChargeCreditCard) -> Result<ChargeResult> {
+        self.charge_card(charge, false).await
+    }
+
+    async fn authorize_saved_card(&self, charge: ChargeSavedCard) -> Result<ChargeResult> {
+        self.charge_token(charge, false).await
+    }
+
+    async fn capture_charge(&self, capture: CaptureCharge) -> Result<CaptureResult> {
+        let mut charges = self.charges.lock().unwrap();
+
+        let charge = match charges.get_mut(&capture.token) {
+            Some(charge) => charge,
+            None => return Ok(CaptureResult::Failure("Charge not found".to_string())),
+        };
+
+        let amount = charge.amount(capture.amount);
+
+        if !amount.is_positive() || amount > charge.open_amount() {
+            return Ok(CaptureResult::Failure(
+                "Capture is above the authorized amount".to_string(),
+            ));
+        }
+
+        charge.captured += amount;
+
+        Ok(CaptureResult::Success(CaptureInfo {
+            reference: format!("fake_capture_{}", random_string(24)),
+        }))
+    }
+
+    async fn refund_credit_card(&self, refund: RefundCreditCard) -> Result<RefundResult> {
+        let mut charges = self.charges.lock().unwrap();
+
+        let charge = match charges.get_mut(&refund.token) {
+            Some(charge) => charge,
+            None => return Ok(RefundResult::Failure("Charge not found".to_string())),
+        };
+
+        let amount = charge.amount(refund.amount);
+
+        if !amount.is_positive() || charge.refunded + amount > charge.captured {
+            return Ok(RefundResult::Failure(
+                "Refund is above the captured amount".to_string(),
+            ));
+        }
+
+        charge.refunded += amount;
+
+        Ok(RefundResult::Success(RefundInfo {
+            reference: format!("fake_refund_{}", random_string(24)),
+        }))
+    }
+
+    async fn void_charge(&self, void: VoidCharge) -> Result<VoidResult> {
+        let mut charges = self.charges.lock().unwrap();
+
+        let charge = match charges.get_mut(&void.token) {
+            Some(charge) => charge,
+            None => return Ok(VoidResult::Failure("Charge not found".to_string())),
+        };
+
+        let amount = charge.amount(void.amount);
+
+        if !amount.is_positive() || amount > charge.open_amount() {
+            return Ok(VoidResult::Failure(
+                "Void is above the uncaptured amount".to_string(),
+            ));
+        }
+
+        charge.voided += amount;
+
+        Ok(VoidResult::Success)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const APPROVED_CARD: &str = "4580000000000001";
+    const DECLINED_CARD: &str = "4580000000000002";
+
+    async fn charge(
+        provider: &FakePaymentProvider,
+        number: &str,
+        amount: f64,
+        capture: bool,
+    ) -> ChargeResult {
+        provider
+            .create_charge(
+                "1000",
+                Money::from_major(amount, Currency::Ils),
+                number.to_string(),
+                capture,
+            )
+            .await
+            .unwrap()
+    }
+
+    fn token(res: ChargeResult) -> String {
+        match res {
+            ChargeResult::Success(info) => info.token,
+            ChargeResult::Failure(err) => panic!("charge was declined: {}", err),
+        }
+    }
+
+    #[tokio::test]
+    async fn charges_and_recharges_the_saved_card() {
+        let provider = FakePaymentProvider::new();
+
+        let token = token(charge(&provider, APPROVED_CARD, 100.0, true).await);
+
+        let res = provider
+            .charge_saved_card(ChargeSavedCard {
+                order_number: "1001".to_string(),
+                token,
+                amount: 50.0,
+                currency_code: None,
+            })
+            .await
+            .unwrap();
+
+        assert!(matches!(res, ChargeResult::Success(_)));
+    }
+
+    #[tokio::test]
+    async fn declines_the_declined_card() {
+        let provider = FakePaymentProvider::new();
+
+        let res = charge(&provider, DECLINED_CARD, 100.0, true).await;
+
+        assert!(matches!(res, ChargeResult::Failure(_)));
+        assert!(provider.charges.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn voids_only_the_uncaptured_amount() {
+        let provider = FakePaymentProvider::new();
+
+        let token = token(charge(&provider, APPROVED_CARD, 100.0, false).await);
+
+        let capture = provider
+            .capture_charge(CaptureCharge {
+                order_number: "1000".to_string(),
+                token: token.clone(),
+                amount: 60.0,
+            })
+            .await
+            .unwrap();
+
+        assert!(matches!(capture, CaptureResult::Success(_)));
+
+        let void = |amount| VoidCharge {
+            order_number: "1000".to_string(),
+            token: token.clone(),
+            amount,
+        };
+
+        let res = provider.void_charge(void(50.0)).await.unwrap();
+        assert!(matches!(res, VoidResult::Failure(_)));
+
+        let res = provider.void_charge(void(40.0)).await.unwrap();
+        assert!(matches!(res, VoidResult::Success));
+
+        let res = provider.void_charge(void(1.0)).await.unwrap();
+        assert!(matches!(res, VoidResult::Failure(_)));
+    }
+
+    #[tokio::test]
+    async fn small_captures_and_voids_empty_the_charge_exactly() {
+        let provider = FakePaymentProvider::new();
+
+        let capture = |token: &String, amount| CaptureCharge {
+            order_number: "1000".to_string(),
+            token: token.clone(),
+            amount,
+        };
+
+        let void = |token: &String, amount| VoidCharge {
+            order_number: "1000".to_string(),
+            token: token.clone(),
+            amount,
+        };
+
+        // 0.04 - 0.01 - 0.01 and 0.06 - 0.03 - 0.01 are just below 0.02 in f64
+        let first = token(charge(&provider, APPROVED_CARD, 0.04, false).await);
+        let second = token(charge(&provider, APPROVED_CARD, 0.06, false).await);
+
+        let res = provider
+            .capture_charge(capture(&first, 0.01))
+            .await
+            .unwrap();
+        assert!(matches!(res, CaptureResult::Success(_)));
+
+        let res = provider.void_charge(void(&first, 0.01)).await.unwrap();
+        assert!(matches!(res, VoidResult::Success));
+
+        let res = provider
+            .capture_charge(capture(&first, 0.02))
+            .await
+            .unwrap();
+        assert!(matches!(res, CaptureResult::Success(_)));
+
+        let res = provider
+            .capture_charge(capture(&second, 0.03))
+            .await
+            .unwrap();
+        assert!(matches!(res, CaptureResult::Success(_)));
+
+        for amount in [0.01, 0.02] {
+            let res = provider.void_charge(void(&second, amount)).await.unwrap();
+            assert!(matches!(res, VoidResult::Success));
+        }
+
+        for token in [&first, &second] {
+            let res = provider.capture_charge(capture(token, 0.01)).await.unwrap();
+            assert!(matches!(res, CaptureResult::Failure(_)));
+
+            let res = provider.void_charge(void(token, 0.01)).await.unwrap();
+            assert!(matches!(res, VoidResult::Failure(_)));
+        }
+    }
+}
@@ -0,0 +1,202 @@
+mod checkout;
+mod fake;
+mod parts;
+mod refunds;
+
+pub use checkout::*;
+pub use fake::*;
+pub use parts::*;
+pub use refunds::*;
+
+use crate::prelude::*;
+use shoppa_core::payments::{
+    types::{ChargeCreditCard, ChargeResult},
+    PaymentClient,
+};
+use std::sync::Arc;
+
+#[derive(Debug, Clone)]
+pub struct ChargeSavedCard {
+    pub order_number: String,
+    // The token of a card the user saved from a previous charge
+    pub token: String,
+    pub amount: f64,
+    pub currency_code: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RefundCreditCard {
+    pub order_number: String,
+    // The token that was saved on the order transaction when it was charged
+    pub token: String,
+    pub amount: f64,
+    pub currency_code: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum RefundResult {
+    Success(RefundInfo),
+    Failure(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct RefundInfo {
+    // The provider reference of the refund
+    pub reference: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct CaptureCharge {
+    pub order_number: String,
+    // The token that was saved on the order transaction when it was authorized
+    pub token: String,
+    pub amount: f64,
+}
+
+#[derive(Debug, Clone)]
+pub enum CaptureResult {
+    Success(CaptureInfo),
+    Failure(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct CaptureInfo {
+    // The provider reference of the capture
+    pub reference: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct VoidCharge {
+    pub order_number: String,
+    // The token that was saved on the order transaction when it was charged
+    pub token: String,
+    // The part of the authorization that is released
+    pub amount: f64,
+}
+
+#[derive(Debug, Clone)]
+pub enum VoidResult {
+    Success,
+    Failure(String),
+}
+
+/// Everything the checkout and the store panel need from a payment processor.
+/// A provider only returns an error when the processor could not be reached,
+/// a declined request is a `Failure` result.
+#[async_trait]
+pub trait PaymentProvider: Send + Sync {
+    async fn charge_credit_card(&self, charge: ChargeCreditCard) -> Result<ChargeResult>;
+
+    async fn charge_saved_card(&self, charge: ChargeSavedCard) -> Result<ChargeResult>;
+
+    /// Whether the provider can charge the token of a previous charge,
+    /// otherwise cards are not saved and saved cards are not offered
+    fn supports_saved_cards(&self) -> bool {
+        false
+    }
+
+    /// Whether the provider can give back captured money,
+    /// otherwise stores can't refund their order parts
+    fn supports_refunds(&self) -> bool {
+        false
+    }
+
+    /// Whether the provider can authorize now and capture later,
+    /// otherwise the checkout charges the full amount up front
+    fn supports_authorization(&self) -> bool {
+        false
+    }
+
+    async fn authorize_credit_card(&self, charge: ChargeCreditCard) -> Result<ChargeResult>;
+
+    async fn authorize_saved_card(&self, charge: ChargeSavedCard) -> Result<ChargeResult>;
+
+    async fn capture_charge(&self, capture: CaptureCharge) -> Result<CaptureResult>;
+
+    async fn refund_credit_card(&self, refund: RefundCreditCard) -> Result<RefundResult>;
+
+    async fn void_charge(&self, void: VoidCharge) -> Result<VoidResult>;
+}
+
+/// `PAYMENT_PROVIDER=fake` runs the checkout against the in-process fake gateway,
+/// anything else uses the real processor.
+pub fn payment_provider_from_env() -> Arc<dyn PaymentProvider> {
+    match ENV_VARS.PAYMENT_PROVIDER.as_str() {
+        "fake" => {
+            if ENV_VARS.is_production() {
+                panic!("The fake payment provider can't be used in production");
+            }
+
+            tracing::warn!("Using the fake payment provider, no card is really charged");
+
+            Arc::new(FakePaymentProvider::new())
+        }
+        _ => Arc::new(PaymentClient::new()),
+    }
+}
+
+#[async_trait]
+impl PaymentProvider for PaymentClient {
+    async fn charge_credit_card(&self, charge: ChargeCreditCard) -> Result<ChargeResult> {
+        PaymentClient::charge_credit_card(self, charge).await
+    }
+
+    // The core payment client has no token charge, so `supports_saved_cards` is false
+    // and the checkout never charges a saved card with it
+    async fn charge_saved_card(&self, charge: ChargeSavedCard) -> Result<ChargeResult> {
+        tracing::warn!(
+            "Saved card charge of order {} was declined, the payment client has no token support",
+            charge.order_number
+        );
+
+        Ok(ChargeResult::Failure(
+            "Saved cards are not supported by the payment provider".to_string(),
+        ))
+    }
+
+    // The core payment client only charges, so `supports_authorization` is false
+    // and the authorize calls are never made by the checkout
+    async fn authorize_credit_card(&self, _charge: ChargeCreditCard) -> Result<ChargeResult> {
+        Ok(ChargeResult::Failure(
+            "Authorizations are not supported by the payment provider".to_string(),
+        ))
+    }
+
+    async fn authorize_saved_card(&self, _charge: ChargeSavedCard) -> Result<ChargeResult> {
+        Ok(ChargeResult::Failure(
+            "Authorizations are not supported by the payment provider".to_string(),
+        ))
+    }
+
+    async fn capture_charge(&self, _capture: CaptureCharge) -> Result<CaptureResult> {
+        Ok(CaptureResult::Failure(
+            "Captures are not supported by the payment provider".to_string(),
+        ))
+    }
+
+    async fn refund_credit_card(&self, refund: RefundCreditCard) -> Result<RefundResult> {
+        // The core payment client only knows how to charge, so `supports_refunds` is false
+        // and the store refund endpoint rejects refunds before calling this
+        tracing::warn!(
+            "Refund of order {} was declined, the payment client has no refund support",
+            refund.order_number
+        );
+
+        Ok(RefundResult::Failure(
+            "Refunds are not supported by the payment provider".to_string(),
+        ))
+    }
+
+    async fn void_charge(&self, void: VoidCharge) -> Result<VoidResult> {
+        // Only an authorization can be voided and this client never authorizes,
+        // so an order charged by it has no part that reaches a void
+        tracing::error!(
+            "Void of order {} was requested from the payment client, which never authorizes",
+            void.order_number
+        );
+
+        Ok(VoidResult::Failure(
+            "Voids are not supported by the payment provider".to_string(),
+        ))
+    }
+}
@@ -0,0 +1,324 @@
+use super::{CaptureCharge, CaptureResult, PaymentProvider, VoidCharge, VoidResult};
+use crate::{
+    db::{
+        CheckoutDiscount, CheckoutSessionFunctions, LedgerFunctions, LocalDBConection,
+        OrderFunctions, OrderPartPaymentStatus, OrderPaymentStatus, PaymentStateFunctions,
+        PendingLedgerReversal,
+    },
+    helpers::money::{Currency, Money},
+    prelude::*,
+};
+use bson::oid::ObjectId;
+use shoppa_core::db::{models::Order, DBConection};
+
+/// The store share of each part of the order, by the parts index, after its share of the discount.
+/// It's what is captured or voided for the part later.
+pub fn order_part_amounts(
+    order: &Order,
+    discount: Option<&CheckoutDiscount>,
+    currency: Currency,
+) -> Vec<f64> {
+    order
+        .parts
+        .iter()
+        .map(|part| {
+            let part_discount = discount
+                .map(|discount| discount.for_store(part.store.ref_doc_id(), currency))
+                .unwrap_or(Money::zero(currency));
+
+            (Money::from_major(part.total, currency) - part_discount).to_major()
+        })
+        .collect()
+}
+
+async fn get_order(db: &DBConection, order_id: &ObjectId) -> Result<Order> {
+    db.get_order_by_id(order_id, None, None, None)
+        .await?
+        .ok_or(Error::ApiErrorWithCode("OrderNotFound", 404))
+}
+
+/// Captures the store share of an authorized order, once the store shipped its part.
+/// Returns the part payment status, `None` if the part had nothing to capture.
+pub async fn capture_order_part(
+    db: &DBConection,
+    provider: &dyn PaymentProvider,
+    order_id: &ObjectId,
+    store_id: &ObjectId,
+) -> Result<Option<OrderPartPaymentStatus>> {
+    let payment = match db
+        .claim_order_part_payment(
+            order_id,
+            store_id,
+            &[
+                OrderPartPaymentStatus::Authorized,
+                OrderPartPaymentStatus::CaptureFailed,
+            ],
+            OrderPartPaymentStatus::Capturing,
+        )
+        .await?
+    {
+        Some(payment) => payment,
+        None => return Ok(None),
+    };
+
+    let order = get_order(db, order_id).await?;
+
+    let res = provider
+        .capture_charge(CaptureCharge {
+            order_number: order.order_number.clone(),
+            token: order.transaction.token.clone(),
+            amount: payment.amount,
+        })
+        .await;
+
+    let (status, reference, error) = match res {
+        Ok(CaptureResult::Success(info)) => {
+            (OrderPartPaymentStatus::Captured, Some(info.reference), None)
+        }
+        Ok(CaptureResult::Failure(err)) => (OrderPartPaymentStatus::CaptureFailed, None, Some(err)),
+        Err(e) => (
+            OrderPartPaymentStatus::CaptureFailed,
+            None,
+            Some(e.to_string()),
+        ),
+    };
+
+    if let Some(error) = &error {
+        tracing::error!(
+            "Failed to capture store {} part of order {}: {}",
+            store_id,
+            order.order_number,
+            error
+        );
+    }
+
+    db.set_order_part_payment_status(
+        order_id,
+        store_id,
+        OrderPartPaymentStatus::Capturing,
+        status,
+        reference,
+        error,
+        None,
+    )
+    .await?;
+
+    db.settle_order_payment(order_id).await?;
+
+    Ok(Some(status))
+}
+
+/// Releases the store share of an authorized order, when the part is canceled (`Voided`)
+/// or the authorization expired (`Expired`).
+/// A failed void leaves the part authorized, so it is retried when the authorization expires.
+/// A successful void takes the part sale back from the store ledger,
+/// see `write_pending_ledger_reversals`.
+pub async fn void_order_part(
+    db: &DBConection,
+    local_db: &LocalDBConection,
+    provider: &dyn PaymentProvider,
+    order_id: &ObjectId,
+    store_id: &ObjectId,
+    status: OrderPartPaymentStatus,
+) -> Result<Option<OrderPartPaymentStatus>> {
+    let payment = match db
+        .claim_order_part_payment(
+            order_id,
+            store_id,
+            &[
+                OrderPartPaymentStatus::Authorized,
+                OrderPartPaymentStatus::CaptureFailed,
+            ],
+            OrderPartPaymentStatus::Voiding,
+        )
+        .await?
+    {
+        Some(payment) => payment,
+        None => return Ok(None),
+    };
+
+    let order = get_order(db, order_id).await?;
+
+    let res = provider
+        .void_charge(VoidCharge {
+            order_number: order.order_number.clone(),
+            token: order.transaction.token.clone(),
+            amount: payment.amount,
+        })
+        .await;
+
+    let (status, error) = match res {
+        Ok(VoidResult::Success) => (status, None),
+        Ok(VoidResult::Failure(err)) => (OrderPartPaymentStatus::Authorized, Some(err)),
+        Err(e) => (OrderPartPaymentStatus::Authorized, Some(e.to_string())),
+    };
+
+    if let Some(error) = &error {
+        tracing::error!(
+            "Failed to void store {} part of order {}: {}",
+            store_id,
+            order.order_number,
+            error
+        );
+    }
+
+    // a voided part takes its sale back from the ledger
+    let ledger_reversal = error.is_none().then_some(PendingLedgerReversal::Reversal);
+
+    db.set_order_part_payment_status(
+        order_id,
+        store_id,
+        OrderPartPaymentStatus::Voiding,
+        status,
+        None,
+        error,
+        ledger_reversal,
+    )
+    .await?;
+
+    db.settle_order_payment(order_id).await?;
+
+    // the part is already voided, the authorizations worker writes what's left
+    if write_pending_ledger_reversals(db, local_db, order_id)
+        .await
+        .is_err()
+    {
+        tracing::error!(
+            "Failed to write ledger reversals of order {}",
+            order.order_number
+        );
+    }
+
+    Ok(Some(status))
+}
+
+/// Applies a payment the processor took back, a `Failed` payment or a `ChargedBack` one.
+/// The part payments are marked as charged back, the parts that were not shipped yet are
+/// canceled with their stock given back, and the store sales are taken back from the ledger.
+/// `amount` is the charged back amount, without it the full payment was taken back.
+/// Every step can run again for the same event without changing anything twice.
+pub async fn reverse_order_payment(
+    db: &DBConection,
+    local_db: &LocalDBConection,
+    order_id: &ObjectId,
+    event_id: &str,
+    status: OrderPaymentStatus,
+    amount: Option<f64>,
+) -> Result<()> {
+    let order = get_order(db, order_id).await?;
+    let part_payments = db.get_order_part_payments(order_id).await?;
+
+    let note = match status {
+        OrderPaymentStatus::Failed => "Payment failed",
+        _ => "Payment charged back",
+    };
+
+    // the share of the payment that was taken back
+    let paid: f64 = part_payments.iter().map(|entry| entry.payment.amount).sum();
+
+    let share = match (status, amount) {
+        (OrderPaymentStatus::ChargedBack, Some(amount)) if paid > 0.0 => (amount / paid).min(1.0),
+        _ => 1.0,
+    };
+
+    let ledger_reversal = if share >= 1.0 {
+        PendingLedgerReversal::Reversal
+    } else {
+        PendingLedgerReversal::Chargeback {
+            event_id: event_id.to_string(),
+            share,
+        }
+    };
+
+    // parts that were voided before keep their status and their ledger reversal
+    for entry in &part_payments {
+        if !OrderPartPaymentStatus::reversible().contains(&entry.payment.status) {
+            continue;
+        }
+
+        db.set_order_part_payment_status(
+            order_id,
+            &entry.store,
+            entry.payment.status,
+            OrderPartPaymentStatus::ChargedBack,
+            None,
+            Some(note.to_string()),
+            Some(ledger_reversal.clone()),
+        )
+        .await?;
+    }
+
+    // An order whose payment was never recorded has no part payments,
+    // its stock is still held by the checkout reservation
+    let recorded = !part_payments.is_empty();
+
+    // a partial chargeback leaves the parts to the stores
+    if share >= 1.0 {
+        let canceled = db
+            .cancel_unshipped_order_parts(&order, note, recorded)
+            .await?;
+
+        if !recorded && db.release_order_reservation(order_id).await? {
+            tracing::info!(
+                "Stock of order {} was given back: {}",
+                order.order_number,
+                note
+            );
+        }
+
+        for store_id in canceled {
+            tracing::info!(
+                "Store {} part of order {} was canceled: {}",
+                store_id,
+                order.order_number,
+                note
+            );
+        }
+    }
+
+    write_pending_ledger_reversals(db, local_db, order_id).await?;
+
+    Ok(())
+}
+
+/// Writes the ledger reversals that were set on the order part payments and clears them.
+/// A reversal that fails to be written stays on the part, the authorizations worker
+/// writes it later. The entries are keyed, so a reversal is never written twice.
+/// A sale that was not written yet is skipped by the ledger step, so there is nothing to reverse.
+pub async fn write_pending_ledger_reversals(
+    db: &DBConection,
+    local_db: &LocalDBConection,
+    order_id: &ObjectId,
+) -> Result<()> {
+    for entry in db.get_order_part_payments(order_id).await? {
+        let ledger_reversal = match entry.payment.ledger_reversal {
+            Some(ledger_reversal) => ledger_reversal,
+            None => continue,
+        };
+
+        let res = match &ledger_reversal {
+            PendingLedgerReversal::Reversal => {
+                local_db.reverse_ledger_sale(order_id, &entry.store).await
+            }
+            PendingLedgerReversal::Chargeback { event_id, share } => {
+                local_db
+                    .charge_back_ledger_sale(order_id, &entry.store, event_id, *share)
+                    .await
+            }
+        };
+
+        if res.is_err() {
+            tracing::error!(
+                "Failed to reverse ledger sale of store {} part of order {}",
+                entry.store,
+                order_id
+            );
+            continue;
+        }
+
+        db.clear_order_part_ledger_reversal(order_id, &entry.store)
+            .await?;
+    }
+
+    Ok(())
+}
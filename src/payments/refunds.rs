@@ -0,0 +1,342 @@
+use crate::{
+    db::{StoreRefund, StoreRefundItem},
+    helpers::money::Money,
+};
+use bson::oid::ObjectId;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+/// An item of a store part, with its price in the charged currency
+#[derive(Debug, Clone)]
+pub struct RefundablePartItem {
+    pub product: ObjectId,
+    pub item_id: ObjectId,
+    pub quantity: u32,
+    pub price: Money,
+}
+
+#[derive(Debug, Clone)]
+pub struct RefundPlan {
+    pub items: Vec<StoreRefundItem>,
+    pub amount: Money,
+    // What is left to refund of the part after this refund
+    pub left: Money,
+}
+
+#[derive(Debug, Clone)]
+pub enum RefundPlanError {
+    AlreadyRefunded,
+    // One error for each requested item that can't be refunded
+    Items(Vec<Value>),
+}
+
+/// What a refund of a store part gives back, never more than what was captured for the part.
+/// `requested` is the items and quantities to refund, when it's None everything that is left
+/// of the part is refunded, delivery included.
+pub fn plan_refund(
+    items: &[RefundablePartItem],
+    captured: Money,
+    refunds: &[&StoreRefund],
+    requested: Option<Vec<(ObjectId, u32)>>,
+) -> std::result::Result<RefundPlan, RefundPlanError> {
+    let currency = captured.currency;
+
+    let refundable_amount = captured
+        - Money::sum(
+            currency,
+            refunds
+                .iter()
+                .map(|refund| Money::from_major(refund.amount, currency)),
+        );
+
+    if !refundable_amount.is_positive() {
+        return Err(RefundPlanError::AlreadyRefunded);
+    }
+
+    let refunded_quantity = |item_id: &ObjectId| -> u32 {
+        refunds
+            .iter()
+            .flat_map(|refund| refund.items.iter())
+            .filter(|item| &item.item_id == item_id)
+            .map(|item| item.quantity)
+            .sum()
+    };
+
+    let (items, amount) = match requested {
+        None => {
+            let items = items
+                .iter()
+                .filter_map(|item| {
+                    let quantity = item
+                        .quantity
+                        .saturating_sub(refunded_quantity(&item.item_id));
+
+                    (quantity > 0).then(|| StoreRefundItem {
+                        product: item.product,
+                        item_id: item.item_id,
+                        quantity,
+                    })
+                })
+                .collect();
+
+            (items, refundable_amount)
+        }
+        Some(requested_items) => {
+            let mut requested: HashMap<ObjectId, u32> = HashMap::new();
+
+            for (item_id, quantity) in requested_items {
+                *requested.entry(item_id).or_default() += quantity;
+            }
+
+            let mut errors = Vec::new();
+            let mut refund_items = Vec::with_capacity(requested.len());
+            let mut amount = Money::zero(currency);
+
+            for (item_id, quantity) in requested {
+                let part_item = match items.iter().find(|item| item.item_id == item_id) {
+                    Some(part_item) => part_item,
+                    None => {
+                        errors.push(json!({
+                            "item": item_id,
+                            "error": "Item is not part of the order"
+                        }));
+                        continue;
+                    }
+                };
+
+                let refundable_quantity = part_item
+                    .quantity
+                    .saturating_sub(refunded_quantity(&item_id));
+
+                if quantity > refundable_quantity {
+                    errors.push(json!({
+                        "item": item_id,
+                        "refundable_quantity": refundable_quantity,
+                        "error": "Quantity is above what is left to refund"
+                    }));
+                    continue;
+                }
+
+                amount += part_item.price.times(quantity);
+
+                refund_items.push(StoreRefundItem {
+                    product: part_item.product,
+                    item_id,
+                    quantity,
+                });
+            }
+
+            if !errors.is_empty() {
+                return Err(RefundPlanError::Items(errors));
+            }
+
+            // the part discount was never charged, so the items can add up to more
+            (refund_items, amount.min(refundable_amount))
+        }
+    };
+
+    Ok(RefundPlan {
+        items,
+        amount,
+        left: refundable_amount - amount,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::StoreRefundStatus,
+        helpers::money::Currency,
+        payments::{
+            CaptureCharge, CaptureResult, FakePaymentProvider, PaymentProvider, RefundCreditCard,
+            RefundResult,
+        },
+    };
+    use shoppa_core::payments::types::ChargeResult;
+
+    const APPROVED_CARD: &str = "4580000000000001";
+
+    fn ils(amount: i64) -> Money {
+        Money::new(amount, Currency::Ils)
+    }
+
+    fn part_items() -> Vec<RefundablePartItem> {
+        vec![
+            RefundablePartItem {
+                product: ObjectId::new(),
+                item_id: ObjectId::new(),
+                quantity: 2,
+                price: ils(1_050),
+            },
+            RefundablePartItem {
+                product: ObjectId::new(),
+                item_id: ObjectId::new(),
+                quantity: 1,
+                price: ils(3_000),
+            },
+        ]
+    }
+
+    async fn charge(provider: &FakePaymentProvider, amount: Money, capture: bool) -> String {
+        match provider
+            .create_charge("1000", amount, APPROVED_CARD.to_string(), capture)
+            .await
+            .unwrap()
+        {
+            ChargeResult::Success(info) => info.token,
+            ChargeResult::Failure(err) => panic!("charge was declined: {}", err),
+        }
+    }
+
+    async fn refund(provider: &FakePaymentProvider, token: &str, amount: Money) -> RefundResult {
+        provider
+            .refund_credit_card(RefundCreditCard {
+                order_number: "1000".to_string(),
+                token: token.to_string(),
+                amount: amount.to_major(),
+                currency_code: Some(amount.currency.to_string()),
+            })
+            .await
+            .unwrap()
+    }
+
+    fn done(plan: &RefundPlan) -> StoreRefund {
+        let mut refund = StoreRefund::new(
+            ObjectId::new(),
+            plan.items.clone(),
+            plan.amount.to_major(),
+            None,
+            ObjectId::new(),
+        );
+
+        refund.status = StoreRefundStatus::Done;
+        refund
+    }
+
+    #[tokio::test]
+    async fn refunds_the_whole_part_once() {
+        let provider = FakePaymentProvider::new();
+        let items = part_items();
+        // 2 * 10.50 + 30 + 15 delivery
+        let captured = ils(6_600);
+
+        let token = charge(&provider, captured, true).await;
+
+        let plan = plan_refund(&items, captured, &[], None).unwrap();
+
+        assert_eq!(plan.amount, captured);
+        assert_eq!(plan.left, ils(0));
+        assert_eq!(
+            plan.items
+                .iter()
+                .map(|item| item.quantity)
+                .collect::<Vec<_>>(),
+            vec![2, 1]
+        );
+        assert!(matches!(
+            refund(&provider, &token, plan.amount).await,
+            RefundResult::Success(_)
+        ));
+
+        let refunds = [done(&plan)];
+        let refunds = refunds.iter().collect::<Vec<_>>();
+
+        assert!(matches!(
+            plan_refund(&items, captured, &refunds, None),
+            Err(RefundPlanError::AlreadyRefunded)
+        ));
+        assert!(matches!(
+            refund(&provider, &token, ils(1)).await,
+            RefundResult::Failure(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn refunds_items_and_then_the_rest() {
+        let provider = FakePaymentProvider::new();
+        let items = part_items();
+        let captured = ils(6_600);
+
+        let token = charge(&provider, captured, true).await;
+
+        let first = plan_refund(&items, captured, &[], Some(vec![(items[0].item_id, 1)])).unwrap();
+
+        assert_eq!(first.amount, ils(1_050));
+        assert_eq!(first.left, ils(5_550));
+        assert!(matches!(
+            refund(&provider, &token, first.amount).await,
+            RefundResult::Success(_)
+        ));
+
+        let refunds = [done(&first)];
+        let refunds = refunds.iter().collect::<Vec<_>>();
+
+        // one of the first item is left, refunding two is rejected
+        assert!(matches!(
+            plan_refund(
+                &items,
+                captured,
+                &refunds,
+                Some(vec![(items[0].item_id, 2)])
+            ),
+            Err(RefundPlanError::Items(_))
+        ));
+
+        let rest = plan_refund(&items, captured, &refunds, None).unwrap();
+
+        assert_eq!(rest.amount, ils(5_550));
+        assert_eq!(rest.left, ils(0));
+        assert_eq!(
+            rest.items
+                .iter()
+                .map(|item| (item.item_id, item.quantity))
+                .collect::<Vec<_>>(),
+            vec![(items[0].item_id, 1), (items[1].item_id, 1)]
+        );
+        assert!(matches!(
+            refund(&provider, &token, rest.amount).await,
+            RefundResult::Success(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn never_refunds_more_than_was_captured() {
+        let provider = FakePaymentProvider::new();
+        let items = part_items();
+        // the store share of the discount was never charged
+        let captured = ils(4_000);
+
+        let plan = plan_refund(
+            &items,
+            captured,
+            &[],
+            Some(vec![(items[0].item_id, 2), (items[1].item_id, 1)]),
+        )
+        .unwrap();
+
+        assert_eq!(plan.amount, captured);
+
+        // only part of the authorization was captured, the provider refunds only that
+        let token = charge(&provider, ils(6_600), false).await;
+
+        let capture = provider
+            .capture_charge(CaptureCharge {
+                order_number: "1000".to_string(),
+                token: token.clone(),
+                amount: captured.to_major(),
+            })
+            .await
+            .unwrap();
+
+        assert!(matches!(capture, CaptureResult::Success(_)));
+        assert!(matches!(
+            refund(&provider, &token, captured + ils(1)).await,
+            RefundResult::Failure(_)
+        ));
+        assert!(matches!(
+            refund(&provider, &token, plan.amount).await,
+            RefundResult::Success(_)
+        ));
+    }
+}
@@ -1,8 +1,8 @@
-use crate::helpers::env::ENV_VARS;
+use crate::{db::ProductsFeed, helpers::env::ENV_VARS};
 use bson::oid::ObjectId;
 use serde::{Deserialize, Serialize};
 use shoppa_core::{
-    db::models::{DBModel, RefrenceField, StoreUser, User, UserStatus, CheckOutSession},
+    db::models::{DBModel, RefrenceField, StoreUser, User, UserStatus, CheckOutSession, Order},
     random::random_string,
     security::TokenManager,
 };
@@ -34,6 +34,22 @@ pub struct CheckOutSessionTokenData {
     pub secret: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GuestOrderTokenData {
+    pub order_id: ObjectId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmailUnsubscribeTokenData {
+    pub user_id: ObjectId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WishlistShareTokenData {
+    pub wishlist_id: ObjectId,
+    pub secret: String,
+}
+
 lazy_static! {
     pub static ref STORE_USER_TOKEN_MANAGER: TokenManager<StoreUserTokenData> = TokenManager::new(
         "store-api",
@@ -54,6 +70,38 @@ lazy_static! {
             ENV_VARS.CHECKOUT_SESSION_TOKEN_SECRET.as_str(),
             1
         );
+    pub static ref GUEST_ORDER_TOKEN_MANAGER: TokenManager<GuestOrderTokenData> =
+        TokenManager::new(
+            "store-api",
+            ENV_VARS.GUEST_ORDER_TOKEN_SECRET.as_str(),
+            1
+        );
+    // The order status links in emails, they have to keep working
+    // until the order is delivered and can no longer be returned
+    pub static ref ORDER_EMAIL_TOKEN_MANAGER: TokenManager<GuestOrderTokenData> =
+        TokenManager::new(
+            "store-api",
+            ENV_VARS.GUEST_ORDER_TOKEN_SECRET.as_str(),
+            180
+        );
+    pub static ref EMAIL_UNSUBSCRIBE_TOKEN_MANAGER: TokenManager<EmailUnsubscribeTokenData> =
+        TokenManager::new(
+            "store-api",
+            ENV_VARS.EMAIL_UNSUBSCRIBE_TOKEN_SECRET.as_str(),
+            90
+        );
+    pub static ref WISHLIST_SHARE_TOKEN_MANAGER: TokenManager<WishlistShareTokenData> =
+        TokenManager::new(
+            "store-api",
+            ENV_VARS.WISHLIST_SHARE_TOKEN_SECRET.as_str(),
+            365
+        );
+    pub static ref PRODUCTS_FEED_TOKEN_MANAGER: TokenManager<ProductsFeed> =
+        TokenManager::new(
+            "store-api",
+            ENV_VARS.PRODUCTS_FEED_TOKEN_SECRET.as_str(),
+            1
+        );
 }
 
 impl StoreUserTokenData {
@@ -115,3 +163,11 @@ impl Into<CheckOutSessionTokenData> for &CheckOutSession {
         }
     }
 }
+
+impl Into<GuestOrderTokenData> for &Order {
+    fn into(self) -> GuestOrderTokenData {
+        GuestOrderTokenData {
+            order_id: self.id().unwrap().clone(),
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use crate::{
+    db::CartReminderFunctions,
+    emails::CustomerEmailFunctions,
+    helpers::env::ENV_VARS,
+    tokens::{EmailUnsubscribeTokenData, EMAIL_UNSUBSCRIBE_TOKEN_MANAGER},
+};
+use bson::DateTime as BsonDateTime;
+use shoppa_core::{db::DBConection, email_sender::EmailClient};
+use std::{sync::Arc, time::Duration};
+
+// How often the worker looks for abandoned carts
+const POLL_INTERVAL_SECS: u64 = 60 * 15;
+// How many carts to handle on each poll
+const CARTS_PER_POLL: i64 = 100;
+
+/// Runs forever, reminding registered users about carts they left.
+/// Does nothing when there is no reminder template.
+pub async fn run_abandoned_carts_worker(db: Arc<DBConection>, email_client: Arc<EmailClient>) {
+    if ENV_VARS.ABANDONED_CART_TEMPLATE_ID.is_empty() {
+        return;
+    }
+
+    let mut interval = tokio::time::interval(Duration::from_secs(POLL_INTERVAL_SECS));
+
+    loop {
+        interval.tick().await;
+
+        let untouched_since = BsonDateTime::from_millis(
+            BsonDateTime::now().timestamp_millis()
+                - ENV_VARS.ABANDONED_CART_AFTER_MINUTES * 60 * 1000,
+        );
+
+        let carts = match db
+            .get_abandoned_carts(
+                untouched_since,
+                ENV_VARS.ABANDONED_CART_MAX_REMINDERS,
+                CARTS_PER_POLL,
+            )
+            .await
+        {
+            Ok(carts) => carts,
+            Err(_) => {
+                tracing::error!("Failed to get abandoned carts");
+                continue;
+            }
+        };
+
+        for cart in carts {
+            let token = match EMAIL_UNSUBSCRIBE_TOKEN_MANAGER.generate_urlsafe_token(
+                EmailUnsubscribeTokenData {
+                    user_id: cart.user_id,
+                },
+                None,
+            ) {
+                Ok(token) => token,
+                Err(_) => {
+                    tracing::error!(
+                        "Failed to create unsubscribe token of user {}",
+                        cart.user_id
+                    );
+                    continue;
+                }
+            };
+
+            // Marked before sending, a failed send is not retried
+            // but a user never gets the same reminder twice
+            match db.mark_cart_reminder(&cart, true).await {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(_) => {
+                    tracing::error!("Failed to mark cart reminder of user {}", cart.user_id);
+                    continue;
+                }
+            }
+
+            let name = cart.name.clone().unwrap_or_default();
+
+            let email = email_client
+                .abandoned_cart_email(name.clone(), cart.items_count, token)
+                .add_to((cart.email.clone(), name).into())
+                .build();
+
+            if email_client.send(email).await.is_err() {
+                tracing::error!("Failed to send cart reminder to user {}", cart.user_id);
+            }
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use crate::{
+    db::{LocalDBConection, OrderPartPaymentStatus, PaymentStateFunctions},
+    payments::{self, PaymentProvider},
+};
+use bson::DateTime as BsonDateTime;
+use shoppa_core::db::DBConection;
+use std::{sync::Arc, time::Duration};
+
+// How often the worker looks for open authorizations
+const POLL_INTERVAL_SECS: u64 = 60 * 10;
+// How many orders to handle on each poll
+const ORDERS_PER_POLL: i64 = 50;
+
+/// Runs forever, retrying failed captures of shipped parts and voiding
+/// the parts nobody captured once the authorization expired.
+/// It also writes the ledger reversals that failed to be written when a part was voided
+/// or charged back.
+pub async fn run_authorizations_worker(
+    db: Arc<DBConection>,
+    local_db: Arc<LocalDBConection>,
+    payment_provider: Arc<dyn PaymentProvider>,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(POLL_INTERVAL_SECS));
+
+    loop {
+        interval.tick().await;
+
+        write_pending_ledger_reversals(&db, &local_db).await;
+
+        let orders = match db.get_open_authorizations(ORDERS_PER_POLL).await {
+            Ok(orders) => orders,
+            Err(_) => {
+                tracing::error!("Failed to get open authorizations");
+                continue;
+            }
+        };
+
+        for order_id in orders {
+            let expired = match db.get_order_payment_state(&order_id).await {
+                Ok(Some(state)) => state
+                    .authorization_expires_at
+                    .map(|expires_at| expires_at <= BsonDateTime::now())
+                    .unwrap_or(false),
+                Ok(None) => continue,
+                Err(_) => {
+                    tracing::error!("Failed to get payment state of order {}", order_id);
+                    continue;
+                }
+            };
+
+            let part_payments = match db.get_order_part_payments(&order_id).await {
+                Ok(part_payments) => part_payments,
+                Err(_) => {
+                    tracing::error!("Failed to get part payments of order {}", order_id);
+                    continue;
+                }
+            };
+
+            for entry in part_payments {
+                let res = match entry.payment.status {
+                    OrderPartPaymentStatus::Authorized | OrderPartPaymentStatus::CaptureFailed
+                        if expired =>
+                    {
+                        payments::void_order_part(
+                            &db,
+                            &local_db,
+                            payment_provider.as_ref(),
+                            &order_id,
+                            &entry.store,
+                            OrderPartPaymentStatus::Expired,
+                        )
+                        .await
+                    }
+                    OrderPartPaymentStatus::CaptureFailed => {
+                        payments::capture_order_part(
+                            &db,
+                            payment_provider.as_ref(),
+                            &order_id,
+                            &entry.store,
+                        )
+                        .await
+                    }
+                    _ => continue,
+                };
+
+                if res.is_err() {
+                    tracing::error!(
+                        "Failed to settle store {} part of order {}",
+                        entry.store,
+                        order_id
+                    );
+                }
+            }
+        }
+    }
+}
+
+async fn write_pending_ledger_reversals(db: &DBConection, local_db: &LocalDBConection) {
+    let orders = match db.get_pending_ledger_reversals(ORDERS_PER_POLL).await {
+        Ok(orders) => orders,
+        Err(_) => {
+            tracing::error!("Failed to get pending ledger reversals");
+            return;
+        }
+    };
+
+    for order_id in orders {
+        if payments::write_pending_ledger_reversals(db, local_db, &order_id)
+            .await
+            .is_err()
+        {
+            tracing::error!("Failed to write ledger reversals of order {}", order_id);
+        }
+    }
+}
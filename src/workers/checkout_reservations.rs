@@ -0,0 +1,53 @@
+use crate::db::CheckoutSessionFunctions;
+use shoppa_core::db::DBConection;
+use std::{sync::Arc, time::Duration};
+
+// How often the worker looks for expired reservations
+const POLL_INTERVAL_SECS: u64 = 60;
+// How many reservations to release on each poll
+const RESERVATIONS_PER_POLL: i64 = 50;
+
+/// Runs forever, giving back the stock of abandoned checkout sessions
+/// once their reservation has expired.
+pub async fn run_checkout_reservations_worker(db: Arc<DBConection>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(POLL_INTERVAL_SECS));
+
+    loop {
+        interval.tick().await;
+
+        let sessions = match db
+            .get_expired_checkout_reservations(RESERVATIONS_PER_POLL)
+            .await
+        {
+            Ok(sessions) => sessions,
+            Err(_) => {
+                tracing::error!("Failed to get expired checkout reservations");
+                continue;
+            }
+        };
+
+        for session_id in sessions {
+            let checkout_session = match db
+                .get_checkout_session_by_id(&session_id, None, None, None)
+                .await
+            {
+                Ok(Some(checkout_session)) => checkout_session,
+                // replaced in the meantime
+                Ok(None) => continue,
+                Err(_) => {
+                    tracing::error!("Failed to get checkout session {}", session_id);
+                    continue;
+                }
+            };
+
+            // only expired, a session that was just confirmed is left as is
+            if db
+                .release_checkout_session_stock(&checkout_session, true)
+                .await
+                .is_err()
+            {
+                tracing::error!("Failed to release stock of checkout session {}", session_id);
+            }
+        }
+    }
+}
@@ -0,0 +1,11 @@
+mod abandoned_carts;
+mod authorizations;
+mod checkout_reservations;
+mod post_payment;
+mod stock_alerts;
+
+pub use abandoned_carts::*;
+pub use authorizations::*;
+pub use checkout_reservations::*;
+pub use post_payment::*;
+pub use stock_alerts::*;
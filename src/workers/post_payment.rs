@@ -0,0 +1,762 @@
+use crate::{
+    db::{
+        ChargeCurrency, CommissionFunctions, CurrencyFunctions, DiscountFunctions, LedgerFunctions,
+        LedgerSale, LedgerTransaction, LocalDBConection, OrderFunctions, OrderPartCommission,
+        OrderPartPaymentStatus, PaymentStateFunctions, PostPaymentFunctions, PostPaymentJob,
+        PostPaymentStepKind, ProductFunctions, PromotionDiscount, PromotionFunctions, TaxFunctions,
+        UserFunctions,
+    },
+    emails::{CustomerEmailFunctions, StoreEmailFunctions},
+    helpers::money::Money,
+    prelude::*,
+    tokens::ORDER_EMAIL_TOKEN_MANAGER,
+};
+use bson::{doc, oid::ObjectId};
+use shoppa_core::{
+    db::{
+        models::{DBModel, FileDocument, FileTypes, Invoice, InvoiceType, Order},
+        populate::{FieldPopulate, OrderPopulate},
+        DBConection,
+    },
+    email_sender::EmailClient,
+    file_storage::{Buckets, StorageClient, StorageFolders},
+    invoice_service::{
+        InvoiceClient, InvoiceCustomer, InvoicePart, InvoicePartItem, InvoicePartUploadUrls,
+        InvoiceStore,
+    },
+};
+use std::{sync::Arc, time::Duration};
+
+// How often the worker looks for jobs that are due
+const POLL_INTERVAL_SECS: u64 = 30;
+// How many jobs to pick up on each poll
+const JOBS_PER_POLL: i64 = 20;
+// How long a worker owns a job before another worker may take it over
+const LOCK_LEASE_SECS: i64 = 5 * 60;
+const MAX_ATTEMPTS: u32 = 10;
+const BACKOFF_BASE_SECS: i64 = 30;
+const BACKOFF_MAX_SECS: i64 = 6 * 60 * 60;
+
+type StepResult = StdResult<(), &'static str>;
+
+/// Runs forever, picking up unfinished post payment jobs,
+/// including the ones that were left behind by a restart.
+pub async fn run_post_payment_worker(
+    db: Arc<DBConection>,
+    local_db: Arc<LocalDBConection>,
+    storage_client: Arc<StorageClient>,
+    invoice_client: Arc<InvoiceClient>,
+    email_client: Arc<EmailClient>,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(POLL_INTERVAL_SECS));
+
+    loop {
+        interval.tick().await;
+
+        let orders = match db.get_due_post_payment_jobs(JOBS_PER_POLL).await {
+            Ok(orders) => orders,
+            Err(_) => {
+                tracing::error!("Failed to get due post payment jobs");
+                continue;
+            }
+        };
+
+        for order_id in orders {
+            process_post_payment_job(
+                db.clone(),
+                local_db.clone(),
+                storage_client.clone(),
+                invoice_client.clone(),
+                email_client.clone(),
+                order_id,
+            )
+            .await;
+        }
+    }
+}
+
+/// Runs the remaining steps of the order post payment job.
+/// Safe to call more than once, a job is processed by one caller at a time.
+pub async fn process_post_payment_job(
+    db: Arc<DBConection>,
+    local_db: Arc<LocalDBConection>,
+    storage_client: Arc<StorageClient>,
+    invoice_client: Arc<InvoiceClient>,
+    email_client: Arc<EmailClient>,
+    order_id: ObjectId,
+) {
+    match db.lock_post_payment_job(&order_id, LOCK_LEASE_SECS).await {
+        Ok(true) => {}
+        // someone else is working on it or it is already done
+        Ok(false) => return,
+        Err(_) => {
+            tracing::error!("Failed to lock post payment job of order {}", order_id);
+            return;
+        }
+    };
+
+    let job = match db.get_post_payment_job(&order_id).await {
+        Ok(Some(job)) => job,
+        _ => {
+            tracing::error!("Failed to get post payment job of order {}", order_id);
+            return;
+        }
+    };
+
+    let steps = [
+        PostPaymentStepKind::Payment,
+        PostPaymentStepKind::Cart,
+        PostPaymentStepKind::Storage,
+        PostPaymentStepKind::Invoices,
+        PostPaymentStepKind::Ledger,
+        PostPaymentStepKind::Emails,
+    ];
+
+    for step in steps {
+        if job.step(step).is_done() {
+            continue;
+        }
+
+        let res = match step {
+            PostPaymentStepKind::Cart => clear_user_cart(&db, &order_id).await,
+            PostPaymentStepKind::Storage => update_storage(&db, &order_id).await,
+            PostPaymentStepKind::Invoices => {
+                create_invoices(&db, &storage_client, &invoice_client, &order_id).await
+            }
+            PostPaymentStepKind::Ledger => write_ledger(&db, &local_db, &order_id).await,
+            PostPaymentStepKind::Emails => send_order_emails(&db, &email_client, &order_id).await,
+            // The checkout records the payment once the card is charged, a job that gives up
+            // waiting for it has a charge to reconcile with the processor
+            PostPaymentStepKind::Payment => Err("Payment was not recorded"),
+        };
+
+        if let Err(err) = res {
+            retry_later(&db, &order_id, &job, step, err).await;
+            return;
+        }
+    }
+
+    if db.complete_post_payment_job(&order_id).await.is_err() {
+        // the lock will expire and the job will be picked up again,
+        // all the steps are already marked as done so nothing will run twice
+        tracing::error!("Failed to complete post payment job of order {}", order_id);
+    }
+}
+
+async fn retry_later(
+    db: &DBConection,
+    order_id: &ObjectId,
+    job: &PostPaymentJob,
+    step: PostPaymentStepKind,
+    err: &'static str,
+) {
+    let attempts = job.attempts + 1;
+
+    let next_attempt_in = if attempts >= MAX_ATTEMPTS {
+        tracing::error!(
+            "Post payment job of order {} failed on step {} after {} attempts: {}",
+            order_id,
+            step,
+            attempts,
+            err
+        );
+        None
+    } else {
+        tracing::warn!(
+            "Post payment job of order {} failed on step {} (attempt {}): {}",
+            order_id,
+            step,
+            attempts,
+            err
+        );
+        Some(backoff_secs(attempts))
+    };
+
+    if db
+        .fail_post_payment_attempt(order_id, step, err, attempts, next_attempt_in)
+        .await
+        .is_err()
+    {
+        tracing::error!("Failed to save post payment job of order {}", order_id);
+    }
+}
+
+fn backoff_secs(attempts: u32) -> i64 {
+    BACKOFF_BASE_SECS
+        .saturating_mul(2_i64.saturating_pow(attempts.saturating_sub(1)))
+        .min(BACKOFF_MAX_SECS)
+}
+
+async fn get_order(
+    db: &DBConection,
+    order_id: &ObjectId,
+    populate: Option<OrderPopulate>,
+) -> StdResult<Order, &'static str> {
+    match db.get_order_by_id(order_id, None, populate, None).await {
+        Ok(Some(order)) => Ok(order),
+        Ok(None) => Err("Order not found"),
+        Err(_) => Err("Failed to get order"),
+    }
+}
+
+// clear user cart + update his phone number if needed
+async fn clear_user_cart(db: &DBConection, order_id: &ObjectId) -> StepResult {
+    let order = get_order(db, order_id, None).await?;
+
+    let user = db
+        .get_user_by_id(order.user.ref_doc_id(), None, None, None)
+        .await
+        .map_err(|_| "Failed to get user")?;
+
+    // the user is gone, there is no cart to clear
+    if let Some(user) = user {
+        db.update_user_after_order(&user, &order)
+            .await
+            .map_err(|_| "Failed to update user after order")?;
+    }
+
+    db.complete_post_payment_step(order_id, PostPaymentStepKind::Cart, None)
+        .await
+        .map_err(|_| "Failed to complete cart step")?;
+
+    Ok(())
+}
+
+// Only runs for jobs that were created before the stock was reserved at checkout.
+// The storage update is not idempotent, so it is committed
+// together with the step status in one transaction
+async fn update_storage(db: &DBConection, order_id: &ObjectId) -> StepResult {
+    let order = get_order(db, order_id, None).await?;
+
+    let mut db_session = db
+        .start_session()
+        .await
+        .map_err(|_| "Failed to start session")?;
+
+    db_session
+        .start_transaction(None)
+        .await
+        .map_err(|_| "Failed to start transaction")?;
+
+    if db
+        .update_products_storage_by_order(&order, None, Some(&mut db_session))
+        .await
+        .is_err()
+    {
+        let _ = db_session.abort_transaction().await;
+        return Err("Failed to update products storage");
+    }
+
+    if db
+        .complete_post_payment_step(
+            order_id,
+            PostPaymentStepKind::Storage,
+            Some(&mut db_session),
+        )
+        .await
+        .is_err()
+    {
+        let _ = db_session.abort_transaction().await;
+        return Err("Failed to complete storage step");
+    }
+
+    db.commit_transaction(&mut db_session, Some(16))
+        .await
+        .map_err(|_| "Failed to commit storage transaction")?;
+
+    Ok(())
+}
+
+// Invoices that were already created on a previous attempt are reused,
+// so a retry never issues a second invoice number for the same store part.
+async fn create_invoices(
+    db: &DBConection,
+    storage_client: &StorageClient,
+    invoice_client: &InvoiceClient,
+    order_id: &ObjectId,
+) -> StepResult {
+    let order = get_order(
+        db,
+        order_id,
+        Some(OrderPopulate {
+            stores: FieldPopulate::Field,
+            products: FieldPopulate::Field,
+            user: FieldPopulate::Field,
+            options: None,
+        }),
+    )
+    .await?;
+
+    let existing_invoices = db
+        .get_invoices(
+            doc! {
+                Invoice::fields().order: order_id,
+                Invoice::fields().type_: InvoiceType::Reciept.to_string(),
+            },
+            None,
+            None,
+            None,
+        )
+        .await
+        .map_err(|_| "Failed to get existing invoices")?;
+
+    let discount = db
+        .get_order_discount(order_id)
+        .await
+        .map_err(|_| "Failed to get order discount")?;
+
+    let currency = db
+        .get_order_currency(order_id)
+        .await
+        .map_err(|_| "Failed to get order currency")?
+        .unwrap_or_else(ChargeCurrency::platform)
+        .currency;
+
+    let customer_name = order
+        .user
+        .as_populated()
+        .and_then(|user| user.name.clone())
+        .unwrap_or("לקוח כללי".to_string());
+
+    let part_taxes = db
+        .get_order_parts_tax(order_id)
+        .await
+        .map_err(|_| "Failed to get order parts tax")?;
+
+    let mut data: Vec<InvoicePart> = Vec::new();
+
+    for part in &order.parts {
+        let store_id = part.store.ref_doc_id();
+
+        let keys = StorageFolders::generate_invoice_keys(order_id, store_id, &InvoiceType::Reciept);
+
+        let original_url = storage_client
+            .generate_secure_upload_url(
+                keys[0].as_str(),
+                // 5 hours
+                18000,
+                "application/pdf",
+                Buckets::Invoice,
+            )
+            .await
+            .map_err(|_| "Failed to generate invoice upload url")?;
+
+        let copy_url = storage_client
+            .generate_secure_upload_url(
+                keys[1].as_str(),
+                18000,
+                "application/pdf",
+                Buckets::Invoice,
+            )
+            .await
+            .map_err(|_| "Failed to generate invoice upload url")?;
+
+        let existing = existing_invoices
+            .iter()
+            .find(|invoice| &invoice.store.doc_id() == store_id);
+
+        let number = match existing {
+            Some(invoice) => invoice.number.clone(),
+            None => {
+                let counter = db
+                    .get_and_increase_counter_by1("reciept", None)
+                    .await
+                    .map_err(|_| "Failed to get reciept counter")?;
+
+                let invoice_doc = Invoice::new(
+                    InvoiceType::Reciept,
+                    order_id.clone(),
+                    store_id.clone(),
+                    InvoiceType::Reciept.generate_number(counter.value),
+                    FileDocument::new(
+                        false,
+                        "original-reciept".to_string(),
+                        keys[0].clone(),
+                        0,
+                        "application/pdf".to_string(),
+                        FileTypes::Document,
+                    ),
+                    FileDocument::new(
+                        false,
+                        "copy-reciept".to_string(),
+                        keys[1].clone(),
+                        0,
+                        "application/pdf".to_string(),
+                        FileTypes::Document,
+                    ),
+                );
+
+                let number = invoice_doc.number.clone();
+
+                db.insert_new_invoice(invoice_doc, None, None)
+                    .await
+                    .map_err(|_| "Failed to insert invoice")?;
+
+                number
+            }
+        };
+
+        let store = part.store.as_populated().ok_or("Store not populated")?;
+
+        // The invoice service only takes lines and a sum, so with VAT the lines are sent
+        // without it and the VAT is a line of its own that brings them up to the sum
+        let tax = part_taxes
+            .iter()
+            .find(|entry| &entry.store == store_id)
+            .and_then(|entry| entry.tax.as_ref())
+            .filter(|tax| !tax.vat_exempt && tax.total.vat != 0.0);
+
+        let mut items = Vec::with_capacity(part.items.len() + 3);
+
+        for item in &part.items {
+            let product = item.product.as_populated().ok_or("Product not populated")?;
+
+            let image = product
+                .assets
+                .get(0)
+                .map(|asset| asset.path.clone())
+                .unwrap_or_default();
+
+            let price = match tax {
+                Some(tax) => {
+                    tax.items
+                        .iter()
+                        .find(|item_tax| item_tax.item_id == item.item_id)
+                        .ok_or("Order part tax has no breakdown for an item")?
+                        .unit
+                        .net
+                }
+                None => item.price,
+            };
+
+            items.push(InvoicePartItem {
+                name: product.name.clone(),
+                price,
+                quantity: item.quantity,
+                _id: product.id().unwrap().to_string(),
+                image,
+            });
+        }
+
+        let part_discount = discount
+            .as_ref()
+            .map(|discount| discount.for_store(store_id, currency))
+            .unwrap_or(Money::zero(currency));
+
+        // the discount is sent as a negative line, so the items add up to the part sum
+        if let Some(discount) = discount.as_ref().filter(|_| part_discount.is_positive()) {
+            items.push(InvoicePartItem {
+                name: format!("הנחה ({})", discount.code),
+                price: match tax {
+                    Some(tax) => tax.discount.net,
+                    None => -part_discount.to_major(),
+                },
+                quantity: 1,
+                _id: discount.promotion.to_string(),
+                image: String::new(),
+            });
+        }
+
+        let sum = Money::from_major(part.total, currency) - part_discount;
+
+        if let Some(tax) = tax {
+            if tax.delivery.gross > 0.0 {
+                items.push(InvoicePartItem {
+                    name: "משלוח".to_string(),
+                    price: tax.delivery.net,
+                    quantity: 1,
+                    _id: String::new(),
+                    image: String::new(),
+                });
+            }
+
+            // taken from the sum and not from the recorded VAT, the lines are rounded
+            // one by one and have to add up to what was charged
+            let net = Money::sum(
+                currency,
+                items
+                    .iter()
+                    .map(|item| Money::from_major(item.price, currency).times(item.quantity)),
+            );
+
+            items.push(InvoicePartItem {
+                name: format!("מע\"מ {}%", tax.vat_percent),
+                price: (sum - net).to_major(),
+                quantity: 1,
+                _id: String::new(),
+                image: String::new(),
+            });
+        }
+
+        data.push(InvoicePart {
+            store: InvoiceStore {
+                display_name: store.name.clone(),
+                legal_name: store.legal_information.name.clone(),
+                legal_id: store.legal_information.legal_id.clone(),
+                address: store.legal_information.address.clone(),
+                store_user_email: store.contact.email.clone(),
+            },
+            order_id: order_id.to_string(),
+            invoice_number: number,
+            upload_urls: InvoicePartUploadUrls {
+                original_url,
+                copy_url,
+            },
+            cc_hint: order.transaction.gen_cc_hint(),
+            sum: sum.to_major(),
+            customer: InvoiceCustomer {
+                name: customer_name.clone(),
+                id: order.info.customer_id.clone(),
+                address: order.address.clone().to_invoice(),
+                phone_number: order.info.phone_number.clone(),
+                email: order.info.email.clone(),
+            },
+            items,
+        });
+    }
+
+    // If we crash after this call and before the step is saved, the invoice
+    // service will get the same invoice numbers again on the next attempt
+    invoice_client
+        .create_invoices(data)
+        .await
+        .map_err(|_| "Failed to create invoices")?;
+
+    db.complete_post_payment_step(order_id, PostPaymentStepKind::Invoices, None)
+        .await
+        .map_err(|_| "Failed to complete invoices step")?;
+
+    Ok(())
+}
+
+// One sale transaction per store part. The transactions are keyed by the order and the store,
+// so parts that were written on a previous attempt are not written again
+async fn write_ledger(
+    db: &DBConection,
+    local_db: &LocalDBConection,
+    order_id: &ObjectId,
+) -> StepResult {
+    let order = get_order(db, order_id, None).await?;
+
+    let discount = db
+        .get_order_discount(order_id)
+        .await
+        .map_err(|_| "Failed to get order discount")?;
+
+    let promotion = match &discount {
+        Some(discount) => local_db
+            .get_promotion_by_id(&discount.promotion)
+            .await
+            .map_err(|_| "Failed to get order promotion")?,
+        None => None,
+    };
+
+    // a free delivery discount comes off the delivery, any other discount off the items
+    let free_delivery = promotion
+        .as_ref()
+        .map(|promotion| promotion.discount == PromotionDiscount::FreeDelivery)
+        .unwrap_or(false);
+
+    // A store promotion is paid by the store, a platform wide one by the platform
+    let platform_funded = promotion
+        .as_ref()
+        .map(|promotion| promotion.store.is_none())
+        .unwrap_or(false);
+
+    let part_payments = db
+        .get_order_part_payments(order_id)
+        .await
+        .map_err(|_| "Failed to get order part payments")?;
+
+    let commissions = db
+        .get_order_parts_commission(order_id)
+        .await
+        .map_err(|_| "Failed to get order parts commission")?;
+
+    // The order amounts are in the charge currency, the store balances in the store currency
+    let charge_currency = db
+        .get_order_currency(order_id)
+        .await
+        .map_err(|_| "Failed to get order currency")?
+        .unwrap_or_else(ChargeCurrency::platform);
+
+    let store_currencies = db
+        .get_stores_currency(
+            &order
+                .parts
+                .iter()
+                .map(|part| part.store.ref_doc_id().clone())
+                .collect::<Vec<_>>(),
+        )
+        .await
+        .map_err(|_| "Failed to get stores currency")?;
+
+    for part in &order.parts {
+        let store_id = part.store.ref_doc_id();
+
+        // the part was canceled or charged back before the ledger was written,
+        // there is nothing to reverse
+        let voided = part_payments.iter().any(|entry| {
+            &entry.store == store_id
+                && matches!(
+                    entry.payment.status,
+                    OrderPartPaymentStatus::Voided
+                        | OrderPartPaymentStatus::Expired
+                        | OrderPartPaymentStatus::ChargedBack
+                )
+        });
+
+        if voided {
+            continue;
+        }
+
+        let charged = charge_currency.currency;
+
+        let part_discount = discount
+            .as_ref()
+            .map(|discount| discount.for_store(store_id, charged))
+            .unwrap_or(Money::zero(charged));
+
+        let mut items_total = Money::sum(
+            charged,
+            part.items
+                .iter()
+                .map(|item| Money::from_major(item.price, charged).times(item.quantity)),
+        );
+
+        let mut delivery_cost =
+            (Money::from_major(part.total, charged) - items_total).max(Money::zero(charged));
+
+        let platform_discount = if platform_funded {
+            part_discount
+        } else {
+            if free_delivery {
+                delivery_cost -= part_discount;
+            } else {
+                items_total -= part_discount;
+            }
+
+            Money::zero(charged)
+        };
+
+        let commission = commissions
+            .iter()
+            .find(|entry| &entry.store == store_id)
+            .and_then(|entry| entry.commission.clone())
+            .unwrap_or_else(|| OrderPartCommission::default_for(items_total));
+
+        let store_currency = store_currencies.get(store_id).copied().unwrap_or_default();
+
+        let to_store = |amount: Money| {
+            charge_currency
+                .to_store(amount.to_major(), store_currency)
+                .map(|amount| Money::from_major(amount, store_currency))
+                .map_err(|_| "Order currency has no rate for the store currency")
+        };
+
+        let transaction = LedgerTransaction::sale(LedgerSale {
+            order: order_id.clone(),
+            store: store_id.clone(),
+            items_total: to_store(items_total)?,
+            delivery_cost: to_store(delivery_cost)?,
+            commission: to_store(Money::from_major(commission.amount, charged))?,
+            platform_discount: to_store(platform_discount)?,
+        });
+
+        local_db
+            .insert_ledger_transaction(&transaction)
+            .await
+            .map_err(|_| "Failed to write ledger transaction")?;
+    }
+
+    db.complete_post_payment_step(order_id, PostPaymentStepKind::Ledger, None)
+        .await
+        .map_err(|_| "Failed to complete ledger step")?;
+
+    Ok(())
+}
+
+// The customer gets a confirmation and each store a new order email.
+// Emails are sent once, a failed send is logged and not retried
+// so nobody gets the same email twice
+async fn send_order_emails(
+    db: &DBConection,
+    email_client: &EmailClient,
+    order_id: &ObjectId,
+) -> StepResult {
+    let order = get_order(
+        db,
+        order_id,
+        Some(OrderPopulate {
+            stores: FieldPopulate::Field,
+            products: FieldPopulate::None,
+            user: FieldPopulate::Field,
+            options: None,
+        }),
+    )
+    .await?;
+
+    let currency = db
+        .get_order_currency(order_id)
+        .await
+        .map_err(|_| "Failed to get order currency")?
+        .unwrap_or_else(ChargeCurrency::platform)
+        .currency;
+
+    let customer_name = order
+        .user
+        .as_populated()
+        .and_then(|user| user.name.clone())
+        .unwrap_or_default();
+
+    if !ENV_VARS.ORDER_CONFIRMATION_TEMPLATE_ID.is_empty() {
+        let status_token = ORDER_EMAIL_TOKEN_MANAGER
+            .generate_urlsafe_token(&order, None)
+            .map_err(|_| "Failed to create order status token")?;
+
+        let email = email_client
+            .order_confirmation_email(
+                customer_name.clone(),
+                order.order_number.clone(),
+                format!("{:.2} {}", order.total, currency),
+                status_token,
+            )
+            .add_to((order.info.email.clone(), customer_name).into())
+            .build();
+
+        if email_client.send(email).await.is_err() {
+            tracing::error!("Failed to send confirmation of order {}", order_id);
+        }
+    }
+
+    if !ENV_VARS.STORE_NEW_ORDER_TEMPLATE_ID.is_empty() {
+        for part in &order.parts {
+            let store = part.store.as_populated().ok_or("Store not populated")?;
+
+            let email = email_client
+                .new_order_email(
+                    store.name.clone(),
+                    order_id.to_string(),
+                    order.order_number.clone(),
+                    part.items.iter().map(|item| item.quantity).sum(),
+                    format!("{:.2} {}", part.total, currency),
+                )
+                .add_to((store.contact.email.clone(), store.name.clone()).into())
+                .build();
+
+            if email_client.send(email).await.is_err() {
+                tracing::error!(
+                    "Failed to send new order email of order {} to store {}",
+                    order_id,
+                    part.store.ref_doc_id()
+                );
+            }
+        }
+    }
+
+    db.complete_post_payment_step(order_id, PostPaymentStepKind::Emails, None)
+        .await
+        .map_err(|_| "Failed to complete emails step")?;
+
+    Ok(())
+}
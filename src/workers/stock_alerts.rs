@@ -0,0 +1,150 @@
+use crate::{
+    db::{
+        is_item_available, CurrencyFunctions, LocalDBConection, StockAlert, StockAlertFunctions,
+        StockAlertKind, StockAlertRecipientFunctions,
+    },
+    emails::CustomerEmailFunctions,
+};
+use shoppa_core::{
+    db::{models::EmbeddedDocument, DBConection},
+    email_sender::EmailClient,
+};
+use std::{sync::Arc, time::Duration};
+
+// How often the worker looks for triggered alerts
+const POLL_INTERVAL_SECS: u64 = 60;
+// How many alerts to send on each poll
+const ALERTS_PER_POLL: usize = 100;
+
+/// Runs forever, emailing users about items that are back in stock or got cheaper.
+/// Does nothing when there are no alert templates.
+pub async fn run_stock_alerts_worker(
+    db: Arc<DBConection>,
+    local_db: Arc<LocalDBConection>,
+    email_client: Arc<EmailClient>,
+) {
+    let kinds = [StockAlertKind::BackInStock, StockAlertKind::PriceDrop]
+        .into_iter()
+        .filter(|kind| !kind.template_id().is_empty())
+        .collect::<Vec<_>>();
+
+    if kinds.is_empty() {
+        return;
+    }
+
+    let mut interval = tokio::time::interval(Duration::from_secs(POLL_INTERVAL_SECS));
+
+    loop {
+        interval.tick().await;
+
+        for _ in 0..ALERTS_PER_POLL {
+            // Taking the alert resets its trigger, an alert that was not sent
+            // waits for the item to change again
+            let alert = match local_db.take_triggered_stock_alert(&kinds).await {
+                Ok(Some(alert)) => alert,
+                Ok(None) => break,
+                Err(_) => {
+                    tracing::error!("Failed to get triggered stock alerts");
+                    break;
+                }
+            };
+
+            match send_stock_alert(&db, &email_client, &alert).await {
+                Ok(true) => {
+                    if local_db.deactivate_stock_alert(&alert.id).await.is_err() {
+                        tracing::error!("Failed to deactivate stock alert {}", alert.id);
+                    }
+                }
+                Ok(false) => {}
+                Err(error) => {
+                    tracing::error!("Failed to send stock alert {}: {}", alert.id, error);
+                }
+            }
+        }
+    }
+}
+
+/// Returns true if the alert is done with, it was sent or it can never be sent
+async fn send_stock_alert(
+    db: &DBConection,
+    email_client: &EmailClient,
+    alert: &StockAlert,
+) -> std::result::Result<bool, &'static str> {
+    let recipient = match db
+        .get_stock_alert_recipient(&alert.user)
+        .await
+        .map_err(|_| "Failed to get user")?
+    {
+        Some(recipient) => recipient,
+        None => return Ok(true),
+    };
+
+    let product = match db
+        .get_product_by_id(&alert.product, None, None, None)
+        .await
+        .map_err(|_| "Failed to get product")?
+    {
+        Some(product) => product,
+        None => return Ok(true),
+    };
+
+    let item = match product
+        .items
+        .iter()
+        .find(|item| item.id() == &alert.item_id)
+    {
+        Some(item) => item,
+        None => return Ok(true),
+    };
+
+    // the item may have changed again since the alert was triggered,
+    // the alert is kept for the next time it's triggered
+    if alert.kind == StockAlertKind::BackInStock && !is_item_available(&product, item) {
+        return Ok(false);
+    }
+
+    if alert.kind == StockAlertKind::PriceDrop && item.price >= alert.price {
+        return Ok(false);
+    }
+
+    let store_id = product.store.ref_doc_id();
+
+    let currency = db
+        .get_stores_currency(&[store_id.clone()])
+        .await
+        .map_err(|_| "Failed to get store currency")?
+        .remove(store_id)
+        .unwrap_or_default();
+
+    let product_name = match &item.name {
+        Some(item_name) => format!("{} - {}", product.name, item_name),
+        None => product.name.clone(),
+    };
+
+    let name = recipient.name.clone().unwrap_or_default();
+
+    let builder = match alert.kind {
+        StockAlertKind::BackInStock => email_client.back_in_stock_email(
+            name.clone(),
+            product_name,
+            alert.product.to_string(),
+            format!("{:.2} {}", item.price, currency),
+        ),
+        StockAlertKind::PriceDrop => email_client.price_drop_email(
+            name.clone(),
+            product_name,
+            alert.product.to_string(),
+            format!("{:.2} {}", alert.price, currency),
+            format!("{:.2} {}", item.price, currency),
+        ),
+    };
+
+    let email = builder.add_to((recipient.email, name).into()).build();
+
+    email_client
+        .send(email)
+        .await
+        .map_err(|_| "Failed to send email")?;
+
+    Ok(true)
+}